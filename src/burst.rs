@@ -0,0 +1,186 @@
+//! Burst sequences: the dozens of near-identical frames a phone shoots while the
+//! shutter is held down.
+//!
+//! iPhones tag every frame of a burst with the same `BurstUUID` in the Apple
+//! maker note. Android cameras instead name the frames, either after the burst's
+//! start time (`00001IMG_00001_BURST20190830123456789.jpg`) or with a frame
+//! number (`IMG_20190830_123456_BURST002.jpg`), and mark the frame shown for the
+//! burst with `_COVER`.
+
+use crate::exif_util::TAG_BURST_UUID;
+use crate::media::MediaFileInfo;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BurstInfo {
+    /// Shared by every frame of the burst, safe to use as a directory name.
+    pub(crate) id: String,
+    /// The frame that stands for the whole burst.
+    pub(crate) cover: bool,
+}
+
+/// Burst membership of the given (already deduplicated) media files, keyed by
+/// long checksum. Files not in a burst are left out.
+///
+/// Android marks its cover frame; iPhone bursts carry no such mark once
+/// exported, so their earliest frame by name is taken as the cover.
+pub(crate) fn find_bursts(media: &[&MediaFileInfo]) -> HashMap<String, BurstInfo> {
+    let mut frames_by_id: BTreeMap<String, Vec<(String, bool, &MediaFileInfo)>> = BTreeMap::new();
+    for m in media {
+        let apple_id = m
+            .exif_info
+            .as_ref()
+            .and_then(|e| e.tags.get(TAG_BURST_UUID))
+            .map(|id| (id.clone(), false));
+        let Some((id, marked_cover)) =
+            apple_id.or_else(|| m.original_path.iter().find_map(|p| android_burst(p)))
+        else {
+            continue;
+        };
+        let mut names = m.original_path.clone();
+        names.sort();
+        let first_name = names.into_iter().next().unwrap_or_default();
+        frames_by_id
+            .entry(sanitize_id(&id))
+            .or_default()
+            .push((first_name, marked_cover, m));
+    }
+
+    let mut bursts = HashMap::new();
+    for (id, mut frames) in frames_by_id {
+        frames.sort_by(|a, b| a.0.cmp(&b.0));
+        let cover = frames
+            .iter()
+            .position(|(_, marked_cover, _)| *marked_cover)
+            .unwrap_or(0);
+        for (i, (_, _, m)) in frames.iter().enumerate() {
+            bursts.insert(
+                m.hash_info.long_checksum.clone(),
+                BurstInfo {
+                    id: id.clone(),
+                    cover: i == cover,
+                },
+            );
+        }
+    }
+    bursts
+}
+
+/// Burst id and cover flag from an Android burst frame's name.
+fn android_burst(path: &str) -> Option<(String, bool)> {
+    let stem = Path::new(path).file_stem()?.to_string_lossy().to_string();
+    let upper = stem.to_uppercase();
+    let pos = upper.rfind("BURST")?;
+    let after = &upper[pos + "BURST".len()..];
+    let digits: String = after.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let cover = match &after[digits.len()..] {
+        "" => false,
+        "_COVER" => true,
+        _ => return None,
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    // A start time identifies the burst by itself; next to a frame number it's
+    // the rest of the name that does.
+    let id = if digits.len() >= 14 {
+        digits
+    } else {
+        stem.get(..pos)
+            .unwrap_or_default()
+            .trim_end_matches('_')
+            .to_string()
+    };
+    (!id.is_empty()).then_some((id, cover))
+}
+
+fn sanitize_id(id: &str) -> String {
+    id.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect()
+}
+
+/// `yyyy/mm/dd/hhmm-ssms` -> `yyyy/mm/dd/bursts/<id>/hhmm-ssms`, keeping a
+/// burst's frames out of the day's own listing.
+pub(crate) fn burst_media_path(desired_media_path: &str, burst_id: &str) -> String {
+    match desired_media_path.rsplit_once('/') {
+        Some((day, name)) => format!("{day}/bursts/{burst_id}/{name}"),
+        None => format!("bursts/{burst_id}/{desired_media_path}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(path: &str, checksum: &str, burst_uuid: Option<&str>) -> MediaFileInfo {
+        let m = MediaFileInfo::new_for_test()
+            .with_path(path)
+            .with_checksum(checksum);
+        match burst_uuid {
+            Some(id) => m.with_exif_tag(TAG_BURST_UUID, id),
+            None => m,
+        }
+    }
+
+    #[test]
+    fn test_android_burst() {
+        assert_eq!(
+            android_burst("DCIM/00000IMG_00000_BURST20190830123456789_COVER.jpg"),
+            Some(("20190830123456789".to_string(), true))
+        );
+        assert_eq!(
+            android_burst("DCIM/00001IMG_00001_BURST20190830123456789.jpg"),
+            Some(("20190830123456789".to_string(), false))
+        );
+        assert_eq!(
+            android_burst("DCIM/IMG_20190830_123456_BURST002.jpg"),
+            Some(("IMG_20190830_123456".to_string(), false))
+        );
+        assert_eq!(
+            android_burst("DCIM/IMG_20190830_123456_BURST001_COVER.JPG"),
+            Some(("IMG_20190830_123456".to_string(), true))
+        );
+        assert_eq!(android_burst("DCIM/IMG_1234.jpg"), None);
+        assert_eq!(android_burst("DCIM/BURST.jpg"), None);
+        assert_eq!(android_burst("DCIM/BURST001_COVER.jpg"), None);
+    }
+
+    #[test]
+    fn test_find_bursts() {
+        let a1 = frame("Photos/IMG_0011.HEIC", "aaaa", Some("0C5B-11"));
+        let a2 = frame("Photos/IMG_0010.HEIC", "bbbb", Some("0C5B-11"));
+        let g1 = frame("DCIM/IMG_20190830_123456_BURST002.jpg", "cccc", None);
+        let g2 = frame("DCIM/IMG_20190830_123456_BURST001_COVER.jpg", "dddd", None);
+        let lone = frame("DCIM/IMG_1234.jpg", "eeee", None);
+        let bursts = find_bursts(&[&a1, &a2, &g1, &g2, &lone]);
+        assert_eq!(
+            bursts.get("bbbb"),
+            Some(&BurstInfo {
+                id: "0C5B-11".to_string(),
+                cover: true
+            })
+        );
+        assert_eq!(bursts.get("aaaa").map(|b| b.cover), Some(false));
+        assert_eq!(bursts.get("dddd").map(|b| b.cover), Some(true));
+        assert_eq!(bursts.get("cccc").map(|b| b.cover), Some(false));
+        assert_eq!(
+            bursts.get("cccc").map(|b| b.id.as_str()),
+            Some("IMG_20190830_123456")
+        );
+        assert!(!bursts.contains_key("eeee"));
+    }
+
+    #[test]
+    fn test_burst_media_path() {
+        assert_eq!(
+            burst_media_path("2019/08/30/1234-56000", "ABC"),
+            "2019/08/30/bursts/ABC/1234-56000"
+        );
+        assert_eq!(
+            burst_media_path("undated/6bfdabd", "ABC"),
+            "undated/bursts/ABC/6bfdabd"
+        );
+    }
+}
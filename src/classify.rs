@@ -68,6 +68,7 @@ pub(crate) enum KnownFileType {
     PhotoWithGuid(String),
     GpCollage(String),
     GpAnimation(String),
+    GpMovie(String),
     GpPrintSubscription,
     GpSharedAlbumComments,
     GpUserGeneratedMemoryTitles,
@@ -89,10 +90,38 @@ impl KnownFileType {
             | KnownFileType::PhotoWithGuid(v)
             | KnownFileType::GpCollage(v)
             | KnownFileType::GpAnimation(v)
+            | KnownFileType::GpMovie(v)
             | KnownFileType::IcpAlbumCsv(v) => Some(v.clone()),
             _ => None,
         }
     }
+
+    /// What Google made, for files it generated rather than the camera took:
+    /// `animation`, `collage` or `movie`.
+    pub(crate) fn generated_kind(&self) -> Option<&'static str> {
+        match self {
+            KnownFileType::GpAnimation(_) => Some("animation"),
+            KnownFileType::GpCollage(_) => Some("collage"),
+            KnownFileType::GpMovie(_) => Some("movie"),
+            _ => None,
+        }
+    }
+}
+
+/// A file Google Photos generated from others, as its kind (see
+/// [`KnownFileType::generated_kind`]) and the timestamp id it was named after,
+/// e.g. `20190830_123456-ANIMATION.gif` -> `("animation", "20190830_123456")`.
+pub(crate) fn generated_file(file_path: &str) -> Option<(&'static str, String)> {
+    let known = classify_file(file_path)?;
+    Some((known.generated_kind()?, known.value()?))
+}
+
+/// What Google made, if any of a media file's original paths names it as generated.
+pub(crate) fn generated_kind(original_paths: &[String]) -> Option<&'static str> {
+    original_paths
+        .iter()
+        .find_map(|p| generated_file(p))
+        .map(|(kind, _)| kind)
 }
 
 fn match_re(haystack: &str, re: &Regex) -> Option<PatternMatch> {
@@ -163,6 +192,7 @@ fn make_file_patterns() -> Vec<(Vec<Regex>, MatchingFilePatternFn)> {
         (&[r"^([\d_]+)-collage.jpg$"], |m| {
             KnownFileType::GpCollage(m.g1)
         }),
+        (&[r"^([\d_]+)-movie.mp4$"], |m| KnownFileType::GpMovie(m.g1)),
         (&[r"^print-subscriptions.json$"], |_| {
             KnownFileType::GpPrintSubscription
         }),
@@ -303,6 +333,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_generated_file() {
+        crate::test_util::setup_log();
+        assert_eq!(
+            generated_file("Photos from 2019/20190830_123456-ANIMATION.gif"),
+            Some(("animation", "20190830_123456".to_string()))
+        );
+        assert_eq!(
+            generated_file("Photos from 2019/IMG_20190830_123456-ANIMATION.gif"),
+            Some(("animation", "20190830_123456".to_string()))
+        );
+        assert_eq!(
+            generated_file("Photos from 2019/20190830_123456-COLLAGE.jpg"),
+            Some(("collage", "20190830_123456".to_string()))
+        );
+        assert_eq!(
+            generated_file("Photos from 2019/20190830_123456-MOVIE.mp4"),
+            Some(("movie", "20190830_123456".to_string()))
+        );
+        assert_eq!(
+            generated_file("Photos from 2019/IMG_20190830_123456.jpg"),
+            None
+        );
+    }
+
     #[test]
     fn test_enum_to_string() {
         crate::test_util::setup_log();
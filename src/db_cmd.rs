@@ -164,6 +164,9 @@ fn db_classify_paths(conn: &Connection, files: &[ScanInfo]) -> anyhow::Result<()
 pub(crate) struct HashInfo {
     pub(crate) short_checksum: String,
     pub(crate) long_checksum: String,
+    /// Checksum of the image data alone, see [`crate::payload`]
+    #[serde(default)]
+    pub(crate) payload_checksum: Option<String>,
 }
 
 fn db_record(conn: &Connection, info: &MediaFileInfo) -> anyhow::Result<()> {
@@ -182,6 +185,8 @@ fn db_record(conn: &Connection, info: &MediaFileInfo) -> anyhow::Result<()> {
         accurate_file_type: info.accurate_file_type.clone().to_string(),
         guessed_datetime,
         file_size: info.file_size as i64,
+        perceptual_hash: info.perceptual_hash.clone(),
+        payload_hash: info.hash_info.payload_checksum.clone(),
     };
     let mut stmt = conn.prepare_cached(DB_MEDIA_ITEM_INSERT)?;
     stmt.execute((
@@ -195,6 +200,8 @@ fn db_record(conn: &Connection, info: &MediaFileInfo) -> anyhow::Result<()> {
         &item.modified_at,
         &item.created_at,
         &item.file_size,
+        &item.perceptual_hash,
+        &item.payload_hash,
     ))?;
 
     Ok(())
@@ -214,6 +221,10 @@ struct DbMediaItem {
     created_at: i64,
     // file size in bytes
     file_size: i64,
+    // dHash of the pixels, as hex
+    perceptual_hash: Option<String>,
+    // sha256 of the image data alone, as hex
+    payload_hash: Option<String>,
 }
 const DB_MEDIA_ITEM_CREATE: &str = "
     CREATE TABLE IF NOT EXISTS media_item  (
@@ -227,13 +238,20 @@ const DB_MEDIA_ITEM_CREATE: &str = "
         guessed_datetime DATETIME,
         modified_at DATETIME DEFAULT CURRENT_TIMESTAMP, -- file last modified
         created_at DATETIME DEFAULT CURRENT_TIMESTAMP, -- file created
-        file_size INTEGER -- size of the file in bytes
+        file_size INTEGER, -- size of the file in bytes
+        perceptual_hash TEXT, -- 64 bit dHash of the pixels as hex, for finding similar photos
+        payload_hash TEXT -- sha256 of the image data without metadata, for photos that differ only in metadata
     )
 ";
+/// Columns `media_item` has gained since it was first created. A table left by
+/// an earlier version is given them before anything is inserted.
+const DB_MEDIA_ITEM_ADDED_COLUMNS: [(&str, &str); 2] =
+    [("perceptual_hash", "TEXT"), ("payload_hash", "TEXT")];
 const DB_MEDIA_ITEM_INSERT: &str = "
     INSERT INTO media_item (media_path, long_hash, short_hash, quick_file_type,
-        accurate_file_type, media_info, guessed_datetime, modified_at, created_at, file_size)
-    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        accurate_file_type, media_info, guessed_datetime, modified_at, created_at, file_size,
+        perceptual_hash, payload_hash)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
 ";
 const DB_MEDIA_ITEM_DELETE_ALL: &str = "
     DELETE FROM media_item
@@ -302,6 +320,7 @@ fn db_conn(path: &str) -> anyhow::Result<Connection> {
 
 fn db_prepare(conn: &Connection) -> anyhow::Result<()> {
     conn.execute(DB_MEDIA_ITEM_CREATE, ())?;
+    add_missing_columns(conn, "media_item", &DB_MEDIA_ITEM_ADDED_COLUMNS)?;
     conn.execute(DB_ALBUM_CREATE, ())?;
     conn.execute(DB_ALBUM_FILE_CREATE, ())?;
     conn.execute(DB_CLASSIFIED_FILE_CREATE, ())?;
@@ -317,6 +336,25 @@ fn db_prepare(conn: &Connection) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Add each of `columns`, a name and type, that `table` lacks.
+fn add_missing_columns(
+    conn: &Connection,
+    table: &str,
+    columns: &[(&str, &str)],
+) -> anyhow::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let existing = stmt
+        .query_map((), |row| row.get::<_, String>(1))?
+        .collect::<Result<HashSet<String>, _>>()?;
+    for (name, kind) in columns {
+        if !existing.contains(*name) {
+            info!("Adding column {name} to {table}");
+            conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {name} {kind}"), ())?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -341,6 +379,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_db_prepare_upgrades_old_media_item() -> anyhow::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        // The table as the first version of `db` created it
+        conn.execute(
+            "CREATE TABLE media_item (
+                media_item_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                media_path TEXT NOT NULL,
+                long_hash TEXT,
+                short_hash TEXT,
+                quick_file_type TEXT,
+                accurate_file_type TEXT,
+                media_info TEXT,
+                guessed_datetime DATETIME,
+                modified_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                file_size INTEGER
+            )",
+            (),
+        )?;
+        db_prepare(&conn)?;
+        // Preparing fails on a column the table lacks
+        conn.prepare(DB_MEDIA_ITEM_INSERT)?;
+        // and a second run leaves the table as it is
+        db_prepare(&conn)?;
+        Ok(())
+    }
+
     #[test]
     fn test_db_scan() -> anyhow::Result<()> {
         crate::test_util::setup_log();
@@ -373,6 +439,25 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_db_scan_records_xmp() -> anyhow::Result<()> {
+        crate::test_util::setup_log();
+        let conn = Connection::open_in_memory()?;
+        let container: Arc<dyn FileSystem> = Arc::new(OsFileSystem::new("test/xmp"));
+        run_db_scan(container, &conn)?;
+
+        let media_info: String = conn.query_row(
+            "SELECT media_info FROM media_item WHERE media_path = 'IMG_0010.jpg'",
+            [],
+            |r| r.get(0),
+        )?;
+        let info: MediaFileInfo = serde_json::from_str(&media_info)?;
+        let xmp = info.xmp_info.ok_or_else(|| anyhow!("No XMP recorded"))?;
+        assert_eq!(xmp.rating, Some(4));
+        assert_eq!(xmp.keywords, vec!["beach", "sunset"]);
+        Ok(())
+    }
+
     #[test]
     fn test_db_scan_classifies_paths() -> anyhow::Result<()> {
         crate::test_util::setup_log();
@@ -1,8 +1,13 @@
-use crate::fs::{FileSystem, OsFileSystem};
-use crate::media::{MediaFileDerivedInfo, MediaFileInfo};
-use crate::util::is_existing_file_same;
+use crate::exif_util::best_guess_taken_exif;
+use crate::fs::Output;
+use crate::media::{MediaFileDerivedInfo, MediaFileInfo, media_dimensions};
+use crate::relate::is_edited_name;
+use crate::util::existing_file_checksum;
 use anyhow::anyhow;
+use nom_exif::ExifTag;
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::path::Path;
 use tracing::{debug, warn};
 
 #[derive(Debug, PartialEq)]
@@ -14,43 +19,232 @@ pub(crate) enum DeDuplicationResult {
     SkipWrite(String),
 }
 
-/// Collects inspected media and removes duplicates. It performs two jobs:
+/// Collects inspected media and removes duplicates, in three ways:
 ///
 /// 1. *Content dedup* - files that share a long (sha256) checksum are the same
 ///    bytes. [`Deduplicator::add`] keeps a single entry per checksum and records every original
 ///    path that resolved to it.
-/// 2. *Path dedup* - [`Deduplicator::resolve_output_path`] finds a free name in
+/// 2. *Shot dedup* - [`Deduplicator::merge_same_shots`] folds together copies of
+///    the same shot that different sources exported with different bytes, e.g.
+///    an iCloud original and the Takeout copy Google rewrote the metadata of.
+/// 3. *Path dedup* - [`Deduplicator::resolve_output_path`] finds a free name in
 ///    the output directory, reusing an existing file when its checksum matches
 ///    and otherwise suffixing the name with the file's own checksum.
 ///
-/// Both are deliberately deterministic: re-running over the same input produces
+/// All three are deliberately deterministic: re-running over the same input produces
 /// the same canonical entries, the same write order, and the same output names
 /// every time. Parallel inspection yields files in whatever order the worker
 /// threads happen to finish, so anything order-sensitive has to be pinned here
 /// rather than left to that race.
 pub(crate) struct Deduplicator {
     by_checksum: HashMap<String, MediaFileInfo>,
+    policy: KeepPolicy,
+}
+
+/// Something that makes one copy of a photo better to keep than another.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub(crate) enum KeepRule {
+    /// The unedited copy, rather than an `-edited` or `IMG_E` one
+    Original,
+    /// The copy with more pixels
+    Resolution,
+    /// The copy with an EXIF capture date and GPS position
+    Metadata,
+    /// The copy from the preferred source
+    Source,
+}
+
+/// Where a copy of a photo came from, as far as keeping it goes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub(crate) enum PhotoSource {
+    /// Anything not exported by Google: iCloud originals, camera folders
+    #[default]
+    Icloud,
+    /// Google Takeout, which rewrites metadata and may hold "storage saver" copies
+    Google,
+}
+
+/// The rules applied by default, in order.
+pub(crate) const DEFAULT_KEEP_ORDER: [KeepRule; 4] = [
+    KeepRule::Original,
+    KeepRule::Resolution,
+    KeepRule::Metadata,
+    KeepRule::Source,
+];
+
+/// Which copy of a photo is kept when copies are merged: the first rule that
+/// tells two copies apart decides, and when none does, the copy whose source
+/// path sorts first. Every rule looks only at the copies themselves, so the
+/// choice never depends on the order files were inspected in.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct KeepPolicy {
+    pub(crate) order: Vec<KeepRule>,
+    pub(crate) preferred_source: PhotoSource,
+}
+
+impl Default for KeepPolicy {
+    fn default() -> Self {
+        KeepPolicy {
+            order: DEFAULT_KEEP_ORDER.to_vec(),
+            preferred_source: PhotoSource::default(),
+        }
+    }
+}
+
+impl KeepPolicy {
+    /// The first rule preferring one copy over the other, with whether it prefers `a`.
+    fn deciding_rule(&self, a: &MediaFileInfo, b: &MediaFileInfo) -> Option<(KeepRule, bool)> {
+        self.order.iter().find_map(
+            |rule| match self.score(*rule, a).cmp(&self.score(*rule, b)) {
+                Ordering::Equal => None,
+                ordering => Some((*rule, ordering == Ordering::Greater)),
+            },
+        )
+    }
+
+    /// How well a copy does by one rule; higher is better.
+    fn score(&self, rule: KeepRule, m: &MediaFileInfo) -> u64 {
+        match rule {
+            KeepRule::Original => u64::from(!is_edited_name(&m.original_file_this_run)),
+            KeepRule::Resolution => match media_dimensions(m) {
+                (Some(width), Some(height)) => width * height,
+                _ => 0,
+            },
+            KeepRule::Metadata => {
+                let has_date = best_guess_taken_exif(&m.exif_info).is_some();
+                let has_gps = m.exif_info.as_ref().is_some_and(|e| e.latitude.is_some());
+                u64::from(has_date) + u64::from(has_gps)
+            }
+            KeepRule::Source => {
+                u64::from((self.preferred_source == PhotoSource::Google) == from_google(m))
+            }
+        }
+    }
+
+    /// Whether `a` is kept rather than `b`.
+    pub(crate) fn keeps(&self, a: &MediaFileInfo, b: &MediaFileInfo) -> bool {
+        match self.deciding_rule(a, b) {
+            Some((_, prefers_a)) => prefers_a,
+            None => a.original_file_this_run <= b.original_file_this_run,
+        }
+    }
+
+    /// Why `kept` was kept rather than `other`, for reports.
+    pub(crate) fn why_kept(&self, kept: &MediaFileInfo, other: &MediaFileInfo) -> String {
+        let reason = match self.deciding_rule(kept, other) {
+            Some((KeepRule::Original, _)) => "the original rather than an edit",
+            Some((KeepRule::Resolution, _)) => "more pixels",
+            Some((KeepRule::Metadata, _)) => "more of the EXIF date and GPS position",
+            Some((KeepRule::Source, _)) => match self.preferred_source {
+                PhotoSource::Icloud => "not exported by Google, so not rewritten by it",
+                PhotoSource::Google => "exported by Google, the preferred source",
+            },
+            None => "its source path sorts first",
+        };
+        reason.to_string()
+    }
+}
+
+/// Whether a copy came out of Google Takeout, going by where it was found
+/// rather than its supplemental metadata, which a merge may hand to another copy.
+fn from_google(m: &MediaFileInfo) -> bool {
+    is_google_path(&m.original_file_this_run)
+}
+
+/// Whether a source path is inside a Google Takeout export.
+pub(crate) fn is_google_path(path: &str) -> bool {
+    path.starts_with("Takeout/")
+        || path.starts_with("Google Photos/")
+        || path.contains("/Google Photos/")
+}
+
+impl Default for Deduplicator {
+    fn default() -> Self {
+        Self::with_policy(KeepPolicy::default())
+    }
 }
 
 impl Deduplicator {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn with_policy(policy: KeepPolicy) -> Self {
         Self {
             by_checksum: HashMap::new(),
+            policy,
         }
     }
 
+    pub(crate) fn policy(&self) -> &KeepPolicy {
+        &self.policy
+    }
+
     /// Fold one inspected file into the collection, collapsing it onto any
     /// existing entry that has the same content hash.
     pub(crate) fn add(&mut self, media: MediaFileInfo) {
         let checksum = media.hash_info.long_checksum.clone();
         match self.by_checksum.get_mut(&checksum) {
-            Some(existing) => merge_into(existing, media),
+            Some(existing) => merge_into(existing, media, &self.policy),
             None => {
                 self.by_checksum.insert(checksum, media);
             }
         }
     }
 
+    /// Fold entries that are the same shot under different checksums into one,
+    /// once every file has been added.
+    ///
+    /// Two files are the same shot when they have the same file name (ignoring
+    /// case and the `(1)` Takeout adds to clashing names) and either the same
+    /// image data (payload checksum) or the same type, EXIF capture time (to the
+    /// sub-second), camera and dimensions. The name keeps edits, collages and
+    /// burst frames, which share their original's EXIF, apart from it.
+    ///
+    /// The copy the [`KeepPolicy`] prefers is kept, by default the one Google
+    /// didn't export, as Google rewrites what it exports; the other's paths, checksum and
+    /// supplemental metadata are recorded on it. Entries are visited in
+    /// checksum order so the result doesn't depend on inspection order.
+    pub(crate) fn merge_same_shots(&mut self) {
+        let mut checksums: Vec<String> = self.by_checksum.keys().cloned().collect();
+        checksums.sort();
+        let mut entry_by_shot: HashMap<String, String> = HashMap::new();
+        // The reverse, so a merge repoints only the keys of the entries merged
+        let mut shots_by_entry: HashMap<String, Vec<String>> = HashMap::new();
+        for checksum in checksums {
+            let Some(media) = self.by_checksum.get(&checksum) else {
+                continue;
+            };
+            let keys = shot_keys(media);
+            let same_shot = keys.iter().find_map(|k| entry_by_shot.get(k)).cloned();
+            let Some(other_checksum) = same_shot else {
+                for key in &keys {
+                    entry_by_shot.insert(key.clone(), checksum.clone());
+                }
+                shots_by_entry.insert(checksum, keys);
+                continue;
+            };
+            let (Some(media), Some(other)) = (
+                self.by_checksum.remove(&checksum),
+                self.by_checksum.remove(&other_checksum),
+            ) else {
+                continue;
+            };
+            debug!(
+                "Same shot with different bytes: {:?} and {:?}",
+                media.original_path, other.original_path
+            );
+            let merged = merge_shot(media, other, &self.policy);
+            let merged_checksum = merged.hash_info.long_checksum.clone();
+            let mut merged_keys = shots_by_entry.remove(&other_checksum).unwrap_or_default();
+            merged_keys.extend(keys);
+            merged_keys.extend(shot_keys(&merged));
+            merged_keys.sort();
+            merged_keys.dedup();
+            for key in &merged_keys {
+                entry_by_shot.insert(key.clone(), merged_checksum.clone());
+            }
+            shots_by_entry.insert(merged_checksum.clone(), merged_keys);
+            self.by_checksum.insert(merged_checksum, merged);
+        }
+    }
+
     /// The deduplicated media keyed by long checksum, for callers (albums) that
     /// look entries up by content hash.
     pub(crate) fn by_checksum(&self) -> &HashMap<String, MediaFileInfo> {
@@ -90,7 +284,7 @@ impl Deduplicator {
     pub(crate) fn resolve_output_path(
         media_file: &MediaFileInfo,
         derived: &MediaFileDerivedInfo,
-        output_container: &OsFileSystem,
+        output_container: &dyn Output,
     ) -> anyhow::Result<DeDuplicationResult> {
         let Some(desired_output_path) = &derived.desired_media_path else {
             debug!("  No desired media path for file: {media_file:?}");
@@ -109,14 +303,21 @@ impl Deduplicator {
                 "{}{}.{}",
                 desired_output_path, suffix, derived.desired_media_extension
             );
-            if !output_container.exists(&desired_output_path_with_ext) {
+            if !output_container.exists(&desired_output_path_with_ext)? {
+                return Ok(DeDuplicationResult::WritePath(desired_output_path_with_ext));
+            }
+            let existing_o =
+                existing_file_checksum(output_container, &desired_output_path_with_ext);
+            // With recovered metadata written in, the archived copy no longer
+            // matches the input, and a copy archived before it's written is upgraded
+            let archived_checksum = derived.archived_checksum.as_ref().unwrap_or(long_checksum);
+            let es_o = existing_o.as_ref().map(|c| c == archived_checksum);
+            if archived_checksum != long_checksum && existing_o.as_ref() == Some(long_checksum) {
+                debug!(
+                    "  Writing recovered metadata into the copy already at {desired_output_path_with_ext}"
+                );
                 return Ok(DeDuplicationResult::WritePath(desired_output_path_with_ext));
             }
-            let es_o = is_existing_file_same(
-                output_container,
-                long_checksum,
-                &desired_output_path_with_ext,
-            );
             match es_o {
                 Some(true) => {
                     debug!(
@@ -150,40 +351,133 @@ impl Deduplicator {
 ///
 /// Parallel inspection means "first one wins" would let a thread race decide
 /// which entry's metadata (modified time, sidecar, the path whose bytes get
-/// written) survives. Instead the entry whose source path sorts first is kept
-/// canonical, and every original path is gathered into a sorted, de-duplicated
-/// list. The chosen entry is therefore independent of inspection order.
-fn merge_into(canonical: &mut MediaFileInfo, dup: MediaFileInfo) {
+/// written) survives. Instead the [`KeepPolicy`] picks the canonical entry
+/// (falling back to the one whose source path sorts first), and every original
+/// path is gathered into a sorted, de-duplicated list. The chosen entry is
+/// therefore independent of inspection order.
+fn merge_into(canonical: &mut MediaFileInfo, dup: MediaFileInfo, policy: &KeepPolicy) {
     let mut paths = std::mem::take(&mut canonical.original_path);
     paths.extend(dup.original_path.iter().cloned());
 
-    if dup.original_file_this_run < canonical.original_file_this_run {
-        *canonical = dup;
-    }
+    let other = if policy.keeps(canonical, &dup) {
+        dup
+    } else {
+        std::mem::replace(canonical, dup)
+    };
+    carry_over_metadata(canonical, other);
 
     paths.sort();
     paths.dedup();
     canonical.original_path = paths;
 }
 
+/// What makes two files the same shot for [`Deduplicator::merge_same_shots`],
+/// one key per distinct file name the entry was found under.
+fn shot_keys(media: &MediaFileInfo) -> Vec<String> {
+    let mut names: Vec<String> = media
+        .original_path
+        .iter()
+        .filter_map(|p| Path::new(p).file_name())
+        .map(|n| without_copy_number(&n.to_string_lossy().to_lowercase()))
+        .collect();
+    names.sort();
+    names.dedup();
+    let mut identities = Vec::new();
+    if let Some(payload) = &media.hash_info.payload_checksum {
+        identities.push(format!("payload|{payload}"));
+    }
+    if let Some(exif) = &media.exif_info {
+        let tag = |t: ExifTag| {
+            exif.tags
+                .get(&t.to_string())
+                .map(|v| v.trim().to_string())
+                .unwrap_or_default()
+        };
+        let taken = tag(ExifTag::DateTimeOriginal);
+        if !taken.is_empty() {
+            let shot = [
+                media.accurate_file_type.to_string(),
+                taken,
+                tag(ExifTag::SubSecTimeOriginal),
+                tag(ExifTag::Make),
+                tag(ExifTag::Model),
+                tag(ExifTag::ExifImageWidth),
+                tag(ExifTag::ExifImageHeight),
+            ]
+            .join("|");
+            identities.push(format!("exif|{shot}"));
+        }
+    }
+    identities
+        .iter()
+        .flat_map(|identity| names.iter().map(move |n| format!("{identity}|{n}")))
+        .collect()
+}
+
+/// `img_1234(1).jpg` -> `img_1234.jpg`: Takeout numbers files whose names clash
+/// within a folder.
+fn without_copy_number(name: &str) -> String {
+    let (stem, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let stem = stem
+        .strip_suffix(')')
+        .and_then(|s| s.rsplit_once('('))
+        .filter(|(_, n)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+        .map(|(s, _)| s)
+        .unwrap_or(stem);
+    if ext.is_empty() {
+        stem.to_string()
+    } else {
+        format!("{stem}.{ext}")
+    }
+}
+
+/// Merge two copies of the same shot with different bytes, keeping the one the
+/// [`KeepPolicy`] prefers. By default that's the one Google didn't rewrite on
+/// export, as the copies share their name, dimensions and EXIF.
+fn merge_shot(a: MediaFileInfo, b: MediaFileInfo, policy: &KeepPolicy) -> MediaFileInfo {
+    let (mut kept, other) = if policy.keeps(&a, &b) { (a, b) } else { (b, a) };
+
+    kept.original_path.extend(other.original_path.clone());
+    kept.original_path.sort();
+    kept.original_path.dedup();
+    kept.merged_checksums
+        .push(other.hash_info.long_checksum.clone());
+    kept.merged_checksums.extend(other.merged_checksums.clone());
+    kept.merged_checksums.sort();
+    kept.merged_checksums.dedup();
+    carry_over_metadata(&mut kept, other);
+    kept
+}
+
+/// Give `kept` what was recorded beside the copy it was kept over and it has
+/// none of: the people and places Google kept in its JSON, and XMP and IPTC.
+fn carry_over_metadata(kept: &mut MediaFileInfo, other: MediaFileInfo) {
+    if kept.supp_info.is_none() {
+        kept.supp_info = other.supp_info;
+    }
+    if kept.xmp_info.is_none() {
+        kept.xmp_info = other.xmp_info;
+    }
+    if kept.iptc_info.is_none() {
+        kept.iptc_info = other.iptc_info;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::db_cmd::HashInfo;
-    use crate::fs::OsFileSystem;
+    use crate::fs::{MemoryOutput, OsFileSystem};
+    use crate::supplemental_info::PsSupplementalInfo;
+    use crate::util::checksum_bytes;
     use anyhow::anyhow;
 
     /// Build a media entry with a controllable source path and content hash so
     /// tests can exercise collapsing and ordering directly.
     fn media_with(path: &str, long_checksum: &str) -> MediaFileInfo {
-        let mut m = MediaFileInfo::new_for_test();
-        m.original_file_this_run = path.to_string();
-        m.original_path = vec![path.to_string()];
-        m.hash_info = HashInfo {
-            short_checksum: long_checksum.chars().take(7).collect(),
-            long_checksum: long_checksum.to_string(),
-        };
-        m
+        MediaFileInfo::new_for_test()
+            .with_path(path)
+            .with_checksum(long_checksum)
     }
 
     #[test]
@@ -232,6 +526,27 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_resolve_against_any_output() -> anyhow::Result<()> {
+        // The same bytes already written skip the write; different ones move on
+        // to the short-checksum name.
+        let c = MemoryOutput::default();
+        c.write(false, "2024/photo.jpg", &mut std::io::Cursor::new(b"photo"))?;
+        let derived = MediaFileDerivedInfo::new_for_test(Some("2024/photo".to_string()), "jpg");
+        let mut same = MediaFileInfo::new_for_test();
+        same.hash_info = checksum_bytes(&mut std::io::Cursor::new(b"photo"))?;
+        assert_eq!(
+            Deduplicator::resolve_output_path(&same, &derived, &c)?,
+            DeDuplicationResult::SkipWrite("2024/photo.jpg".to_string())
+        );
+        let other = MediaFileInfo::new_for_test();
+        assert_eq!(
+            Deduplicator::resolve_output_path(&other, &derived, &c)?,
+            DeDuplicationResult::WritePath("2024/photo-tsc.jpg".to_string())
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_resolve_all_candidates_taken_errors() -> anyhow::Result<()> {
         // The bare, short- and long-checksum names all exist with different
@@ -255,6 +570,7 @@ mod tests {
             short_checksum: "6bfdabd".to_string(),
             long_checksum: "6bfdabd4fc33d112283c147acccc574e770bbe6fbdbc3d4da968ba7b606ecc2f"
                 .to_string(),
+            payload_checksum: None,
         };
         let derived = MediaFileDerivedInfo::new_for_test(Some("Canon_40D".to_string()), "jpg");
         let res = Deduplicator::resolve_output_path(&mfi, &derived, &c)?;
@@ -267,7 +583,7 @@ mod tests {
 
     #[test]
     fn test_collapses_files_with_same_checksum() -> anyhow::Result<()> {
-        let mut d = Deduplicator::new();
+        let mut d = Deduplicator::default();
         d.add(media_with("a/photo.jpg", "hashX"));
         d.add(media_with("b/photo.jpg", "hashX"));
 
@@ -287,11 +603,11 @@ mod tests {
     fn test_collapse_canonical_entry_is_order_independent() -> anyhow::Result<()> {
         // The same two byte-identical files, added in opposite orders, must
         // collapse to the same canonical entry and the same path list.
-        let mut forward = Deduplicator::new();
+        let mut forward = Deduplicator::default();
         forward.add(media_with("b/photo.jpg", "hashX"));
         forward.add(media_with("a/photo.jpg", "hashX"));
 
-        let mut reverse = Deduplicator::new();
+        let mut reverse = Deduplicator::default();
         reverse.add(media_with("a/photo.jpg", "hashX"));
         reverse.add(media_with("b/photo.jpg", "hashX"));
 
@@ -311,9 +627,160 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_collapse_keeps_takeout_metadata_of_icloud_copy() -> anyhow::Result<()> {
+        for order in [[true, false], [false, true]] {
+            let mut d = Deduplicator::default();
+            for from_takeout in order {
+                d.add(if from_takeout {
+                    shot("Takeout/Photos from 2025/IMG_5071.HEIC", "aaaa", true)
+                } else {
+                    shot("iCloud Photos/Photos/IMG_5071.HEIC", "aaaa", false)
+                });
+            }
+            let kept = d
+                .by_checksum()
+                .get("aaaa")
+                .ok_or_else(|| anyhow!("collapsed entry missing"))?;
+            assert_eq!(
+                kept.original_file_this_run,
+                "iCloud Photos/Photos/IMG_5071.HEIC"
+            );
+            assert!(kept.supp_info.is_some(), "Takeout JSON dropped");
+        }
+        Ok(())
+    }
+
+    /// A photo as `source` exported it: the same shot, with the given bytes.
+    fn shot(path: &str, long_checksum: &str, from_takeout: bool) -> MediaFileInfo {
+        let mut m = media_with(path, long_checksum)
+            .with_exif_tag(
+                &ExifTag::DateTimeOriginal.to_string(),
+                "2025-03-01T10:00:00",
+            )
+            .with_exif_tag(&ExifTag::SubSecTimeOriginal.to_string(), "123")
+            .with_exif_tag(&ExifTag::Make.to_string(), "Apple")
+            .with_exif_tag(&ExifTag::Model.to_string(), "iPhone 15");
+        if from_takeout {
+            m.supp_info = Some(PsSupplementalInfo {
+                geo_data: None,
+                geo_data_exif: None,
+                people: vec![],
+                photo_taken_time: None,
+                creation_time: None,
+                description: None,
+            });
+        }
+        m
+    }
+
+    #[test]
+    fn test_merges_same_shot_from_different_sources() -> anyhow::Result<()> {
+        for order in [["icloud", "google"], ["google", "icloud"]] {
+            let mut d = Deduplicator::default();
+            for source in order {
+                d.add(match source {
+                    "icloud" => shot("iCloud Photos/Photos/IMG_5071.HEIC", "bbbb", false),
+                    _ => shot("Takeout/Photos from 2025/IMG_5071(1).HEIC", "aaaa", true),
+                });
+            }
+            d.merge_same_shots();
+            assert_eq!(d.by_checksum().len(), 1);
+            // The copy Google didn't rewrite is kept
+            let kept = d
+                .by_checksum()
+                .get("bbbb")
+                .ok_or_else(|| anyhow!("iCloud copy not kept"))?;
+            assert_eq!(kept.merged_checksums, vec!["aaaa".to_string()]);
+            assert_eq!(
+                kept.original_path,
+                vec![
+                    "Takeout/Photos from 2025/IMG_5071(1).HEIC".to_string(),
+                    "iCloud Photos/Photos/IMG_5071.HEIC".to_string()
+                ]
+            );
+            assert!(kept.supp_info.is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merges_a_shot_exported_three_times() -> anyhow::Result<()> {
+        let mut d = Deduplicator::default();
+        d.add(shot("Takeout/Photos from 2025/IMG_5071.HEIC", "aaaa", true));
+        d.add(shot("iCloud Photos/Photos/IMG_5071.HEIC", "bbbb", false));
+        d.add(shot(
+            "Takeout/Photos from 2025/IMG_5071(1).HEIC",
+            "cccc",
+            true,
+        ));
+        d.merge_same_shots();
+        assert_eq!(d.by_checksum().len(), 1);
+        let kept = d
+            .by_checksum()
+            .get("bbbb")
+            .ok_or_else(|| anyhow!("iCloud copy not kept"))?;
+        assert_eq!(kept.merged_checksums.len(), 2);
+        assert_eq!(kept.original_path.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keeps_apart_other_shots_sharing_exif() {
+        let mut d = Deduplicator::default();
+        d.add(shot("Photos/IMG_5071.HEIC", "aaaa", false));
+        // An edit keeps its original's EXIF, but not its name
+        d.add(shot("Photos/IMG_E5071.HEIC", "bbbb", false));
+        let mut other_instant = shot("Takeout/IMG_5071.HEIC", "cccc", true);
+        if let Some(exif) = &mut other_instant.exif_info {
+            exif.tags
+                .insert(ExifTag::SubSecTimeOriginal.to_string(), "456".to_string());
+        }
+        d.add(other_instant);
+        let mut undated = media_with("Takeout/IMG_0001.JPG", "dddd");
+        undated.supp_info = None;
+        d.add(undated);
+        d.add(media_with("iCloud/IMG_0001.JPG", "eeee"));
+        d.merge_same_shots();
+        assert_eq!(d.by_checksum().len(), 5);
+    }
+
+    #[test]
+    fn test_merges_same_image_data_without_exif() {
+        let with_payload = |path: &str, checksum: &str, payload: &str| {
+            let mut m = media_with(path, checksum);
+            m.hash_info.payload_checksum = Some(payload.to_string());
+            m
+        };
+        let mut d = Deduplicator::default();
+        d.add(with_payload("iCloud/IMG_0001.PNG", "aaaa", "pixels"));
+        d.add(with_payload("Takeout/IMG_0001.PNG", "bbbb", "pixels"));
+        // Same picture under another name, e.g. a copy saved from a chat
+        d.add(with_payload("Takeout/IMG-WA0001.PNG", "cccc", "pixels"));
+        d.add(with_payload("Takeout/IMG_0002.PNG", "dddd", "other pixels"));
+        d.merge_same_shots();
+        assert_eq!(d.by_checksum().len(), 3);
+        // The copy not exported by Google is kept, though the Takeout path sorts first
+        assert_eq!(
+            d.by_checksum()
+                .get("aaaa")
+                .map(|m| m.merged_checksums.clone()),
+            Some(vec!["bbbb".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_without_copy_number() {
+        assert_eq!(without_copy_number("img_5071(1).heic"), "img_5071.heic");
+        assert_eq!(without_copy_number("img_5071(12).heic"), "img_5071.heic");
+        assert_eq!(without_copy_number("img_5071.heic"), "img_5071.heic");
+        assert_eq!(without_copy_number("party (dad).jpg"), "party (dad).jpg");
+        assert_eq!(without_copy_number("img_5071()"), "img_5071()");
+    }
+
     #[test]
     fn test_sorted_media_is_stable() {
-        let mut d = Deduplicator::new();
+        let mut d = Deduplicator::default();
         d.add(media_with("p3", "cccc"));
         d.add(media_with("p1", "aaaa"));
         d.add(media_with("p2", "bbbb"));
@@ -325,4 +792,101 @@ mod tests {
             .collect();
         assert_eq!(order, vec!["aaaa", "bbbb", "cccc"]);
     }
+
+    #[test]
+    fn test_keep_policy_rules() {
+        let policy = KeepPolicy::default();
+        let sized = |path: &str, width: &str, height: &str| {
+            let mut m = shot(path, "aaaa", false);
+            if let Some(exif) = &mut m.exif_info {
+                exif.tags
+                    .insert(ExifTag::ExifImageWidth.to_string(), width.to_string());
+                exif.tags
+                    .insert(ExifTag::ExifImageHeight.to_string(), height.to_string());
+            }
+            m
+        };
+        let check = |kept: &MediaFileInfo, other: &MediaFileInfo, why: &str| {
+            assert!(policy.keeps(kept, other), "{}", kept.original_file_this_run);
+            assert!(
+                !policy.keeps(other, kept),
+                "{}",
+                kept.original_file_this_run
+            );
+            assert_eq!(policy.why_kept(kept, other), why);
+        };
+
+        let original = sized("Takeout/Google Photos/IMG_1.JPG", "4032", "3024");
+        let edit = sized("iCloud Photos/IMG_1-edited.JPG", "4032", "3024");
+        check(&original, &edit, "the original rather than an edit");
+
+        let storage_saver = sized("iCloud Photos/IMG_1.JPG", "2048", "1536");
+        check(&original, &storage_saver, "more pixels");
+
+        let mut no_gps = sized("iCloud Photos/IMG_1.JPG", "4032", "3024");
+        let mut gps = sized("Takeout/Google Photos/IMG_1.JPG", "4032", "3024");
+        if let Some(exif) = &mut gps.exif_info {
+            exif.latitude = Some(-36.8);
+        }
+        check(&gps, &no_gps, "more of the EXIF date and GPS position");
+
+        if let Some(exif) = &mut no_gps.exif_info {
+            exif.latitude = Some(-36.8);
+        }
+        check(
+            &no_gps,
+            &gps,
+            "not exported by Google, so not rewritten by it",
+        );
+
+        let google = KeepPolicy {
+            preferred_source: PhotoSource::Google,
+            ..KeepPolicy::default()
+        };
+        assert!(google.keeps(&gps, &no_gps));
+        assert_eq!(
+            google.why_kept(&gps, &no_gps),
+            "exported by Google, the preferred source"
+        );
+
+        // Rules left out of the order are ignored, here the pixels
+        let paths_only = KeepPolicy {
+            order: vec![KeepRule::Original],
+            ..KeepPolicy::default()
+        };
+        assert!(paths_only.keeps(&storage_saver, &edit));
+        let a = sized("a/IMG_1.JPG", "1", "1");
+        let b = sized("b/IMG_1.JPG", "1", "1");
+        assert!(paths_only.keeps(&a, &b) && !paths_only.keeps(&b, &a));
+        assert_eq!(paths_only.why_kept(&a, &b), "its source path sorts first");
+    }
+
+    #[test]
+    fn test_keeps_the_same_copy_in_any_order() {
+        let copies = [
+            ("Takeout/Google Photos/IMG_1.JPG", "aaaa"),
+            ("iCloud Photos/IMG_1.JPG", "bbbb"),
+            ("Camera/IMG_1.JPG", "cccc"),
+        ];
+        let kept = |order: [usize; 3]| {
+            let mut d = Deduplicator::default();
+            for i in order {
+                let (path, checksum) = copies[i];
+                let mut m = shot(path, checksum, false);
+                m.hash_info.payload_checksum = Some("pixels".to_string());
+                d.add(m);
+            }
+            d.merge_same_shots();
+            let kept: Vec<String> = d
+                .by_checksum()
+                .values()
+                .map(|m| m.original_file_this_run.clone())
+                .collect();
+            kept
+        };
+        for order in [[0, 1, 2], [2, 1, 0], [1, 0, 2], [2, 0, 1]] {
+            // Neither of the others is from Google, so the path decides between them
+            assert_eq!(kept(order), vec!["Camera/IMG_1.JPG".to_string()]);
+        }
+    }
 }
@@ -0,0 +1,474 @@
+use crate::dedup::{Deduplicator, KeepPolicy};
+use crate::file_type::QuickFileType;
+use crate::fs::{FileSystem, OsFileSystem, ZipFileSystem};
+use crate::inspect::inspect_media_files;
+use crate::media::{MediaFileInfo, media_dimensions};
+use crate::phash::{find_similar, similar_groups};
+use crate::progress::Progress;
+use crate::util::{ScanInfo, scan_fs};
+use anyhow::anyhow;
+use nom_exif::ExifTag;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// How the `duplicates` report is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub(crate) enum ReportFormat {
+    /// A note with a table per cluster
+    #[default]
+    Markdown,
+    /// One row per copy
+    Csv,
+    /// The clusters as a JSON array
+    Json,
+}
+
+/// Copies of one photo or video found in the input.
+#[derive(Debug, Serialize)]
+pub(crate) struct DuplicateCluster {
+    /// `exact` for identical bytes, `likely` for the same picture stored differently.
+    pub(crate) kind: &'static str,
+    /// What the copies have in common.
+    pub(crate) reasons: Vec<&'static str>,
+    pub(crate) copies: Vec<DuplicateCopy>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DuplicateCopy {
+    pub(crate) path: String,
+    pub(crate) checksum: String,
+    pub(crate) size: u64,
+    pub(crate) width: Option<u64>,
+    pub(crate) height: Option<u64>,
+    /// Whether `sync` stores this copy in the archive.
+    pub(crate) kept: bool,
+    pub(crate) why: String,
+}
+
+const SAME_BYTES: &str = "same bytes";
+const SAME_IMAGE_DATA: &str = "same image data";
+const LOOK_ALIKE: &str = "look alike";
+const SAME_INSTANT: &str = "same capture instant";
+
+pub(crate) fn main(
+    input: &String,
+    format: ReportFormat,
+    output: &Option<String>,
+    keep: &KeepPolicy,
+) -> anyhow::Result<()> {
+    let path = Path::new(input);
+    if !path.exists() {
+        return Err(anyhow!("Input path does not exist: {}", input));
+    }
+    let container: Arc<dyn FileSystem> = if path.is_dir() {
+        info!("Input directory: {input}");
+        Arc::new(OsFileSystem::new(input))
+    } else {
+        info!("Input zip: {input}");
+        Arc::new(ZipFileSystem::new(input)?)
+    };
+
+    let media_si_files: Vec<ScanInfo> = scan_fs(container.as_ref())
+        .into_iter()
+        .filter(|m| m.quick_file_type == QuickFileType::Media)
+        .collect();
+    info!("Inspecting {} photo and video files", media_si_files.len());
+    let prog = Arc::new(Progress::new(media_si_files.len() as u64));
+    let mut inspected = inspect_media_files(container, media_si_files, prog.clone());
+    let media: Vec<MediaFileInfo> = inspected.by_ref().collect();
+    let skipped = inspected.skipped_count();
+    if skipped > 0 {
+        warn!("{skipped} files could not be processed");
+    }
+    drop(prog);
+
+    let clusters = find_clusters(media, keep);
+    info!("Found {} clusters of duplicates", clusters.len());
+    let report = match format {
+        ReportFormat::Markdown => markdown_report(&clusters),
+        ReportFormat::Csv => csv_report(&clusters)?,
+        ReportFormat::Json => serde_json::to_string_pretty(&clusters)? + "\n",
+    };
+    match output {
+        Some(output) => std::fs::write(output, report)?,
+        None => print!("{report}"),
+    }
+    Ok(())
+}
+
+/// Exact duplicates (one checksum found at several paths) and likely
+/// duplicates (distinct files with the same image data, pixels that look
+/// alike, or the same EXIF capture instant), each with the copy `sync` keeps.
+///
+/// Decisions are the deduplicator's own, so the report says what a sync would do.
+pub(crate) fn find_clusters(media: Vec<MediaFileInfo>, keep: &KeepPolicy) -> Vec<DuplicateCluster> {
+    let mut deduper = Deduplicator::with_policy(keep.clone());
+    for m in media {
+        deduper.add(m);
+    }
+    let distinct: Vec<MediaFileInfo> = deduper.sorted_media().into_iter().cloned().collect();
+    let mut clusters: Vec<DuplicateCluster> = distinct
+        .iter()
+        .filter(|m| m.original_path.len() > 1)
+        .map(|m| exact_cluster(m, keep))
+        .collect();
+
+    // Which entry each merged copy of a shot went into
+    deduper.merge_same_shots();
+    let mut merged_into: HashMap<&String, &String> = HashMap::new();
+    for m in deduper.by_checksum().values() {
+        for other in &m.merged_checksums {
+            merged_into.insert(other, &m.hash_info.long_checksum);
+        }
+    }
+
+    let by_checksum: HashMap<&String, &MediaFileInfo> = distinct
+        .iter()
+        .map(|m| (&m.hash_info.long_checksum, m))
+        .collect();
+    let refs: Vec<&MediaFileInfo> = distinct.iter().collect();
+    let look_alike = find_similar(&refs);
+    let mut alike = look_alike.clone();
+    for key in [payload_key, instant_key] {
+        let mut by_key: BTreeMap<String, Vec<&String>> = BTreeMap::new();
+        for m in &distinct {
+            if let Some(key) = key(m) {
+                by_key
+                    .entry(key)
+                    .or_default()
+                    .push(&m.hash_info.long_checksum);
+            }
+        }
+        for checksums in by_key.values().filter(|c| c.len() > 1) {
+            for a in checksums {
+                for b in checksums.iter().filter(|b| b != &a) {
+                    alike.entry(a.to_string()).or_default().push(b.to_string());
+                }
+            }
+        }
+    }
+
+    for group in similar_groups(&alike) {
+        let members: Vec<&MediaFileInfo> = group
+            .iter()
+            .filter_map(|c| by_checksum.get(c).copied())
+            .collect();
+        let shared = |key: fn(&MediaFileInfo) -> Option<String>| {
+            let keys: Vec<Option<String>> = members.iter().map(|m| key(m)).collect();
+            keys.iter()
+                .enumerate()
+                .any(|(i, k)| k.is_some() && keys[i + 1..].contains(k))
+        };
+        let mut reasons = Vec::new();
+        if shared(payload_key) {
+            reasons.push(SAME_IMAGE_DATA);
+        }
+        if group.iter().any(|c| look_alike.contains_key(c)) {
+            reasons.push(LOOK_ALIKE);
+        }
+        if shared(instant_key) {
+            reasons.push(SAME_INSTANT);
+        }
+        let copies = members
+            .iter()
+            .map(|m| {
+                let checksum = &m.hash_info.long_checksum;
+                let (kept, why) = match merged_into.get(checksum) {
+                    Some(into) => {
+                        let into_path = by_checksum
+                            .get(*into)
+                            .map(|k| k.original_file_this_run.clone())
+                            .unwrap_or_default();
+                        (
+                            false,
+                            format!("the same shot as {into_path}, merged into it"),
+                        )
+                    }
+                    None => match members
+                        .iter()
+                        .find(|o| merged_into.get(&o.hash_info.long_checksum) == Some(&checksum))
+                    {
+                        Some(other) => (true, deduper.policy().why_kept(m, other)),
+                        None => (
+                            true,
+                            "not certain enough to merge; stored as its own file".to_string(),
+                        ),
+                    },
+                };
+                copy_of(m, &m.original_file_this_run, kept, why)
+            })
+            .collect();
+        clusters.push(DuplicateCluster {
+            kind: "likely",
+            reasons,
+            copies,
+        });
+    }
+    clusters
+}
+
+fn exact_cluster(m: &MediaFileInfo, keep: &KeepPolicy) -> DuplicateCluster {
+    let kept_path = &m.original_file_this_run;
+    // The copies differ only in where they were found
+    let found_at = |path: &String| MediaFileInfo {
+        original_file_this_run: path.clone(),
+        ..m.clone()
+    };
+    let copies = m
+        .original_path
+        .iter()
+        .map(|path| {
+            if path == kept_path {
+                let other = m.original_path.iter().find(|p| *p != kept_path);
+                let reason = match other {
+                    Some(other) => keep.why_kept(&found_at(kept_path), &found_at(other)),
+                    None => "its source path sorts first".to_string(),
+                };
+                copy_of(m, path, true, format!("of identical copies, {reason}"))
+            } else {
+                let why = format!("identical to {kept_path}, stored once");
+                copy_of(m, path, false, why)
+            }
+        })
+        .collect();
+    DuplicateCluster {
+        kind: "exact",
+        reasons: vec![SAME_BYTES],
+        copies,
+    }
+}
+
+fn copy_of(m: &MediaFileInfo, path: &str, kept: bool, why: String) -> DuplicateCopy {
+    let (width, height) = media_dimensions(m);
+    DuplicateCopy {
+        path: path.to_string(),
+        checksum: m.hash_info.long_checksum.clone(),
+        size: m.file_size,
+        width,
+        height,
+        kept,
+        why,
+    }
+}
+
+fn payload_key(m: &MediaFileInfo) -> Option<String> {
+    m.hash_info.payload_checksum.clone()
+}
+
+/// The EXIF capture time to the sub-second, which two shots rarely share.
+fn instant_key(m: &MediaFileInfo) -> Option<String> {
+    let tags = &m.exif_info.as_ref()?.tags;
+    let taken = tags.get(&ExifTag::DateTimeOriginal.to_string())?;
+    let sub_sec = tags
+        .get(&ExifTag::SubSecTimeOriginal.to_string())
+        .map(|s| s.trim())
+        .unwrap_or_default();
+    Some(format!("{}.{sub_sec}", taken.trim()))
+}
+
+fn size_and_dimensions(copy: &DuplicateCopy) -> (String, String) {
+    let dimensions = match (copy.width, copy.height) {
+        (Some(w), Some(h)) => format!("{w}x{h}"),
+        _ => String::new(),
+    };
+    (copy.size.to_string(), dimensions)
+}
+
+fn markdown_report(clusters: &[DuplicateCluster]) -> String {
+    let mut md = String::from("# Duplicates\n");
+    if clusters.is_empty() {
+        md.push_str("\nNo duplicates found.\n");
+    }
+    for (i, cluster) in clusters.iter().enumerate() {
+        let _ = write!(
+            md,
+            "\n## {} {}: {}\n\n| Path | Size | Dimensions | Kept | Why |\n| --- | --- | --- | --- | --- |\n",
+            cluster.kind,
+            i + 1,
+            cluster.reasons.join(", ")
+        );
+        for copy in &cluster.copies {
+            let (size, dimensions) = size_and_dimensions(copy);
+            let _ = writeln!(
+                md,
+                "| {} | {size} | {dimensions} | {} | {} |",
+                copy.path.replace('|', "\\|"),
+                if copy.kept { "yes" } else { "no" },
+                copy.why.replace('|', "\\|")
+            );
+        }
+    }
+    md
+}
+
+fn csv_report(clusters: &[DuplicateCluster]) -> anyhow::Result<String> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    wtr.write_record([
+        "cluster",
+        "kind",
+        "reasons",
+        "path",
+        "checksum",
+        "size",
+        "dimensions",
+        "kept",
+        "why",
+    ])?;
+    for (i, cluster) in clusters.iter().enumerate() {
+        for copy in &cluster.copies {
+            let (size, dimensions) = size_and_dimensions(copy);
+            wtr.write_record([
+                (i + 1).to_string(),
+                cluster.kind.to_string(),
+                cluster.reasons.join("; "),
+                copy.path.clone(),
+                copy.checksum.clone(),
+                size,
+                dimensions,
+                copy.kept.to_string(),
+                copy.why.clone(),
+            ])?;
+        }
+    }
+    Ok(String::from_utf8(wtr.into_inner()?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_to_string;
+
+    fn media(path: &str, checksum: &str, payload: Option<&str>) -> MediaFileInfo {
+        let mut m = MediaFileInfo::new_for_test()
+            .with_path(path)
+            .with_checksum(checksum);
+        m.hash_info.payload_checksum = payload.map(|p| p.to_string());
+        m
+    }
+
+    #[test]
+    fn test_find_clusters() -> anyhow::Result<()> {
+        let clusters = find_clusters(
+            vec![
+                media("b/IMG_0001.JPG", "aaaa", None),
+                media("a/IMG_0001.JPG", "aaaa", None),
+                // Same picture, one under another name so it isn't merged
+                media("iCloud/IMG_0002.JPG", "bbbb", Some("pixels")),
+                media("Takeout/IMG_0002.JPG", "cccc", Some("pixels")),
+                media("Chat/IMG-WA0002.JPG", "dddd", Some("pixels")),
+                media("iCloud/IMG_0003.JPG", "eeee", Some("other pixels")),
+            ],
+            &KeepPolicy::default(),
+        );
+        assert_eq!(clusters.len(), 2);
+        // Copies are listed in checksum order
+
+        let exact = &clusters[0];
+        assert_eq!(
+            (exact.kind, exact.reasons.clone()),
+            ("exact", vec![SAME_BYTES])
+        );
+        let kept: Vec<(&str, bool)> = exact
+            .copies
+            .iter()
+            .map(|c| (c.path.as_str(), c.kept))
+            .collect();
+        assert_eq!(
+            kept,
+            vec![("a/IMG_0001.JPG", true), ("b/IMG_0001.JPG", false)]
+        );
+
+        let likely = &clusters[1];
+        assert_eq!(
+            (likely.kind, likely.reasons.clone()),
+            ("likely", vec![SAME_IMAGE_DATA])
+        );
+        let kept: Vec<(&str, bool, &str)> = likely
+            .copies
+            .iter()
+            .map(|c| (c.path.as_str(), c.kept, c.why.as_str()))
+            .collect();
+        assert_eq!(
+            kept,
+            vec![
+                (
+                    "iCloud/IMG_0002.JPG",
+                    true,
+                    "not exported by Google, so not rewritten by it"
+                ),
+                (
+                    "Takeout/IMG_0002.JPG",
+                    false,
+                    "the same shot as iCloud/IMG_0002.JPG, merged into it"
+                ),
+                (
+                    "Chat/IMG-WA0002.JPG",
+                    true,
+                    "not certain enough to merge; stored as its own file"
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reports() -> anyhow::Result<()> {
+        crate::test_util::setup_log();
+        let temp = tempfile::tempdir()?;
+        let report = |input: &str, format: ReportFormat| -> anyhow::Result<String> {
+            let output = temp.path().join("report").to_string_lossy().to_string();
+            main(
+                &input.to_string(),
+                format,
+                &Some(output.clone()),
+                &KeepPolicy::default(),
+            )?;
+            Ok(read_to_string(output)?)
+        };
+
+        let md = report("test/takeout_basic", ReportFormat::Markdown)?;
+        assert!(
+            md.starts_with("# Duplicates\n\n## exact 1: same bytes\n\n"),
+            "{md}"
+        );
+        assert!(md.contains(
+            "| Google Photos/Holiday/Canon_40D.jpg | 7958 | 100x68 | yes | of identical copies, \
+             its source path sorts first |\n"
+        ));
+        assert!(md.contains(
+            "| Google Photos/Photos from 2024/Canon_40D.jpg | 7958 | 100x68 | no | identical to \
+             Google Photos/Holiday/Canon_40D.jpg, stored once |\n"
+        ));
+
+        let csv = report("test/cross_source", ReportFormat::Csv)?;
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3, "{csv}");
+        assert_eq!(
+            lines[0],
+            "cluster,kind,reasons,path,checksum,size,dimensions,kept,why"
+        );
+        assert!(lines[1].starts_with(
+            "1,likely,same image data; look alike; same capture instant,\
+             Takeout/Google Photos/Photos from 2008/IMG_0009.JPG,"
+        ));
+        assert!(lines[1].ends_with(
+            ",false,\"the same shot as iCloud Photos/Photos/IMG_0009.JPG, merged into it\""
+        ));
+        assert!(lines[2].ends_with(",true,\"not exported by Google, so not rewritten by it\""));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&report("test/cross_source", ReportFormat::Json)?)?;
+        assert_eq!(json[0]["kind"], "likely");
+        assert_eq!(
+            json[0]["copies"][1]["path"],
+            "iCloud Photos/Photos/IMG_0009.JPG"
+        );
+        assert_eq!(json[0]["copies"][1]["kept"], true);
+        assert_eq!(json[0]["copies"][1]["width"], 100);
+        Ok(())
+    }
+}
@@ -0,0 +1,917 @@
+//! Writes the capture date and GPS position that Google Takeout keeps only in
+//! its supplemental JSON into the archived copy of a photo (`sync --embed-metadata`).
+//!
+//! Only what the file lacks is added: `DateTimeOriginal` and `OffsetTimeOriginal`
+//! when it has no `DateTimeOriginal`, and the GPS position when it has none.
+//! Google records when a photo was taken as an instant, so the time is written
+//! in UTC with an offset of `+00:00`.
+//!
+//! The EXIF is edited without moving anything already in it: the IFDs that gain
+//! tags are copied, with the new tags, to the end of the TIFF block (the
+//! thumbnail's IFD with them, as readers expect it after the first) and the
+//! pointers to them updated, so offsets into the rest of the block (maker notes,
+//! thumbnails) stay valid. The edited block replaces the JPEG's `APP1` segment,
+//! or for HEIC and AVIF is appended in an `mdat` box of its own, with the `iloc`
+//! entry of the `Exif` item pointed at it. The image data is untouched, so the
+//! payload checksum of the copy is the original's.
+
+use crate::file_type::AccurateFileType;
+use crate::fs::FileSystem;
+use crate::markdown::non_null_island;
+use crate::media::MediaFileInfo;
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, FixedOffset};
+use std::io::{Read, Seek, SeekFrom};
+
+/// Largest `meta` box read whole; real ones are a few kilobytes.
+const MAX_META_SIZE: u64 = 16 * 1024 * 1024;
+/// Content type of the `mime` item a HEIF file keeps its XMP in.
+const XMP_CONTENT_TYPE: &[u8] = b"application/rdf+xml";
+
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const GPS_IFD_POINTER: u16 = 0x8825;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const GPS_VERSION_ID: u16 = 0x0000;
+const GPS_LATITUDE_REF: u16 = 0x0001;
+const GPS_LATITUDE: u16 = 0x0002;
+const GPS_LONGITUDE_REF: u16 = 0x0003;
+const GPS_LONGITUDE: u16 = 0x0004;
+
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+
+/// What the supplemental JSON knows of a photo that the file itself doesn't.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct RecoveredMetadata {
+    pub(crate) taken: Option<DateTime<FixedOffset>>,
+    pub(crate) coords: Option<(f64, f64)>,
+}
+
+/// Metadata to write into `media`, or `None` when it has nothing missing that
+/// its supplemental JSON could fill in.
+pub(crate) fn recovered_metadata(media: &MediaFileInfo) -> Option<RecoveredMetadata> {
+    let supp = media.supp_info.as_ref()?;
+    let exif = media.exif_info.as_ref();
+    let has_date = exif.is_some_and(|e| e.tags.contains_key("DateTimeOriginal"));
+    let has_coords = exif.is_some_and(|e| non_null_island(e.latitude, e.longitude).is_some());
+    let recovered = RecoveredMetadata {
+        taken: supp
+            .photo_taken_time
+            .as_ref()
+            .and_then(|t| t.timestamp_s_as_iso_8601())
+            .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+            .filter(|_| !has_date),
+        coords: [&supp.geo_data, &supp.geo_data_exif]
+            .into_iter()
+            .flatten()
+            .find_map(|geo| non_null_island(geo.latitude, geo.longitude))
+            .filter(|_| !has_coords),
+    };
+    (recovered != RecoveredMetadata::default()).then_some(recovered)
+}
+
+/// The bytes to archive for `media`, with its recovered metadata written in,
+/// or `None` when there's nothing to add or it can't be added to this type of file.
+pub(crate) fn embedded_copy(
+    media: &MediaFileInfo,
+    input_container: &dyn FileSystem,
+) -> Result<Option<Vec<u8>>> {
+    let Some(recovered) = recovered_metadata(media) else {
+        return Ok(None);
+    };
+    let file_type = &media.accurate_file_type;
+    if !matches!(
+        file_type,
+        AccurateFileType::Jpg | AccurateFileType::Heic | AccurateFileType::Avif
+    ) {
+        return Ok(None);
+    }
+    // Growing the JPEG would move the clip after it away from its recorded offset
+    if media
+        .exif_info
+        .as_ref()
+        .is_some_and(|e| e.motion_photo_offset.is_some())
+    {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    input_container
+        .open(&media.original_file_this_run)?
+        .read_to_end(&mut bytes)?;
+    embed_metadata(&bytes, file_type, &recovered).map(Some)
+}
+
+pub(crate) fn embed_metadata(
+    bytes: &[u8],
+    file_type: &AccurateFileType,
+    recovered: &RecoveredMetadata,
+) -> Result<Vec<u8>> {
+    match file_type {
+        AccurateFileType::Jpg => embed_in_jpeg(bytes, recovered),
+        AccurateFileType::Heic | AccurateFileType::Avif => embed_in_heif(bytes, recovered),
+        _ => Err(anyhow!("Can't write metadata into a {file_type:?} file")),
+    }
+}
+
+/// The TIFF block a JPEG, HEIC or AVIF file keeps its EXIF in, if it has one.
+pub(crate) fn exif_block(bytes: &[u8], file_type: &AccurateFileType) -> Result<Option<Vec<u8>>> {
+    match file_type {
+        AccurateFileType::Jpg => {
+            let (exif_segment, _) = jpeg_exif_segment(bytes)?;
+            Ok(exif_segment.map(|(start, end)| bytes[start + 10..end].to_vec()))
+        }
+        AccurateFileType::Heic | AccurateFileType::Avif => {
+            let Ok((_, location)) = heif_item_location(bytes, b"Exif") else {
+                return Ok(None);
+            };
+            heif_exif_tiff(bytes, &location).map(Some)
+        }
+        _ => Err(anyhow!("Can't read the EXIF of a {file_type:?} file")),
+    }
+}
+
+/// The bytes of the first item of `item_type` in a HEIF file.
+pub(crate) fn heif_item(bytes: &[u8], item_type: &[u8; 4]) -> Result<Vec<u8>> {
+    let (_, location) = heif_item_location(bytes, item_type)?;
+    Ok(bytes[item_range(bytes, &location)?].to_vec())
+}
+
+fn embed_in_jpeg(bytes: &[u8], recovered: &RecoveredMetadata) -> Result<Vec<u8>> {
+    let (exif_segment, insert_at) = jpeg_exif_segment(bytes)?;
+    let tiff = exif_segment.map(|(start, end)| &bytes[start + 10..end]);
+    let tiff = with_recovered_tags(tiff, recovered)?;
+    let len = u16::try_from(2 + 6 + tiff.len()).map_err(|_| {
+        anyhow!(
+            "EXIF of {} bytes is too large for a JPEG segment",
+            tiff.len()
+        )
+    })?;
+    let (start, end) = exif_segment.unwrap_or((insert_at, insert_at));
+    let mut out = Vec::with_capacity(bytes.len() + tiff.len());
+    out.extend_from_slice(&bytes[..start]);
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(b"Exif\0\0");
+    out.extend_from_slice(&tiff);
+    out.extend_from_slice(&bytes[end..]);
+    Ok(out)
+}
+
+/// Where a JPEG's EXIF segment starts and ends, if it has one, and where one
+/// goes: after SOI and any JFIF APP0.
+fn jpeg_exif_segment(bytes: &[u8]) -> Result<(Option<(usize, usize)>, usize)> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return Err(anyhow!("Not a JPEG"));
+    }
+    let mut exif_segment = None;
+    let mut insert_at = 2;
+    let mut pos = 2;
+    while let Some(&[0xFF, marker]) = bytes.get(pos..pos + 2) {
+        if marker == 0xFF {
+            // fill byte
+            pos += 1;
+            continue;
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let len = read_uint(bytes, pos + 2, 2, true)? as usize;
+        let end = pos + 2 + len;
+        let body = bytes
+            .get(pos + 4..end)
+            .ok_or_else(|| anyhow!("JPEG segment runs past the end of the file"))?;
+        if marker == 0xE1 && body.starts_with(b"Exif\0\0") {
+            exif_segment = Some((pos, end));
+            break;
+        }
+        if marker == 0xE0 && insert_at == pos {
+            insert_at = end;
+        }
+        pos = end;
+    }
+    Ok((exif_segment, insert_at))
+}
+
+/// An ISOBMFF box: its type, and where it and its content start and end.
+#[derive(Debug, Clone)]
+struct IsoBox {
+    kind: [u8; 4],
+    start: usize,
+    content: usize,
+    end: usize,
+}
+
+fn embed_in_heif(bytes: &[u8], recovered: &RecoveredMetadata) -> Result<Vec<u8>> {
+    let (boxes, location) = heif_item_location(bytes, b"Exif")?;
+    let tiff = with_recovered_tags(Some(&heif_exif_tiff(bytes, &location)?), recovered)?;
+    let extent = location.single_extent()?;
+    if extent.offset_field.1 == 0 || extent.length_field.1 == 0 {
+        return Err(anyhow!("Item's extent has no offset or length to change"));
+    }
+
+    let mut out = bytes.to_vec();
+    // A last box that runs to the end of the file would take in the new one
+    if let Some(last) = boxes.last()
+        && read_uint(bytes, last.start, 4, true)? == 0
+    {
+        let size = u32::try_from(last.end - last.start)
+            .map_err(|_| anyhow!("Last box is too large to give a size"))?;
+        out[last.start..last.start + 4].copy_from_slice(&size.to_be_bytes());
+    }
+    let mdat_start = out.len() as u64;
+    let payload_len = 4 + 6 + tiff.len() as u64;
+    let box_size =
+        u32::try_from(8 + payload_len).map_err(|_| anyhow!("EXIF is too large for a box"))?;
+    out.extend_from_slice(&box_size.to_be_bytes());
+    out.extend_from_slice(b"mdat");
+    out.extend_from_slice(&6u32.to_be_bytes());
+    out.extend_from_slice(b"Exif\0\0");
+    out.extend_from_slice(&tiff);
+    let extent_offset = (mdat_start + 8)
+        .checked_sub(location.base_offset)
+        .ok_or_else(|| anyhow!("Item's base offset is past the end of the file"))?;
+    write_uint(&mut out, extent.offset_field, extent_offset)?;
+    write_uint(&mut out, extent.length_field, payload_len)?;
+    Ok(out)
+}
+
+/// The top level boxes of a HEIF file, and where the first item of
+/// `item_type` is in it.
+fn heif_item_location(bytes: &[u8], item_type: &[u8; 4]) -> Result<(Vec<IsoBox>, ItemLocation)> {
+    let boxes = child_boxes(bytes, 0, bytes.len())?;
+    let meta = find_box(&boxes, b"meta")?;
+    let location = meta_items(bytes, &meta)?
+        .into_iter()
+        .find(|(kind, _)| kind == item_type)
+        .map(|(_, location)| location)
+        .ok_or_else(|| anyhow!("No {} item", String::from_utf8_lossy(item_type)))?;
+    Ok((boxes, location))
+}
+
+/// Where the data of the items of `item_types` is in a HEIF file, as offset
+/// and length pairs in the order of the items' ids. `meta` is the bytes of the
+/// file's `meta` box alone, which is all that's needed to tell.
+pub(crate) fn heif_item_extents(meta: &[u8], item_types: &[&[u8; 4]]) -> Result<Vec<(u64, u64)>> {
+    let meta_box = find_box(&child_boxes(meta, 0, meta.len())?, b"meta")?;
+    let locations: Vec<ItemLocation> = meta_items(meta, &meta_box)?
+        .into_iter()
+        .filter(|(kind, _)| item_types.contains(&kind))
+        .map(|(_, location)| location)
+        .collect();
+    extents_of(locations)
+}
+
+/// Where the XMP packet of a HEIF file is, as for [`heif_item_extents`]: the
+/// first `mime` item with the XMP content type.
+pub(crate) fn heif_xmp_extents(meta: &[u8]) -> Result<Vec<(u64, u64)>> {
+    let meta_box = find_box(&child_boxes(meta, 0, meta.len())?, b"meta")?;
+    let children = child_boxes(meta, meta_box.content + 4, meta_box.end)?;
+    let Some(id) = item_types(meta, &find_box(&children, b"iinf")?)?
+        .into_iter()
+        .find(|item| &item.kind == b"mime" && item.content_type == Some(XMP_CONTENT_TYPE))
+        .map(|item| item.id)
+    else {
+        return Ok(Vec::new());
+    };
+    let locations = item_locations(meta, &find_box(&children, b"iloc")?)?
+        .into_iter()
+        .filter(|location| location.id == id)
+        .collect();
+    extents_of(locations)
+}
+
+/// The bytes of the top level `meta` box of a HEIF file, read on its own.
+pub(crate) fn read_heif_meta<R: Read + Seek>(reader: &mut R) -> Option<Vec<u8>> {
+    let end = reader.seek(SeekFrom::End(0)).ok()?;
+    let mut pos = 0u64;
+    loop {
+        if pos + 8 > end {
+            return None;
+        }
+        reader.seek(SeekFrom::Start(pos)).ok()?;
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header).ok()?;
+        let size = u64::from(u32::from_be_bytes([
+            header[0], header[1], header[2], header[3],
+        ]));
+        let (header_len, size) = match size {
+            0 => (8, end - pos),
+            1 => {
+                let mut large = [0u8; 8];
+                reader.read_exact(&mut large).ok()?;
+                (16, u64::from_be_bytes(large))
+            }
+            _ => (8, size),
+        };
+        if size < header_len {
+            return None;
+        }
+        if &header[4..8] == b"meta" {
+            if size > MAX_META_SIZE {
+                return None;
+            }
+            reader.seek(SeekFrom::Start(pos)).ok()?;
+            let mut meta = vec![0u8; size as usize];
+            reader.read_exact(&mut meta).ok()?;
+            return Some(meta);
+        }
+        pos += size;
+    }
+}
+
+/// The extents of the items at `locations`, as offset and length pairs in the
+/// order of the items' ids.
+fn extents_of(mut locations: Vec<ItemLocation>) -> Result<Vec<(u64, u64)>> {
+    locations.sort_by_key(|location| location.id);
+    let mut extents = Vec::new();
+    for location in locations {
+        if !location.in_file {
+            return Err(anyhow!("Item {} isn't in the file", location.id));
+        }
+        for extent in &location.extents {
+            // A length of 0 runs to the end of the file
+            if extent.length == 0 {
+                return Err(anyhow!(
+                    "Item {} has an extent of no set length",
+                    location.id
+                ));
+            }
+            let offset = location
+                .base_offset
+                .checked_add(extent.offset)
+                .ok_or_else(|| anyhow!("Item {} has a bad offset", location.id))?;
+            extents.push((offset, extent.length));
+        }
+    }
+    Ok(extents)
+}
+
+/// Where in `bytes` the item at `location` is, when it's one extent of the file.
+fn item_range(bytes: &[u8], location: &ItemLocation) -> Result<std::ops::Range<usize>> {
+    let extent = location.single_extent()?;
+    let start = location.base_offset.checked_add(extent.offset);
+    start
+        .zip(start.and_then(|start| start.checked_add(extent.length)))
+        .filter(|(_, end)| *end <= bytes.len() as u64)
+        .map(|(start, end)| start as usize..end as usize)
+        .ok_or_else(|| anyhow!("Item runs past the end of the file"))
+}
+
+/// The TIFF block in the `Exif` item at `location`.
+fn heif_exif_tiff(bytes: &[u8], location: &ItemLocation) -> Result<Vec<u8>> {
+    let payload = &bytes[item_range(bytes, location)?];
+    // The payload starts with the offset of the TIFF header after it
+    let tiff_offset = read_uint(payload, 0, 4, true)? as usize;
+    let tiff = payload
+        .get(4 + tiff_offset..)
+        .ok_or_else(|| anyhow!("Exif item is too short"))?;
+    // Some writers keep the JPEG style prefix without counting it in the offset
+    Ok(tiff.strip_prefix(b"Exif\0\0").unwrap_or(tiff).to_vec())
+}
+
+/// The first of `boxes` of type `kind`.
+fn find_box(boxes: &[IsoBox], kind: &[u8; 4]) -> Result<IsoBox> {
+    boxes
+        .iter()
+        .find(|b| &b.kind == kind)
+        .cloned()
+        .ok_or_else(|| anyhow!("No {} box", String::from_utf8_lossy(kind)))
+}
+
+/// The boxes laid end to end from `start` to `end`.
+fn child_boxes(bytes: &[u8], start: usize, end: usize) -> Result<Vec<IsoBox>> {
+    let mut boxes = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        let kind = [
+            bytes[pos + 4],
+            bytes[pos + 5],
+            bytes[pos + 6],
+            bytes[pos + 7],
+        ];
+        let (size, header_len) = match read_uint(bytes, pos, 4, true)? {
+            0 => ((end - pos) as u64, 8),
+            1 => (read_uint(bytes, pos + 8, 8, true)?, 16),
+            size => (size, 8),
+        };
+        let box_end = usize::try_from(size)
+            .ok()
+            .and_then(|size| pos.checked_add(size))
+            .filter(|box_end| size >= header_len && *box_end <= end)
+            .ok_or_else(|| anyhow!("Box {} has a bad size", String::from_utf8_lossy(&kind)))?;
+        boxes.push(IsoBox {
+            kind,
+            start: pos,
+            content: pos + header_len as usize,
+            end: box_end,
+        });
+        pos = box_end;
+    }
+    Ok(boxes)
+}
+
+/// The items listed in a `meta` box, in the order `iinf` lists them: each
+/// one's type and where it is. Items with no location are left out.
+fn meta_items(bytes: &[u8], meta: &IsoBox) -> Result<Vec<([u8; 4], ItemLocation)>> {
+    // `meta` is a full box: its children follow a version and flags
+    let children = child_boxes(bytes, meta.content + 4, meta.end)?;
+    let locations = item_locations(bytes, &find_box(&children, b"iloc")?)?;
+    Ok(item_types(bytes, &find_box(&children, b"iinf")?)?
+        .into_iter()
+        .filter_map(|item| {
+            let location = locations.iter().find(|l| l.id == item.id)?;
+            Some((item.kind, location.clone()))
+        })
+        .collect())
+}
+
+/// An item listed in `iinf`.
+struct ItemInfo<'a> {
+    id: u64,
+    kind: [u8; 4],
+    /// The content type of a `mime` item.
+    content_type: Option<&'a [u8]>,
+}
+
+/// The items listed in `iinf`.
+fn item_types<'a>(bytes: &'a [u8], iinf: &IsoBox) -> Result<Vec<ItemInfo<'a>>> {
+    let version = read_uint(bytes, iinf.content, 1, true)?;
+    let count_size = if version == 0 { 2 } else { 4 };
+    let entries = child_boxes(bytes, iinf.content + 4 + count_size, iinf.end)?;
+    let mut items = Vec::new();
+    for infe in entries.iter().filter(|b| &b.kind == b"infe") {
+        // Item types are only named from version 2 of `infe`
+        let version = read_uint(bytes, infe.content, 1, true)?;
+        let id_size = match version {
+            2 => 2,
+            3 => 4,
+            _ => continue,
+        };
+        let id = read_uint(bytes, infe.content + 4, id_size, true)?;
+        let kind = infe.content + 4 + id_size + 2;
+        let Some(&[a, b, c, d]) = bytes.get(kind..kind + 4) else {
+            continue;
+        };
+        // A `mime` item's name is followed by its content type, each ending in a NUL
+        let content_type = if [a, b, c, d] == *b"mime" {
+            bytes
+                .get(kind + 4..infe.end)
+                .and_then(|names| names.split(|b| *b == 0).nth(1))
+        } else {
+            None
+        };
+        items.push(ItemInfo {
+            id,
+            kind: [a, b, c, d],
+            content_type,
+        });
+    }
+    Ok(items)
+}
+
+/// Where an item is, as listed in `iloc`.
+#[derive(Debug, Clone)]
+struct ItemLocation {
+    id: u64,
+    /// Whether the extents are of this file, rather than of `idat` or another file.
+    in_file: bool,
+    base_offset: u64,
+    extents: Vec<Extent>,
+}
+
+/// One extent of an item, and where in the file the fields giving it are.
+#[derive(Debug, Clone)]
+struct Extent {
+    offset: u64,
+    length: u64,
+    /// Position and size of the extent's offset field.
+    offset_field: (usize, usize),
+    /// Position and size of the extent's length field.
+    length_field: (usize, usize),
+}
+
+impl ItemLocation {
+    /// The extent the item is in, when it's all in one extent of the file.
+    fn single_extent(&self) -> Result<Extent> {
+        match self.extents.as_slice() {
+            [extent] if self.in_file => Ok(extent.clone()),
+            _ => Err(anyhow!("Item isn't a single extent of the file")),
+        }
+    }
+}
+
+fn item_locations(bytes: &[u8], iloc: &IsoBox) -> Result<Vec<ItemLocation>> {
+    let version = read_uint(bytes, iloc.content, 1, true)?;
+    let mut pos = iloc.content + 4;
+    let sizes = read_uint(bytes, pos, 2, true)?;
+    let offset_size = (sizes >> 12) as usize;
+    let length_size = ((sizes >> 8) & 0xF) as usize;
+    let base_offset_size = ((sizes >> 4) & 0xF) as usize;
+    let index_size = if version == 0 {
+        0
+    } else {
+        (sizes & 0xF) as usize
+    };
+    pos += 2;
+    let (id_size, count_size) = if version < 2 { (2, 2) } else { (4, 4) };
+    let item_count = read_uint(bytes, pos, count_size, true)?;
+    pos += count_size;
+    let mut locations = Vec::new();
+    for _ in 0..item_count {
+        let id = read_uint(bytes, pos, id_size, true)?;
+        pos += id_size;
+        let construction_method = if version == 0 {
+            0
+        } else {
+            pos += 2;
+            read_uint(bytes, pos - 2, 2, true)? & 0xF
+        };
+        let data_reference_index = read_uint(bytes, pos, 2, true)?;
+        pos += 2;
+        let base_offset = read_uint(bytes, pos, base_offset_size, true)?;
+        pos += base_offset_size;
+        let extent_count = read_uint(bytes, pos, 2, true)?;
+        pos += 2;
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            let offset_pos = pos + index_size;
+            let length_pos = offset_pos + offset_size;
+            extents.push(Extent {
+                offset: read_uint(bytes, offset_pos, offset_size, true)?,
+                length: read_uint(bytes, length_pos, length_size, true)?,
+                offset_field: (offset_pos, offset_size),
+                length_field: (length_pos, length_size),
+            });
+            pos = length_pos + length_size;
+        }
+        locations.push(ItemLocation {
+            id,
+            in_file: construction_method == 0 && data_reference_index == 0,
+            base_offset,
+            extents,
+        });
+    }
+    Ok(locations)
+}
+
+/// Read an unsigned integer of `size` bytes (0 reads as 0).
+fn read_uint(bytes: &[u8], pos: usize, size: usize, big_endian: bool) -> Result<u64> {
+    let field = bytes
+        .get(pos..pos + size)
+        .ok_or_else(|| anyhow!("Unexpected end of data at {pos}"))?;
+    let fold = |v: u64, b: &u8| (v << 8) | *b as u64;
+    Ok(if big_endian {
+        field.iter().fold(0, fold)
+    } else {
+        field.iter().rev().fold(0, fold)
+    })
+}
+
+/// Overwrite the big-endian field at `(pos, size)` with `value`.
+fn write_uint(bytes: &mut [u8], (pos, size): (usize, usize), value: u64) -> Result<()> {
+    if size < 8 && value >> (size * 8) != 0 {
+        return Err(anyhow!("{value} doesn't fit in a {size} byte field"));
+    }
+    let field = bytes
+        .get_mut(pos..pos + size)
+        .ok_or_else(|| anyhow!("Unexpected end of data at {pos}"))?;
+    for (i, b) in field.iter_mut().enumerate() {
+        *b = (value >> ((size - 1 - i) * 8)) as u8;
+    }
+    Ok(())
+}
+
+/// A TIFF block, as EXIF is kept in, that IFDs are appended to.
+struct Tiff {
+    data: Vec<u8>,
+    big_endian: bool,
+}
+
+/// A 12 byte IFD entry. `value` is the value itself when it fits in 4 bytes,
+/// otherwise the offset of it in the TIFF block.
+#[derive(Debug, Clone)]
+struct IfdEntry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: [u8; 4],
+}
+
+impl Tiff {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let big_endian = match data.get(..4) {
+            Some(b"II*\0") => false,
+            Some(b"MM\0*") => true,
+            _ => return Err(anyhow!("EXIF doesn't start with a TIFF header")),
+        };
+        Ok(Self {
+            data: data.to_vec(),
+            big_endian,
+        })
+    }
+
+    /// A TIFF block holding one empty IFD.
+    fn empty() -> Self {
+        Self {
+            data: b"II*\0\x08\0\0\0\0\0\0\0\0\0".to_vec(),
+            big_endian: false,
+        }
+    }
+
+    fn uint(&self, pos: usize, size: usize) -> Result<u32> {
+        Ok(read_uint(&self.data, pos, size, self.big_endian)? as u32)
+    }
+
+    fn u16_bytes(&self, v: u16) -> [u8; 2] {
+        if self.big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    }
+
+    fn u32_bytes(&self, v: u32) -> [u8; 4] {
+        if self.big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    }
+
+    /// The entries of the IFD at `offset`, and the offset of the IFD after it.
+    fn ifd(&self, offset: u32) -> Result<(Vec<IfdEntry>, u32)> {
+        let offset = offset as usize;
+        let count = self.uint(offset, 2)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let pos = offset + 2 + i * 12;
+            let value = self
+                .data
+                .get(pos + 8..pos + 12)
+                .ok_or_else(|| anyhow!("IFD runs past the end of the EXIF"))?;
+            entries.push(IfdEntry {
+                tag: self.uint(pos, 2)? as u16,
+                kind: self.uint(pos + 2, 2)? as u16,
+                count: self.uint(pos + 4, 4)?,
+                value: [value[0], value[1], value[2], value[3]],
+            });
+        }
+        Ok((entries, self.uint(offset + 2 + count * 12, 4)?))
+    }
+
+    /// The IFD a pointer entry in `entries` points to, or an empty one.
+    fn sub_ifd(&self, entries: &[IfdEntry], tag: u16) -> Result<(Vec<IfdEntry>, u32)> {
+        match entries.iter().find(|e| e.tag == tag) {
+            Some(e) => self.ifd(self.value_u32(e)),
+            None => Ok((Vec::new(), 0)),
+        }
+    }
+
+    fn value_u32(&self, entry: &IfdEntry) -> u32 {
+        if self.big_endian {
+            u32::from_be_bytes(entry.value)
+        } else {
+            u32::from_le_bytes(entry.value)
+        }
+    }
+
+    /// Offset of the end of the block, padded to a word boundary as TIFF offsets must be.
+    fn aligned_end(&mut self) -> u32 {
+        if self.data.len() % 2 == 1 {
+            self.data.push(0);
+        }
+        self.data.len() as u32
+    }
+
+    /// An entry for `bytes`, which are appended to the block unless they fit in the entry.
+    fn entry(&mut self, tag: u16, kind: u16, count: u32, bytes: &[u8]) -> IfdEntry {
+        let mut value = [0; 4];
+        if bytes.len() <= 4 {
+            value[..bytes.len()].copy_from_slice(bytes);
+        } else {
+            let offset = self.aligned_end();
+            value = self.u32_bytes(offset);
+            self.data.extend_from_slice(bytes);
+        }
+        IfdEntry {
+            tag,
+            kind,
+            count,
+            value,
+        }
+    }
+
+    fn ascii(&mut self, tag: u16, text: &str) -> IfdEntry {
+        let mut bytes = text.as_bytes().to_vec();
+        bytes.push(0);
+        self.entry(tag, TYPE_ASCII, bytes.len() as u32, &bytes)
+    }
+
+    fn long(&mut self, tag: u16, value: u32) -> IfdEntry {
+        let bytes = self.u32_bytes(value);
+        self.entry(tag, TYPE_LONG, 1, &bytes)
+    }
+
+    /// Degrees, minutes and seconds of a coordinate, as three rationals.
+    fn degrees(&mut self, tag: u16, value: f64) -> IfdEntry {
+        let value = value.abs();
+        let degrees = value.trunc();
+        let minutes = ((value - degrees) * 60.0).trunc();
+        let seconds = ((value - degrees) * 60.0 - minutes) * 60.0;
+        let mut bytes = Vec::new();
+        for (numerator, denominator) in [
+            (degrees as u32, 1),
+            (minutes as u32, 1),
+            ((seconds * 10000.0).round() as u32, 10000),
+        ] {
+            bytes.extend_from_slice(&self.u32_bytes(numerator));
+            bytes.extend_from_slice(&self.u32_bytes(denominator));
+        }
+        self.entry(tag, TYPE_RATIONAL, 3, &bytes)
+    }
+
+    /// Append an IFD of `entries`, returning its offset.
+    fn append_ifd(&mut self, mut entries: Vec<IfdEntry>, next: u32) -> u32 {
+        entries.sort_by_key(|e| e.tag);
+        let offset = self.aligned_end();
+        self.data
+            .extend_from_slice(&self.u16_bytes(entries.len() as u16));
+        for e in entries {
+            self.data.extend_from_slice(&self.u16_bytes(e.tag));
+            self.data.extend_from_slice(&self.u16_bytes(e.kind));
+            self.data.extend_from_slice(&self.u32_bytes(e.count));
+            self.data.extend_from_slice(&e.value);
+        }
+        self.data.extend_from_slice(&self.u32_bytes(next));
+        offset
+    }
+
+    /// Point the IFD at `offset` to `next` as the one after it.
+    fn set_next(&mut self, offset: u32, next: u32) -> Result<()> {
+        let pos = offset as usize + 2 + self.uint(offset as usize, 2)? as usize * 12;
+        let next = self.u32_bytes(next);
+        self.data
+            .get_mut(pos..pos + 4)
+            .ok_or_else(|| anyhow!("IFD runs past the end of the EXIF"))?
+            .copy_from_slice(&next);
+        Ok(())
+    }
+}
+
+/// Add `entry` to `entries`, in place of any with the same tag.
+fn set_entry(entries: &mut Vec<IfdEntry>, entry: IfdEntry) {
+    entries.retain(|e| e.tag != entry.tag);
+    entries.push(entry);
+}
+
+/// The TIFF block `tiff` (or a new one) with the recovered tags added.
+fn with_recovered_tags(tiff: Option<&[u8]>, recovered: &RecoveredMetadata) -> Result<Vec<u8>> {
+    let mut tiff = match tiff {
+        Some(data) => Tiff::parse(data)?,
+        None => Tiff::empty(),
+    };
+    let (mut ifd0, next) = tiff.ifd(tiff.uint(4, 4)?)?;
+    if let Some(taken) = recovered.taken {
+        let (mut exif, exif_next) = tiff.sub_ifd(&ifd0, EXIF_IFD_POINTER)?;
+        let date = taken.format("%Y:%m:%d %H:%M:%S").to_string();
+        set_entry(&mut exif, tiff.ascii(DATE_TIME_ORIGINAL, &date));
+        let offset = taken.format("%:z").to_string();
+        set_entry(&mut exif, tiff.ascii(OFFSET_TIME_ORIGINAL, &offset));
+        let exif_offset = tiff.append_ifd(exif, exif_next);
+        set_entry(&mut ifd0, tiff.long(EXIF_IFD_POINTER, exif_offset));
+    }
+    if let Some((latitude, longitude)) = recovered.coords {
+        let (mut gps, gps_next) = tiff.sub_ifd(&ifd0, GPS_IFD_POINTER)?;
+        if !gps.iter().any(|e| e.tag == GPS_VERSION_ID) {
+            gps.push(tiff.entry(GPS_VERSION_ID, TYPE_BYTE, 4, &[2, 2, 0, 0]));
+        }
+        let latitude_ref = if latitude < 0.0 { "S" } else { "N" };
+        set_entry(&mut gps, tiff.ascii(GPS_LATITUDE_REF, latitude_ref));
+        set_entry(&mut gps, tiff.degrees(GPS_LATITUDE, latitude));
+        let longitude_ref = if longitude < 0.0 { "W" } else { "E" };
+        set_entry(&mut gps, tiff.ascii(GPS_LONGITUDE_REF, longitude_ref));
+        set_entry(&mut gps, tiff.degrees(GPS_LONGITUDE, longitude));
+        let gps_offset = tiff.append_ifd(gps, gps_next);
+        set_entry(&mut ifd0, tiff.long(GPS_IFD_POINTER, gps_offset));
+    }
+    let ifd0_offset = tiff.append_ifd(ifd0, next);
+    // Readers look for the thumbnail's IFD after the first, so it moves along
+    if next != 0 {
+        let (ifd1, ifd1_next) = tiff.ifd(next)?;
+        let ifd1_offset = tiff.append_ifd(ifd1, ifd1_next);
+        tiff.set_next(ifd0_offset, ifd1_offset)?;
+    }
+    let ifd0_offset = tiff.u32_bytes(ifd0_offset);
+    tiff.data[4..8].copy_from_slice(&ifd0_offset);
+    Ok(tiff.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif_util::{PsExifInfo, parse_exif_info};
+    use crate::fs::OsFileSystem;
+    use crate::supplemental_info::load_supplemental_info;
+    use std::io::Cursor;
+
+    const TAKEOUT_EMBED: &str = "test/embed/Google Photos/Photos from 2024";
+
+    fn recovered() -> anyhow::Result<RecoveredMetadata> {
+        Ok(RecoveredMetadata {
+            taken: Some(DateTime::parse_from_rfc3339("2024-05-22T00:17:51+00:00")?),
+            coords: Some((-21.6303194, 152.2605444)),
+        })
+    }
+
+    fn exif_of(bytes: &[u8]) -> anyhow::Result<PsExifInfo> {
+        parse_exif_info(Cursor::new(bytes))?.ok_or_else(|| anyhow!("No EXIF"))
+    }
+
+    fn assert_coords(exif: &PsExifInfo, latitude: f64, longitude: f64) {
+        let close = |a: Option<f64>, b: f64| a.is_some_and(|a| (a - b).abs() < 1e-6);
+        assert!(close(exif.latitude, latitude), "{:?}", exif.latitude);
+        assert!(close(exif.longitude, longitude), "{:?}", exif.longitude);
+    }
+
+    #[test]
+    fn test_rejects_box_sizes_past_the_end() {
+        // A 64-bit size that would wrap the end of the box around
+        let mut bytes = vec![0, 0, 0, 1];
+        bytes.extend(b"ftyp");
+        bytes.extend(u64::MAX.to_be_bytes());
+        assert!(child_boxes(&bytes, 0, bytes.len()).is_err());
+
+        let mut bytes = 64u32.to_be_bytes().to_vec();
+        bytes.extend(b"ftyp");
+        assert!(child_boxes(&bytes, 0, bytes.len()).is_err());
+    }
+
+    #[test]
+    fn test_embeds_in_jpeg_without_exif() -> anyhow::Result<()> {
+        let original = std::fs::read(format!("{TAKEOUT_EMBED}/IMG_0337.jpg"))?;
+        let embedded = embed_metadata(&original, &AccurateFileType::Jpg, &recovered()?)?;
+        // The EXIF goes after the JFIF segment
+        assert_eq!(embedded[20..22], [0xFF, 0xE1]);
+        let exif = exif_of(&embedded)?;
+        assert_eq!(
+            exif.tags.get("DateTimeOriginal").map(String::as_str),
+            Some("2024-05-22T00:17:51+00:00")
+        );
+        assert_coords(&exif, -21.6303194, 152.2605444);
+        Ok(())
+    }
+
+    #[test]
+    fn test_adds_gps_keeping_what_the_exif_had() -> anyhow::Result<()> {
+        let original = std::fs::read("test/Canon_40D.jpg")?;
+        let recovered = RecoveredMetadata {
+            taken: None,
+            coords: Some((51.5, -0.125)),
+        };
+        let embedded = embed_metadata(&original, &AccurateFileType::Jpg, &recovered)?;
+        let (before, after) = (exif_of(&original)?, exif_of(&embedded)?);
+        assert_coords(&after, 51.5, -0.125);
+        for (tag, value) in before.tags.iter().filter(|(t, _)| *t != "GPSInfo") {
+            assert_eq!(after.tags.get(tag), Some(value), "{tag}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_embeds_in_avif() -> anyhow::Result<()> {
+        let original = std::fs::read("test/formats/photo.avif")?;
+        let recovered = RecoveredMetadata {
+            taken: None,
+            coords: Some((35.6586, 139.7454)),
+        };
+        let embedded = embed_metadata(&original, &AccurateFileType::Avif, &recovered)?;
+        let (before, after) = (exif_of(&original)?, exif_of(&embedded)?);
+        assert_coords(&after, 35.6586, 139.7454);
+        assert_eq!(
+            after.tags.get("DateTimeOriginal"),
+            before.tags.get("DateTimeOriginal")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovers_only_what_the_file_lacks() -> anyhow::Result<()> {
+        let c = OsFileSystem::new(TAKEOUT_EMBED);
+        let mut media = MediaFileInfo::new_for_test();
+        media.supp_info =
+            load_supplemental_info(&"IMG_0337.jpg.supplemental-metadata.json".to_string(), &c);
+        assert_eq!(recovered_metadata(&media), Some(recovered()?));
+
+        media.exif_info = parse_exif_info(std::fs::File::open("test/Canon_40D.jpg")?)?;
+        let recovered = recovered_metadata(&media).ok_or_else(|| anyhow!("Nothing recovered"))?;
+        assert_eq!(recovered.taken, None);
+        assert!(recovered.coords.is_some());
+
+        media.supp_info = None;
+        assert_eq!(recovered_metadata(&media), None);
+        Ok(())
+    }
+}
@@ -1,7 +1,13 @@
+use crate::embed::{heif_xmp_extents, read_heif_meta};
+use crate::file_type::is_orf_magic;
+use crate::util::{PatchedReader, naive_to_iso_8601, read_header};
+use crate::xmp_util::simple_properties;
+use anyhow::anyhow;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
 use nom_exif::{ExifIter, ExifIterEntry, ExifTag, MediaKind, MediaParser, MediaSource};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use tracing::debug;
 
 /*
@@ -24,11 +30,130 @@ pub(crate) struct PsExifInfo {
     pub(crate) gps: Option<String>,
     pub(crate) latitude: Option<f64>,
     pub(crate) longitude: Option<f64>,
+    /// Where the MP4 clip a Google Motion Photo carries after its JPEG starts
+    #[serde(default)]
+    pub(crate) motion_photo_offset: Option<u64>,
+}
+
+/// WebP and JPEG XL keep EXIF as a plain TIFF block in a chunk/box of their own,
+/// which nom-exif doesn't look for. Pull it out so it can be parsed as a TIFF.
+fn exif_from_container<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(header) = read_header::<R, 12>(reader) else {
+        return Ok(None);
+    };
+    let tiff = if &header[..4] == b"RIFF" && &header[8..12] == b"WEBP" {
+        find_webp_chunk(reader, b"EXIF")?.map(|mut data| {
+            // Some writers keep the JPEG style `Exif\0\0` prefix
+            if data.starts_with(b"Exif\0\0") {
+                data.drain(..6);
+            }
+            data
+        })
+    } else if header == *JXL_CONTAINER_SIGNATURE {
+        find_jxl_exif(reader)?
+    } else {
+        return Ok(None);
+    };
+    reader.seek(SeekFrom::Start(0))?;
+    // nom-exif reads a little past the last value, so leave it some slack
+    Ok(tiff.map(|mut t| {
+        t.extend_from_slice(&[0; 32]);
+        t
+    }))
+}
+
+const JXL_CONTAINER_SIGNATURE: &[u8; 12] = b"\0\0\0\x0cJXL \r\n\x87\n";
+
+/// The first chunk of a RIFF WebP with the given id.
+fn find_webp_chunk<R: Read + Seek>(
+    reader: &mut R,
+    id: &[u8; 4],
+) -> anyhow::Result<Option<Vec<u8>>> {
+    reader.seek(SeekFrom::Start(12))?;
+    let mut chunk_header = [0u8; 8];
+    while reader.read_exact(&mut chunk_header).is_ok() {
+        let size = u32::from_le_bytes([
+            chunk_header[4],
+            chunk_header[5],
+            chunk_header[6],
+            chunk_header[7],
+        ]) as u64;
+        if &chunk_header[..4] == id {
+            let mut data = Vec::new();
+            reader.by_ref().take(size).read_to_end(&mut data)?;
+            return Ok(Some(data));
+        }
+        // chunks are padded to an even size
+        reader.seek(SeekFrom::Current((size + size % 2) as i64))?;
+    }
+    Ok(None)
+}
+
+/// `Exif` box of a JPEG XL container: a 4 byte offset to the TIFF header, then
+/// the TIFF block. A bare JPEG XL codestream has nowhere to keep EXIF.
+fn find_jxl_exif<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut pos = 0;
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut box_header = [0u8; 8];
+    while reader.read_exact(&mut box_header).is_ok() {
+        let mut size =
+            u32::from_be_bytes([box_header[0], box_header[1], box_header[2], box_header[3]]) as u64;
+        let mut header_len = 8;
+        if size == 1 {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large)?;
+            size = u64::from_be_bytes(large);
+            header_len = 16;
+        }
+        if &box_header[4..8] == b"Exif" && size >= header_len + 4 {
+            let mut data = Vec::new();
+            reader
+                .by_ref()
+                .take(size - header_len)
+                .read_to_end(&mut data)?;
+            let Some(offset) = data.get(..4) else {
+                return Ok(None);
+            };
+            let tiff_offset = u32::from_be_bytes([offset[0], offset[1], offset[2], offset[3]]);
+            return Ok(data.get(4 + tiff_offset as usize..).map(|t| t.to_vec()));
+        }
+        if size < header_len {
+            // size 0 runs to the end of the file
+            break;
+        }
+        pos += size;
+        reader.seek(SeekFrom::Start(pos))?;
+    }
+    Ok(None)
+}
+
+/// Olympus ORF differs from TIFF only in its magic number, so show nom-exif
+/// the TIFF one.
+fn orf_as_tiff<R: Read + Seek>(mut reader: R) -> anyhow::Result<PatchedReader<R>> {
+    match read_header::<R, 4>(&mut reader) {
+        Some(magic) if is_orf_magic(&magic) => {
+            let tiff_magic = if &magic[..2] == b"II" {
+                b"\x2a\x00"
+            } else {
+                b"\x00\x2a"
+            };
+            PatchedReader::new(reader, 2, tiff_magic.to_vec())
+        }
+        _ => PatchedReader::unpatched(reader),
+    }
 }
 
 pub(crate) fn parse_exif_info<R: Read + Seek>(mut reader: R) -> anyhow::Result<Option<PsExifInfo>> {
+    if let Some(tiff) = exif_from_container(&mut reader)? {
+        let mut info = parse_exif_info(Cursor::new(tiff))?;
+        // The container keeps any XMP apart from the TIFF block
+        if let Some(info) = &mut info {
+            insert_xmp_tags(&mut info.tags, &mut reader)?;
+        }
+        return Ok(info);
+    }
     reader.seek(SeekFrom::Start(0))?;
-    let ms = MediaSource::seekable(reader);
+    let ms = MediaSource::seekable(orf_as_tiff(&mut reader)?);
     let Ok(ms) = ms else {
         debug!("Could not create MediaSource");
         return Ok(None);
@@ -43,12 +168,30 @@ pub(crate) fn parse_exif_info<R: Read + Seek>(mut reader: R) -> anyhow::Result<O
     let mut ps_gps_info = None;
     let mut lat = None;
     let mut long = None;
+    let mut has_embedded_track = false;
     match exif_iter_r {
         Ok(exif_iter) => {
+            has_embedded_track = exif_iter.has_embedded_track();
             for entry in exif_iter.clone() {
                 let Some(tag_enum) = entry.tag().tag() else {
                     continue; // skip unrecognised tags
                 };
+                if tag_enum == ExifTag::MakerNote {
+                    // Opaque to nom-exif, but Apple's carries the Live Photo pairing and burst ids
+                    if let Some(nom_exif::EntryValue::Undefined(bytes)) = entry.value() {
+                        m.extend(parse_apple_maker_note(bytes));
+                    }
+                    continue;
+                }
+                if tag_enum == ExifTag::UserComment {
+                    // Undefined to nom-exif too, but iOS marks screenshots with it
+                    if let Some(nom_exif::EntryValue::Undefined(bytes)) = entry.value()
+                        && let Some(comment) = decode_user_comment(bytes)
+                    {
+                        m.insert(tag_enum.to_string(), comment);
+                    }
+                    continue;
+                }
                 let tag_name = tag_enum.to_string();
                 let s_o = field_to_opt_string(&entry);
                 let Some(s) = s_o else {
@@ -71,14 +214,266 @@ pub(crate) fn parse_exif_info<R: Read + Seek>(mut reader: R) -> anyhow::Result<O
             debug!("Could not read EXIF data: {e}");
         }
     }
+    let motion_photo_offset = if has_embedded_track {
+        find_trailing_mp4(&mut reader)?
+    } else {
+        None
+    };
+    insert_xmp_tags(&mut m, &mut reader)?;
     Ok(Some(PsExifInfo {
         tags: m,
         gps: ps_gps_info,
         latitude: lat,
         longitude: long,
+        motion_photo_offset,
     }))
 }
 
+/// EXIF `UserComment`: an 8 byte character code, then the comment.
+fn decode_user_comment(bytes: &[u8]) -> Option<String> {
+    let (code, text) = (bytes.get(..8)?, bytes.get(8..)?);
+    let comment = if code == b"UNICODE\0" {
+        let big_endian = text.first() == Some(&0);
+        let units: Vec<u16> = text
+            .chunks_exact(2)
+            .map(|c| {
+                if big_endian {
+                    u16::from_be_bytes([c[0], c[1]])
+                } else {
+                    u16::from_le_bytes([c[0], c[1]])
+                }
+            })
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(text).to_string()
+    };
+    let comment = comment.trim_end_matches(['\0', ' ']).to_string();
+    (!comment.is_empty() && comment.len() <= 1024).then_some(comment)
+}
+
+/// Name stored in [`PsExifInfo::tags`] for the Google panorama projection XMP
+/// property (`equirectangular`, `cylindrical`).
+pub(crate) const TAG_GPANO_PROJECTION_TYPE: &str = "GPano:ProjectionType";
+
+/// Name stored in [`PsExifInfo::tags`] for the Google camera XMP property naming
+/// the mode a shot was taken in (e.g. a portrait).
+pub(crate) const TAG_GCAMERA_SPECIAL_TYPE_ID: &str = "GCamera:SpecialTypeID";
+
+const NS_GPANO: &str = "http://ns.google.com/photos/1.0/panorama/";
+const NS_GCAMERA: &str = "http://ns.google.com/photos/1.0/camera/";
+
+/// XMP properties kept alongside the EXIF tags: the name each is kept under,
+/// and its namespace and name in the XMP.
+const XMP_TAGS: [(&str, &str, &str); 3] = [
+    (TAG_GPANO_PROJECTION_TYPE, NS_GPANO, "ProjectionType"),
+    ("GPano:UsePanoramaViewer", NS_GPANO, "UsePanoramaViewer"),
+    (TAG_GCAMERA_SPECIAL_TYPE_ID, NS_GCAMERA, "SpecialTypeID"),
+];
+
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const TAG_XMP: u16 = 0x02BC;
+
+/// Largest IFD value read, to bound memory use on a damaged file.
+const MAX_IFD_VALUE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Add the [`XMP_TAGS`] the file's embedded XMP has to `tags`.
+fn insert_xmp_tags<R: Read + Seek>(
+    tags: &mut HashMap<String, String>,
+    reader: &mut R,
+) -> anyhow::Result<()> {
+    if let Some(xmp) = embedded_xmp(reader)? {
+        let names: Vec<(&str, &str)> = XMP_TAGS.iter().map(|(_, ns, name)| (*ns, *name)).collect();
+        for ((tag, _, _), value) in XMP_TAGS.iter().zip(simple_properties(&xmp, &names)) {
+            if let Some(value) = value {
+                tags.insert(tag.to_string(), value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The XMP packet of a JPEG, from its `APP1` segment, of a WebP, from its `XMP `
+/// chunk, of a HEIC or AVIF, from its XMP `mime` item, or of a TIFF based file,
+/// from the `XMP` tag of its first IFD. Only the metadata is read, never the
+/// image data after it.
+pub(crate) fn embedded_xmp<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<String>> {
+    let packet = match jpeg_segments(reader, 0xE1)? {
+        Some(segments) => segments
+            .into_iter()
+            .find_map(|s| s.strip_prefix(JPEG_XMP_HEADER).map(<[u8]>::to_vec)),
+        None => match read_header::<R, 12>(reader) {
+            Some(header) if &header[..4] == b"RIFF" && &header[8..12] == b"WEBP" => {
+                find_webp_chunk(reader, b"XMP ")?
+            }
+            Some(header) if &header[4..8] == b"ftyp" => heif_xmp(reader)?,
+            _ => tiff_ifd0_value(reader, TAG_XMP)?,
+        },
+    };
+    Ok(packet.map(|p| String::from_utf8_lossy(&p).to_string()))
+}
+
+/// The XMP `mime` item of a HEIF file, found through its `meta` box.
+fn heif_xmp<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(meta) = read_heif_meta(reader) else {
+        return Ok(None);
+    };
+    let extents = match heif_xmp_extents(&meta) {
+        Ok(extents) if !extents.is_empty() => extents,
+        Ok(_) => return Ok(None),
+        Err(e) => {
+            debug!("Could not find the XMP item: {e}");
+            return Ok(None);
+        }
+    };
+    let mut packet = Vec::new();
+    for (offset, length) in extents {
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.by_ref().take(length).read_to_end(&mut packet)?;
+    }
+    Ok(Some(packet))
+}
+
+/// The contents of a JPEG's segments with `marker`, in order, or `None` when
+/// it isn't a JPEG. Other segments are skipped over, and the walk stops at the
+/// image data.
+pub(crate) fn jpeg_segments<R: Read + Seek>(
+    reader: &mut R,
+    marker: u8,
+) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut soi = [0u8; 2];
+    if reader.read_exact(&mut soi).is_err() || soi != [0xff, 0xd8] {
+        return Ok(None);
+    }
+    let mut segments = Vec::new();
+    let mut header = [0u8; 4];
+    while reader.read_exact(&mut header).is_ok() && header[0] == 0xff {
+        // start of scan: the image data follows, no more metadata
+        if header[1] == 0xda {
+            break;
+        }
+        let len = u16::from_be_bytes([header[2], header[3]]) as u64;
+        if len < 2 {
+            break;
+        }
+        if header[1] == marker {
+            let mut segment = Vec::new();
+            reader.by_ref().take(len - 2).read_to_end(&mut segment)?;
+            segments.push(segment);
+        } else {
+            reader.seek(SeekFrom::Current(len as i64 - 2))?;
+        }
+    }
+    Ok(Some(segments))
+}
+
+/// The value of `tag` in the first IFD of a TIFF, or of a RAW laid out as one,
+/// or `None` when the file isn't one or the IFD hasn't the tag.
+pub(crate) fn tiff_ifd0_value<R: Read + Seek>(
+    reader: &mut R,
+    tag: u16,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; 8];
+    if reader.read_exact(&mut header).is_err() {
+        return Ok(None);
+    }
+    // The byte order, then a magic number that some RAW formats change
+    let big_endian = match &header[..2] {
+        b"II" => false,
+        b"MM" => true,
+        _ => return Ok(None),
+    };
+    let u16_of = |b: [u8; 2]| {
+        if big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    };
+    let u32_of = |b: [u8; 4]| {
+        if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    };
+    let ifd = u32_of([header[4], header[5], header[6], header[7]]);
+    reader.seek(SeekFrom::Start(u64::from(ifd)))?;
+    let mut count = [0u8; 2];
+    reader.read_exact(&mut count)?;
+    let mut entries = vec![0u8; usize::from(u16_of(count)) * 12];
+    reader.read_exact(&mut entries)?;
+    for entry in entries.chunks_exact(12) {
+        if u16_of([entry[0], entry[1]]) != tag {
+            continue;
+        }
+        let unit = match u16_of([entry[2], entry[3]]) {
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => 1,
+        };
+        let size = unit * u32_of([entry[4], entry[5], entry[6], entry[7]]) as usize;
+        if size <= 4 {
+            return Ok(Some(entry[8..8 + size].to_vec()));
+        }
+        if size > MAX_IFD_VALUE_SIZE {
+            return Err(anyhow!("IFD value of tag {tag:#06x} is too large"));
+        }
+        let offset = u32_of([entry[8], entry[9], entry[10], entry[11]]);
+        reader.seek(SeekFrom::Start(u64::from(offset)))?;
+        let mut value = vec![0u8; size];
+        reader.read_exact(&mut value)?;
+        return Ok(Some(value));
+    }
+    Ok(None)
+}
+
+/// Start of the MP4 appended to a Motion Photo. nom-exif only says one is there,
+/// so look for an `ftyp` box from which the boxes run exactly to the end of the
+/// file; a chance `ftyp` in the JPEG data won't line up like that.
+fn find_trailing_mp4<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<u64>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let offset = bytes
+        .windows(4)
+        .enumerate()
+        .filter(|(i, w)| *i >= 4 && *w == b"ftyp")
+        .map(|(i, _)| i - 4)
+        .find(|start| boxes_reach_end(&bytes, *start));
+    Ok(offset.map(|o| o as u64))
+}
+
+fn boxes_reach_end(bytes: &[u8], start: usize) -> bool {
+    let mut pos = start;
+    while pos < bytes.len() {
+        let size = match be_u32_at(bytes, pos) {
+            Some(0) => return true,
+            Some(1) => bytes
+                .get(pos + 8..pos + 16)
+                .and_then(|b| b.try_into().ok())
+                .map(u64::from_be_bytes),
+            size => size.map(u64::from),
+        };
+        let Some(size) = size.filter(|s| *s >= 8) else {
+            return false;
+        };
+        pos = match usize::try_from(size).ok().and_then(|s| pos.checked_add(s)) {
+            Some(next) => next,
+            None => return false,
+        };
+    }
+    pos == bytes.len()
+}
+
+fn be_u32_at(bytes: &[u8], pos: usize) -> Option<u32> {
+    let b: [u8; 4] = bytes.get(pos..pos + 4)?.try_into().ok()?;
+    Some(u32::from_be_bytes(b))
+}
+
 fn field_to_opt_string(field: &ExifIterEntry) -> Option<String> {
     if let Ok(value) = field.clone().into_result() {
         match value {
@@ -95,28 +490,121 @@ fn field_to_opt_string(field: &ExifIterEntry) -> Option<String> {
     None
 }
 
+/// Name stored in [`PsExifInfo::tags`] for the Apple maker note `ContentIdentifier`,
+/// shared by the still and the motion clip of a Live Photo.
+pub(crate) const TAG_CONTENT_IDENTIFIER: &str = "ContentIdentifier";
+
+/// Name stored in [`PsExifInfo::tags`] for the Apple maker note `BurstUUID`,
+/// shared by every frame of an iPhone burst.
+pub(crate) const TAG_BURST_UUID: &str = "BurstUUID";
+
+/// Apple maker note header: `Apple iOS\0`, a version, then the byte order mark.
+const APPLE_MAKER_NOTE_PREFIX: &[u8] = b"Apple iOS\0";
+
+/// Read the string tags we care about from an Apple maker note. The note is a
+/// TIFF style IFD starting 14 bytes in, with value offsets relative to the start
+/// of the note itself.
+fn parse_apple_maker_note(note: &[u8]) -> HashMap<String, String> {
+    let mut m = HashMap::new();
+    if !note.starts_with(APPLE_MAKER_NOTE_PREFIX) || note.len() < 16 {
+        return m;
+    }
+    let big_endian = match &note[12..14] {
+        b"MM" => true,
+        b"II" => false,
+        _ => return m,
+    };
+    let u16_at = |pos: usize| -> Option<u16> {
+        let b: [u8; 2] = note.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    };
+    let u32_at = |pos: usize| -> Option<u32> {
+        let b: [u8; 4] = note.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    };
+    let Some(count) = u16_at(14) else {
+        return m;
+    };
+    for i in 0..count as usize {
+        let entry = 16 + i * 12;
+        let (Some(tag), Some(format), Some(len)) =
+            (u16_at(entry), u16_at(entry + 2), u32_at(entry + 4))
+        else {
+            break;
+        };
+        let name = match tag {
+            0x000b => TAG_BURST_UUID,
+            0x0011 => TAG_CONTENT_IDENTIFIER,
+            _ => continue,
+        };
+        // only ASCII strings
+        if format != 2 {
+            continue;
+        }
+        let len = len as usize;
+        let start = if len <= 4 {
+            entry + 8
+        } else {
+            let Some(offset) = u32_at(entry + 8) else {
+                continue;
+            };
+            offset as usize
+        };
+        let Some(bytes) = note.get(start..start + len) else {
+            continue;
+        };
+        let s = String::from_utf8_lossy(bytes)
+            .trim_end_matches('\0')
+            .to_string();
+        if !s.is_empty() {
+            m.insert(name.to_string(), s);
+        }
+    }
+    m
+}
+
 fn field_value(exif: &PsExifInfo, code: ExifTag) -> Option<String> {
     exif.tags.get(&code.to_string()).cloned()
 }
 
 pub(crate) fn best_guess_taken_exif(exif: &Option<PsExifInfo>) -> Option<String> {
     match exif {
-        Some(exif) => {
-            if let Some(dt) = field_value(exif, ExifTag::DateTimeOriginal) {
-                return Some(dt);
-            }
-            if let Some(dt) = field_value(exif, ExifTag::ModifyDate) {
-                return Some(dt);
-            }
-            if let Some(dt) = field_value(exif, ExifTag::GPSDateStamp) {
-                return Some(dt);
-            }
-            None
-        }
+        Some(exif) => [
+            ExifTag::DateTimeOriginal,
+            ExifTag::ModifyDate,
+            ExifTag::GPSDateStamp,
+        ]
+        .into_iter()
+        .find_map(|tag| field_value(exif, tag).and_then(|dt| exif_dt_to_iso_8601(&dt))),
         None => None,
     }
 }
 
+/// nom-exif renders EXIF dates without a timezone (`2008-05-30 15:56:01`), or
+/// as RFC 3339 when an offset tag is present. Timezone-less values keep the
+/// camera's wall clock time, without an offset.
+fn exif_dt_to_iso_8601(dt: &str) -> Option<String> {
+    if let Ok(d) = DateTime::parse_from_rfc3339(dt) {
+        return Some(d.to_rfc3339());
+    }
+    NaiveDateTime::parse_from_str(dt, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(dt, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)
+        })
+        .map(naive_to_iso_8601)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -206,6 +694,45 @@ mod tests {
         Ok(())
     }
 
+    /// Build a big-endian Apple maker note holding one ASCII tag.
+    fn apple_maker_note(tag: u16, value: &str) -> Vec<u8> {
+        let mut value_bytes = value.as_bytes().to_vec();
+        value_bytes.push(0);
+        let mut note = APPLE_MAKER_NOTE_PREFIX.to_vec();
+        note.extend_from_slice(&[0, 1]);
+        note.extend_from_slice(b"MM");
+        note.extend_from_slice(&1u16.to_be_bytes());
+        note.extend_from_slice(&tag.to_be_bytes());
+        note.extend_from_slice(&2u16.to_be_bytes());
+        note.extend_from_slice(&(value_bytes.len() as u32).to_be_bytes());
+        // value follows the single entry and the next-IFD offset
+        let value_offset = note.len() as u32 + 4 + 4;
+        note.extend_from_slice(&value_offset.to_be_bytes());
+        note.extend_from_slice(&0u32.to_be_bytes());
+        note.extend_from_slice(&value_bytes);
+        note
+    }
+
+    #[test]
+    fn test_parse_apple_maker_note() {
+        let note = apple_maker_note(0x0011, "6A1C8E1B-3F1A-4C3E-9E0B-1D2F3A4B5C6D");
+        let tags = parse_apple_maker_note(&note);
+        assert_eq!(
+            tags.get(TAG_CONTENT_IDENTIFIER).map(|s| s.as_str()),
+            Some("6A1C8E1B-3F1A-4C3E-9E0B-1D2F3A4B5C6D")
+        );
+        let note = apple_maker_note(0x000b, "0C5B1B2A-7D7A-4E55-A3C3-3B0C2C8E1F11");
+        assert_eq!(
+            parse_apple_maker_note(&note)
+                .get(TAG_BURST_UUID)
+                .map(|s| s.as_str()),
+            Some("0C5B1B2A-7D7A-4E55-A3C3-3B0C2C8E1F11")
+        );
+        // Unknown tags and non-Apple notes are ignored.
+        assert!(parse_apple_maker_note(&apple_maker_note(0x0001, "x")).is_empty());
+        assert!(parse_apple_maker_note(b"Canon maker note").is_empty());
+    }
+
     #[test]
     fn test_gps_version_only_yields_no_coords() -> anyhow::Result<()> {
         use anyhow::anyhow;
@@ -220,4 +747,150 @@ mod tests {
         assert_eq!(info.longitude, None);
         Ok(())
     }
+
+    #[test]
+    fn test_parse_exif_raw() -> anyhow::Result<()> {
+        use anyhow::anyhow;
+        crate::test_util::setup_log();
+        let c = OsFileSystem::new("test/raw");
+        for (name, model, taken) in [
+            ("IMG_0002.DNG", "iPhone 15 Pro", "2024-07-15 14:30:25"),
+            ("P0003.ORF", "E-M10", "2019-03-02 10:11:12"),
+        ] {
+            let t = parse_exif_info(c.open(name)?)?
+                .ok_or_else(|| anyhow!("Failed to parse exif of {name}"))?
+                .tags;
+            assert_eq!(t.get("Model").map(|s| s.as_str()), Some(model));
+            assert_eq!(t.get("DateTimeOriginal").map(|s| s.as_str()), Some(taken));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_exif_newer_image_formats() -> anyhow::Result<()> {
+        use anyhow::anyhow;
+        crate::test_util::setup_log();
+        let c = OsFileSystem::new("test/formats");
+        for (name, model, taken) in [
+            ("photo.webp", "Pixel 8", "2024-08-01 12:00:00"),
+            ("photo.jxl", "iPhone 16", "2024-08-02 12:00:00"),
+            ("photo.avif", "SM-S928B", "2024-08-03 12:00:00"),
+            ("scan.tif", "Perfection V600", "1987-06-01 09:00:00"),
+        ] {
+            let t = parse_exif_info(c.open(name)?)?
+                .ok_or_else(|| anyhow!("Failed to parse exif of {name}"))?
+                .tags;
+            assert_eq!(t.get("Model").map(|s| s.as_str()), Some(model));
+            assert_eq!(t.get("DateTimeOriginal").map(|s| s.as_str()), Some(taken));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_exif_motion_photo() -> anyhow::Result<()> {
+        use anyhow::anyhow;
+        crate::test_util::setup_log();
+        let c = OsFileSystem::new("test");
+        // Both carry test/Hello.mp4 (30927 bytes) after the JPEG
+        for (name, offset) in [
+            ("motion/MVIMG_20080530_155601.jpg", 8320),
+            ("motion/PXL_20210704_102030000.MP.jpg", 8759),
+        ] {
+            let t = parse_exif_info(c.open(name)?)?.ok_or_else(|| anyhow!("no exif in {name}"))?;
+            assert_eq!(t.motion_photo_offset, Some(offset), "{name}");
+        }
+        let t = parse_exif_info(c.open("Canon_40D.jpg")?)?.ok_or_else(|| anyhow!("no exif"))?;
+        assert_eq!(t.motion_photo_offset, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_user_comment() {
+        assert_eq!(
+            decode_user_comment(b"ASCII\0\0\0Screenshot"),
+            Some("Screenshot".to_string())
+        );
+        assert_eq!(
+            decode_user_comment(b"UNICODE\0S\0h\0o\0t\0"),
+            Some("Shot".to_string())
+        );
+        assert_eq!(decode_user_comment(b"\0\0\0\0\0\0\0\0    \0\0"), None);
+        assert_eq!(decode_user_comment(b"ASCII"), None);
+    }
+
+    #[test]
+    fn test_embedded_xmp() -> anyhow::Result<()> {
+        let mut jpeg = std::fs::File::open("test/kinds/IMG_0007.jpg")?;
+        let xmp = embedded_xmp(&mut jpeg)?.ok_or_else(|| anyhow!("no XMP in the JPEG"))?;
+        assert!(xmp.contains("GPano"));
+
+        // A little-endian TIFF whose only IFD entry is the XMP, stored after it
+        let packet = b"<x:xmpmeta/>";
+        let mut tiff = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        tiff.extend(TAG_XMP.to_le_bytes());
+        tiff.extend(1u16.to_le_bytes());
+        tiff.extend((packet.len() as u32).to_le_bytes());
+        tiff.extend(26u32.to_le_bytes());
+        tiff.extend(0u32.to_le_bytes());
+        tiff.extend(packet);
+        let xmp = embedded_xmp(&mut Cursor::new(tiff))?;
+        assert_eq!(xmp.as_deref(), Some("<x:xmpmeta/>"));
+
+        for (path, title) in [
+            ("test/xmp/photo.heic", "Lighthouse"),
+            ("test/xmp/photo.webp", "Harbour"),
+        ] {
+            let xmp = embedded_xmp(&mut std::fs::File::open(path)?)?
+                .ok_or_else(|| anyhow!("no XMP in {path}"))?;
+            assert!(
+                xmp.starts_with("<x:xmpmeta") && xmp.contains(title),
+                "{path}"
+            );
+        }
+        // The EXIF of the WebP is in a chunk of its own, and its XMP in another
+        let webp = parse_exif_info(std::fs::File::open("test/xmp/photo.webp")?)?
+            .ok_or_else(|| anyhow!("no EXIF in the WebP"))?;
+        assert_eq!(
+            webp.tags.get(TAG_GPANO_PROJECTION_TYPE).map(String::as_str),
+            Some("equirectangular")
+        );
+        assert!(
+            webp.tags
+                .contains_key(&ExifTag::DateTimeOriginal.to_string())
+        );
+        let mut no_xmp = std::fs::File::open("test/heif/tiles.heic")?;
+        assert_eq!(embedded_xmp(&mut no_xmp)?, None);
+
+        assert_eq!(embedded_xmp(&mut Cursor::new(b"not an image"))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_boxes_reach_end() {
+        let ftyp = b"\0\0\0\x10ftypisom\0\0\0\0";
+        let mut file = b"\xff\xd8ftyp\xff\xd9".to_vec();
+        file.extend_from_slice(ftyp);
+        file.extend_from_slice(b"\0\0\0\x08mdat");
+        assert!(boxes_reach_end(&file, 8));
+        assert!(!boxes_reach_end(&file, 9));
+        file.push(0);
+        assert!(!boxes_reach_end(&file, 8));
+    }
+
+    #[test]
+    fn test_exif_dt_to_iso_8601() {
+        assert_eq!(
+            exif_dt_to_iso_8601("2008-05-30 15:56:01"),
+            Some("2008-05-30T15:56:01".to_string())
+        );
+        assert_eq!(
+            exif_dt_to_iso_8601("2024-07-15T14:30:25+02:00"),
+            Some("2024-07-15T14:30:25+02:00".to_string())
+        );
+        assert_eq!(
+            exif_dt_to_iso_8601("2008-05-30"),
+            Some("2008-05-30T00:00:00".to_string())
+        );
+        assert_eq!(exif_dt_to_iso_8601("0000:00:00 00:00:00"), None);
+    }
 }
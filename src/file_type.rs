@@ -1,3 +1,4 @@
+use crate::util::read_header;
 use serde::{Deserialize, Serialize};
 use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
@@ -29,7 +30,11 @@ pub(crate) fn find_quick_file_type(file_path: &str) -> QuickFileType {
         .map(|s| s.to_lowercase())
         .unwrap_or_default();
     match lowercase_file_ext.as_str() {
-        "jpg" | "jpeg" | "png" | "gif" | "heic" | "mp4" => QuickFileType::Media,
+        "jpg" | "jpeg" | "png" | "gif" | "heic" => QuickFileType::Media,
+        "webp" | "avif" | "jxl" | "tif" | "tiff" | "bmp" => QuickFileType::Media,
+        "mp4" | "mov" | "qt" | "m4v" | "3gp" => QuickFileType::Media,
+        "avi" | "mkv" | "webm" | "mts" | "m2ts" => QuickFileType::Media,
+        "dng" | "cr2" | "cr3" | "nef" | "arw" | "raf" | "orf" => QuickFileType::Media,
         "csv" => QuickFileType::AlbumCsv,
         _ => QuickFileType::Unknown,
     }
@@ -42,8 +47,27 @@ pub(crate) enum AccurateFileType {
     Png,
     Heic,
     Gif,
+    Webp,
+    Avif,
+    Jxl,
+    Tiff,
+    Bmp,
     Mp4,
     Mov,
+    M4v,
+    ThreeGp,
+    Avi,
+    Mkv,
+    Webm,
+    Mts,
+    M2ts,
+    Dng,
+    Cr2,
+    Cr3,
+    Nef,
+    Arw,
+    Raf,
+    Orf,
     Json,
     Csv,
     Unsupported,
@@ -55,8 +79,27 @@ pub(crate) fn file_ext_from_file_type(ff: &AccurateFileType) -> String {
         AccurateFileType::Gif => "gif".to_string(),
         AccurateFileType::Png => "png".to_string(),
         AccurateFileType::Heic => "heic".to_string(),
+        AccurateFileType::Webp => "webp".to_string(),
+        AccurateFileType::Avif => "avif".to_string(),
+        AccurateFileType::Jxl => "jxl".to_string(),
+        AccurateFileType::Tiff => "tif".to_string(),
+        AccurateFileType::Bmp => "bmp".to_string(),
         AccurateFileType::Mp4 => "mp4".to_string(),
         AccurateFileType::Mov => "mov".to_string(),
+        AccurateFileType::M4v => "m4v".to_string(),
+        AccurateFileType::ThreeGp => "3gp".to_string(),
+        AccurateFileType::Avi => "avi".to_string(),
+        AccurateFileType::Mkv => "mkv".to_string(),
+        AccurateFileType::Webm => "webm".to_string(),
+        AccurateFileType::Mts => "mts".to_string(),
+        AccurateFileType::M2ts => "m2ts".to_string(),
+        AccurateFileType::Dng => "dng".to_string(),
+        AccurateFileType::Cr2 => "cr2".to_string(),
+        AccurateFileType::Cr3 => "cr3".to_string(),
+        AccurateFileType::Nef => "nef".to_string(),
+        AccurateFileType::Arw => "arw".to_string(),
+        AccurateFileType::Raf => "raf".to_string(),
+        AccurateFileType::Orf => "orf".to_string(),
         AccurateFileType::Unsupported => "bin".to_string(),
         AccurateFileType::Json => "json".to_string(),
         AccurateFileType::Csv => "csv".to_string(),
@@ -74,24 +117,76 @@ pub(crate) fn metadata_type(ff: &AccurateFileType) -> MetadataType {
         AccurateFileType::Jpg
         | AccurateFileType::Png
         | AccurateFileType::Heic
-        | AccurateFileType::Gif => MetadataType::ExifTags,
-        AccurateFileType::Mp4 | AccurateFileType::Mov => MetadataType::Track,
-        AccurateFileType::Json | AccurateFileType::Csv | AccurateFileType::Unsupported => {
-            MetadataType::NoMetadata
-        }
+        | AccurateFileType::Gif
+        | AccurateFileType::Webp
+        | AccurateFileType::Avif
+        | AccurateFileType::Jxl
+        | AccurateFileType::Tiff => MetadataType::ExifTags,
+        AccurateFileType::Dng
+        | AccurateFileType::Cr2
+        | AccurateFileType::Cr3
+        | AccurateFileType::Nef
+        | AccurateFileType::Arw
+        | AccurateFileType::Raf
+        | AccurateFileType::Orf => MetadataType::ExifTags,
+        AccurateFileType::Mp4
+        | AccurateFileType::Mov
+        | AccurateFileType::M4v
+        | AccurateFileType::ThreeGp
+        | AccurateFileType::Avi
+        | AccurateFileType::Mkv
+        | AccurateFileType::Webm
+        | AccurateFileType::Mts
+        | AccurateFileType::M2ts => MetadataType::Track,
+        AccurateFileType::Bmp
+        | AccurateFileType::Json
+        | AccurateFileType::Csv
+        | AccurateFileType::Unsupported => MetadataType::NoMetadata,
     }
 }
 
+/// Camera RAW formats, which are kept next to the JPEG/HEIC developed from them.
+pub(crate) fn is_raw(ft: &AccurateFileType) -> bool {
+    matches!(
+        ft,
+        AccurateFileType::Dng
+            | AccurateFileType::Cr2
+            | AccurateFileType::Cr3
+            | AccurateFileType::Nef
+            | AccurateFileType::Arw
+            | AccurateFileType::Raf
+            | AccurateFileType::Orf
+    )
+}
+
 pub(crate) fn file_type_from_content_type(ct: &str) -> AccurateFileType {
     match ct {
         "image/jpeg" => AccurateFileType::Jpg,
         "image/gif" => AccurateFileType::Gif,
         "image/png" => AccurateFileType::Png,
         "image/heic" => AccurateFileType::Heic,
+        "image/webp" => AccurateFileType::Webp,
+        "image/avif" => AccurateFileType::Avif,
+        "image/jxl" => AccurateFileType::Jxl,
+        "image/tiff" => AccurateFileType::Tiff,
+        "image/bmp" => AccurateFileType::Bmp,
         "video/mp4" => AccurateFileType::Mp4,
         "application/mp4" => AccurateFileType::Mp4,
         "video/mov" => AccurateFileType::Mov,
-        "video/quicktime" => AccurateFileType::Mp4,
+        "video/quicktime" => AccurateFileType::Mov,
+        "video/x-m4v" => AccurateFileType::M4v,
+        "video/3gpp" => AccurateFileType::ThreeGp,
+        "video/x-msvideo" => AccurateFileType::Avi,
+        "video/matroska" => AccurateFileType::Mkv,
+        "video/x-matroska" => AccurateFileType::Mkv,
+        "video/webm" => AccurateFileType::Webm,
+        "video/mp2t" => AccurateFileType::Mts,
+        "image/x-canon-cr2" => AccurateFileType::Cr2,
+        "image/x-canon-cr3" => AccurateFileType::Cr3,
+        "image/x-nikon-nef" => AccurateFileType::Nef,
+        "image/x-sony-arw" => AccurateFileType::Arw,
+        "image/x-fuji-raf" => AccurateFileType::Raf,
+        "image/x-olympus-orf" => AccurateFileType::Orf,
         "application/octet-stream" => AccurateFileType::Unsupported,
         "application/json" => AccurateFileType::Unsupported,
         "text/csv" => AccurateFileType::Csv,
@@ -107,6 +202,12 @@ pub(crate) fn determine_file_type<R: Read + Seek>(
     if name.to_lowercase().ends_with(".json") {
         return Ok(AccurateFileType::Json);
     }
+    // file-format only knows ORFs with one particular IFD size
+    if let Some(magic) = read_header::<R, 4>(&mut reader)
+        && is_orf_magic(&magic)
+    {
+        return Ok(AccurateFileType::Orf);
+    }
     reader.seek(SeekFrom::Start(0))?;
     let fmt = match file_format::FileFormat::from_reader(reader) {
         Err(e) => {
@@ -124,11 +225,41 @@ pub(crate) fn determine_file_type<R: Read + Seek>(
         debug!("  file appears to be empty file:{name:?}");
         return Ok(AccurateFileType::Unsupported);
     }
-    let ft = file_type_from_content_type(mt);
+    let ft = match file_type_from_content_type(mt) {
+        AccurateFileType::Tiff => tiff_raw_from_name(name).unwrap_or(AccurateFileType::Tiff),
+        // Camcorders name AVCHD streams .MTS, Blu-ray and editors .m2ts
+        AccurateFileType::Mts if lowercase_ext(name) == "m2ts" => AccurateFileType::M2ts,
+        ft => ft,
+    };
     debug!("  file:{name:?}: mime type {mt:?}, file type {ft:?}");
     Ok(ft)
 }
 
+/// Olympus ORF is TIFF with its own magic number in place of 42.
+pub(crate) fn is_orf_magic(magic: &[u8; 4]) -> bool {
+    matches!(magic, b"IIRO" | b"IIRS" | b"MMOR")
+}
+
+/// DNG, NEF and ARW are plain TIFF containers as far as magic numbers go, so
+/// trust the extension to tell them apart from ordinary TIFFs.
+fn tiff_raw_from_name(name: &str) -> Option<AccurateFileType> {
+    match lowercase_ext(name).as_str() {
+        "dng" => Some(AccurateFileType::Dng),
+        "nef" => Some(AccurateFileType::Nef),
+        "arw" => Some(AccurateFileType::Arw),
+        "cr2" => Some(AccurateFileType::Cr2),
+        _ => None,
+    }
+}
+
+fn lowercase_ext(name: &str) -> String {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|s| s.to_lowercase())
+        .unwrap_or_default()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -162,6 +293,17 @@ mod tests {
         );
         assert_eq!(find_quick_file_type("test/tes"), QuickFileType::Unknown);
         assert_eq!(find_quick_file_type("test/te.s.jpg"), QuickFileType::Media);
+        assert_eq!(find_quick_file_type("test/IMG_1.MOV"), QuickFileType::Media);
+        assert_eq!(find_quick_file_type("test/clip.m4v"), QuickFileType::Media);
+        assert_eq!(find_quick_file_type("test/clip.3gp"), QuickFileType::Media);
+        assert_eq!(find_quick_file_type("test/IMG_2.DNG"), QuickFileType::Media);
+        assert_eq!(find_quick_file_type("test/P3.orf"), QuickFileType::Media);
+        for name in ["a.avi", "a.MKV", "a.webm", "a.MTS", "a.m2ts"] {
+            assert_eq!(find_quick_file_type(name), QuickFileType::Media);
+        }
+        for name in ["a.webp", "a.AVIF", "a.jxl", "a.tif", "a.tiff", "a.bmp"] {
+            assert_eq!(find_quick_file_type(name), QuickFileType::Media);
+        }
     }
 
     #[test]
@@ -173,6 +315,125 @@ mod tests {
         let r = root.open(&name)?;
         assert_eq!(determine_file_type(r, &name)?, AccurateFileType::Jpg);
 
+        let root = OsFileSystem::new("test/quicktime");
+        for (name, ft) in [
+            ("Hello.mov", AccurateFileType::Mov),
+            ("Hello.m4v", AccurateFileType::M4v),
+            ("Hello.3gp", AccurateFileType::ThreeGp),
+        ] {
+            let name = name.to_string();
+            let r = root.open(&name)?;
+            assert_eq!(determine_file_type(r, &name)?, ft);
+        }
+
+        let root = OsFileSystem::new("test/raw");
+        for (name, ft) in [
+            ("IMG_0002.DNG", AccurateFileType::Dng),
+            ("P0003.ORF", AccurateFileType::Orf),
+        ] {
+            let name = name.to_string();
+            let r = root.open(&name)?;
+            assert_eq!(determine_file_type(r, &name)?, ft);
+            assert!(is_raw(&ft));
+        }
+
+        // Headers of the other RAW formats, which file-format knows by their
+        // magic or, where it sees a plain TIFF, which are told by extension
+        let header = |magic: &[(usize, &[u8])]| {
+            let mut bytes = vec![0u8; 64];
+            for (offset, value) in magic {
+                bytes[*offset..offset + value.len()].copy_from_slice(value);
+            }
+            bytes
+        };
+        let tiff_le: &[u8] = b"II*\0\x08\0\0\0";
+        let tiff_be: &[u8] = b"MM\0*\0\0\0\x08";
+        for (bytes, name, ft) in [
+            (
+                header(&[(0, tiff_le), (8, b"CR")]),
+                "IMG_1.CR2",
+                AccurateFileType::Cr2,
+            ),
+            (
+                header(&[(0, tiff_be), (8, b"CR")]),
+                "IMG_1.CR2",
+                AccurateFileType::Cr2,
+            ),
+            (header(&[(0, tiff_le)]), "IMG_1.cr2", AccurateFileType::Cr2),
+            (
+                header(&[(4, b"ftypcrx")]),
+                "IMG_1.CR3",
+                AccurateFileType::Cr3,
+            ),
+            (
+                header(&[(0, b"II*\0"), (8, b"\x1c\0\xfe\0")]),
+                "D_1.NEF",
+                AccurateFileType::Nef,
+            ),
+            (
+                header(&[(0, b"MM\0*"), (8, b"\x1f\0\x0b\0")]),
+                "D_1.NEF",
+                AccurateFileType::Nef,
+            ),
+            (header(&[(0, tiff_le)]), "D_1.nef", AccurateFileType::Nef),
+            (
+                header(&[(0, tiff_le), (10, b"\xfe\0\x04\0\x01\0\0\0\x01\0\0\0")]),
+                "DSC1.ARW",
+                AccurateFileType::Arw,
+            ),
+            (header(&[(0, tiff_le)]), "DSC1.arw", AccurateFileType::Arw),
+            (
+                header(&[(0, b"FUJIFILMCCD-RAW 0201")]),
+                "DSCF1.RAF",
+                AccurateFileType::Raf,
+            ),
+        ] {
+            assert_eq!(
+                determine_file_type(Cursor::new(bytes), &name.to_string())?,
+                ft,
+                "{name}"
+            );
+            assert!(is_raw(&ft));
+        }
+        // Without a RAW extension a plain TIFF header is a TIFF
+        assert_eq!(
+            determine_file_type(
+                Cursor::new(header(&[(0, tiff_le)])),
+                &"scan.tif".to_string()
+            )?,
+            AccurateFileType::Tiff
+        );
+
+        let root = OsFileSystem::new("test/formats");
+        for (name, ft) in [
+            ("photo.webp", AccurateFileType::Webp),
+            ("photo.avif", AccurateFileType::Avif),
+            ("photo.jxl", AccurateFileType::Jxl),
+            ("scan.tif", AccurateFileType::Tiff),
+            ("drawing.bmp", AccurateFileType::Bmp),
+        ] {
+            let name = name.to_string();
+            let r = root.open(&name)?;
+            assert_eq!(determine_file_type(r, &name)?, ft);
+        }
+
+        let root = OsFileSystem::new("test/video");
+        for (name, ft) in [
+            ("MVI_0001.AVI", AccurateFileType::Avi),
+            ("clip.mkv", AccurateFileType::Mkv),
+            ("screen.webm", AccurateFileType::Webm),
+            ("00001.MTS", AccurateFileType::Mts),
+        ] {
+            let name = name.to_string();
+            let r = root.open(&name)?;
+            assert_eq!(determine_file_type(r, &name)?, ft);
+        }
+        let r = root.open("00001.MTS")?;
+        assert_eq!(
+            determine_file_type(r, &"00001.m2ts".to_string())?,
+            AccurateFileType::M2ts
+        );
+
         let bad: Vec<u8> = vec![];
         assert_eq!(
             determine_file_type(Cursor::new(&bad), &"bad.bad".to_string())?,
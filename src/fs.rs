@@ -1,11 +1,12 @@
 use anyhow::{Result, anyhow};
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 use zip::{ExtraField, ZipArchive};
 
 #[cfg(not(test))]
@@ -32,17 +33,53 @@ pub trait FileSystem: Send + Sync {
     // Walk returns all files recursively as relative paths
     fn walk(&self) -> Vec<String>;
     fn metadata(&self, path: &str) -> Result<FileMetadata>;
+    /// Where a file is on the local disk, when it's a file of its own there
+    fn local_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Where `sync` writes the archive. Paths are relative to its root, with `/`
+/// separators, like those of a [`FileSystem`].
+pub trait Output: Send + Sync {
+    /// Whether something is already at `path`. Errors when that can't be told,
+    /// so nothing is written over what may be there.
+    fn exists(&self, path: &str) -> Result<bool>;
+    fn open(&self, path: &str) -> Result<Box<dyn ReadSeek>>;
+    /// Write `reader` to `path`, failing when it couldn't be written in full, so
+    /// nothing links to a file that isn't there.
+    fn write(&self, dry_run: bool, path: &str, reader: &mut dyn Read) -> Result<()>;
+    /// Write `bytes` to `path` unless it already holds them. Returns whether a
+    /// write was performed - under `dry_run`, whether one would have been.
+    fn write_if_changed(&self, dry_run: bool, path: &str, bytes: &[u8]) -> Result<bool>;
+    fn set_modified(&self, dry_run: bool, path: &str, modified_datetime: &Option<i64>);
+    /// Move a file within the output, creating the directories it moves into.
+    fn rename(&self, dry_run: bool, from: &str, to: &str) -> Result<()>;
+    /// SHA-256 of `path` as recorded when it was written, where the output keeps
+    /// one, saving reading the file back to compare it.
+    fn checksum(&self, _path: &str) -> Option<String> {
+        None
+    }
+    /// The local directory behind this output, for transfers that link or move
+    /// files rather than copying their bytes.
+    fn local(&self) -> Option<&OsFileSystem> {
+        None
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct OsFileSystem {
     root: PathBuf,
+    /// Set once the file system has refused to clone a file, so later files
+    /// are copied without trying again.
+    cannot_reflink: Arc<AtomicBool>,
 }
 
 impl OsFileSystem {
     pub fn new(root: &str) -> Self {
         Self {
             root: PathBuf::from(root),
+            cannot_reflink: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -50,42 +87,44 @@ impl OsFileSystem {
         self.root.exists()
     }
 
-    pub fn write<R: Read>(&self, dry_run: bool, path: &str, mut reader: R) {
+    /// Write `reader` to `path`, failing when it couldn't be written in full.
+    pub fn try_write<R: Read>(&self, dry_run: bool, path: &str, mut reader: R) -> Result<()> {
         let p = self.root.join(path);
         if dry_run {
             debug!("Dry run: would write file {:?}", p);
-            return;
+            return Ok(());
         }
-        if let Some(parent) = p.parent()
-            && let Err(e) = fs::create_dir_all(parent)
-        {
-            error!("Unable to create directory {:?}: {}", parent, e);
-            return;
+        if let Some(parent) = p.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("Unable to create directory {parent:?}: {e}"))?;
         }
-        let mut file = match File::create(&p) {
-            Ok(f) => f,
-            Err(e) => {
-                error!("Unable to create file {p:?}: {e}");
-                return;
-            }
-        };
-        if let Err(e) = std::io::copy(&mut reader, &mut file) {
-            error!("Unable to write file {p:?}: {e}");
-            return;
+        // Written beside it and renamed over it, so a file already there that is
+        // a hard link to an input file is unlinked rather than written through
+        let file_name = p.file_name().unwrap_or_default().to_string_lossy();
+        let partial = p.with_file_name(format!(".{file_name}.partial"));
+        let mut file = File::create(&partial)
+            .map_err(|e| anyhow!("Unable to create file {partial:?}: {e}"))?;
+        let copy_r = std::io::copy(&mut reader, &mut file);
+        drop(file);
+        let write_r = copy_r.and_then(|_| fs::rename(&partial, &p));
+        if let Err(e) = write_r {
+            let _ = fs::remove_file(&partial);
+            return Err(anyhow!("Unable to write file {p:?}: {e}"));
         }
         debug!("Wrote file {p:?}");
+        Ok(())
     }
 
     /// Write `bytes` to `path`, but only when they differ from what is already on
     /// disk. Returns whether write was performed - under `dry_run`, whether one would
     /// have been.
-    pub fn write_if_changed(&self, dry_run: bool, path: &str, bytes: &[u8]) -> bool {
+    pub fn write_if_changed(&self, dry_run: bool, path: &str, bytes: &[u8]) -> Result<bool> {
         if self.file_has_contents(path, bytes) {
             debug!("Unchanged, skipping write of {:?}", self.root.join(path));
-            return false;
+            return Ok(false);
         }
-        self.write(dry_run, path, Cursor::new(bytes));
-        true
+        self.try_write(dry_run, path, Cursor::new(bytes))?;
+        Ok(true)
     }
 
     /// True when `path` exists and its contents are exactly `bytes`. The length
@@ -130,6 +169,99 @@ impl OsFileSystem {
             debug!("Set modified datetime for file {p:?} to {dt}");
         }
     }
+
+    /// Make `path` a hard link to `source`, another name for the same file.
+    pub fn hard_link(&self, dry_run: bool, source: &Path, path: &str) -> Result<()> {
+        let p = self.root.join(path);
+        if dry_run {
+            debug!("Dry run: would hard link {source:?} as {p:?}");
+            return Ok(());
+        }
+        self.create_parent(&p)?;
+        fs::hard_link(source, &p)
+            .map_err(|e| anyhow!("Unable to hard link {source:?} as {p:?}: {e}"))?;
+        debug!("Hard linked {source:?} as {p:?}");
+        Ok(())
+    }
+
+    /// Make `path` a copy-on-write clone of `source`, sharing its blocks until
+    /// either is changed. Only file systems like Btrfs, XFS and APFS can; on any
+    /// other this returns false, and after the first refusal it doesn't try.
+    pub fn reflink(&self, dry_run: bool, source: &Path, path: &str) -> Result<bool> {
+        let p = self.root.join(path);
+        if dry_run {
+            debug!("Dry run: would clone {source:?} as {p:?}");
+            return Ok(true);
+        }
+        if self.cannot_reflink.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        self.create_parent(&p)?;
+        match reflink_copy::reflink(source, &p) {
+            Ok(()) => {
+                debug!("Cloned {source:?} as {p:?}");
+                Ok(true)
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::Unsupported | ErrorKind::CrossesDevices) => {
+                warn!(
+                    "{:?} can't clone files, copying them instead: {e}",
+                    self.root
+                );
+                self.cannot_reflink.store(true, Ordering::Relaxed);
+                Ok(false)
+            }
+            Err(e) => Err(anyhow!("Unable to clone {source:?} as {p:?}: {e}")),
+        }
+    }
+
+    /// Move `source`, from anywhere on the same file system, to `path`.
+    pub fn move_in(&self, dry_run: bool, source: &Path, path: &str) -> Result<()> {
+        let p = self.root.join(path);
+        if dry_run {
+            debug!("Dry run: would move {source:?} to {p:?}");
+            return Ok(());
+        }
+        self.create_parent(&p)?;
+        fs::rename(source, &p).map_err(|e| anyhow!("Unable to move {source:?} to {p:?}: {e}"))?;
+        debug!("Moved {source:?} to {p:?}");
+        Ok(())
+    }
+
+    fn create_parent(&self, p: &Path) -> Result<()> {
+        if let Some(parent) = p.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("Unable to create directory {parent:?}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, dry_run: bool, path: &str) -> Result<()> {
+        let p = self.root.join(path);
+        if dry_run {
+            debug!("Dry run: would remove file {p:?}");
+            return Ok(());
+        }
+        fs::remove_file(&p).map_err(|e| anyhow!("Unable to remove file {p:?}: {e}"))
+    }
+
+    /// Remove the directories holding `path` that are left empty, up to the root.
+    pub fn remove_empty_dirs(&self, dry_run: bool, path: &str) {
+        let mut dir = Path::new(path).parent();
+        while let Some(d) = dir.filter(|d| !d.as_os_str().is_empty()) {
+            let p = self.root.join(d);
+            let is_empty = fs::read_dir(&p).is_ok_and(|mut entries| entries.next().is_none());
+            if !is_empty {
+                return;
+            }
+            if dry_run {
+                debug!("Dry run: would remove empty directory {p:?}");
+            } else if let Err(e) = fs::remove_dir(&p) {
+                error!("Unable to remove empty directory {p:?}: {e}");
+                return;
+            }
+            dir = d.parent();
+        }
+    }
 }
 
 impl FileSystem for OsFileSystem {
@@ -152,6 +284,10 @@ impl FileSystem for OsFileSystem {
         files
     }
 
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        Some(self.root.join(path))
+    }
+
     fn metadata(&self, path: &str) -> Result<FileMetadata> {
         let p = self.root.join(path);
         let m = fs::metadata(&p)?;
@@ -172,6 +308,145 @@ impl FileSystem for OsFileSystem {
     }
 }
 
+impl Output for OsFileSystem {
+    fn exists(&self, path: &str) -> Result<bool> {
+        let p = self.root.join(path);
+        p.try_exists()
+            .map_err(|e| anyhow!("Unable to tell whether {p:?} exists: {e}"))
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn ReadSeek>> {
+        FileSystem::open(self, path)
+    }
+
+    fn write(&self, dry_run: bool, path: &str, reader: &mut dyn Read) -> Result<()> {
+        OsFileSystem::try_write(self, dry_run, path, reader)
+    }
+
+    fn write_if_changed(&self, dry_run: bool, path: &str, bytes: &[u8]) -> Result<bool> {
+        OsFileSystem::write_if_changed(self, dry_run, path, bytes)
+    }
+
+    fn set_modified(&self, dry_run: bool, path: &str, modified_datetime: &Option<i64>) {
+        OsFileSystem::set_modified(self, dry_run, path, modified_datetime)
+    }
+
+    fn rename(&self, dry_run: bool, from: &str, to: &str) -> Result<()> {
+        let (from_p, to_p) = (self.root.join(from), self.root.join(to));
+        if dry_run {
+            debug!("Dry run: would move file {from_p:?} to {to_p:?}");
+            return Ok(());
+        }
+        self.create_parent(&to_p)?;
+        fs::rename(&from_p, &to_p)
+            .map_err(|e| anyhow!("Unable to move file {from_p:?} to {to_p:?}: {e}"))?;
+        debug!("Moved file {from_p:?} to {to_p:?}");
+        Ok(())
+    }
+
+    fn local(&self) -> Option<&OsFileSystem> {
+        Some(self)
+    }
+}
+
+/// An [`Output`] held in memory, so tests can see what sync would write
+/// without touching the disk.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct MemoryOutput {
+    files: Mutex<HashMap<String, MemoryFile>>,
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+struct MemoryFile {
+    bytes: Vec<u8>,
+    modified: Option<i64>,
+}
+
+#[cfg(test)]
+impl MemoryOutput {
+    /// The contents of `path`, if it has been written.
+    pub(crate) fn bytes(&self, path: &str) -> Option<Vec<u8>> {
+        self.lock().get(path).map(|f| f.bytes.clone())
+    }
+
+    /// The modified time of `path` in milliseconds, if one was set.
+    pub(crate) fn modified(&self, path: &str) -> Option<i64> {
+        self.lock().get(path).and_then(|f| f.modified)
+    }
+
+    /// Every path written, sorted.
+    pub(crate) fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = self.lock().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, MemoryFile>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+impl Output for MemoryOutput {
+    fn exists(&self, path: &str) -> Result<bool> {
+        Ok(self.lock().contains_key(path))
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn ReadSeek>> {
+        let bytes = self
+            .bytes(path)
+            .ok_or_else(|| anyhow!("Unable to open file {path:?}: not written"))?;
+        Ok(Box::new(Cursor::new(bytes)))
+    }
+
+    fn write(&self, dry_run: bool, path: &str, reader: &mut dyn Read) -> Result<()> {
+        if dry_run {
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(|e| anyhow!("Unable to write file {path:?}: {e}"))?;
+        let file = MemoryFile {
+            bytes,
+            modified: None,
+        };
+        self.lock().insert(path.to_string(), file);
+        Ok(())
+    }
+
+    fn write_if_changed(&self, dry_run: bool, path: &str, bytes: &[u8]) -> Result<bool> {
+        if self.bytes(path).is_some_and(|b| b == bytes) {
+            return Ok(false);
+        }
+        self.write(dry_run, path, &mut Cursor::new(bytes))?;
+        Ok(true)
+    }
+
+    fn set_modified(&self, dry_run: bool, path: &str, modified_datetime: &Option<i64>) {
+        if dry_run || modified_datetime.is_none() {
+            return;
+        }
+        if let Some(file) = self.lock().get_mut(path) {
+            file.modified = *modified_datetime;
+        }
+    }
+
+    fn rename(&self, dry_run: bool, from: &str, to: &str) -> Result<()> {
+        if dry_run {
+            return Ok(());
+        }
+        let mut files = self.lock();
+        let file = files
+            .remove(from)
+            .ok_or_else(|| anyhow!("Unable to move file {from:?}: not written"))?;
+        files.insert(to.to_string(), file);
+        Ok(())
+    }
+}
+
 fn scan_dir_recursively(files: &mut Vec<String>, dir_path: &Path, root_path: &Path) {
     if !dir_path.exists() || !dir_path.is_dir() {
         return;
@@ -399,16 +674,16 @@ mod tests {
         let on_disk = dir.path().join(path);
 
         // First write creates the file and reports that it wrote.
-        assert!(fs.write_if_changed(false, path, b"hello"));
+        assert!(fs.write_if_changed(false, path, b"hello")?);
         let mtime_after_create = fs::metadata(&on_disk)?.modified()?;
 
         // Re-writing identical bytes is a no-op: nothing is written and the
         // file's modified time is untouched.
-        assert!(!fs.write_if_changed(false, path, b"hello"));
+        assert!(!fs.write_if_changed(false, path, b"hello")?);
         assert_eq!(mtime_after_create, fs::metadata(&on_disk)?.modified()?);
 
         // Changed content is written through.
-        assert!(fs.write_if_changed(false, path, b"hello world"));
+        assert!(fs.write_if_changed(false, path, b"hello world")?);
         assert_eq!(fs::read(&on_disk)?, b"hello world");
         Ok(())
     }
@@ -420,8 +695,45 @@ mod tests {
 
         // A dry run reports it would write (content differs from the absent file)
         // but must not actually create it.
-        assert!(fs.write_if_changed(true, "albums/trip.md", b"hello"));
+        assert!(fs.write_if_changed(true, "albums/trip.md", b"hello")?);
         assert!(!dir.path().join("albums/trip.md").exists());
         Ok(())
     }
+
+    #[test]
+    fn test_failed_write_is_an_error() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fs = OsFileSystem::new(&dir.path().to_string_lossy());
+        // A file where the directory would go
+        fs::write(dir.path().join("albums"), b"")?;
+        let output: &dyn Output = &fs;
+        assert!(
+            output
+                .write(false, "albums/trip.md", &mut Cursor::new(b"hello"))
+                .is_err()
+        );
+        assert!(
+            output
+                .write_if_changed(false, "albums/trip.md", b"hello")
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reflink_stops_trying_once_refused() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("source.jpg");
+        fs::write(&source, b"hello")?;
+        let fs = OsFileSystem::new(&dir.path().join("out").to_string_lossy());
+        // Either this file system clones, or it refuses and later files are
+        // left to the caller to copy
+        let cloned = fs.reflink(false, &source, "a.jpg")?;
+        assert_eq!(fs.reflink(false, &source, "b.jpg")?, cloned);
+        assert_eq!(dir.path().join("out/b.jpg").exists(), cloned);
+        if cloned {
+            assert_eq!(fs::read(dir.path().join("out/a.jpg"))?, b"hello");
+        }
+        Ok(())
+    }
 }
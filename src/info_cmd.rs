@@ -3,7 +3,9 @@ use crate::file_type::QuickFileType;
 use crate::fs::{FileSystem, OsFileSystem};
 use crate::inspect::analyze_file;
 use crate::markdown::{assemble_markdown, mfm_from_media_file_info};
+use crate::relate::{candidate_paths, find_relations};
 use crate::util::{ScanInfo, scan_fs};
+use std::collections::HashMap;
 use std::fmt::Write;
 use tracing::{debug, warn};
 
@@ -45,7 +47,40 @@ pub(crate) fn media(si: &ScanInfo, root: &dyn FileSystem) -> anyhow::Result<Stri
         media_file_info.hash_info.long_checksum
     )?;
 
-    let mfm = mfm_from_media_file_info(&media_file_info, &[]);
+    // Relations need the other files: inspect the ones sharing this file's name.
+    let files = scan_fs(root);
+    let mut others = Vec::new();
+    for path in candidate_paths(&si.file_path, &files) {
+        let Some(other_si) = files.iter().find(|f| f.file_path == path) else {
+            continue;
+        };
+        if let Ok(Some(other)) = analyze_file(root, other_si) {
+            others.push(other);
+        }
+    }
+    let mut all = vec![&media_file_info];
+    all.extend(others.iter());
+    let relations = find_relations(&all);
+    let path_by_checksum: HashMap<String, String> = all
+        .iter()
+        .map(|m| {
+            (
+                m.hash_info.long_checksum.clone(),
+                m.original_file_this_run.clone(),
+            )
+        })
+        .collect();
+    let links = relations.links(&media_file_info.hash_info.long_checksum, &path_by_checksum);
+    if !links.is_empty() {
+        writeln!(out, "Related:")?;
+        for (kind, paths) in &links {
+            for path in paths {
+                writeln!(out, " {kind}: {path}")?;
+            }
+        }
+    }
+
+    let mfm = mfm_from_media_file_info(&media_file_info, &[], &links, None);
     let s = assemble_markdown(&mfm, &None, "")?.into_string();
     writeln!(out, "Markdown:")?;
     writeln!(out, "{s}")?;
@@ -111,6 +146,17 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_info_media_shows_live_photo_pairing() -> anyhow::Result<()> {
+        crate::test_util::setup_log();
+        let root = OsFileSystem::new("test/live_photo");
+        let si = ScanInfo::new("IMG_0001.MOV".to_string(), None, None, 0);
+        let out = media(&si, &root)?;
+        assert!(out.contains("Related:\n live-photo: IMG_0001.JPG\n"));
+        assert!(out.contains("[[IMG_0001.JPG]]"));
+        Ok(())
+    }
+
     #[test]
     fn test_info_album_google_takeout() -> anyhow::Result<()> {
         crate::test_util::setup_log();
@@ -3,6 +3,7 @@ use crate::media::{MediaFileInfo, media_file_info_from_readable};
 use crate::progress::Progress;
 use crate::supplemental_info::{detect_supplemental_info, load_supplemental_info};
 use crate::util::{ScanInfo, checksum_bytes};
+use crate::xmp_util::{detect_xmp_sidecar, load_xmp_sidecar};
 use anyhow::anyhow;
 use rayon::prelude::*;
 use std::sync::Arc;
@@ -125,6 +126,9 @@ pub(crate) fn analyze_file(
         supp_info_o = load_supplemental_info(&supp_info_path, root);
     }
 
+    let xmp_sidecar_o = detect_xmp_sidecar(&media_si.file_path, root)
+        .and_then(|xmp_path| load_xmp_sidecar(&xmp_path, root));
+
     let mut reader = root.open(&media_si.file_path.clone())?;
     let hash_info_o = checksum_bytes(&mut reader).ok();
     let Some(hash_info) = hash_info_o else {
@@ -138,8 +142,13 @@ pub(crate) fn analyze_file(
         ));
     };
 
-    let media_info_r =
-        media_file_info_from_readable(media_si, &mut reader, &supp_info_o, &hash_info);
+    let media_info_r = media_file_info_from_readable(
+        media_si,
+        &mut reader,
+        &supp_info_o,
+        &xmp_sidecar_o,
+        &hash_info,
+    );
     match media_info_r {
         Ok(media_info) => Ok(Some(media_info)),
         Err(_) => Ok(None),
@@ -0,0 +1,186 @@
+//! IPTC-IIM, the metadata Photoshop, Picasa and scanning software wrote before
+//! XMP: a caption, keywords, the photographer and where the photo was taken.
+//!
+//! In a JPEG it's the IPTC resource (`0x0404`) among the Photoshop image
+//! resources of the `APP13` segment; in a TIFF, the `IPTC-NAA` tag (`0x83BB`)
+//! of the first IFD. Each field is a dataset: `0x1C`, the record and dataset
+//! numbers, a length and the value. Text is UTF-8 when record 1 says so with
+//! `ESC % G` (`1:90`), and otherwise taken as Latin-1, as older software wrote it.
+
+use crate::exif_util::{jpeg_segments, tiff_ifd0_value};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Seek};
+use tracing::debug;
+
+const PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+const RESOURCE_IPTC: u16 = 0x0404;
+const TAG_IPTC_NAA: u16 = 0x83BB;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all(deserialize = "camelCase", serialize = "camelCase"))]
+pub(crate) struct PsIptcInfo {
+    /// `2:120` Caption/Abstract
+    pub(crate) caption: Option<String>,
+    /// `2:25` Keywords
+    #[serde(default)]
+    pub(crate) keywords: Vec<String>,
+    /// `2:80` By-line
+    #[serde(default)]
+    pub(crate) by_line: Vec<String>,
+    /// `2:92` Sub-location
+    pub(crate) sublocation: Option<String>,
+    /// `2:90` City
+    pub(crate) city: Option<String>,
+    /// `2:95` Province/State
+    pub(crate) state: Option<String>,
+    /// `2:101` Country/Primary Location Name
+    pub(crate) country: Option<String>,
+}
+
+/// IPTC-IIM embedded in a JPEG or TIFF. Only the `APP13` segments or the
+/// `IPTC-NAA` value are read.
+pub(crate) fn parse_iptc_info<R: Read + Seek>(
+    reader: &mut R,
+) -> anyhow::Result<Option<PsIptcInfo>> {
+    let iim = match jpeg_segments(reader, 0xED)? {
+        Some(segments) => photoshop_resources(segments).and_then(|r| iptc_resource(&r)),
+        None => tiff_ifd0_value(reader, TAG_IPTC_NAA)?,
+    };
+    Ok(iim.and_then(|iim| parse_iim(&iim)))
+}
+
+/// The Photoshop image resources of a JPEG, joined from its `APP13` segments
+/// as large blocks are spread over several.
+fn photoshop_resources(segments: Vec<Vec<u8>>) -> Option<Vec<u8>> {
+    let resources: Vec<u8> = segments
+        .iter()
+        .filter_map(|s| s.strip_prefix(PHOTOSHOP_HEADER))
+        .flatten()
+        .copied()
+        .collect();
+    (!resources.is_empty()).then_some(resources)
+}
+
+/// The IPTC resource among Photoshop image resources: each is `8BIM`, an id, a
+/// name padded to an even length, and a size and data also padded to one.
+fn iptc_resource(resources: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    while resources.get(pos..pos + 4) == Some(b"8BIM") {
+        let id = u16::from_be_bytes([*resources.get(pos + 4)?, *resources.get(pos + 5)?]);
+        let name_len = *resources.get(pos + 6)? as usize;
+        let size_pos = pos + 6 + (name_len + 1).next_multiple_of(2);
+        let size = u32::from_be_bytes(resources.get(size_pos..size_pos + 4)?.try_into().ok()?);
+        let data = resources.get(size_pos + 4..size_pos + 4 + size as usize)?;
+        if id == RESOURCE_IPTC {
+            return Some(data.to_vec());
+        }
+        pos = size_pos + 4 + (size as usize).next_multiple_of(2);
+    }
+    None
+}
+
+/// The datasets of an IPTC-IIM block that ptsync keeps, or `None` when it has none.
+fn parse_iim(iim: &[u8]) -> Option<PsIptcInfo> {
+    let mut datasets: Vec<(u8, u8, &[u8])> = Vec::new();
+    let mut pos = 0;
+    while let Some(&[0x1C, record, dataset, hi, lo]) = iim.get(pos..pos + 5) {
+        let mut len = u16::from_be_bytes([hi, lo]) as usize;
+        pos += 5;
+        // An extended dataset gives the size of its length first
+        if len & 0x8000 != 0 {
+            let len_size = len & 0x7FFF;
+            len = iim
+                .get(pos..pos + len_size)?
+                .iter()
+                .fold(0, |l, b| (l << 8) | *b as usize);
+            pos += len_size;
+        }
+        let Some(value) = iim.get(pos..pos + len) else {
+            debug!("IPTC dataset {record}:{dataset} runs past the end of the block");
+            break;
+        };
+        datasets.push((record, dataset, value));
+        pos += len;
+    }
+    let utf8 = datasets
+        .iter()
+        .any(|(r, d, v)| (*r, *d) == (1, 90) && *v == b"\x1b%G");
+    let text = |value: &[u8]| {
+        let text = match std::str::from_utf8(value) {
+            Ok(text) if utf8 || text.is_ascii() => text.to_string(),
+            _ => value.iter().map(|b| *b as char).collect(),
+        };
+        let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        (!text.is_empty()).then(|| text.to_string())
+    };
+    let all = |number: u8| -> Vec<String> {
+        datasets
+            .iter()
+            .filter(|(r, d, _)| (*r, *d) == (2, number))
+            .filter_map(|(_, _, v)| text(v))
+            .collect()
+    };
+    let first = |number: u8| all(number).into_iter().next();
+    let info = PsIptcInfo {
+        caption: first(120),
+        keywords: all(25),
+        by_line: all(80),
+        sublocation: first(92),
+        city: first(90),
+        state: first(95),
+        country: first(101),
+    };
+    (info != PsIptcInfo::default()).then_some(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::fs::File;
+
+    #[test]
+    fn test_parse_jpeg_iptc() -> anyhow::Result<()> {
+        let mut file = File::open("test/iptc/IMG_0011.jpg")?;
+        let info = parse_iptc_info(&mut file)?.ok_or_else(|| anyhow!("No IPTC"))?;
+        assert_eq!(
+            info,
+            PsIptcInfo {
+                caption: Some("Lake at dusk".to_string()),
+                keywords: vec!["lake".to_string(), "Zürich".to_string()],
+                by_line: vec!["Jane Doe".to_string()],
+                sublocation: Some("Seefeld".to_string()),
+                city: Some("Zürich".to_string()),
+                state: Some("ZH".to_string()),
+                country: Some("Switzerland".to_string()),
+            }
+        );
+        assert_eq!(
+            parse_iptc_info(&mut File::open("test/Canon_40D.jpg")?)?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tiff_iptc_in_latin1() -> anyhow::Result<()> {
+        let mut file = File::open("test/iptc/scan.tif")?;
+        let info = parse_iptc_info(&mut file)?.ok_or_else(|| anyhow!("No IPTC"))?;
+        assert_eq!(info.caption, Some("Grand-mère à Genève".to_string()));
+        assert_eq!(info.by_line, vec!["Studio Photo".to_string()]);
+        assert_eq!(
+            parse_iptc_info(&mut File::open("test/formats/scan.tif")?)?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_extended_dataset() {
+        let caption = "x".repeat(40000);
+        let mut iim = vec![0x1C, 2, 120, 0x80, 0x04];
+        iim.extend_from_slice(&(caption.len() as u32).to_be_bytes());
+        iim.extend_from_slice(caption.as_bytes());
+        assert_eq!(parse_iim(&iim).and_then(|i| i.caption), Some(caption));
+    }
+}
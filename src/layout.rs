@@ -0,0 +1,517 @@
+//! Where in the archive a media file is stored, as a path template.
+//!
+//! A template is literal text with `{token}`s filled in from the file:
+//!
+//! | Token | Value |
+//! | --- | --- |
+//! | `{yyyy}` `{yy}` `{mm}` `{dd}` | year, two-digit year, month and day taken |
+//! | `{hh}` `{mi}` `{ss}` `{ms}` | hour, minute, second and millisecond taken |
+//! | `{checksum}` | short checksum |
+//! | `{stem}` | original file name without its extension |
+//! | `{camera}` | camera model, or `unknown` |
+//! | `{source}` | `google`, `icloud` or `other` |
+//! | `{kind}` | kind of shot (`screenshot`, ...), otherwise `photo` or `video` |
+//!
+//! Dated files use one template and undated files another. Every value comes
+//! from the file itself, so a template names a file the same way on every run.
+//! Templates are checked when parsed so that names stay unique: a dated one must
+//! name the second a photo was taken (or its checksum), an undated one its
+//! checksum. When two files still get the same name, `resolve_output_path` adds
+//! a checksum suffix as it always has.
+//!
+//! Nor can a template put media where sync keeps anything else: `albums/`,
+//! `bursts/`, `generated/`, a kind's folder or `similar.md`. A camera or file
+//! name that renders to one of those gets a leading `_` instead.
+
+use crate::dedup::is_google_path;
+use crate::file_type::{MetadataType, metadata_type};
+use crate::media::{MediaFileInfo, best_guess_taken_dt};
+use crate::subtype::{MediaSubtype, media_subtype};
+use crate::util::parse_taken;
+use anyhow::anyhow;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use clap::ValueEnum;
+use nom_exif::ExifTag;
+use std::path::Path;
+use tracing::warn;
+
+/// `yyyy/mm/dd/hhmm-ssms`, eg `2024/07/15/1430-22417`
+pub(crate) const DEFAULT_DATED_TEMPLATE: &str = "{yyyy}/{mm}/{dd}/{hh}{mi}-{ss}{ms}";
+/// `undated/checksum`, eg `undated/9f8e7d6`
+pub(crate) const DEFAULT_UNDATED_TEMPLATE: &str = "undated/{checksum}";
+
+/// Top-level directories the archive uses for files other than media, or for
+/// media it stores apart (generated files, bursts and `--separate-kind` kinds).
+const RESERVED_DIRS: &[&str] = &["albums", "bursts", "generated"];
+/// Top-level files the archive writes, without their `.md`.
+const RESERVED_FILES: &[&str] = &["similar"];
+
+/// Whether a media path's first segment is a name sync uses for something else:
+/// `dir` if more of the path follows it, otherwise a file whose note would be
+/// `<name>.md`.
+fn is_reserved(first: &str, dir: bool) -> bool {
+    if dir {
+        RESERVED_DIRS.contains(&first)
+            || MediaSubtype::value_variants()
+                .iter()
+                .any(|kind| kind.name() == first)
+    } else {
+        RESERVED_FILES.contains(&first)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Year,
+    ShortYear,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Millis,
+    Checksum,
+    Stem,
+    Camera,
+    Source,
+    Kind,
+}
+
+impl Token {
+    fn from_name(name: &str) -> Option<Token> {
+        Some(match name {
+            "yyyy" => Token::Year,
+            "yy" => Token::ShortYear,
+            "mm" => Token::Month,
+            "dd" => Token::Day,
+            "hh" => Token::Hour,
+            "mi" => Token::Minute,
+            "ss" => Token::Second,
+            "ms" => Token::Millis,
+            "checksum" => Token::Checksum,
+            "stem" => Token::Stem,
+            "camera" => Token::Camera,
+            "source" => Token::Source,
+            "kind" => Token::Kind,
+            _ => return None,
+        })
+    }
+
+    fn is_date(&self) -> bool {
+        matches!(
+            self,
+            Token::Year
+                | Token::ShortYear
+                | Token::Month
+                | Token::Day
+                | Token::Hour
+                | Token::Minute
+                | Token::Second
+                | Token::Millis
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Token(Token),
+}
+
+/// A parsed path template, see the module docs.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PathTemplate {
+    parts: Vec<Part>,
+}
+
+impl PathTemplate {
+    fn parse(template: &str) -> anyhow::Result<PathTemplate> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            match rest.find(['{', '}']) {
+                Some(0) => {
+                    let end = rest
+                        .find('}')
+                        .filter(|_| rest.starts_with('{'))
+                        .ok_or_else(|| anyhow!("Unmatched brace in path template: {template}"))?;
+                    let name = &rest[1..end];
+                    let token = Token::from_name(name).ok_or_else(|| {
+                        anyhow!("Unknown token {{{name}}} in path template: {template}")
+                    })?;
+                    parts.push(Part::Token(token));
+                    rest = &rest[end + 1..];
+                }
+                Some(i) => {
+                    parts.push(Part::Text(rest[..i].to_string()));
+                    rest = &rest[i..];
+                }
+                None => {
+                    parts.push(Part::Text(rest.to_string()));
+                    rest = "";
+                }
+            }
+        }
+        let parsed = PathTemplate { parts };
+        parsed.check_segments(template)?;
+        Ok(parsed)
+    }
+
+    /// The path must stay inside the archive, away from its other files, and
+    /// end in a file name.
+    fn check_segments(&self, template: &str) -> anyhow::Result<()> {
+        if template.contains('\\') {
+            return Err(anyhow!("Path template must use `/`: {template}"));
+        }
+        let segments: Vec<&str> = template.split('/').collect();
+        for segment in &segments {
+            if segment.is_empty() || *segment == "." || *segment == ".." {
+                return Err(anyhow!(
+                    "Path template has an empty, `.` or `..` directory: {template}"
+                ));
+            }
+            if segment.starts_with('.') {
+                return Err(anyhow!(
+                    "Path template makes hidden files or directories: {template}"
+                ));
+            }
+        }
+        if is_reserved(segments[0], segments.len() > 1) {
+            return Err(anyhow!(
+                "Path template puts media at `{}`, which holds other files: {template}",
+                segments[0]
+            ));
+        }
+        Ok(())
+    }
+
+    fn uses(&self, token: Token) -> bool {
+        self.parts.contains(&Part::Token(token))
+    }
+
+    fn render(&self, media: &MediaFileInfo, taken: Option<&NaiveDateTime>) -> String {
+        let mut path = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => path.push_str(text),
+                Part::Token(token) => path.push_str(&token_value(*token, media, taken)),
+            }
+        }
+        // A value like a camera named `albums` can still land on a reserved name
+        let (first, rest) = path.split_once('/').unwrap_or((&path, ""));
+        if is_reserved(first, !rest.is_empty()) {
+            path.insert(0, '_');
+        }
+        path
+    }
+}
+
+/// Parse a template for files with a date taken: it must name the second the
+/// photo was taken, or its checksum.
+pub(crate) fn parse_dated_template(template: &str) -> anyhow::Result<PathTemplate> {
+    let parsed = PathTemplate::parse(template)?;
+    let names_second = (parsed.uses(Token::Year) || parsed.uses(Token::ShortYear))
+        && [
+            Token::Month,
+            Token::Day,
+            Token::Hour,
+            Token::Minute,
+            Token::Second,
+        ]
+        .iter()
+        .all(|t| parsed.uses(*t));
+    if !names_second && !parsed.uses(Token::Checksum) {
+        return Err(anyhow!(
+            "Path template must include {{yyyy}} (or {{yy}}), {{mm}}, {{dd}}, {{hh}}, {{mi}} and \
+             {{ss}}, or {{checksum}}, to give each file its own name: {template}"
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Parse a template for files without a date taken: it can't use date tokens
+/// and must include the checksum.
+pub(crate) fn parse_undated_template(template: &str) -> anyhow::Result<PathTemplate> {
+    let parsed = PathTemplate::parse(template)?;
+    if parsed
+        .parts
+        .iter()
+        .any(|p| matches!(p, Part::Token(t) if t.is_date()))
+    {
+        return Err(anyhow!(
+            "Undated path template can't use date tokens: {template}"
+        ));
+    }
+    if !parsed.uses(Token::Checksum) {
+        return Err(anyhow!(
+            "Undated path template must include {{checksum}} to give each file its own name: \
+             {template}"
+        ));
+    }
+    Ok(parsed)
+}
+
+/// The templates for dated and undated files.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PathLayout {
+    pub(crate) dated: PathTemplate,
+    pub(crate) undated: PathTemplate,
+}
+
+impl Default for PathLayout {
+    fn default() -> Self {
+        let text = |t: &str| Part::Text(t.to_string());
+        PathLayout {
+            // DEFAULT_DATED_TEMPLATE
+            dated: PathTemplate {
+                parts: vec![
+                    Part::Token(Token::Year),
+                    text("/"),
+                    Part::Token(Token::Month),
+                    text("/"),
+                    Part::Token(Token::Day),
+                    text("/"),
+                    Part::Token(Token::Hour),
+                    Part::Token(Token::Minute),
+                    text("-"),
+                    Part::Token(Token::Second),
+                    Part::Token(Token::Millis),
+                ],
+            },
+            // DEFAULT_UNDATED_TEMPLATE
+            undated: PathTemplate {
+                parts: vec![text("undated/"), Part::Token(Token::Checksum)],
+            },
+        }
+    }
+}
+
+impl PathLayout {
+    /// Desired path of a media file relative to the output directory, without
+    /// its extension.
+    pub(crate) fn media_path(&self, media: &MediaFileInfo) -> String {
+        self.media_path_taken_at(media, best_guess_taken_dt(media))
+    }
+
+    /// As [`PathLayout::media_path`], for a file taken at `taken` (RFC 3339, or ISO 8601 without an offset),
+    /// eg as recorded in its note.
+    pub(crate) fn media_path_taken_at(
+        &self,
+        media: &MediaFileInfo,
+        taken: Option<String>,
+    ) -> String {
+        let taken = taken.and_then(|dt_s| {
+            let taken = parse_taken(&dt_s);
+            if taken.is_none() {
+                warn!("Could not parse datetime: {dt_s:?}");
+            }
+            taken
+        });
+        match &taken {
+            Some(dt) => self.dated.render(media, Some(dt)),
+            None => self.undated.render(media, None),
+        }
+    }
+}
+
+fn token_value(token: Token, media: &MediaFileInfo, taken: Option<&NaiveDateTime>) -> String {
+    let date_part = |f: fn(&NaiveDateTime) -> String| taken.map(f).unwrap_or_default();
+    match token {
+        Token::Year => date_part(|dt| dt.year().to_string()),
+        Token::ShortYear => date_part(|dt| format!("{:0>2}", dt.year() % 100)),
+        Token::Month => date_part(|dt| format!("{:0>2}", dt.month())),
+        Token::Day => date_part(|dt| format!("{:0>2}", dt.day())),
+        Token::Hour => date_part(|dt| format!("{:0>2}", dt.hour())),
+        Token::Minute => date_part(|dt| format!("{:0>2}", dt.minute())),
+        Token::Second => date_part(|dt| format!("{:0>2}", dt.second())),
+        Token::Millis => date_part(|dt| format!("{:0>3}", dt.nanosecond() / 1_000_000)),
+        Token::Checksum => media.hash_info.short_checksum.clone(),
+        Token::Stem => path_safe(
+            Path::new(source_path(media))
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string()),
+        ),
+        Token::Camera => path_safe(camera_model(media)),
+        Token::Source => source_name(media).to_string(),
+        Token::Kind => kind_name(media).to_string(),
+    }
+}
+
+fn camera_model(media: &MediaFileInfo) -> Option<String> {
+    media
+        .exif_info
+        .as_ref()
+        .and_then(|e| e.tags.get(&ExifTag::Model.to_string()).cloned())
+        .or_else(|| media.track_info.as_ref().and_then(|t| t.model.clone()))
+}
+
+/// The path of the copy kept, which names come from. Notes record it as
+/// `source-path`, so `relayout` finds the same one.
+fn source_path(media: &MediaFileInfo) -> &str {
+    match media.original_file_this_run.as_str() {
+        "" => media.original_path.first().map_or("", String::as_str),
+        path => path,
+    }
+}
+
+fn source_name(media: &MediaFileInfo) -> &'static str {
+    let path = source_path(media);
+    if is_google_path(path) {
+        "google"
+    } else if path.contains("iCloud Photos/") {
+        "icloud"
+    } else {
+        "other"
+    }
+}
+
+fn kind_name(media: &MediaFileInfo) -> &'static str {
+    match media_subtype(media) {
+        Some(kind) => kind.name(),
+        None if matches!(
+            metadata_type(&media.accurate_file_type),
+            MetadataType::Track
+        ) =>
+        {
+            "video"
+        }
+        None => "photo",
+    }
+}
+
+/// A value as a single path segment: characters that separate or hide paths, or
+/// that some file systems reject, become `_`.
+fn path_safe(value: Option<String>) -> String {
+    let value: String = value
+        .unwrap_or_default()
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ' ') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let value = value.trim_start_matches('.').trim().to_string();
+    if value.is_empty() {
+        "unknown".to_string()
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dated(path: &str, taken: &str) -> MediaFileInfo {
+        MediaFileInfo::new_for_test()
+            .with_path(path)
+            .with_checksum("a1b2c3d")
+            .with_exif_tag(&ExifTag::DateTimeOriginal.to_string(), taken)
+            .with_exif_tag(&ExifTag::Model.to_string(), "iPhone 15/Pro")
+    }
+
+    #[test]
+    fn test_default_layout() -> anyhow::Result<()> {
+        let layout = PathLayout::default();
+        assert_eq!(layout.dated, parse_dated_template(DEFAULT_DATED_TEMPLATE)?);
+        assert_eq!(
+            layout.undated,
+            parse_undated_template(DEFAULT_UNDATED_TEMPLATE)?
+        );
+        let m = dated("iCloud Photos/IMG_3986.JPG", "2024-07-15 14:30:22");
+        assert_eq!(layout.media_path(&m), "2024/07/15/1430-22000");
+        let undated = MediaFileInfo::new_for_test();
+        assert_eq!(layout.media_path(&undated), "undated/tsc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_templates() -> anyhow::Result<()> {
+        let m = dated("iCloud Photos/IMG_3986.JPG", "2024-07-15 14:30:22");
+        let render = |template: &str| -> anyhow::Result<String> {
+            Ok(parse_dated_template(template)?.render(&m, best_taken(&m).as_ref()))
+        };
+        assert_eq!(
+            render("{yyyy}/{yyyy}-{mm}/{yyyy}-{mm}-{dd}_{hh}{mi}{ss}")?,
+            "2024/2024-07/2024-07-15_143022"
+        );
+        assert_eq!(
+            render("{yyyy}/{mm}/{dd}/{hh}{mi}-{ss}{ms}_{stem}")?,
+            "2024/07/15/1430-22000_IMG_3986"
+        );
+        assert_eq!(
+            render("{source}/{kind}/{camera}/{yy}{mm}{dd}-{checksum}")?,
+            "icloud/photo/iPhone 15_Pro/240715-a1b2c3d"
+        );
+        let google = dated("Takeout/Google Photos/IMG_1.JPG", "2024-07-15 14:30:22");
+        let template = parse_undated_template("{source}/{checksum}")?;
+        assert_eq!(template.render(&google, None), "google/a1b2c3d");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rendered_reserved_names_are_escaped() -> anyhow::Result<()> {
+        let m = dated("Takeout/generated/screenshot.PNG", "2024-07-15 14:30:22")
+            .with_exif_tag(&ExifTag::Model.to_string(), "albums");
+        let render = |template: &str| -> anyhow::Result<String> {
+            Ok(parse_dated_template(template)?.render(&m, best_taken(&m).as_ref()))
+        };
+        assert_eq!(render("{camera}/{checksum}")?, "_albums/a1b2c3d");
+        assert_eq!(render("{stem}/{checksum}")?, "_screenshot/a1b2c3d");
+        assert_eq!(
+            render("{yyyy}/{stem}-{checksum}")?,
+            "2024/screenshot-a1b2c3d"
+        );
+        assert_eq!(render("{stem}-{checksum}")?, "screenshot-a1b2c3d");
+        assert!(is_reserved("similar", false));
+        assert!(!is_reserved("similar", true));
+        Ok(())
+    }
+
+    fn best_taken(m: &MediaFileInfo) -> Option<NaiveDateTime> {
+        best_guess_taken_dt(m).and_then(|dt| parse_taken(&dt))
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for template in [
+            "{yyyy}/{mm}/{dd}",
+            "{yyyy}/{mm}/{dd}/{hh}{mi}-{stem}",
+            "{yyyy}/{month}/{dd}/{hh}{mi}{ss}",
+            "{yyyy}/{mm}/{dd}/{hh}{mi}{ss",
+            "{yyyy}/{mm}/}{dd}/{hh}{mi}{ss}",
+            "/{yyyy}/{mm}/{dd}/{hh}{mi}{ss}",
+            "{yyyy}/../{mm}{dd}{hh}{mi}{ss}",
+            "{yyyy}//{mm}{dd}{hh}{mi}{ss}",
+            "{yyyy}/{mm}{dd}{hh}{mi}{ss}/",
+            "{yyyy}\\{mm}{dd}{hh}{mi}{ss}",
+            ".hidden/{checksum}",
+            "albums/{checksum}",
+            "generated/{yyyy}/{mm}{dd}{hh}{mi}{ss}",
+            "screenshot/{checksum}",
+        ] {
+            assert!(parse_dated_template(template).is_err(), "{template}");
+        }
+        for template in [
+            "undated/{stem}",
+            "undated/{yyyy}-{checksum}",
+            "bursts/{checksum}",
+        ] {
+            assert!(parse_undated_template(template).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn test_path_safe() {
+        assert_eq!(
+            path_safe(Some(" ../Canon: EOS 40D ".to_string())),
+            "_Canon_ EOS 40D"
+        );
+        assert_eq!(path_safe(Some("...".to_string())), "unknown");
+        assert_eq!(path_safe(None), "unknown");
+    }
+}
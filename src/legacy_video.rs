@@ -0,0 +1,348 @@
+//! Date, duration and frame size for the older video containers nom-exif
+//! doesn't read: RIFF AVI from digicams, and the MPEG-2 transport streams
+//! (`.MTS`/`.m2ts`) AVCHD camcorders record.
+
+use crate::track_util::PsTrackInfo;
+use crate::util::naive_to_iso_8601;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use std::io::{Read, Seek, SeekFrom};
+use tracing::debug;
+
+/// Largest RIFF header list (`hdrl`/`INFO`) read into memory. Real ones are a
+/// few KB.
+const MAX_AVI_LIST_SIZE: u64 = 1024 * 1024;
+
+/// How much of each end of a transport stream is searched for timestamps and
+/// the recording date. Camcorders repeat both at least every second of video.
+const TS_SCAN_WINDOW: u64 = 4 * 1024 * 1024;
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+/// Program clock reference ticks per millisecond.
+const PCR_TICKS_PER_MS: u64 = 90;
+
+/// UUID of the H.264 SEI user data holding the AVCHD `MDPM` (modified digital
+/// video pack metadata), followed by the `MDPM` marker itself.
+const MDPM_MARKER: &[u8] = b"\x17\xee\x8c\x60\xf8\x4d\x11\xd9\x8c\xd6\x08\x00\x20\x0c\x9a\x66MDPM";
+const MDPM_TAG_DATE: u8 = 0x18;
+const MDPM_TAG_TIME: u8 = 0x19;
+
+/// The containers read here rather than by nom-exif.
+pub(crate) enum LegacyContainer {
+    Avi,
+    TransportStream { packet_size: usize },
+}
+
+/// Which of the containers here, if any, the reader holds, going by its first
+/// packet or two.
+pub(crate) fn legacy_container<R: Read + Seek>(reader: &mut R) -> Option<LegacyContainer> {
+    let mut header = Vec::new();
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    reader
+        .by_ref()
+        .take(2 * (TS_PACKET_SIZE as u64 + 4))
+        .read_to_end(&mut header)
+        .ok()?;
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    let read = header.len();
+    if read >= 12 && &header[..4] == b"RIFF" && &header[8..12] == b"AVI " {
+        return Some(LegacyContainer::Avi);
+    }
+    // Plain TS packets are 188 bytes; BDAV (AVCHD) prefixes each with a 4 byte
+    // arrival timestamp.
+    for (prefix, packet_size) in [(0, TS_PACKET_SIZE), (4, TS_PACKET_SIZE + 4)] {
+        if read > packet_size + prefix
+            && header[prefix] == TS_SYNC_BYTE
+            && header[packet_size + prefix] == TS_SYNC_BYTE
+        {
+            return Some(LegacyContainer::TransportStream { packet_size });
+        }
+    }
+    None
+}
+
+/// Frame size and duration from the AVI main header, and the recording date
+/// from the `IDIT` chunk digicams write, or failing that the `INFO` list's
+/// `ICRD`.
+pub(crate) fn parse_avi_info<R: Read + Seek>(reader: &mut R) -> anyhow::Result<PsTrackInfo> {
+    let mut info = empty_track_info();
+    let mut idit = None;
+    let mut icrd = None;
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut pos = 12;
+    while pos + 8 <= file_len {
+        reader.seek(SeekFrom::Start(pos))?;
+        let (fourcc, size) = read_riff_chunk_header(reader)?;
+        if &fourcc == b"LIST" && (4..=MAX_AVI_LIST_SIZE).contains(&size) {
+            let mut list = Vec::new();
+            reader.by_ref().take(size).read_to_end(&mut list)?;
+            for (id, data) in riff_chunks(list.get(4..).unwrap_or_default()) {
+                match &id {
+                    b"avih" => avih_into(data, &mut info),
+                    b"IDIT" => idit = Some(riff_string(data)),
+                    b"ICRD" => icrd = Some(riff_string(data)),
+                    _ => {}
+                }
+            }
+        }
+        pos += 8 + size + size % 2;
+    }
+    info.creation_time = idit
+        .and_then(|s| parse_avi_date(&s))
+        .or_else(|| icrd.and_then(|s| parse_avi_date(&s)))
+        .map(naive_to_iso_8601);
+    Ok(info)
+}
+
+fn read_riff_chunk_header<R: Read>(reader: &mut R) -> anyhow::Result<([u8; 4], u64)> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    let fourcc = [header[0], header[1], header[2], header[3]];
+    let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+    Ok((fourcc, size))
+}
+
+/// The chunks of a RIFF list body, descending into nested lists.
+fn riff_chunks(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    while data.len() >= 8 {
+        let id = [data[0], data[1], data[2], data[3]];
+        let size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let Some(body) = data.get(8..8 + size) else {
+            break;
+        };
+        if &id == b"LIST" {
+            chunks.extend(riff_chunks(body.get(4..).unwrap_or_default()));
+        } else {
+            chunks.push((id, body));
+        }
+        data = data.get(8 + size + size % 2..).unwrap_or_default();
+    }
+    chunks
+}
+
+fn riff_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches(['\0', '\n', '\r', ' '])
+        .to_string()
+}
+
+/// `avih`: microseconds per frame, then total frames at 16, width and height
+/// at 32 and 36.
+fn avih_into(data: &[u8], info: &mut PsTrackInfo) {
+    let le_u32 = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64)
+    };
+    if let (Some(us_per_frame), Some(frames)) = (le_u32(0), le_u32(16)) {
+        info.duration_ms = Some(us_per_frame * frames / 1000);
+    }
+    info.width = le_u32(32);
+    info.height = le_u32(36);
+}
+
+/// `IDIT` is usually C's `asctime` (`SAT DEC 24 12:34:56 2005`), but some
+/// cameras write EXIF style (`2005:12:24 12:34:56`). `ICRD` is often just a date.
+fn parse_avi_date(s: &str) -> Option<NaiveDateTime> {
+    let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+    [
+        "%a %b %d %H:%M:%S %Y",
+        "%Y:%m:%d %H:%M:%S",
+        "%Y-%m-%d %H:%M:%S",
+    ]
+    .iter()
+    .find_map(|f| NaiveDateTime::parse_from_str(&s, f).ok())
+    .or_else(|| {
+        NaiveDate::parse_from_str(&s, "%Y-%m-%d")
+            .ok()
+            .map(|d| d.and_time(NaiveTime::MIN))
+    })
+}
+
+/// Duration from the first and last program clock reference, and the recording
+/// date from the AVCHD `MDPM` metadata camcorders put in the H.264 stream.
+pub(crate) fn parse_transport_stream_info<R: Read + Seek>(
+    reader: &mut R,
+    packet_size: usize,
+) -> anyhow::Result<PsTrackInfo> {
+    let mut info = empty_track_info();
+    let file_len = reader.seek(SeekFrom::End(0))?;
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut head = Vec::new();
+    reader
+        .by_ref()
+        .take(TS_SCAN_WINDOW)
+        .read_to_end(&mut head)?;
+    info.creation_time = find_mdpm_date(&head);
+
+    let tail_start =
+        file_len.saturating_sub(TS_SCAN_WINDOW) / packet_size as u64 * packet_size as u64;
+    reader.seek(SeekFrom::Start(tail_start))?;
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail)?;
+
+    let first = pcrs(&head, packet_size).into_iter().next();
+    let last = first.and_then(|(pid, _)| {
+        pcrs(&tail, packet_size)
+            .into_iter()
+            .rfind(|(p, _)| *p == pid)
+    });
+    if let (Some((_, first)), Some((_, last))) = (first, last)
+        && last >= first
+    {
+        info.duration_ms = Some((last - first) / PCR_TICKS_PER_MS);
+    }
+    debug!("Transport stream: {info:?}");
+    Ok(info)
+}
+
+/// `(pid, pcr base)` of every packet carrying a program clock reference.
+fn pcrs(data: &[u8], packet_size: usize) -> Vec<(u16, u64)> {
+    let prefix = packet_size - TS_PACKET_SIZE;
+    data.chunks_exact(packet_size)
+        .filter_map(|packet| {
+            let p = &packet[prefix..];
+            let has_adaptation = p[3] & 0x20 != 0;
+            if p[0] != TS_SYNC_BYTE || !has_adaptation || p[4] < 7 || p[5] & 0x10 == 0 {
+                return None;
+            }
+            let pid = u16::from_be_bytes([p[1] & 0x1f, p[2]]);
+            let pcr = (p[6] as u64) << 25
+                | (p[7] as u64) << 17
+                | (p[8] as u64) << 9
+                | (p[9] as u64) << 1
+                | (p[10] as u64) >> 7;
+            Some((pid, pcr))
+        })
+        .collect()
+}
+
+/// Recording date from the first `MDPM` block in `data`: tag 0x18 holds the
+/// timezone then BCD year and month, tag 0x19 BCD day, hour, minute and second.
+fn find_mdpm_date(data: &[u8]) -> Option<String> {
+    let start = data
+        .windows(MDPM_MARKER.len())
+        .position(|w| w == MDPM_MARKER)?
+        + MDPM_MARKER.len();
+    let mdpm = unescape_nal(
+        data.get(start..start + 1 + 5 * 32)
+            .unwrap_or(&data[start..]),
+    );
+    let count = *mdpm.first()? as usize;
+    let mut date = None;
+    let mut time = None;
+    for entry in mdpm.get(1..)?.chunks_exact(5).take(count) {
+        match entry[0] {
+            MDPM_TAG_DATE => date = Some([entry[1], entry[2], entry[3], entry[4]]),
+            MDPM_TAG_TIME => time = Some([entry[1], entry[2], entry[3], entry[4]]),
+            _ => {}
+        }
+    }
+    let ([tz, year_hi, year_lo, month], [day, hour, minute, second]) = (date?, time?);
+    let year = bcd(year_hi)? * 100 + bcd(year_lo)?;
+    let dt = NaiveDate::from_ymd_opt(year as i32, bcd(month)?, bcd(day)?)?.and_hms_opt(
+        bcd(hour)?,
+        bcd(minute)?,
+        bcd(second)?,
+    )?;
+    // bit 5 is the sign, bits 1-4 hours and bit 0 an extra half hour
+    let sign = if tz & 0x20 != 0 { -1 } else { 1 };
+    let offset_s = sign * (((tz >> 1) & 0x0f) as i32 * 3600 + (tz & 0x01) as i32 * 1800);
+    let offset = chrono::FixedOffset::east_opt(offset_s)?;
+    Some(dt.and_local_timezone(offset).single()?.to_rfc3339())
+}
+
+fn bcd(b: u8) -> Option<u32> {
+    let (hi, lo) = ((b >> 4) as u32, (b & 0x0f) as u32);
+    (hi < 10 && lo < 10).then_some(hi * 10 + lo)
+}
+
+/// Drop the emulation prevention bytes H.264 inserts after two zero bytes.
+fn unescape_nal(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &b in data {
+        if zeros >= 2 && b == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+fn empty_track_info() -> PsTrackInfo {
+    PsTrackInfo {
+        width: None,
+        height: None,
+        creation_time: None,
+        duration_ms: None,
+        make: None,
+        model: None,
+        software: None,
+        author: None,
+        gps_iso_6709: None,
+        content_identifier: None,
+        full_frame_rate_playback_intent: None,
+        capture_fps: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{FileSystem, OsFileSystem};
+
+    #[test]
+    fn test_parse_avi_info() -> anyhow::Result<()> {
+        let c = OsFileSystem::new("test/video");
+        let mut r = c.open("MVI_0001.AVI")?;
+        assert!(matches!(
+            legacy_container(&mut r),
+            Some(LegacyContainer::Avi)
+        ));
+        let info = parse_avi_info(&mut r)?;
+        assert_eq!(info.creation_time, Some("2005-12-24T12:34:56".to_string()));
+        // 75 frames at 66733us each
+        assert_eq!(info.duration_ms, Some(5004));
+        assert_eq!((info.width, info.height), (Some(320), Some(240)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_avi_date() {
+        let expected =
+            NaiveDate::from_ymd_opt(2005, 12, 24).and_then(|d| d.and_hms_opt(12, 34, 56));
+        assert_eq!(parse_avi_date("SAT DEC 24 12:34:56 2005"), expected);
+        assert_eq!(parse_avi_date("Sat Dec 24  12:34:56 2005\n"), expected);
+        assert_eq!(parse_avi_date("2005:12:24 12:34:56"), expected);
+        assert!(parse_avi_date("2005-12-24").is_some());
+        assert_eq!(parse_avi_date("garbage"), None);
+    }
+
+    #[test]
+    fn test_parse_transport_stream_info() -> anyhow::Result<()> {
+        let c = OsFileSystem::new("test/video");
+        let mut r = c.open("00001.MTS")?;
+        let Some(LegacyContainer::TransportStream { packet_size }) = legacy_container(&mut r)
+        else {
+            anyhow::bail!("not detected as a transport stream");
+        };
+        assert_eq!(packet_size, 192);
+        let info = parse_transport_stream_info(&mut r, packet_size)?;
+        assert_eq!(
+            info.creation_time,
+            Some("2009-08-15T18:20:05+09:00".to_string())
+        );
+        assert_eq!(info.duration_ms, Some(6000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unescape_nal() {
+        assert_eq!(unescape_nal(&[1, 0, 0, 3, 1, 0, 3]), vec![1, 0, 0, 1, 0, 3]);
+    }
+}
@@ -1,20 +1,35 @@
 mod album;
+mod burst;
 mod classify;
 mod db_cmd;
 mod dedup;
+mod duplicates_cmd;
+mod embed;
 mod exif_util;
 mod file_type;
 mod fs;
 mod info_cmd;
 mod inspect;
+mod iptc_util;
+mod layout;
+mod legacy_video;
 mod markdown;
 mod media;
+mod payload;
+mod phash;
 mod progress;
+mod relate;
+mod relayout_cmd;
+mod s3;
+mod subtype;
 mod supplemental_info;
 mod sync_cmd;
 mod test_util;
+mod thumbnail;
 mod track_util;
 mod util;
+mod xmp;
+mod xmp_util;
 
 use clap::{Parser, Subcommand};
 use tracing::{Level, debug, error, info};
@@ -62,6 +77,33 @@ enum Commands {
         #[arg(short, long, default_value = "db.sqlite")]
         output: String,
     },
+    /// List exact and likely duplicate photos and videos, and which copy sync keeps
+    Duplicates {
+        /// Turn debugging information on
+        #[arg(short, long)]
+        debug: bool,
+
+        /// Google Takeout or iCloud zip/directory, or an archive made by sync
+        #[arg(short, long)]
+        input: String,
+
+        /// Format of the report
+        #[arg(long, value_enum, default_value_t)]
+        format: duplicates_cmd::ReportFormat,
+
+        /// File to write the report to, rather than standard output
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// The order in which copies of a duplicate are compared to choose the one kept;
+        /// the copy whose source path sorts first is kept when none tells them apart
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = dedup::DEFAULT_KEEP_ORDER)]
+        keep_order: Vec<dedup::KeepRule>,
+
+        /// The source whose copy is kept when `--keep-order` gets to `source`
+        #[arg(long, value_enum, default_value_t)]
+        prefer_source: dedup::PhotoSource,
+    },
     /// Sync files in an archive or directory into a standardised directory structure
     Sync {
         /// Turn debugging information on
@@ -76,7 +118,8 @@ enum Commands {
         #[arg(long)]
         input: String,
 
-        /// Directory to sync photos and videos into
+        /// Directory to sync photos and videos into, or an S3-compatible bucket as
+        /// `s3://bucket/prefix` (credentials from the `AWS_*` environment variables)
         #[arg(short, long)]
         output: Option<String>,
 
@@ -91,6 +134,124 @@ enum Commands {
         /// Skip inspecting and copying albums
         #[arg(long)]
         skip_albums: bool,
+
+        /// Store edited photos next to their original as `<name>-edited.<ext>`,
+        /// rather than under their own date
+        #[arg(long)]
+        edits_next_to_original: bool,
+
+        /// Store burst frames, other than the cover, under `<day>/bursts/<id>/`
+        #[arg(long)]
+        bursts_in_subfolder: bool,
+
+        /// Also store the clip inside a Google Motion Photo beside it as `<name>.mp4`,
+        /// leaving the archived photo untouched
+        #[arg(long)]
+        extract_motion_photos: bool,
+
+        /// Also write an XMP sidecar, `<name>.xmp`, beside each photo and video with its date,
+        /// GPS position, people, albums and caption, for photo tools to read
+        #[arg(long)]
+        write_xmp: bool,
+
+        /// Write the capture date and GPS position Google kept only in its JSON into archived
+        /// JPEG, HEIC and AVIF photos lacking them, noting the copy's `archived-checksum:`
+        #[arg(long)]
+        embed_metadata: bool,
+
+        /// Also write a small JPEG preview of each photo under `.thumbnails/`, taken from the
+        /// preview the camera embedded when there is one
+        #[arg(long)]
+        thumbnails: bool,
+
+        /// What to do with the animations, collages and movies Google Photos generated: tag
+        /// them `generated:` in their notes, skip them, or store them under `generated/`
+        #[arg(long, value_enum, default_value_t)]
+        generated: sync_cmd::GeneratedMedia,
+
+        /// Leave media of this kind out of the archive (may be repeated)
+        #[arg(long, value_enum)]
+        skip_kind: Vec<subtype::MediaSubtype>,
+
+        /// Store media of this kind under `<kind>/` rather than the timeline (may be repeated)
+        #[arg(long, value_enum)]
+        separate_kind: Vec<subtype::MediaSubtype>,
+
+        /// List the photos that look alike (re-compressed or resized copies) under
+        /// `similar:` in each note. They're always listed in `similar.md`, never removed
+        #[arg(long)]
+        link_similar: bool,
+
+        /// The order in which copies of a duplicate are compared to choose the one kept;
+        /// the copy whose source path sorts first is kept when none tells them apart
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = dedup::DEFAULT_KEEP_ORDER)]
+        keep_order: Vec<dedup::KeepRule>,
+
+        /// The source whose copy is kept when `--keep-order` gets to `source`
+        #[arg(long, value_enum, default_value_t)]
+        prefer_source: dedup::PhotoSource,
+
+        /// Path, without extension, of a photo or video with a date. Tokens: {yyyy} {yy} {mm}
+        /// {dd} {hh} {mi} {ss} {ms} (date taken), {checksum} (short checksum), {stem} (original
+        /// name), {camera}, {source} (google, icloud or other) and {kind} (photo, video,
+        /// screenshot, ...). Must name the second taken, or include {checksum}
+        #[arg(long, value_name = "TEMPLATE", default_value = layout::DEFAULT_DATED_TEMPLATE, value_parser = layout::parse_dated_template)]
+        path_template: layout::PathTemplate,
+
+        /// Path, without extension, of a photo or video without a date. Takes the tokens of
+        /// --path-template other than the date, and must include {checksum}
+        #[arg(long, value_name = "TEMPLATE", default_value = layout::DEFAULT_UNDATED_TEMPLATE, value_parser = layout::parse_undated_template)]
+        undated_template: layout::PathTemplate,
+
+        /// How photos and videos get from an input directory into the archive. Where a hard
+        /// link or clone isn't possible they're copied; a move copies and checks the copy
+        /// before removing the input file
+        #[arg(long, value_enum, default_value_t)]
+        transfer: sync_cmd::Transfer,
+    },
+    /// Move the files of an archive made by sync into another layout, along with their notes
+    Relayout {
+        /// Turn debugging information on
+        #[arg(short, long)]
+        debug: bool,
+
+        /// If set, don't do anything, just print what would be done.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Archive directory made by sync
+        #[arg(short, long)]
+        archive: String,
+
+        /// Store edited photos next to their original as `<name>-edited.<ext>`,
+        /// rather than under their own date
+        #[arg(long)]
+        edits_next_to_original: bool,
+
+        /// Store burst frames, other than the cover, under `<day>/bursts/<id>/`
+        #[arg(long)]
+        bursts_in_subfolder: bool,
+
+        /// Store the animations, collages and movies Google Photos generated under
+        /// `generated/` (separate), or in the timeline
+        #[arg(long, value_enum, default_value_t)]
+        generated: sync_cmd::GeneratedMedia,
+
+        /// Store media of this kind under `<kind>/` rather than the timeline (may be repeated)
+        #[arg(long, value_enum)]
+        separate_kind: Vec<subtype::MediaSubtype>,
+
+        /// Path, without extension, of a photo or video with a date. Tokens: {yyyy} {yy} {mm}
+        /// {dd} {hh} {mi} {ss} {ms} (date taken), {checksum} (short checksum), {stem} (original
+        /// name), {camera}, {source} (google, icloud or other) and {kind} (photo, video,
+        /// screenshot, ...). Must name the second taken, or include {checksum}
+        #[arg(long, value_name = "TEMPLATE", default_value = layout::DEFAULT_DATED_TEMPLATE, value_parser = layout::parse_dated_template)]
+        path_template: layout::PathTemplate,
+
+        /// Path, without extension, of a photo or video without a date. Takes the tokens of
+        /// --path-template other than the date, and must include {checksum}
+        #[arg(long, value_name = "TEMPLATE", default_value = layout::DEFAULT_UNDATED_TEMPLATE, value_parser = layout::parse_undated_template)]
+        undated_template: layout::PathTemplate,
     },
 }
 
@@ -119,6 +280,21 @@ fn go() -> anyhow::Result<()> {
             enable_debug(debug);
             db_cmd::main(&input, &output)?
         }
+        Commands::Duplicates {
+            debug,
+            input,
+            format,
+            output,
+            keep_order,
+            prefer_source,
+        } => {
+            enable_debug(debug);
+            let keep = dedup::KeepPolicy {
+                order: keep_order,
+                preferred_source: prefer_source,
+            };
+            duplicates_cmd::main(&input, format, &output, &keep)?
+        }
         Commands::Sync {
             debug,
             dry_run,
@@ -127,17 +303,75 @@ fn go() -> anyhow::Result<()> {
             output,
             skip_media,
             skip_albums,
+            edits_next_to_original,
+            bursts_in_subfolder,
+            extract_motion_photos,
+            write_xmp,
+            embed_metadata,
+            thumbnails,
+            generated,
+            skip_kind,
+            separate_kind,
+            link_similar,
+            keep_order,
+            prefer_source,
+            path_template,
+            undated_template,
+            transfer,
         } => {
             enable_debug(debug);
             enable_dry_run(dry_run);
-            sync_cmd::main(
-                dry_run,
-                &input,
-                &output,
+            let options = sync_cmd::SyncOptions {
                 skip_markdown,
                 skip_media,
                 skip_albums,
-            )?;
+                edits_next_to_original,
+                bursts_in_subfolder,
+                extract_motion_photos,
+                write_xmp,
+                embed_metadata,
+                thumbnails,
+                generated,
+                skip_kinds: skip_kind,
+                separate_kinds: separate_kind,
+                link_similar,
+                keep: dedup::KeepPolicy {
+                    order: keep_order,
+                    preferred_source: prefer_source,
+                },
+                layout: layout::PathLayout {
+                    dated: path_template,
+                    undated: undated_template,
+                },
+                transfer,
+            };
+            sync_cmd::main(dry_run, &input, &output, &options)?;
+        }
+        Commands::Relayout {
+            debug,
+            dry_run,
+            archive,
+            edits_next_to_original,
+            bursts_in_subfolder,
+            generated,
+            separate_kind,
+            path_template,
+            undated_template,
+        } => {
+            enable_debug(debug);
+            enable_dry_run(dry_run);
+            let options = sync_cmd::SyncOptions {
+                edits_next_to_original,
+                bursts_in_subfolder,
+                generated,
+                separate_kinds: separate_kind,
+                layout: layout::PathLayout {
+                    dated: path_template,
+                    undated: undated_template,
+                },
+                ..Default::default()
+            };
+            relayout_cmd::main(dry_run, &archive, &options)?;
         }
     }
     Ok(())
@@ -1,5 +1,9 @@
-use crate::fs::{FileSystem, OsFileSystem};
+use crate::burst::BurstInfo;
+use crate::classify::generated_kind;
+use crate::fs::Output;
 use crate::media::{MediaFileDerivedInfo, MediaFileInfo, best_guess_taken_dt};
+use crate::relate::MediaLinks;
+use crate::subtype::media_subtype;
 use crate::util::name_part;
 use anyhow::anyhow;
 use std::io::{Cursor, Read};
@@ -10,12 +14,19 @@ use yaml_rust2::{Yaml, YamlEmitter, YamlLoader};
 pub(crate) fn mfm_from_media_file_info(
     media_info: &MediaFileInfo,
     album_names: &[String],
+    links: &MediaLinks,
+    burst: Option<&BurstInfo>,
 ) -> PhotoSorterFrontMatter {
     let guessed_datetime = best_guess_taken_dt(media_info);
     let (latitude, longitude) = best_guess_coords(media_info);
+    let iptc = media_info.iptc_info.clone().unwrap_or_default();
     PhotoSorterFrontMatter {
         path_original: media_info.original_path.clone(),
+        source_path: Some(media_info.original_file_this_run.clone()).filter(|p| !p.is_empty()),
         checksum: media_info.hash_info.long_checksum.clone(),
+        merged_checksums: media_info.merged_checksums.clone(),
+        payload_checksum: media_info.hash_info.payload_checksum.clone(),
+        archived_checksum: None,
         datetime: guessed_datetime,
         latitude,
         longitude,
@@ -23,9 +34,48 @@ pub(crate) fn mfm_from_media_file_info(
         // Render album membership as wikilinks so each photo note links
         // back to the album files under `albums/`
         albums: album_names.iter().map(|n| as_wikilink(n)).collect(),
+        links: links
+            .iter()
+            .map(|(k, paths)| (k.clone(), paths.iter().map(|p| as_wikilink(p)).collect()))
+            .collect(),
+        burst: burst.cloned(),
+        motion_photo_offset: media_info
+            .exif_info
+            .as_ref()
+            .and_then(|e| e.motion_photo_offset),
+        generated: generated_kind(&media_info.original_path).map(|k| k.to_string()),
+        kind: media_subtype(media_info).map(|k| k.name().to_string()),
+        description: media_info
+            .supp_info
+            .as_ref()
+            .and_then(|s| s.description.as_ref())
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty())
+            .or(iptc.caption),
+        rating: media_info.xmp_info.as_ref().and_then(|x| x.rating),
+        title: media_info.xmp_info.as_ref().and_then(|x| x.title.clone()),
+        keywords: keywords(media_info),
+        creator: iptc.by_line,
+        location: iptc.sublocation,
+        city: iptc.city,
+        state: iptc.state,
+        country: iptc.country,
     }
 }
 
+/// Keywords from XMP, then any only IPTC-IIM has.
+fn keywords(media_info: &MediaFileInfo) -> Vec<String> {
+    let xmp = media_info.xmp_info.iter().flat_map(|x| &x.keywords);
+    let iptc = media_info.iptc_info.iter().flat_map(|i| &i.keywords);
+    let mut keywords: Vec<String> = Vec::new();
+    for keyword in xmp.chain(iptc) {
+        if !keywords.contains(keyword) {
+            keywords.push(keyword.clone());
+        }
+    }
+    keywords
+}
+
 /// Best guess at GPS coordinates, preferring the EXIF embedded in the file, then
 /// the supplemental metadata Google ships alongside it (Takeout often strips EXIF
 /// GPS but keeps it in the metadata JSON). Google writes `0,0` to mean "no
@@ -46,7 +96,7 @@ fn best_guess_coords(media_info: &MediaFileInfo) -> (Option<f64>, Option<f64>) {
     (None, None)
 }
 
-fn non_null_island(lat: Option<f64>, long: Option<f64>) -> Option<(f64, f64)> {
+pub(crate) fn non_null_island(lat: Option<f64>, long: Option<f64>) -> Option<(f64, f64)> {
     match (lat, long) {
         (Some(lat), Some(long)) if lat != 0.0 || long != 0.0 => Some((lat, long)),
         _ => None,
@@ -54,17 +104,26 @@ fn non_null_island(lat: Option<f64>, long: Option<f64>) -> Option<(f64, f64)> {
 }
 
 /// People (face tags) from Google supplemental metadata, rendered as wikilinks
+/// People Google tagged, then those named by face regions in the XMP.
 fn people_links(media_info: &MediaFileInfo) -> Vec<String> {
-    let Some(supp) = &media_info.supp_info else {
-        return vec![];
-    };
-    supp.people
+    let google = media_info
+        .supp_info
         .iter()
-        .filter_map(|p| p.name.as_ref())
+        .flat_map(|s| &s.people)
+        .filter_map(|p| p.name.as_ref());
+    let xmp = media_info.xmp_info.iter().flat_map(|x| &x.people);
+    let mut links: Vec<String> = Vec::new();
+    for link in google
+        .chain(xmp)
         .map(|n| n.trim())
         .filter(|n| !n.is_empty())
         .map(as_wikilink)
-        .collect()
+    {
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
 }
 
 fn as_wikilink(name: &str) -> String {
@@ -73,7 +132,15 @@ fn as_wikilink(name: &str) -> String {
 
 pub(crate) struct PhotoSorterFrontMatter {
     pub(crate) path_original: Vec<String>,
+    /// The original path of the copy kept, which the archived name comes from.
+    pub(crate) source_path: Option<String>,
     pub(crate) checksum: String,
+    /// Checksums of the other copies of this shot, as exported by other sources.
+    pub(crate) merged_checksums: Vec<String>,
+    /// Checksum of the image data alone, the same for copies differing only in metadata.
+    pub(crate) payload_checksum: Option<String>,
+    /// Checksum of the archived copy, when recovered metadata was written into it.
+    pub(crate) archived_checksum: Option<String>,
     pub(crate) datetime: Option<String>,
     pub(crate) latitude: Option<f64>,
     pub(crate) longitude: Option<f64>,
@@ -81,6 +148,32 @@ pub(crate) struct PhotoSorterFrontMatter {
     pub(crate) people: Vec<String>,
     /// Albums this photo belongs to, as wikilinks.
     pub(crate) albums: Vec<String>,
+    /// Related media files (e.g. the other half of a Live Photo), as wikilinks
+    /// keyed by relation.
+    pub(crate) links: MediaLinks,
+    /// Burst sequence this photo is a frame of.
+    pub(crate) burst: Option<BurstInfo>,
+    /// Where the clip of a Google Motion Photo starts within the file.
+    pub(crate) motion_photo_offset: Option<u64>,
+    /// What Google Photos made, for an animation, collage or movie it generated.
+    pub(crate) generated: Option<String>,
+    /// Sort of shot, e.g. a screenshot or panorama (see [`crate::subtype::MediaSubtype`]).
+    pub(crate) kind: Option<String>,
+    /// Caption from Google Photos.
+    pub(crate) description: Option<String>,
+    /// Star rating from XMP, -1 for rejected.
+    pub(crate) rating: Option<i64>,
+    /// Title from XMP.
+    pub(crate) title: Option<String>,
+    /// Keywords from XMP and IPTC-IIM.
+    pub(crate) keywords: Vec<String>,
+    /// Photographers, from the IPTC-IIM by-line.
+    pub(crate) creator: Vec<String>,
+    /// Where the photo was taken, from IPTC-IIM: the place within the city.
+    pub(crate) location: Option<String>,
+    pub(crate) city: Option<String>,
+    pub(crate) state: Option<String>,
+    pub(crate) country: Option<String>,
 }
 
 pub(crate) fn sync_markdown(
@@ -88,7 +181,9 @@ pub(crate) fn sync_markdown(
     media_file: &MediaFileInfo,
     derived: &MediaFileDerivedInfo,
     album_names: &[String],
-    output_c: &mut OsFileSystem,
+    links: &MediaLinks,
+    burst: Option<&BurstInfo>,
+    output_c: &dyn Output,
 ) -> anyhow::Result<()> {
     let Some(desired_media_path) = derived.desired_media_path.clone() else {
         warn!(
@@ -97,15 +192,16 @@ pub(crate) fn sync_markdown(
         );
         return Ok(());
     };
-    let output_path = get_desired_markdown_path(desired_media_path.clone())?;
-    let mfm = mfm_from_media_file_info(media_file, album_names);
+    let output_path = get_desired_markdown_path(sidecar_stem(derived, &desired_media_path))?;
+    let mut mfm = mfm_from_media_file_info(media_file, album_names, links, burst);
+    mfm.archived_checksum = derived.archived_checksum.clone();
     // On first creation the body embeds the photo itself, so opening the note in
     // A markdown viewer shows the image. The body is preserved
     // verbatim on later runs, so user notes and this embed are never clobbered.
     let mut e_md = new_note_body(&desired_media_path, &derived.desired_media_extension);
     let mut e_yaml = None;
 
-    if output_c.exists(&output_path) {
+    if output_c.exists(&output_path)? {
         let mut reader = output_c.open(&output_path)?;
         let mut existing_md_bytes = Vec::new();
         match reader.read_to_end(&mut existing_md_bytes) {
@@ -126,11 +222,23 @@ pub(crate) fn sync_markdown(
     let md_res = assemble_markdown(&mfm, &e_yaml, &e_md)?;
     if let AssembledMarkdown::Modified(md_str) = md_res {
         let md_bytes = md_str.as_bytes().to_vec();
-        output_c.write(dry_run, &output_path, Cursor::new(&md_bytes));
+        output_c.write(dry_run, &output_path, &mut Cursor::new(&md_bytes))?;
     }
     Ok(())
 }
 
+/// Path, without extension, of the sidecars of a media file stored at
+/// `desired_media_path`. A file sharing its name with another (the clip of a
+/// Live Photo) keeps its extension in the sidecar name, leaving `<name>.md` to
+/// the other file.
+pub(crate) fn sidecar_stem(derived: &MediaFileDerivedInfo, desired_media_path: &str) -> String {
+    if derived.shares_stem {
+        format!("{desired_media_path}.{}", derived.desired_media_extension)
+    } else {
+        desired_media_path.to_string()
+    }
+}
+
 /// Grab anything between "---[\r]\n" and "---[\r]\n" and put into .0. Put everything else into .1.
 /// If any sort of invalid case is encountered, return empty frontmatter and original content.
 pub(crate) fn split_frontmatter(file_contents: &str) -> (String, String) {
@@ -295,9 +403,73 @@ fn merge_yaml(s: &Option<String>, fm: &PhotoSorterFrontMatter) -> anyhow::Result
         set_scalar(&mut root, "datetime", Yaml::String(dt.to_string()));
     }
     set_scalar(&mut root, "checksum", Yaml::String(fm.checksum.to_string()));
+    if let Some(payload_checksum) = &fm.payload_checksum {
+        set_scalar(
+            &mut root,
+            "payload-checksum",
+            Yaml::String(payload_checksum.clone()),
+        );
+    }
+    if let Some(archived_checksum) = &fm.archived_checksum {
+        set_scalar(
+            &mut root,
+            "archived-checksum",
+            Yaml::String(archived_checksum.clone()),
+        );
+    }
+    yaml_array_merge(
+        &mut root,
+        &"merged-checksums".to_string(),
+        &fm.merged_checksums,
+    );
     yaml_array_merge(&mut root, &"original-paths".to_string(), &fm.path_original);
+    if let Some(source_path) = &fm.source_path {
+        set_scalar(&mut root, "source-path", Yaml::String(source_path.clone()));
+    }
     yaml_array_merge(&mut root, &"people".to_string(), &fm.people);
     yaml_array_merge(&mut root, &"albums".to_string(), &fm.albums);
+    for (key, links) in &fm.links {
+        yaml_array_merge(&mut root, key, links);
+    }
+    if let Some(burst) = &fm.burst {
+        set_scalar(&mut root, "burst", Yaml::String(burst.id.clone()));
+        set_scalar(&mut root, "burst-cover", Yaml::Boolean(burst.cover));
+    }
+    if let Some(kind) = &fm.generated {
+        set_scalar(&mut root, "generated", Yaml::String(kind.clone()));
+    }
+    if let Some(kind) = &fm.kind {
+        set_scalar(&mut root, "kind", Yaml::String(kind.clone()));
+    }
+    if let Some(description) = &fm.description {
+        set_scalar(&mut root, "description", Yaml::String(description.clone()));
+    }
+    if let Some(title) = &fm.title {
+        set_scalar(&mut root, "title", Yaml::String(title.clone()));
+    }
+    if let Some(rating) = fm.rating {
+        set_scalar(&mut root, "rating", Yaml::Integer(rating));
+    }
+    yaml_array_merge(&mut root, &"keywords".to_string(), &fm.keywords);
+    yaml_array_merge(&mut root, &"creator".to_string(), &fm.creator);
+    for (key, place) in [
+        ("location", &fm.location),
+        ("city", &fm.city),
+        ("state", &fm.state),
+        ("country", &fm.country),
+    ] {
+        if let Some(place) = place {
+            set_scalar(&mut root, key, Yaml::String(place.clone()));
+        }
+    }
+    if let Some(offset) = fm.motion_photo_offset {
+        set_scalar(&mut root, "motion-photo", Yaml::Boolean(true));
+        set_scalar(
+            &mut root,
+            "motion-photo-offset",
+            Yaml::Integer(offset as i64),
+        );
+    }
 
     if let Some(lat) = fm.latitude {
         set_scalar(&mut root, "latitude", Yaml::Real(lat.to_string()));
@@ -405,6 +577,8 @@ pub(crate) fn get_desired_markdown_path(desired_media_path: String) -> anyhow::R
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::iptc_util::PsIptcInfo;
+    use crate::xmp_util::PsXmpInfo;
 
     fn assert_split(text: &str, expected_fm: &str, expected_md: &str) {
         let (fm, md) = split_frontmatter(text);
@@ -419,12 +593,30 @@ mod tests {
     fn get_mfi() -> PhotoSorterFrontMatter {
         PhotoSorterFrontMatter {
             path_original: vec!["p1".to_string(), "p2".to_string()],
+            source_path: None,
             datetime: None,
             checksum: "abcdefg".to_string(),
+            merged_checksums: vec![],
+            payload_checksum: None,
+            archived_checksum: None,
             latitude: None,
             longitude: None,
             people: vec![],
             albums: vec![],
+            links: MediaLinks::new(),
+            burst: None,
+            motion_photo_offset: None,
+            generated: None,
+            kind: None,
+            description: None,
+            rating: None,
+            title: None,
+            keywords: vec![],
+            creator: vec![],
+            location: None,
+            city: None,
+            state: None,
+            country: None,
         }
     }
 
@@ -595,6 +787,7 @@ checksum: abcdefg
                 .collect(),
             photo_taken_time: None,
             creation_time: None,
+            description: None,
         });
         m
     }
@@ -609,13 +802,57 @@ checksum: abcdefg
             longitude: Some(152.2605),
         };
         let m = mfi_with_supp(Some(geo), &["Tim Tam", "  ", "Nandor"]);
-        let mfm = mfm_from_media_file_info(&m, &["Holiday".to_string()]);
+        let mfm = mfm_from_media_file_info(&m, &["Holiday".to_string()], &MediaLinks::new(), None);
         assert_eq!(mfm.people, vec!["[[Tim Tam]]", "[[Nandor]]"]);
         assert_eq!(mfm.albums, vec!["[[Holiday]]"]);
         assert_eq!(mfm.latitude, Some(-21.6303));
         assert_eq!(mfm.longitude, Some(152.2605));
     }
 
+    #[test]
+    fn test_mfm_xmp_rating_keywords_title_and_faces() {
+        let mut m = mfi_with_supp(None, &["Tim Tam"]);
+        m.xmp_info = Some(PsXmpInfo {
+            rating: Some(5),
+            title: Some("Reef".to_string()),
+            keywords: vec!["snorkel".to_string()],
+            people: vec!["Tim Tam".to_string(), "Nandor".to_string()],
+        });
+        let mfm = mfm_from_media_file_info(&m, &[], &MediaLinks::new(), None);
+        assert_eq!(mfm.people, vec!["[[Tim Tam]]", "[[Nandor]]"]);
+        assert_eq!(mfm.rating, Some(5));
+        assert_eq!(mfm.title, Some("Reef".to_string()));
+        assert_eq!(mfm.keywords, vec!["snorkel"]);
+    }
+
+    #[test]
+    fn test_mfm_iptc_caption_keywords_creator_and_place() {
+        let mut m = mfi_with_supp(None, &[]);
+        m.xmp_info = Some(PsXmpInfo {
+            keywords: vec!["lake".to_string()],
+            ..Default::default()
+        });
+        m.iptc_info = Some(PsIptcInfo {
+            caption: Some("Lake at dusk".to_string()),
+            keywords: vec!["lake".to_string(), "dusk".to_string()],
+            by_line: vec!["Jane Doe".to_string()],
+            city: Some("Zürich".to_string()),
+            ..Default::default()
+        });
+        let mfm = mfm_from_media_file_info(&m, &[], &MediaLinks::new(), None);
+        assert_eq!(mfm.description, Some("Lake at dusk".to_string()));
+        assert_eq!(mfm.keywords, vec!["lake", "dusk"]);
+        assert_eq!(mfm.creator, vec!["Jane Doe"]);
+        assert_eq!(mfm.city, Some("Zürich".to_string()));
+
+        // A caption typed in Google Photos is the one kept
+        if let Some(supp) = &mut m.supp_info {
+            supp.description = Some("Evening swim".to_string());
+        }
+        let mfm = mfm_from_media_file_info(&m, &[], &MediaLinks::new(), None);
+        assert_eq!(mfm.description, Some("Evening swim".to_string()));
+    }
+
     #[test]
     fn test_mfm_null_island_gps_is_dropped() {
         use crate::supplemental_info::SupplementalInfoGeoData;
@@ -625,7 +862,7 @@ checksum: abcdefg
             longitude: Some(0.0),
         };
         let m = mfi_with_supp(Some(geo), &[]);
-        let mfm = mfm_from_media_file_info(&m, &[]);
+        let mfm = mfm_from_media_file_info(&m, &[], &MediaLinks::new(), None);
         assert_eq!(mfm.latitude, None);
         assert_eq!(mfm.longitude, None);
     }
@@ -651,6 +888,20 @@ checksum: abcdefg
         Ok(())
     }
 
+    #[test]
+    fn test_yaml_links_are_wikilinks() -> anyhow::Result<()> {
+        crate::test_util::setup_log();
+        let mut links = MediaLinks::new();
+        links.insert(
+            "live-photo".to_string(),
+            vec!["2024/07/15/1430-22417.mov".to_string()],
+        );
+        let mfm = mfm_from_media_file_info(&MediaFileInfo::new_for_test(), &[], &links, None);
+        let yaml = merge_yaml(&None, &mfm)?.yaml;
+        assert!(yaml.contains("live-photo:\n  - \"[[2024/07/15/1430-22417.mov]]\""));
+        Ok(())
+    }
+
     #[test]
     fn test_no_rewrite_when_reformatted_but_current() -> anyhow::Result<()> {
         crate::test_util::setup_log();
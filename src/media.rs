@@ -4,11 +4,16 @@ use crate::file_type::{
     AccurateFileType, MetadataType, QuickFileType, determine_file_type, file_ext_from_file_type,
     metadata_type,
 };
+use crate::iptc_util::{PsIptcInfo, parse_iptc_info};
+use crate::layout::PathLayout;
+use crate::phash::perceptual_hash;
 use crate::supplemental_info::PsSupplementalInfo;
 use crate::track_util::{PsTrackInfo, parse_track_info};
-use crate::util::ScanInfo;
+use crate::util::{ScanInfo, parse_taken};
+use crate::xmp_util::{PsXmpInfo, combine_xmp, parse_xmp_info};
 use anyhow::anyhow;
-use chrono::{DateTime, Datelike, Timelike};
+use chrono::{Datelike, Timelike};
+use nom_exif::ExifTag;
 use serde::{Deserialize, Serialize};
 use std::io::{Read, Seek};
 use tracing::warn;
@@ -29,6 +34,19 @@ pub(crate) struct MediaFileInfo {
     pub(crate) created: Option<i64>,
     // Size of the file in bytes
     pub(crate) file_size: u64,
+    /// dHash of the pixels, for finding the same photo saved as different bytes
+    #[serde(default)]
+    pub(crate) perceptual_hash: Option<String>,
+    /// Long checksums of other copies of this shot, exported by another source
+    /// with different bytes, that were merged into this entry
+    #[serde(default)]
+    pub(crate) merged_checksums: Vec<String>,
+    /// XMP embedded in the file, overlaid with its sidecar in the input
+    #[serde(default)]
+    pub(crate) xmp_info: Option<PsXmpInfo>,
+    /// IPTC-IIM embedded in a JPEG or TIFF
+    #[serde(default)]
+    pub(crate) iptc_info: Option<PsIptcInfo>,
 }
 
 #[derive(Debug, Clone)]
@@ -37,15 +55,62 @@ pub(crate) struct MediaFileDerivedInfo {
     pub(crate) desired_media_path: Option<String>,
     /// Desired file extension (eg, jpg, mp4)
     pub(crate) desired_media_extension: String,
+    /// Another media file with a different extension is stored under the same
+    /// path (eg, the still of a Live Photo), so this file's sidecar includes its
+    /// extension to stay distinct
+    pub(crate) shares_stem: bool,
+    /// Checksum of the archived copy, when recovered metadata was written into
+    /// it and it no longer matches the input (`sync --embed-metadata`)
+    pub(crate) archived_checksum: Option<String>,
 }
 
 pub(crate) fn media_file_info_from_readable<R: Read + Seek>(
     si: &ScanInfo,
     reader: &mut R,
     supp_info: &Option<PsSupplementalInfo>,
+    xmp_sidecar: &Option<PsXmpInfo>,
     hash_info: &HashInfo,
 ) -> anyhow::Result<MediaFileInfo> {
     let name = &si.file_path;
+    let (guessed_ff, exif_o, track_o) = media_metadata_from_readable(&mut *reader, name)?;
+    let hash_info = hash_info.clone();
+    let perceptual_hash = perceptual_hash(&mut *reader, &guessed_ff, si.file_size);
+    let embedded_xmp = match metadata_type(&guessed_ff) {
+        MetadataType::ExifTags => parse_xmp_info(&mut *reader)?,
+        _ => None,
+    };
+    let iptc_o = match guessed_ff {
+        AccurateFileType::Jpg | AccurateFileType::Tiff | AccurateFileType::Dng => {
+            parse_iptc_info(&mut *reader)?
+        }
+        _ => None,
+    };
+
+    let media_file_info = MediaFileInfo {
+        original_file_this_run: name.clone(),
+        original_path: vec![name.clone()],
+        accurate_file_type: guessed_ff.clone(),
+        quick_file_type: si.quick_file_type.clone(),
+        exif_info: exif_o.clone(),
+        track_info: track_o.clone(),
+        hash_info,
+        supp_info: supp_info.clone(),
+        modified: si.modified_datetime,
+        created: si.created_datetime,
+        file_size: si.file_size,
+        perceptual_hash,
+        merged_checksums: vec![],
+        xmp_info: combine_xmp(embedded_xmp, xmp_sidecar.clone()),
+        iptc_info: iptc_o,
+    };
+    Ok(media_file_info)
+}
+
+/// The actual type of a media file and the metadata embedded in it.
+pub(crate) fn media_metadata_from_readable<R: Read + Seek>(
+    reader: &mut R,
+    name: &String,
+) -> anyhow::Result<(AccurateFileType, Option<PsExifInfo>, Option<PsTrackInfo>)> {
     let guessed_ff = determine_file_type(&mut *reader, name)?;
     if guessed_ff == AccurateFileType::Unsupported {
         warn!("Not a valid media file {name:?}");
@@ -63,34 +128,20 @@ pub(crate) fn media_file_info_from_readable<R: Read + Seek>(
         }
         MetadataType::NoMetadata => {}
     }
-    let hash_info = hash_info.clone();
-
-    let media_file_info = MediaFileInfo {
-        original_file_this_run: name.clone(),
-        original_path: vec![name.clone()],
-        accurate_file_type: guessed_ff.clone(),
-        quick_file_type: si.quick_file_type.clone(),
-        exif_info: exif_o.clone(),
-        track_info: track_o.clone(),
-        hash_info,
-        supp_info: supp_info.clone(),
-        modified: si.modified_datetime,
-        created: si.created_datetime,
-        file_size: si.file_size,
-    };
-    Ok(media_file_info)
+    Ok((guessed_ff, exif_o, track_o))
 }
 
 pub(crate) fn media_file_derived_from_media_info(
     media_info: &MediaFileInfo,
+    layout: &PathLayout,
 ) -> anyhow::Result<MediaFileDerivedInfo> {
     let ext = file_ext_from_file_type(&media_info.accurate_file_type);
-    let guessed_datetime = best_guess_taken_dt(media_info);
-    let short_checksum = &media_info.hash_info.short_checksum;
-    let desired_media_path_o = Some(get_desired_media_path(short_checksum, &guessed_datetime));
+    let desired_media_path_o = Some(layout.media_path(media_info));
     let media_file_info = MediaFileDerivedInfo {
         desired_media_path: desired_media_path_o.clone(),
         desired_media_extension: ext,
+        shares_stem: false,
+        archived_checksum: None,
     };
     Ok(media_file_info)
 }
@@ -100,11 +151,12 @@ pub(crate) fn media_file_derived_from_media_info(
 /// 2. EXIF DateTimeOriginal
 /// 3. EXIF DateTime
 /// 4. EXIF GPSDateStamp - only accurate up to minute
-/// 5. SupplementalInfo creation_time
-/// 6. File modified time
+/// 5. Video container creation time
+/// 6. SupplementalInfo creation_time
+/// 7. File modified time
 ///   - no timezone info, unreliable in zips, somewhat unreliable in directories due to file
 ///     copying / syncing not preserving, only use as second to last resort
-/// 7. File creation time
+/// 8. File creation time
 ///   - no timezone info, unavailable in zips, somewhat unreliable in directories due to file
 ///     copying / syncing not preserving, only use as a last resort
 ///
@@ -122,6 +174,14 @@ pub(crate) fn best_guess_taken_dt(info: &MediaFileInfo) -> Option<String> {
     if let Some(dt) = time_taken_from_exif {
         return Some(dt);
     }
+    if let Some(dt) = info
+        .track_info
+        .as_ref()
+        .and_then(|ti| ti.creation_time.as_ref())
+        .filter(|dt| is_plausible_track_time(dt))
+    {
+        return Some(dt.clone());
+    }
     if let Some(dt) = info
         .supp_info
         .as_ref()
@@ -145,38 +205,37 @@ pub(crate) fn best_guess_taken_dt(info: &MediaFileInfo) -> Option<String> {
     None
 }
 
-/// `yyyy/mm/dd/hhmm-ssms`
-/// OR `undated/checksum`
-pub(crate) fn get_desired_media_path(
-    short_checksum: &str,
-    media_datetime: &Option<String>,
-) -> String {
-    let date_dir;
-    let name;
-    if let Some(dt_s) = media_datetime {
-        let dt_r = DateTime::parse_from_rfc3339(dt_s);
-        match dt_r {
-            Ok(dt) => {
-                date_dir = format!("{}/{:0>2}/{:0>2}", dt.year(), dt.month(), dt.day());
-                name = format!(
-                    "{:0>2}{:0>2}-{:0>2}{:0>3}",
-                    dt.hour(),
-                    dt.minute(),
-                    dt.second(),
-                    dt.timestamp_subsec_millis()
-                );
-            }
-            Err(_) => {
-                warn!("Could not parse datetime: {dt_s:?}");
-                date_dir = "undated".to_string();
-                name = short_checksum.to_string();
-            }
-        }
-    } else {
-        date_dir = "undated".to_string();
-        name = short_checksum.to_string();
+/// Width and height in pixels, from the video track or the EXIF.
+pub(crate) fn media_dimensions(m: &MediaFileInfo) -> (Option<u64>, Option<u64>) {
+    if let Some(track) = &m.track_info {
+        return (track.width, track.height);
     }
-    format!("{date_dir}/{name}")
+    let tag = |t: ExifTag| {
+        m.exif_info
+            .as_ref()
+            .and_then(|e| e.tags.get(&t.to_string()))
+            .and_then(|v| v.trim().parse().ok())
+    };
+    (tag(ExifTag::ExifImageWidth), tag(ExifTag::ExifImageHeight))
+}
+
+/// Containers with an unset creation time report their epoch (1904 for
+/// QuickTime, 2001 for Matroska) rather than nothing, so an epoch date is not
+/// believed.
+fn is_plausible_track_time(dt: &str) -> bool {
+    parse_taken(dt).is_some_and(|d| {
+        !matches!(
+            (
+                d.year(),
+                d.month(),
+                d.day(),
+                d.hour(),
+                d.minute(),
+                d.second()
+            ),
+            (1904 | 1970 | 2001, 1, 1, 0, 0, 0)
+        )
+    })
 }
 
 #[cfg(test)]
@@ -192,13 +251,47 @@ impl MediaFileInfo {
             hash_info: HashInfo {
                 short_checksum: "tsc".to_string(),
                 long_checksum: "tlc".to_string(),
+                payload_checksum: None,
             },
             supp_info: None,
             modified: None,
             created: None,
             file_size: 0,
+            perceptual_hash: None,
+            merged_checksums: vec![],
+            xmp_info: None,
+            iptc_info: None,
         }
     }
+
+    /// This, found at `path` in the input.
+    pub(crate) fn with_path(mut self, path: &str) -> Self {
+        self.original_file_this_run = path.to_string();
+        self.original_path = vec![path.to_string()];
+        self
+    }
+
+    /// This, with the bytes `checksum` stands for.
+    pub(crate) fn with_checksum(mut self, checksum: &str) -> Self {
+        self.hash_info.short_checksum = checksum.chars().take(7).collect();
+        self.hash_info.long_checksum = checksum.to_string();
+        self
+    }
+
+    /// This, with the EXIF tag `tag`, giving it EXIF if it had none.
+    pub(crate) fn with_exif_tag(mut self, tag: &str, value: &str) -> Self {
+        self.exif_info
+            .get_or_insert_with(|| PsExifInfo {
+                tags: Default::default(),
+                gps: None,
+                latitude: None,
+                longitude: None,
+                motion_photo_offset: None,
+            })
+            .tags
+            .insert(tag.to_string(), value.to_string());
+        self
+    }
 }
 
 #[cfg(test)]
@@ -210,6 +303,8 @@ impl MediaFileDerivedInfo {
         MediaFileDerivedInfo {
             desired_media_path,
             desired_media_extension: desired_media_extension.to_string(),
+            shares_stem: false,
+            archived_checksum: None,
         }
     }
 }
@@ -249,21 +344,19 @@ mod tests {
 
         let c = OsFileSystem::new("test");
         let mut reader = c.open("Canon_40D.jpg")?;
-        let short_checksum = checksum_bytes(&mut reader)?.short_checksum;
+        let mut info = MediaFileInfo::new_for_test();
+        info.hash_info = checksum_bytes(&mut reader)?;
+        let layout = PathLayout::default();
 
+        assert_eq!(layout.media_path(&info), "undated/6bfdabd".to_string());
+        info.modified = Some(1212162961000);
         assert_eq!(
-            get_desired_media_path(&short_checksum, &None),
-            "undated/6bfdabd".to_string()
-        );
-        assert_eq!(
-            get_desired_media_path(&short_checksum, &Some("2008-05-30T15:56:01Z".to_string())),
+            layout.media_path(&info),
             "2008/05/30/1556-01000".to_string()
         );
+        info.modified = Some(1212162961009);
         assert_eq!(
-            get_desired_media_path(
-                &short_checksum,
-                &Some("2008-05-30T15:56:01.009Z".to_string())
-            ),
+            layout.media_path(&info),
             "2008/05/30/1556-01009".to_string()
         );
         Ok(())
@@ -282,12 +375,13 @@ mod tests {
         let hash_info = HashInfo {
             short_checksum: "dummy".to_string(),
             long_checksum: "dummy".to_string(),
+            payload_checksum: None,
         };
 
         let start = std::time::Instant::now();
         for _ in 0..100 {
             let mut reader = fs.open(file_path)?;
-            let _ = media_file_info_from_readable(&si, &mut reader, &None, &hash_info);
+            let _ = media_file_info_from_readable(&si, &mut reader, &None, &None, &hash_info);
         }
         let duration = start.elapsed();
         println!("Time taken for 100 iterations: {:?}", duration);
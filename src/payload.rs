@@ -0,0 +1,227 @@
+//! Checksums over just the picture in an image file, leaving out its metadata.
+//!
+//! Editing EXIF, adding XMP or Google rewriting a file on export changes every
+//! byte-level checksum, yet leaves the compressed image data untouched. Hashing
+//! only that data recognises such files as the same picture:
+//!
+//! - JPEG: from the first start-of-scan marker to the end-of-image marker, so
+//!   the APP segments before it and anything appended after it are left out.
+//! - PNG: the contents of the `IDAT` chunks, in order.
+//! - HEIC and AVIF: the data of the coded image items (`hvc1`, `av01`), tiles
+//!   of a grid included, located through `iinf` and `iloc`. The `Exif` and XMP
+//!   items kept in the same `mdat` are left out.
+//!
+//! Other types, including videos (whose `mdat` can run to gigabytes), have no
+//! payload checksum.
+
+use crate::embed;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom};
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Brands of the ISO base media files that hold a still image (or a sequence of them).
+const IMAGE_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+/// Types of the HEIF items that hold coded image data.
+const IMAGE_ITEM_TYPES: &[&[u8; 4]] = &[b"hvc1", b"av01"];
+
+/// Hex SHA-256 of the image data alone, or `None` for a type without one or a
+/// file too damaged to find it in. Leaves the reader at an arbitrary position.
+pub(crate) fn payload_checksum<R: Read + Seek>(reader: &mut R) -> Option<String> {
+    let mut header = [0u8; 12];
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    reader.read_exact(&mut header).ok()?;
+    let mut hasher = Sha256::new();
+    let found = if header.starts_with(&[0xFF, 0xD8]) {
+        hash_jpeg_scan(reader, &mut hasher)
+    } else if header.starts_with(PNG_SIGNATURE) {
+        hash_png_idat(reader, &mut hasher)
+    } else if &header[4..8] == b"ftyp" && IMAGE_BRANDS.iter().any(|b| **b == header[8..12]) {
+        hash_heif_items(reader, &mut hasher)
+    } else {
+        None
+    };
+    found.filter(|found| *found)?;
+    Some(hex::encode(hasher.finalize()))
+}
+
+/// Walk the segments after SOI to the first SOS, then hash up to and including EOI.
+fn hash_jpeg_scan<R: Read + Seek>(reader: &mut R, hasher: &mut Sha256) -> Option<bool> {
+    let mut pos = 2u64;
+    loop {
+        reader.seek(SeekFrom::Start(pos)).ok()?;
+        let mut marker = [0u8; 4];
+        reader.read_exact(&mut marker).ok()?;
+        if marker[0] != 0xFF {
+            return None;
+        }
+        if marker[1] == 0xDA {
+            break;
+        }
+        let length = u16::from_be_bytes([marker[2], marker[3]]);
+        pos += 2 + u64::from(length);
+    }
+    reader.seek(SeekFrom::Start(pos)).ok()?;
+    // Within scan data 0xFF is always followed by 0x00 or a marker, so the first
+    // FF D9 is the end of the image.
+    let mut buffer = [0u8; 64 * 1024];
+    let mut after_ff = false;
+    loop {
+        let count = reader.read(&mut buffer).ok()?;
+        if count == 0 {
+            return Some(false);
+        }
+        for (i, b) in buffer[..count].iter().enumerate() {
+            if after_ff && *b == 0xD9 {
+                hasher.update(&buffer[..=i]);
+                return Some(true);
+            }
+            after_ff = *b == 0xFF;
+        }
+        hasher.update(&buffer[..count]);
+    }
+}
+
+/// Hash the data of every IDAT chunk.
+fn hash_png_idat<R: Read + Seek>(reader: &mut R, hasher: &mut Sha256) -> Option<bool> {
+    let mut pos = PNG_SIGNATURE.len() as u64;
+    let mut found = false;
+    loop {
+        reader.seek(SeekFrom::Start(pos)).ok()?;
+        let mut chunk = [0u8; 8];
+        if reader.read_exact(&mut chunk).is_err() {
+            return Some(found);
+        }
+        let length = u64::from(u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+        match &chunk[4..8] {
+            b"IDAT" => {
+                hash_next(reader, hasher, length)?;
+                found = true;
+            }
+            b"IEND" => return Some(found),
+            _ => {}
+        }
+        // Length, type, data and CRC
+        pos += 8 + length + 4;
+    }
+}
+
+/// Hash the data of every coded image item (the tiles of a grid among them),
+/// found through the `meta` box, in the order of the items' ids.
+fn hash_heif_items<R: Read + Seek>(reader: &mut R, hasher: &mut Sha256) -> Option<bool> {
+    let meta = embed::read_heif_meta(reader)?;
+    let extents = embed::heif_item_extents(&meta, IMAGE_ITEM_TYPES).ok()?;
+    for (offset, length) in &extents {
+        reader.seek(SeekFrom::Start(*offset)).ok()?;
+        hash_next(reader, hasher, *length)?;
+    }
+    Some(!extents.is_empty())
+}
+
+/// Hash the next `length` bytes of the reader.
+fn hash_next<R: Read>(reader: &mut R, hasher: &mut Sha256, length: u64) -> Option<()> {
+    let mut buffer = [0u8; 64 * 1024];
+    let mut left = length;
+    while left > 0 {
+        let want = left.min(buffer.len() as u64) as usize;
+        reader.read_exact(&mut buffer[..want]).ok()?;
+        hasher.update(&buffer[..want]);
+        left -= want as u64;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::RecoveredMetadata;
+    use crate::file_type::AccurateFileType;
+    use std::fs::File;
+    use std::io::Cursor;
+
+    fn checksum_of(bytes: Vec<u8>) -> Option<String> {
+        payload_checksum(&mut Cursor::new(bytes))
+    }
+
+    #[test]
+    fn test_jpeg_payload_ignores_metadata() -> anyhow::Result<()> {
+        let original = std::fs::read("test/Canon_40D.jpg")?;
+        let checksum = payload_checksum(&mut File::open("test/Canon_40D.jpg")?);
+        assert!(checksum.is_some());
+
+        // An XMP segment added before the scan, and bytes appended after it
+        let xmp = b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>";
+        let mut rewritten = original[..2].to_vec();
+        rewritten.extend([0xFF, 0xE1]);
+        rewritten.extend(((xmp.len() + 2) as u16).to_be_bytes());
+        rewritten.extend(xmp);
+        rewritten.extend(&original[2..]);
+        rewritten.extend(b"google");
+        assert_eq!(checksum_of(rewritten), checksum);
+
+        // A change to the picture itself
+        let mut retouched = original.clone();
+        let last = retouched.len() - 3;
+        retouched[last] ^= 0x01;
+        assert_ne!(checksum_of(retouched), checksum);
+
+        // Cut off before the end of the image
+        assert_eq!(checksum_of(original[..original.len() - 100].to_vec()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_png_payload_ignores_metadata() -> anyhow::Result<()> {
+        let original = std::fs::read("test/kinds/Screenshot_20190830-123456.png")?;
+        let checksum = checksum_of(original.clone());
+        assert!(checksum.is_some());
+        // The other fixture is the same 1x1 pixel with an eXIf chunk added
+        assert_eq!(
+            payload_checksum(&mut File::open("test/kinds/IMG_0006.PNG")?),
+            checksum
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_heif_payload_ignores_metadata() -> anyhow::Result<()> {
+        let original = std::fs::read("test/heif/tiles.heic")?;
+        let checksum = checksum_of(original.clone());
+        let tiles = b"\0\0\0\x0chevc tile one\0\0\0\x0chevc tile two";
+        assert_eq!(
+            checksum,
+            Some(hex::encode(Sha256::digest(tiles))),
+            "only the tiles are hashed"
+        );
+
+        // The EXIF rewritten into an `mdat` of its own
+        let recovered = RecoveredMetadata {
+            taken: None,
+            coords: Some((35.6586, 139.7454)),
+        };
+        let embedded = embed::embed_metadata(&original, &AccurateFileType::Heic, &recovered)?;
+        assert_ne!(embedded, original);
+        assert_eq!(checksum_of(embedded), checksum);
+
+        // A change to a tile
+        let tile = original
+            .windows(8)
+            .position(|w| w == b"tile two")
+            .ok_or_else(|| anyhow::anyhow!("No second tile"))?;
+        let mut retouched = original.clone();
+        retouched[tile] ^= 0x01;
+        assert_ne!(checksum_of(retouched), checksum);
+
+        // Videos are left out
+        assert_eq!(payload_checksum(&mut File::open("test/Hello.mp4")?), None);
+        // As are files without image items
+        assert_eq!(
+            payload_checksum(&mut File::open("test/formats/photo.avif")?),
+            None
+        );
+        Ok(())
+    }
+}
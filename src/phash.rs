@@ -0,0 +1,368 @@
+//! Perceptual hashes, for finding the same photo stored as different bytes:
+//! re-compressed by a messaging app, resized by a "storage saver" upload, or
+//! re-encoded in another format.
+//!
+//! The hash is a 64 bit difference hash (dHash): the image is shrunk to 9x8
+//! grey pixels and each bit records whether a pixel is brighter than the one
+//! to its right. Re-compression and resizing barely move it, so photos whose
+//! hashes differ in only a few bits look alike. Similar photos are only ever
+//! reported and linked, never removed.
+
+use crate::file_type::AccurateFileType;
+use crate::media::MediaFileInfo;
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageReader};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use tracing::debug;
+
+/// Most bits two hashes may differ in for the photos to count as similar.
+pub(crate) const SIMILAR_MAX_DISTANCE: u32 = 10;
+
+/// Images larger than this aren't decoded for a hash, to bound memory use.
+const MAX_HASHED_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Format to decode a file type as, for the types there's a pure Rust decoder for.
+pub(crate) fn decodable_format(ft: &AccurateFileType) -> Option<ImageFormat> {
+    match ft {
+        AccurateFileType::Jpg => Some(ImageFormat::Jpeg),
+        AccurateFileType::Png => Some(ImageFormat::Png),
+        AccurateFileType::Gif => Some(ImageFormat::Gif),
+        AccurateFileType::Webp => Some(ImageFormat::WebP),
+        AccurateFileType::Bmp => Some(ImageFormat::Bmp),
+        AccurateFileType::Tiff => Some(ImageFormat::Tiff),
+        _ => None,
+    }
+}
+
+/// dHash of an image as 16 hex digits, or `None` when its type can't be
+/// decoded (HEIC, RAW, video) or decoding fails.
+pub(crate) fn perceptual_hash<R: Read + Seek>(
+    reader: &mut R,
+    ft: &AccurateFileType,
+    file_size: u64,
+) -> Option<String> {
+    let format = decodable_format(ft)?;
+    if file_size > MAX_HASHED_FILE_SIZE {
+        return None;
+    }
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    let image_r = ImageReader::with_format(BufReader::new(reader), format).decode();
+    let image = match image_r {
+        Ok(image) => image,
+        Err(e) => {
+            debug!("Could not decode image for perceptual hash: {e}");
+            return None;
+        }
+    };
+    let small = image.grayscale().resize_exact(9, 8, FilterType::Triangle);
+    let pixels = small.to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = pixels.get_pixel(x, y)[0] > pixels.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(brighter);
+        }
+    }
+    Some(format!("{hash:016x}"))
+}
+
+fn parse_hash(hash: &str) -> Option<u64> {
+    u64::from_str_radix(hash, 16).ok()
+}
+
+/// Photos that look like each photo, keyed and valued by long checksum. Only
+/// distinct files are compared: byte-identical copies were merged already.
+///
+/// Hashes are parsed once and kept in a BK-tree, so each photo is compared
+/// with a few others rather than with every photo in the library.
+pub(crate) fn find_similar(media: &[&MediaFileInfo]) -> BTreeMap<String, Vec<String>> {
+    let mut hashed: Vec<(&String, u64)> = media
+        .iter()
+        .filter_map(|m| {
+            let hash = parse_hash(m.perceptual_hash.as_ref()?)?;
+            Some((&m.hash_info.long_checksum, hash))
+        })
+        .collect();
+    hashed.sort();
+    let mut tree = BkTree::default();
+    let mut similar: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (i, (b, b_hash)) in hashed.iter().enumerate() {
+        for j in tree.within(*b_hash, SIMILAR_MAX_DISTANCE) {
+            let (a, _) = hashed[j];
+            similar
+                .entry(a.to_string())
+                .or_default()
+                .push(b.to_string());
+            similar
+                .entry(b.to_string())
+                .or_default()
+                .push(a.to_string());
+        }
+        tree.insert(*b_hash, i);
+    }
+    for others in similar.values_mut() {
+        others.sort();
+    }
+    similar
+}
+
+/// A BK-tree of hashes under Hamming distance. Each child is filed under its
+/// distance from its parent, so by the triangle inequality a search need only
+/// follow the children within the search distance of its own distance to the parent.
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    hash: u64,
+    /// What the hash belongs to, returned by searches.
+    index: usize,
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    fn insert(&mut self, hash: u64, index: usize) {
+        let new = self.nodes.len();
+        let mut at = 0;
+        while let Some(node) = self.nodes.get(at) {
+            let distance = (node.hash ^ hash).count_ones();
+            match node.children.iter().find(|(d, _)| *d == distance) {
+                Some((_, child)) => at = *child,
+                None => {
+                    self.nodes[at].children.push((distance, new));
+                    break;
+                }
+            }
+        }
+        self.nodes.push(BkNode {
+            hash,
+            index,
+            children: Vec::new(),
+        });
+    }
+
+    /// What the hashes within `max` bits of `hash` belong to.
+    fn within(&self, hash: u64, max: u32) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut pending = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+        while let Some(at) = pending.pop() {
+            let node = &self.nodes[at];
+            let distance = (node.hash ^ hash).count_ones();
+            if distance <= max {
+                found.push(node.index);
+            }
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| d.abs_diff(distance) <= max)
+                    .map(|(_, child)| *child),
+            );
+        }
+        found
+    }
+}
+
+/// Group photos that look alike, each group sorted and the groups in order of
+/// their first member, so a report lists the same groups every run. Photos
+/// are grouped when linked through any chain of similar pairs.
+pub(crate) fn similar_groups(similar: &BTreeMap<String, Vec<String>>) -> Vec<Vec<String>> {
+    let mut group_of: HashMap<&String, usize> = HashMap::new();
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    for start in similar.keys() {
+        if group_of.contains_key(start) {
+            continue;
+        }
+        let mut group = vec![start.clone()];
+        group_of.insert(start, groups.len());
+        let mut i = 0;
+        while let Some(checksum) = group.get(i).cloned() {
+            for other in similar.get(&checksum).into_iter().flatten() {
+                if !group_of.contains_key(other) {
+                    group_of.insert(other, groups.len());
+                    group.push(other.clone());
+                }
+            }
+            i += 1;
+        }
+        group.sort();
+        groups.push(group);
+    }
+    groups
+}
+
+/// `similar.md`: the groups of photos that look alike, linked by their paths
+/// in the archive, for a person to review. `None` when there are none.
+pub(crate) fn similar_report(
+    groups: &[Vec<String>],
+    final_path_by_checksum: &HashMap<String, String>,
+) -> Option<String> {
+    let mut md = String::new();
+    let mut group_count = 0;
+    for group in groups {
+        let mut paths: Vec<&String> = group
+            .iter()
+            .filter_map(|checksum| final_path_by_checksum.get(checksum))
+            .collect();
+        if paths.len() < 2 {
+            continue;
+        }
+        paths.sort();
+        group_count += 1;
+        md.push_str(&format!("\n## Group {group_count}\n\n"));
+        for path in paths {
+            md.push_str(&format!("- [[{path}]]\n"));
+        }
+    }
+    if group_count == 0 {
+        return None;
+    }
+    Some(format!(
+        "# Similar photos\n\n\
+         Photos that look alike but aren't identical files: re-compressed, resized \
+         or re-encoded copies, or shots taken moments apart. Nothing has been \
+         removed; delete the copies you don't want yourself.\n{md}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageBuffer, Luma};
+    use std::io::Cursor;
+
+    /// Number of bits two hashes differ in.
+    fn hash_distance(a: &str, b: &str) -> Option<u32> {
+        Some((parse_hash(a)? ^ parse_hash(b)?).count_ones())
+    }
+
+    fn encoded(image: &DynamicImage, format: ImageFormat) -> anyhow::Result<Cursor<Vec<u8>>> {
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn test_perceptual_hash_survives_resize_and_reencode() -> anyhow::Result<()> {
+        let mut original = std::fs::File::open("test/Canon_40D.jpg")?;
+        let hash = perceptual_hash(&mut original, &AccurateFileType::Jpg, 7958)
+            .ok_or_else(|| anyhow::anyhow!("no hash"))?;
+
+        let image = image::open("test/Canon_40D.jpg")?;
+        let smaller = image.resize(50, 50, FilterType::Lanczos3);
+        let mut png = encoded(&smaller, ImageFormat::Png)?;
+        let png_hash = perceptual_hash(&mut png, &AccurateFileType::Png, 0)
+            .ok_or_else(|| anyhow::anyhow!("no hash"))?;
+        assert!(hash_distance(&hash, &png_hash).is_some_and(|d| d <= SIMILAR_MAX_DISTANCE));
+
+        let gradient = DynamicImage::ImageLuma8(ImageBuffer::from_fn(64, 64, |x, y| {
+            Luma([((x * 4) ^ (y * 4)) as u8])
+        }));
+        let mut other = encoded(&gradient, ImageFormat::Png)?;
+        let other_hash = perceptual_hash(&mut other, &AccurateFileType::Png, 0)
+            .ok_or_else(|| anyhow::anyhow!("no hash"))?;
+        assert!(hash_distance(&hash, &other_hash).is_some_and(|d| d > SIMILAR_MAX_DISTANCE));
+
+        // No decoder for these
+        let mut clip = std::fs::File::open("test/Hello.mp4")?;
+        assert_eq!(perceptual_hash(&mut clip, &AccurateFileType::Mp4, 0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_distance() {
+        assert_eq!(
+            hash_distance("0000000000000000", "000000000000000f"),
+            Some(4)
+        );
+        assert_eq!(
+            hash_distance("ffffffffffffffff", "0000000000000000"),
+            Some(64)
+        );
+        assert_eq!(hash_distance("not hex", "0000000000000000"), None);
+    }
+
+    #[test]
+    fn test_find_similar_and_groups() {
+        let photo = |checksum: &str, hash: Option<&str>| {
+            let mut m = MediaFileInfo::new_for_test().with_checksum(checksum);
+            m.perceptual_hash = hash.map(|h| h.to_string());
+            m
+        };
+        let a = photo("a", Some("0000000000000000"));
+        let b = photo("b", Some("00000000000000ff"));
+        let c = photo("c", Some("000000000000ffff"));
+        let d = photo("d", Some("ffffffffffffffff"));
+        let e = photo("e", None);
+        let similar = find_similar(&[&c, &a, &b, &d, &e]);
+        assert_eq!(similar.get("a"), Some(&vec!["b".to_string()]));
+        assert_eq!(
+            similar.get("b"),
+            Some(&vec!["a".to_string(), "c".to_string()])
+        );
+        assert!(!similar.contains_key("d"));
+        assert_eq!(
+            similar_groups(&similar),
+            vec![vec!["a".to_string(), "b".to_string(), "c".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_bk_tree_finds_what_comparing_every_pair_does() {
+        // A fixed pseudo-random spread of hashes, with some near copies among them
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut hashes = Vec::new();
+        for i in 0..500 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let hash = match hashes.get(i / 2) {
+                Some(near) if i % 3 == 0 => near ^ (state >> 56),
+                _ => state,
+            };
+            hashes.push(hash);
+        }
+        let mut tree = BkTree::default();
+        for (i, hash) in hashes.iter().enumerate() {
+            tree.insert(*hash, i);
+        }
+        let mut pairs = 0;
+        for (i, hash) in hashes.iter().enumerate() {
+            let mut found = tree.within(*hash, SIMILAR_MAX_DISTANCE);
+            found.sort();
+            let every_pair: Vec<usize> = (0..hashes.len())
+                .filter(|j| (hashes[*j] ^ hash).count_ones() <= SIMILAR_MAX_DISTANCE)
+                .collect();
+            assert_eq!(found, every_pair, "{i}");
+            pairs += found.len() - 1;
+        }
+        assert!(pairs > 100, "{pairs}");
+    }
+
+    #[test]
+    fn test_similar_report() -> anyhow::Result<()> {
+        let groups = vec![
+            vec!["a".to_string(), "b".to_string()],
+            vec!["c".to_string(), "d".to_string()],
+        ];
+        let mut final_paths = HashMap::new();
+        final_paths.insert("a".to_string(), "2019/08/30/1234-56000.png".to_string());
+        final_paths.insert("b".to_string(), "2019/08/30/1234-56000.jpg".to_string());
+        // Only one of these was written, so there's nothing to compare it with
+        final_paths.insert("c".to_string(), "2020/01/01/1010-10000.jpg".to_string());
+        let report =
+            similar_report(&groups, &final_paths).ok_or_else(|| anyhow::anyhow!("no report"))?;
+        assert!(report.starts_with("# Similar photos\n"));
+        assert!(report.ends_with(
+            "\n## Group 1\n\n- [[2019/08/30/1234-56000.jpg]]\n- [[2019/08/30/1234-56000.png]]\n"
+        ));
+        assert!(!report.contains("Group 2"));
+        assert_eq!(similar_report(&groups[1..], &final_paths), None);
+        Ok(())
+    }
+}
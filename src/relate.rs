@@ -0,0 +1,700 @@
+//! Relationships between separate media files that belong together, such as
+//! the still and the motion clip of an Apple Live Photo, a camera RAW and the
+//! JPEG developed from it, an edited photo and its original, or an animation
+//! Google Photos made and the shots it was made from.
+//!
+//! Relations are found over the whole deduplicated collection, so both sides of
+//! a pair are known before anything is written. They are keyed by long checksum
+//! like the rest of the sync pipeline, and are deterministic: the same input
+//! always yields the same pairs regardless of inspection order.
+
+use crate::classify::{generated_file, generated_kind};
+use crate::exif_util::TAG_CONTENT_IDENTIFIER;
+use crate::file_type::{AccurateFileType, MetadataType, is_raw, metadata_type};
+use crate::media::MediaFileInfo;
+use crate::util::ScanInfo;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use tracing::debug;
+
+/// Frontmatter key -> paths of the related media files, rendered as wikilinks
+/// in the sidecar.
+pub(crate) type MediaLinks = BTreeMap<String, Vec<String>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum RelationKind {
+    /// Apple Live Photo: a still and its short motion clip.
+    LivePhoto,
+    /// The other file is the camera RAW this JPEG/HEIC was developed from.
+    Raw,
+    /// The other file is the JPEG/HEIC developed from this camera RAW.
+    Developed,
+    /// The other file is an edited version of this one.
+    EditedVersion,
+    /// The other file is the original this one was edited from.
+    OriginalVersion,
+    /// The other file is one of the shots this generated animation, collage or
+    /// movie was made from.
+    GeneratedFrom,
+    /// The other file is an animation, collage or movie generated from this one.
+    UsedIn,
+}
+
+impl RelationKind {
+    /// The frontmatter key the relation is recorded under.
+    pub(crate) fn frontmatter_key(&self) -> &'static str {
+        match self {
+            RelationKind::LivePhoto => "live-photo",
+            RelationKind::Raw => "raw",
+            RelationKind::Developed => "developed",
+            RelationKind::EditedVersion => "edited-version",
+            RelationKind::OriginalVersion => "original-version",
+            RelationKind::GeneratedFrom => "generated-from",
+            RelationKind::UsedIn => "used-in",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Relation {
+    /// What the other file is to this one.
+    pub(crate) kind: RelationKind,
+    /// Long checksum of the other media file.
+    pub(crate) other: String,
+    /// This file is stored next to `other`, taking its name (e.g. the clip of a
+    /// Live Photo follows its still).
+    pub(crate) follows: bool,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Relations {
+    by_checksum: HashMap<String, Vec<Relation>>,
+}
+
+impl Relations {
+    /// Every relation recorded for the media file with this long checksum.
+    pub(crate) fn for_checksum(&self, long_checksum: &str) -> &[Relation] {
+        self.by_checksum
+            .get(long_checksum)
+            .map(|r| r.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Long checksum of the media file this one is stored next to, if any.
+    pub(crate) fn leader_of(&self, long_checksum: &str) -> Option<&String> {
+        self.for_checksum(long_checksum)
+            .iter()
+            .find(|r| r.follows)
+            .map(|r| &r.other)
+    }
+
+    /// Long checksum of the original this media file was edited from, if any.
+    /// Edits are archived under their own name unless the caller chooses to
+    /// store them beside the original.
+    pub(crate) fn original_of(&self, long_checksum: &str) -> Option<&String> {
+        self.for_checksum(long_checksum)
+            .iter()
+            .find(|r| r.kind == RelationKind::OriginalVersion)
+            .map(|r| &r.other)
+    }
+
+    /// Links for a media file's sidecar, resolving each related file's long
+    /// checksum to a path with `path_by_checksum`. Relations whose other side
+    /// has no path (e.g. it failed to write) are left out.
+    pub(crate) fn links(
+        &self,
+        long_checksum: &str,
+        path_by_checksum: &HashMap<String, String>,
+    ) -> MediaLinks {
+        let mut links = MediaLinks::new();
+        for relation in self.for_checksum(long_checksum) {
+            if let Some(path) = path_by_checksum.get(&relation.other) {
+                let paths = links
+                    .entry(relation.kind.frontmatter_key().to_string())
+                    .or_default();
+                if !paths.contains(path) {
+                    paths.push(path.clone());
+                }
+            }
+        }
+        links
+    }
+
+    /// Record a pair. `leader_kind` is what the follower is to the leader, and
+    /// `follower_kind` what the leader is to the follower.
+    fn add_pair(
+        &mut self,
+        leader_kind: RelationKind,
+        leader: &str,
+        follower_kind: RelationKind,
+        follower: &str,
+    ) {
+        self.add(leader, leader_kind, follower, false);
+        self.add(follower, follower_kind, leader, true);
+    }
+
+    /// Record two files that are related but each stored under its own name.
+    fn add_link(&mut self, a_kind: RelationKind, a: &str, b_kind: RelationKind, b: &str) {
+        self.add(a, a_kind, b, false);
+        self.add(b, b_kind, a, false);
+    }
+
+    fn add(&mut self, checksum: &str, kind: RelationKind, other: &str, follows: bool) {
+        self.by_checksum
+            .entry(checksum.to_string())
+            .or_default()
+            .push(Relation {
+                kind,
+                other: other.to_string(),
+                follows,
+            });
+    }
+}
+
+/// Find all relations between the given (already deduplicated) media files.
+pub(crate) fn find_relations(media: &[&MediaFileInfo]) -> Relations {
+    let mut sorted: Vec<&MediaFileInfo> = media.to_vec();
+    sorted.sort_by(|a, b| a.hash_info.long_checksum.cmp(&b.hash_info.long_checksum));
+
+    let mut relations = Relations::default();
+    for (still, clip) in live_photo_pairs(&sorted) {
+        debug!("Live Photo: {still} + {clip}");
+        relations.add_pair(
+            RelationKind::LivePhoto,
+            &still,
+            RelationKind::LivePhoto,
+            &clip,
+        );
+    }
+    for (developed, raw) in raw_pairs(&sorted) {
+        debug!("RAW+JPEG: {developed} + {raw}");
+        relations.add_pair(RelationKind::Raw, &developed, RelationKind::Developed, &raw);
+    }
+    for (original, edit) in edit_pairs(&sorted) {
+        debug!("Edited version: {original} + {edit}");
+        relations.add_link(
+            RelationKind::EditedVersion,
+            &original,
+            RelationKind::OriginalVersion,
+            &edit,
+        );
+    }
+    for (source, generated) in generated_pairs(&sorted) {
+        debug!("Generated: {source} -> {generated}");
+        relations.add_link(
+            RelationKind::UsedIn,
+            &source,
+            RelationKind::GeneratedFrom,
+            &generated,
+        );
+    }
+    relations
+}
+
+fn is_live_photo_still(ft: &AccurateFileType) -> bool {
+    matches!(ft, AccurateFileType::Heic | AccurateFileType::Jpg)
+}
+
+fn is_live_photo_clip(ft: &AccurateFileType) -> bool {
+    matches!(ft, AccurateFileType::Mov | AccurateFileType::Mp4)
+}
+
+fn is_developed_image(ft: &AccurateFileType) -> bool {
+    matches!(ft, AccurateFileType::Heic | AccurateFileType::Jpg)
+}
+
+/// Apple's `ContentIdentifier`, written into both halves of a Live Photo.
+fn content_identifier(media: &MediaFileInfo) -> Option<&String> {
+    if let Some(exif) = &media.exif_info {
+        return exif.tags.get(TAG_CONTENT_IDENTIFIER);
+    }
+    if let Some(track) = &media.track_info {
+        return track.content_identifier.as_ref();
+    }
+    None
+}
+
+/// Lowercased source path without its extension, e.g.
+/// `Photos/IMG_1234.HEIC` -> `photos/img_1234`. Two files sharing this sat side
+/// by side in the export with the same name.
+fn stem_key(path: &str) -> String {
+    let p = Path::new(path);
+    let stem = p
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let dir = p
+        .parent()
+        .map(|d| d.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("{dir}/{stem}").to_lowercase()
+}
+
+/// Pair Live Photo stills with their clips as `(still, clip)` long checksums.
+///
+/// The `ContentIdentifier` both halves carry is preferred. Files without one
+/// (e.g. Google Takeout, which strips it from the still) fall back to sharing a
+/// name in the same source directory (`IMG_1234.HEIC` + `IMG_1234.MOV`). Two
+/// files whose identifiers are both known but differ are never paired, and a
+/// name shared by more than one still or clip is ambiguous so is left alone.
+fn live_photo_pairs(media: &[&MediaFileInfo]) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut paired: HashSet<String> = HashSet::new();
+
+    let mut by_identifier: BTreeMap<&String, (Vec<&MediaFileInfo>, Vec<&MediaFileInfo>)> =
+        BTreeMap::new();
+    for m in media {
+        if let Some(id) = content_identifier(m) {
+            let entry = by_identifier.entry(id).or_default();
+            if is_live_photo_still(&m.accurate_file_type) {
+                entry.0.push(m);
+            } else if is_live_photo_clip(&m.accurate_file_type) {
+                entry.1.push(m);
+            }
+        }
+    }
+    for (stills, clips) in by_identifier.values() {
+        if let ([still], [clip]) = (stills.as_slice(), clips.as_slice()) {
+            paired.insert(still.hash_info.long_checksum.clone());
+            paired.insert(clip.hash_info.long_checksum.clone());
+            pairs.push((
+                still.hash_info.long_checksum.clone(),
+                clip.hash_info.long_checksum.clone(),
+            ));
+        }
+    }
+
+    pairs.extend(stem_pairs(
+        media,
+        &mut paired,
+        |m| is_live_photo_still(&m.accurate_file_type),
+        |m| is_live_photo_clip(&m.accurate_file_type),
+        |still, clip| match (content_identifier(still), content_identifier(clip)) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        },
+    ));
+    pairs
+}
+
+/// Pair camera RAWs with the JPEG/HEIC shot alongside them as `(developed,
+/// raw)` long checksums. Cameras write both under one name (`P1000123.JPG` +
+/// `P1000123.ORF`), so only the name is used, and a name shared by more than
+/// one of either is ambiguous so is left alone.
+fn raw_pairs(media: &[&MediaFileInfo]) -> Vec<(String, String)> {
+    stem_pairs(
+        media,
+        &mut HashSet::new(),
+        |m| is_developed_image(&m.accurate_file_type),
+        |m| is_raw(&m.accurate_file_type),
+        |_, _| true,
+    )
+}
+
+/// Pair files that shared a name in the same source directory, one matching
+/// `is_leader` with one matching `is_follower`, as `(leader, follower)` long
+/// checksums. Files already in `paired` are skipped, and newly paired ones are
+/// added to it.
+fn stem_pairs(
+    media: &[&MediaFileInfo],
+    paired: &mut HashSet<String>,
+    is_leader: impl Fn(&MediaFileInfo) -> bool,
+    is_follower: impl Fn(&MediaFileInfo) -> bool,
+    compatible: impl Fn(&MediaFileInfo, &MediaFileInfo) -> bool,
+) -> Vec<(String, String)> {
+    let mut by_stem: BTreeMap<String, (Vec<&MediaFileInfo>, Vec<&MediaFileInfo>)> = BTreeMap::new();
+    for m in media {
+        if paired.contains(&m.hash_info.long_checksum) {
+            continue;
+        }
+        let leads = is_leader(m);
+        if !leads && !is_follower(m) {
+            continue;
+        }
+        let mut keys: Vec<String> = m.original_path.iter().map(|p| stem_key(p)).collect();
+        keys.dedup();
+        for key in keys {
+            let entry = by_stem.entry(key).or_default();
+            if leads {
+                entry.0.push(m);
+            } else {
+                entry.1.push(m);
+            }
+        }
+    }
+    let mut pairs = Vec::new();
+    for (leaders, followers) in by_stem.values() {
+        let ([leader], [follower]) = (leaders.as_slice(), followers.as_slice()) else {
+            continue;
+        };
+        let leader_checksum = &leader.hash_info.long_checksum;
+        let follower_checksum = &follower.hash_info.long_checksum;
+        if paired.contains(leader_checksum) || paired.contains(follower_checksum) {
+            continue;
+        }
+        if !compatible(leader, follower) {
+            continue;
+        }
+        paired.insert(leader_checksum.clone());
+        paired.insert(follower_checksum.clone());
+        pairs.push((leader_checksum.clone(), follower_checksum.clone()));
+    }
+    pairs
+}
+
+/// Stem key of the original an edit was made from, going by the names Google
+/// (`IMG_1234-edited.jpg`) and iCloud (`IMG_E1234.JPG`) give edits.
+fn original_stem_key(path: &str) -> Option<String> {
+    let key = stem_key(path);
+    let (dir, stem) = key.rsplit_once('/')?;
+    if let Some(original) = stem.strip_suffix("-edited")
+        && !original.is_empty()
+    {
+        return Some(format!("{dir}/{original}"));
+    }
+    let digits = stem.strip_prefix("img_e")?;
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        return Some(format!("{dir}/img_{digits}"));
+    }
+    None
+}
+
+/// Whether a path is named as Google or iCloud name an edited photo.
+pub(crate) fn is_edited_name(path: &str) -> bool {
+    original_stem_key(path).is_some()
+}
+
+fn is_video(ft: &AccurateFileType) -> bool {
+    matches!(metadata_type(ft), MetadataType::Track)
+}
+
+/// Pair edits with their originals as `(original, edit)` long checksums. The
+/// original is the one file that shared the edit's unedited name in the same
+/// source directory and is a photo or video like it (an edited HEIC is often
+/// exported as JPEG, so the type itself may differ). An edit whose original is
+/// missing or ambiguous is left alone.
+fn edit_pairs(media: &[&MediaFileInfo]) -> Vec<(String, String)> {
+    let mut by_stem: BTreeMap<String, Vec<&MediaFileInfo>> = BTreeMap::new();
+    for m in media {
+        let mut keys: Vec<String> = m.original_path.iter().map(|p| stem_key(p)).collect();
+        keys.dedup();
+        for key in keys {
+            by_stem.entry(key).or_default().push(m);
+        }
+    }
+    let mut pairs = Vec::new();
+    for edit in media {
+        let mut originals: Vec<&String> = edit
+            .original_path
+            .iter()
+            .filter_map(|p| original_stem_key(p))
+            .filter_map(|key| by_stem.get(&key))
+            .flatten()
+            .filter(|o| {
+                o.hash_info.long_checksum != edit.hash_info.long_checksum
+                    && is_video(&o.accurate_file_type) == is_video(&edit.accurate_file_type)
+            })
+            .map(|o| &o.hash_info.long_checksum)
+            .collect();
+        originals.sort();
+        originals.dedup();
+        if let [original] = originals.as_slice() {
+            pairs.push(((*original).clone(), edit.hash_info.long_checksum.clone()));
+        }
+    }
+    pairs
+}
+
+/// Lowercased file name without extension or camera prefix, e.g.
+/// `DCIM/PXL_20190830_123456789.jpg` -> `20190830_123456789`.
+fn capture_stem(path: &str) -> String {
+    let stem = Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match stem.split_once('_') {
+        Some((prefix, rest)) if prefix.chars().all(|c| c.is_ascii_alphabetic()) => rest.to_string(),
+        _ => stem,
+    }
+}
+
+/// Pair what Google Photos generated (animations, collages, movies) with the
+/// shots it was made from, as `(source, generated)` long checksums. Google names
+/// the result after the first shot's timestamp (`20190830_123456-ANIMATION.gif`),
+/// so every other file named from that timestamp is taken as a source.
+fn generated_pairs(media: &[&MediaFileInfo]) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    for generated in media {
+        let Some(id) = generated
+            .original_path
+            .iter()
+            .find_map(|p| generated_file(p))
+            .map(|(_, id)| id)
+        else {
+            continue;
+        };
+        for source in media {
+            let is_source = generated_kind(&source.original_path).is_none()
+                && source
+                    .original_path
+                    .iter()
+                    .any(|p| capture_stem(p).starts_with(&id));
+            if is_source {
+                pairs.push((
+                    source.hash_info.long_checksum.clone(),
+                    generated.hash_info.long_checksum.clone(),
+                ));
+            }
+        }
+    }
+    pairs
+}
+
+/// Other media files in `files` that could be related to `path`: those sharing
+/// its name in the same directory, its original or its edits. Used by `info`, which inspects a single file
+/// rather than a whole collection.
+pub(crate) fn candidate_paths(path: &str, files: &[ScanInfo]) -> Vec<String> {
+    let key = stem_key(path);
+    let original_key = original_stem_key(path);
+    files
+        .iter()
+        .filter(|f| {
+            f.file_path != path
+                && (stem_key(&f.file_path) == key
+                    || original_key.as_ref() == Some(&stem_key(&f.file_path))
+                    || original_stem_key(&f.file_path).as_ref() == Some(&key))
+        })
+        .map(|f| f.file_path.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track_util::PsTrackInfo;
+
+    fn media(path: &str, ft: AccurateFileType, checksum: &str) -> MediaFileInfo {
+        let mut m = MediaFileInfo::new_for_test()
+            .with_path(path)
+            .with_checksum(checksum);
+        m.accurate_file_type = ft;
+        m
+    }
+
+    fn still(path: &str, checksum: &str, id: Option<&str>) -> MediaFileInfo {
+        let m = media(path, AccurateFileType::Heic, checksum);
+        match id {
+            Some(id) => m.with_exif_tag(TAG_CONTENT_IDENTIFIER, id),
+            None => m,
+        }
+    }
+
+    fn clip(path: &str, checksum: &str, id: Option<&str>) -> MediaFileInfo {
+        let mut m = media(path, AccurateFileType::Mov, checksum);
+        m.track_info = Some(PsTrackInfo {
+            width: None,
+            height: None,
+            creation_time: None,
+            duration_ms: None,
+            make: None,
+            model: None,
+            software: None,
+            author: None,
+            gps_iso_6709: None,
+            content_identifier: id.map(|s| s.to_string()),
+            full_frame_rate_playback_intent: None,
+            capture_fps: None,
+        });
+        m
+    }
+
+    #[test]
+    fn test_pairs_by_content_identifier_across_names() {
+        let s = still("Photos/IMG_1234.HEIC", "aaaa", Some("ID-1"));
+        let c = clip("Photos/IMG_1235.MOV", "bbbb", Some("ID-1"));
+        let relations = find_relations(&[&s, &c]);
+        assert_eq!(relations.leader_of("bbbb"), Some(&"aaaa".to_string()));
+        assert_eq!(relations.leader_of("aaaa"), None);
+        assert_eq!(
+            relations.for_checksum("aaaa"),
+            &[Relation {
+                kind: RelationKind::LivePhoto,
+                other: "bbbb".to_string(),
+                follows: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_pairs_by_name_without_identifier() {
+        let s = still("Photos/IMG_1234.HEIC", "aaaa", None);
+        let c = clip("Photos/img_1234.mov", "bbbb", None);
+        let other_dir = clip("Other/IMG_1234.MOV", "cccc", None);
+        let relations = find_relations(&[&c, &other_dir, &s]);
+        assert_eq!(relations.leader_of("bbbb"), Some(&"aaaa".to_string()));
+        assert!(relations.for_checksum("cccc").is_empty());
+    }
+
+    #[test]
+    fn test_conflicting_identifiers_are_not_paired_by_name() {
+        let s = still("Photos/IMG_1234.HEIC", "aaaa", Some("ID-1"));
+        let c = clip("Photos/IMG_1234.MOV", "bbbb", Some("ID-2"));
+        let relations = find_relations(&[&s, &c]);
+        assert!(relations.for_checksum("aaaa").is_empty());
+        assert!(relations.for_checksum("bbbb").is_empty());
+    }
+
+    #[test]
+    fn test_ambiguous_names_are_not_paired() {
+        let s1 = still("Photos/IMG_1234.HEIC", "aaaa", None);
+        let s2 = media("Photos/IMG_1234.JPG", AccurateFileType::Jpg, "cccc");
+        let c = clip("Photos/IMG_1234.MOV", "bbbb", None);
+        let relations = find_relations(&[&s1, &s2, &c]);
+        assert!(relations.for_checksum("bbbb").is_empty());
+    }
+
+    #[test]
+    fn test_pairs_raw_with_developed() {
+        let jpg = media("DCIM/P1000123.JPG", AccurateFileType::Jpg, "aaaa");
+        let raw = media("DCIM/P1000123.ORF", AccurateFileType::Orf, "bbbb");
+        let lone = media("DCIM/P1000124.ORF", AccurateFileType::Orf, "cccc");
+        let relations = find_relations(&[&raw, &lone, &jpg]);
+        assert_eq!(relations.leader_of("bbbb"), Some(&"aaaa".to_string()));
+        assert_eq!(
+            relations.for_checksum("aaaa"),
+            &[Relation {
+                kind: RelationKind::Raw,
+                other: "bbbb".to_string(),
+                follows: false,
+            }]
+        );
+        assert_eq!(
+            relations.for_checksum("bbbb")[0].kind,
+            RelationKind::Developed
+        );
+        assert!(relations.for_checksum("cccc").is_empty());
+    }
+
+    #[test]
+    fn test_live_photo_still_can_also_have_raw() {
+        let s = still("DCIM/IMG_1234.HEIC", "aaaa", None);
+        let c = clip("DCIM/IMG_1234.MOV", "bbbb", None);
+        let raw = media("DCIM/IMG_1234.DNG", AccurateFileType::Dng, "cccc");
+        let relations = find_relations(&[&s, &c, &raw]);
+        assert_eq!(relations.leader_of("bbbb"), Some(&"aaaa".to_string()));
+        assert_eq!(relations.leader_of("cccc"), Some(&"aaaa".to_string()));
+        assert_eq!(relations.for_checksum("aaaa").len(), 2);
+    }
+
+    #[test]
+    fn test_original_stem_key() {
+        assert_eq!(
+            original_stem_key("Photos/IMG_1234-edited.jpg"),
+            Some("photos/img_1234".to_string())
+        );
+        assert_eq!(
+            original_stem_key("Photos/IMG_E1234.JPG"),
+            Some("photos/img_1234".to_string())
+        );
+        assert_eq!(original_stem_key("Photos/IMG_1234.JPG"), None);
+        assert_eq!(original_stem_key("Photos/IMG_EDIT.JPG"), None);
+        assert_eq!(original_stem_key("Photos/-edited.JPG"), None);
+    }
+
+    #[test]
+    fn test_links_edits_to_originals() {
+        let original = still("Photos/IMG_1234.HEIC", "aaaa", None);
+        let edit = media("Photos/IMG_E1234.JPG", AccurateFileType::Jpg, "bbbb");
+        let clip = clip("Photos/IMG_1234.MOV", "cccc", None);
+        let google = media("Takeout/IMG_1234-edited.jpg", AccurateFileType::Jpg, "dddd");
+        let relations = find_relations(&[&original, &edit, &clip, &google]);
+        assert_eq!(relations.original_of("bbbb"), Some(&"aaaa".to_string()));
+        // Edits are stored under their own name by default
+        assert_eq!(relations.leader_of("bbbb"), None);
+        assert!(
+            relations
+                .for_checksum("aaaa")
+                .iter()
+                .any(|r| r.kind == RelationKind::EditedVersion && r.other == "bbbb")
+        );
+        // The Google edit's original is in another directory
+        assert_eq!(relations.original_of("dddd"), None);
+    }
+
+    #[test]
+    fn test_links_generated_to_sources() {
+        let first = media(
+            "2019/IMG_20190830_123456.jpg",
+            AccurateFileType::Jpg,
+            "aaaa",
+        );
+        let second = media(
+            "2019/PXL_20190830_123457.jpg",
+            AccurateFileType::Jpg,
+            "bbbb",
+        );
+        let frame = media(
+            "2019/IMG_20190830_123456_BURST002.jpg",
+            AccurateFileType::Jpg,
+            "cccc",
+        );
+        let gif = media(
+            "2019/20190830_123456-ANIMATION.gif",
+            AccurateFileType::Gif,
+            "dddd",
+        );
+        let collage = media(
+            "2019/20190830_123456-COLLAGE.jpg",
+            AccurateFileType::Jpg,
+            "eeee",
+        );
+        let relations = find_relations(&[&first, &second, &frame, &gif, &collage]);
+        let sources = |checksum: &str| -> Vec<String> {
+            relations
+                .for_checksum(checksum)
+                .iter()
+                .filter(|r| r.kind == RelationKind::GeneratedFrom)
+                .map(|r| r.other.clone())
+                .collect()
+        };
+        assert_eq!(sources("dddd"), vec!["aaaa", "cccc"]);
+        assert_eq!(sources("eeee"), vec!["aaaa", "cccc"]);
+        assert!(
+            relations
+                .for_checksum("aaaa")
+                .iter()
+                .any(|r| r.kind == RelationKind::UsedIn && r.other == "dddd")
+        );
+        assert!(relations.for_checksum("bbbb").is_empty());
+        assert_eq!(relations.leader_of("dddd"), None);
+    }
+
+    #[test]
+    fn test_capture_stem() {
+        assert_eq!(
+            capture_stem("DCIM/PXL_20190830_123456789.jpg"),
+            "20190830_123456789"
+        );
+        assert_eq!(capture_stem("DCIM/20190830_123456.jpg"), "20190830_123456");
+        assert_eq!(
+            capture_stem("DCIM/MVIMG_20190830_123456.jpg"),
+            "20190830_123456"
+        );
+    }
+
+    #[test]
+    fn test_links_resolve_paths() {
+        let s = still("Photos/IMG_1234.HEIC", "aaaa", Some("ID-1"));
+        let c = clip("Photos/IMG_1234.MOV", "bbbb", Some("ID-1"));
+        let relations = find_relations(&[&s, &c]);
+        let mut paths = HashMap::new();
+        paths.insert("bbbb".to_string(), "2024/07/15/1430-22417.mov".to_string());
+        let links = relations.links("aaaa", &paths);
+        assert_eq!(
+            links.get("live-photo"),
+            Some(&vec!["2024/07/15/1430-22417.mov".to_string()])
+        );
+        // The still has no path, so the clip gets no link.
+        assert!(relations.links("bbbb", &paths).is_empty());
+    }
+}
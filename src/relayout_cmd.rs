@@ -0,0 +1,645 @@
+//! Moves an archive made by `sync` into another layout without importing it again.
+//!
+//! Each media file's new path is worked out as `sync` would: from the layout's
+//! templates, the date taken and original paths recorded in its note, and the
+//! metadata in the file itself. A media file, its note and its XMP sidecar move
+//! together, and the files stored under its name (the clip of a Live Photo, a
+//! RAW, an extracted Motion Photo clip) move with it, as do their thumbnails.
+//! Links to moved files are then rewritten in every note, in the albums under
+//! `albums/` and in `similar.md`; the rest of each note is left as it was.
+//!
+//! The moves are planned up front and saved in the archive as
+//! [`PLAN_PATH`] before any file is touched, so an interrupted relayout
+//! finishes the same plan when run again. A file never takes a name that
+//! another file had at the start of a pass, so a link can't be rewritten to a
+//! file other than the one it pointed at; a file given a checksum suffix to
+//! make way is moved to its own name by the next pass.
+
+use crate::burst::BurstInfo;
+use crate::db_cmd::HashInfo;
+use crate::file_type::QuickFileType;
+use crate::fs::{FileSystem, OsFileSystem};
+use crate::media::{MediaFileInfo, media_metadata_from_readable};
+use crate::subtype::MediaSubtype;
+use crate::sync_cmd::{SyncOptions, path_without_extension, placed_media_path};
+use crate::thumbnail::thumbnail_path;
+use anyhow::anyhow;
+use clap::ValueEnum;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+use tracing::{info, warn};
+use yaml_rust2::yaml::Hash;
+use yaml_rust2::{Yaml, YamlLoader};
+
+/// The moves of a relayout in progress, relative to the archive.
+pub(crate) const PLAN_PATH: &str = ".ptsync-relayout.json";
+
+/// What follows the name of the media file a sidecar describes in its own name.
+const SIDECAR_SUFFIXES: [&str; 2] = [".md", ".xmp"];
+
+/// Passes over the archive before giving up on files still waiting for a name.
+const MAX_PASSES: usize = 3;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RelayoutPlan {
+    moves: Vec<Move>,
+    /// New path of every media file moved, by its old path.
+    links: BTreeMap<String, String>,
+    /// Notes whose embed of their own media file is renamed.
+    embeds: Vec<Embed>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Move {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Embed {
+    /// The note, at its new path.
+    note: String,
+    from: String,
+    to: String,
+}
+
+/// A media file with its note, and the files stored under its name.
+struct Group {
+    stem: String,
+    /// Each file with what follows the stem in its name: `.jpg`, `.md`, `.mov.md`, `.xmp`.
+    files: Vec<(String, String)>,
+    media: String,
+    note: Hash,
+}
+
+pub(crate) fn main(dry_run: bool, archive: &str, options: &SyncOptions) -> anyhow::Result<()> {
+    if !Path::new(archive).is_dir() {
+        return Err(anyhow!("Archive directory does not exist: {archive}"));
+    }
+    let archive_c = OsFileSystem::new(archive);
+    if archive_c.exists(PLAN_PATH) {
+        info!("Finishing an interrupted relayout");
+        let mut reader = archive_c.open(PLAN_PATH)?;
+        let plan: RelayoutPlan = serde_json::from_reader(&mut reader)?;
+        apply_plan(dry_run, &archive_c, &plan)?;
+    }
+    for _ in 0..MAX_PASSES {
+        let plan = plan_relayout(&archive_c, options)?;
+        if plan.moves.is_empty() {
+            info!("Archive is in the new layout");
+            return Ok(());
+        }
+        info!("Moving {} files", plan.moves.len());
+        if dry_run {
+            for m in &plan.moves {
+                info!("Would move {} to {}", m.from, m.to);
+            }
+            return Ok(());
+        }
+        apply_plan(dry_run, &archive_c, &plan)?;
+    }
+    warn!("Some files are still waiting for their name, run relayout again");
+    Ok(())
+}
+
+/// Save the plan, make its moves and rewrite links, then remove the plan.
+/// Moves already made are skipped, so a saved plan can be applied again.
+fn apply_plan(dry_run: bool, archive_c: &OsFileSystem, plan: &RelayoutPlan) -> anyhow::Result<()> {
+    if !dry_run {
+        let json = serde_json::to_string(plan)?;
+        archive_c
+            .try_write(dry_run, PLAN_PATH, json.as_bytes())
+            .map_err(|e| anyhow!("Could not save the relayout plan, nothing moved: {e}"))?;
+    }
+    // Files are moved through the archive's `Output`, as sync writes them
+    let output: &dyn crate::fs::Output = archive_c;
+    for m in &plan.moves {
+        if !archive_c.exists(&m.from) {
+            if !archive_c.exists(&m.to) {
+                warn!("Missing file, not moved: {}", m.from);
+            }
+            continue;
+        }
+        if archive_c.exists(&m.to) {
+            return Err(anyhow!("Can't move {} to {}, which exists", m.from, m.to));
+        }
+        output.rename(dry_run, &m.from, &m.to)?;
+        archive_c.remove_empty_dirs(dry_run, &m.from);
+    }
+    rewrite_links(dry_run, archive_c, plan)?;
+    if !dry_run {
+        archive_c.remove(dry_run, PLAN_PATH)?;
+    }
+    Ok(())
+}
+
+/// Point every link to a moved media file at its new path: `[[path]]` in notes
+/// and `similar.md`, `](../path)` in albums, and a note's embed of its own file.
+fn rewrite_links(
+    dry_run: bool,
+    archive_c: &OsFileSystem,
+    plan: &RelayoutPlan,
+) -> anyhow::Result<()> {
+    let wikilink = Regex::new(r"\[\[([^\]]+)\]\]")?;
+    let album_link = Regex::new(r"\]\(\.\./([^)]+)\)")?;
+    let embeds: HashMap<&String, &Embed> = plan.embeds.iter().map(|e| (&e.note, e)).collect();
+    for path in archive_c.walk() {
+        if !path.ends_with(".md") || is_hidden(&path) {
+            continue;
+        }
+        let mut md = String::new();
+        archive_c.open(&path)?.read_to_string(&mut md)?;
+        let moved = |caps: &Captures, wrap: fn(&str) -> String| match plan.links.get(&caps[1]) {
+            Some(to) => wrap(to),
+            None => caps[0].to_string(),
+        };
+        let mut new_md = wikilink
+            .replace_all(&md, |caps: &Captures| moved(caps, |to| format!("[[{to}]]")))
+            .to_string();
+        new_md = album_link
+            .replace_all(&new_md, |caps: &Captures| {
+                moved(caps, |to| format!("](../{to})"))
+            })
+            .to_string();
+        if let Some(embed) = embeds.get(&path) {
+            new_md = new_md.replace(&format!("]({})", embed.from), &format!("]({})", embed.to));
+        }
+        if new_md != md {
+            archive_c.write_if_changed(dry_run, &path, new_md.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn plan_relayout(archive_c: &OsFileSystem, options: &SyncOptions) -> anyhow::Result<RelayoutPlan> {
+    let mut files = archive_c.walk();
+    files.sort();
+    let existing: HashSet<String> = files.iter().cloned().collect();
+    let media: Vec<&String> = files
+        .iter()
+        .filter(|p| !is_sidecar(p) && !is_hidden(p) && !p.starts_with("albums/"))
+        .collect();
+
+    let groups = find_groups(archive_c, &media, &existing)?;
+    // Every name in use at the start of the pass stays taken
+    let mut taken = existing.clone();
+    let mut plan = RelayoutPlan::default();
+    let mut new_stems = HashMap::<String, String>::new();
+    // Edits stored beside their original wait for its new name
+    let (edits, leaders): (Vec<&Group>, Vec<&Group>) = groups
+        .iter()
+        .partition(|g| options.edits_next_to_original && original_of(g).is_some());
+    for group in leaders.into_iter().chain(edits) {
+        let base = match original_of(group).filter(|_| options.edits_next_to_original) {
+            Some(original) => match new_stems.get(&original) {
+                Some(stem) => format!("{stem}-edited"),
+                None => continue,
+            },
+            None => match layout_path(archive_c, group, options) {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("Left {} where it is: {e}", group.media);
+                    continue;
+                }
+            },
+        };
+        let checksum = note_str(&group.note, "checksum").unwrap_or_default();
+        let short_checksum: String = checksum.chars().take(7).collect();
+        let candidates = [
+            base.clone(),
+            format!("{base}-{short_checksum}"),
+            format!("{base}-{checksum}"),
+        ];
+        let free = |stem: &String| {
+            group.files.iter().all(|(from, suffix)| {
+                let to = format!("{stem}{suffix}");
+                *from == to || !(taken.contains(&to) || taken.contains(&thumbnail_path(&to)))
+            })
+        };
+        let Some(stem) = candidates.into_iter().find(free) else {
+            warn!("No free name for {}, left where it is", group.media);
+            continue;
+        };
+        new_stems.insert(group.media.clone(), stem.clone());
+        if stem == group.stem {
+            continue;
+        }
+        let file_name = |p: &str| p.rsplit('/').next().unwrap_or(p).to_string();
+        for (from, suffix) in &group.files {
+            let to = format!("{stem}{suffix}");
+            taken.insert(to.clone());
+            if let Some(media_suffix) = suffix.strip_suffix(".md") {
+                // `.md` is the note of the group's media file, `.mov.md` of its `.mov`
+                let media_from = match media_suffix {
+                    "" => group.media.clone(),
+                    _ => format!("{}{media_suffix}", group.stem),
+                };
+                let media_to = match media_suffix {
+                    "" => format!("{stem}{}", &group.media[group.stem.len()..]),
+                    _ => format!("{stem}{media_suffix}"),
+                };
+                plan.embeds.push(Embed {
+                    note: to.clone(),
+                    from: file_name(&media_from),
+                    to: file_name(&media_to),
+                });
+            } else if !is_sidecar(suffix) {
+                plan.links.insert(from.clone(), to.clone());
+                let thumbnail = thumbnail_path(from);
+                if existing.contains(&thumbnail) {
+                    let thumbnail_to = thumbnail_path(&to);
+                    taken.insert(thumbnail_to.clone());
+                    plan.moves.push(Move {
+                        from: thumbnail,
+                        to: thumbnail_to,
+                    });
+                }
+            }
+            plan.moves.push(Move {
+                from: from.clone(),
+                to,
+            });
+        }
+    }
+    Ok(plan)
+}
+
+/// Group each media file that has a note (`<stem>.md`) with the other files
+/// stored under its name.
+fn find_groups(
+    archive_c: &OsFileSystem,
+    media: &[&String],
+    existing: &HashSet<String>,
+) -> anyhow::Result<Vec<Group>> {
+    let mut by_stem = BTreeMap::<String, Vec<&String>>::new();
+    for m in media {
+        by_stem
+            .entry(path_without_extension(m))
+            .or_default()
+            .push(*m);
+    }
+    let mut groups = Vec::new();
+    for (stem, files) in by_stem {
+        let note_path = format!("{stem}.md");
+        if !existing.contains(&note_path) {
+            for f in files {
+                warn!("No note for {f}, left where it is");
+            }
+            continue;
+        }
+        let note = read_note(archive_c, &note_path)?;
+        // The note's own file is the one it doesn't link to as a related file
+        let linked = note_links(&note);
+        let Some(own) = files
+            .iter()
+            .find(|f| !linked.contains(**f) && !existing.contains(&format!("{f}.md")))
+        else {
+            warn!("Can't tell which file {note_path} is the note of, left where it is");
+            continue;
+        };
+        let mut group_files = Vec::new();
+        for (path, suffix) in std::iter::once((&stem, String::new()))
+            .chain(files.iter().map(|f| (*f, f[stem.len()..].to_string())))
+        {
+            for sidecar in SIDECAR_SUFFIXES {
+                if existing.contains(&format!("{path}{sidecar}")) {
+                    group_files.push((format!("{path}{sidecar}"), format!("{suffix}{sidecar}")));
+                }
+            }
+            if *path != stem {
+                group_files.push((path.clone(), suffix));
+            }
+        }
+        groups.push(Group {
+            stem,
+            files: group_files,
+            media: (*own).clone(),
+            note,
+        });
+    }
+    Ok(groups)
+}
+
+/// The path `sync` would give the group's media file, from its note and metadata.
+fn layout_path(
+    archive_c: &OsFileSystem,
+    group: &Group,
+    options: &SyncOptions,
+) -> anyhow::Result<String> {
+    let checksum =
+        note_str(&group.note, "checksum").ok_or_else(|| anyhow!("its note has no checksum"))?;
+    let mut reader = archive_c.open(&group.media)?;
+    let (accurate_file_type, exif_info, track_info) =
+        media_metadata_from_readable(&mut reader, &group.media)?;
+    let original_path = note_strs(&group.note, "original-paths");
+    let media = MediaFileInfo {
+        original_file_this_run: note_str(&group.note, "source-path")
+            .or_else(|| original_path.first().cloned())
+            .unwrap_or_default(),
+        original_path,
+        quick_file_type: QuickFileType::Media,
+        exif_info,
+        track_info,
+        accurate_file_type,
+        hash_info: HashInfo {
+            short_checksum: checksum.chars().take(7).collect(),
+            long_checksum: checksum,
+            payload_checksum: None,
+        },
+        supp_info: None,
+        modified: None,
+        created: None,
+        file_size: 0,
+        perceptual_hash: None,
+        merged_checksums: vec![],
+        xmp_info: None,
+        iptc_info: None,
+    };
+    let path = options
+        .layout
+        .media_path_taken_at(&media, note_str(&group.note, "datetime"));
+    let kind = note_str(&group.note, "kind").and_then(|k| MediaSubtype::from_str(&k, false).ok());
+    let burst = note_str(&group.note, "burst").map(|id| BurstInfo {
+        id,
+        cover: group
+            .note
+            .get(&Yaml::String("burst-cover".to_string()))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+    });
+    Ok(placed_media_path(
+        &path,
+        options,
+        note_str(&group.note, "generated").is_some(),
+        kind,
+        burst.as_ref(),
+    ))
+}
+
+/// The original an edit stored beside it was made from.
+fn original_of(group: &Group) -> Option<String> {
+    note_strs(&group.note, "original-version")
+        .first()
+        .map(|link| unwikilink(link))
+}
+
+fn read_note(archive_c: &OsFileSystem, path: &str) -> anyhow::Result<Hash> {
+    let mut md = String::new();
+    archive_c.open(path)?.read_to_string(&mut md)?;
+    let (yaml, _) = crate::markdown::split_frontmatter(&md);
+    let docs = YamlLoader::load_from_str(&yaml)
+        .map_err(|e| anyhow!("Could not parse frontmatter of {path}: {e}"))?;
+    match docs.into_iter().next() {
+        Some(Yaml::Hash(hash)) => Ok(hash),
+        _ => Ok(Hash::default()),
+    }
+}
+
+fn note_str(note: &Hash, key: &str) -> Option<String> {
+    note.get(&Yaml::String(key.to_string()))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+}
+
+fn note_strs(note: &Hash, key: &str) -> Vec<String> {
+    note.get(&Yaml::String(key.to_string()))
+        .and_then(|v| v.as_vec())
+        .map(|v| {
+            v.iter()
+                .filter_map(|i| i.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Every file the note links to as `[[path]]`.
+fn note_links(note: &Hash) -> HashSet<String> {
+    note.values()
+        .filter_map(|v| v.as_vec())
+        .flatten()
+        .filter_map(|i| i.as_str())
+        .filter(|s| s.starts_with("[[") && s.ends_with("]]"))
+        .map(unwikilink)
+        .collect()
+}
+
+fn unwikilink(link: &str) -> String {
+    link.trim_start_matches("[[")
+        .trim_end_matches("]]")
+        .to_string()
+}
+
+/// Notes and XMP sidecars, named after the media file they describe.
+fn is_sidecar(path: &str) -> bool {
+    SIDECAR_SUFFIXES.iter().any(|suffix| path.ends_with(suffix))
+}
+
+fn is_hidden(path: &str) -> bool {
+    path.split('/').any(|segment| segment.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{PathLayout, parse_dated_template, parse_undated_template};
+    use std::fs::read_to_string;
+
+    fn new_layout() -> anyhow::Result<SyncOptions> {
+        Ok(SyncOptions {
+            layout: PathLayout {
+                dated: parse_dated_template("{yyyy}/{yyyy}-{mm}-{dd}_{hh}{mi}{ss}_{stem}")?,
+                undated: parse_undated_template("undated/{checksum}")?,
+            },
+            ..Default::default()
+        })
+    }
+
+    fn synced(inputs: &[&str]) -> anyhow::Result<(tempfile::TempDir, String)> {
+        crate::test_util::setup_log();
+        let temp = tempfile::tempdir()?;
+        let archive = temp.path().join("archive").to_string_lossy().to_string();
+        for input in inputs {
+            crate::sync_cmd::main(
+                false,
+                &input.to_string(),
+                &Some(archive.clone()),
+                &SyncOptions::default(),
+            )?;
+        }
+        Ok((temp, archive))
+    }
+
+    #[test]
+    fn test_relayout() -> anyhow::Result<()> {
+        let (_temp, archive) = synced(&["test/takeout_basic", "test/live_photo"])?;
+        let root = Path::new(&archive);
+        let note = root.join("2024/05/22/0017-51000.md");
+        let with_notes = read_to_string(&note)? + "\nTaken on the ferry.\n";
+        std::fs::write(&note, &with_notes)?;
+
+        let options = new_layout()?;
+        main(true, &archive, &options)?;
+        assert!(note.exists(), "a dry run moves nothing");
+
+        main(false, &archive, &options)?;
+        let photo = "2024/2024-05-22_001751_Canon_40D";
+        assert!(root.join(format!("{photo}.jpg")).exists());
+        assert!(
+            !root.join("2024/05").exists(),
+            "emptied directories are removed"
+        );
+        let md = read_to_string(root.join(format!("{photo}.md")))?;
+        assert!(md.ends_with(&format!(
+            "\n![]({}.jpg)\n\nTaken on the ferry.\n",
+            &photo[5..]
+        )));
+        assert!(md.contains("checksum: 6bfdabd"));
+        let album = read_to_string(root.join("albums/Holiday.md"))?;
+        assert!(album.contains(&format!("](../{photo}.jpg)")));
+
+        // The clip of a Live Photo moves with its still, and their links follow
+        let still = "2024/2024-07-15_143022_IMG_0001";
+        assert!(root.join(format!("{still}.mov")).exists());
+        let clip_md = read_to_string(root.join(format!("{still}.mov.md")))?;
+        assert!(clip_md.contains(&format!("live-photo:\n  - \"[[{still}.jpg]]\"")));
+        assert!(clip_md.contains(&format!("![]({}.mov)", &still[5..])));
+        assert!(!root.join(PLAN_PATH).exists());
+
+        // Once moved, neither relayout nor sync in the new layout change anything
+        let before = std::fs::read_dir(root.join("2024"))?.count();
+        main(false, &archive, &options)?;
+        crate::sync_cmd::main(
+            false,
+            &"test/takeout_basic".to_string(),
+            &Some(archive.clone()),
+            &options,
+        )?;
+        assert_eq!(std::fs::read_dir(root.join("2024"))?.count(), before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relayout_moves_thumbnails() -> anyhow::Result<()> {
+        crate::test_util::setup_log();
+        let temp = tempfile::tempdir()?;
+        let archive = temp.path().join("archive").to_string_lossy().to_string();
+        let options = SyncOptions {
+            thumbnails: true,
+            ..Default::default()
+        };
+        crate::sync_cmd::main(
+            false,
+            &"test/takeout_basic".to_string(),
+            &Some(archive.clone()),
+            &options,
+        )?;
+        let root = Path::new(&archive);
+        assert!(
+            root.join(".thumbnails/2024/05/22/0017-51000.jpg.jpg")
+                .exists()
+        );
+
+        main(false, &archive, &new_layout()?)?;
+        assert!(
+            root.join(".thumbnails/2024/2024-05-22_001751_Canon_40D.jpg.jpg")
+                .exists()
+        );
+        assert!(!root.join(".thumbnails/2024/05").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_relayout_moves_xmp_sidecars() -> anyhow::Result<()> {
+        crate::test_util::setup_log();
+        let temp = tempfile::tempdir()?;
+        let archive = temp.path().join("archive").to_string_lossy().to_string();
+        let options = SyncOptions {
+            write_xmp: true,
+            ..Default::default()
+        };
+        for input in ["test/takeout_basic", "test/live_photo"] {
+            crate::sync_cmd::main(false, &input.to_string(), &Some(archive.clone()), &options)?;
+        }
+        let root = Path::new(&archive);
+        assert!(root.join("2024/05/22/0017-51000.xmp").exists());
+        assert!(root.join("2024/07/15/1430-22000.mov.xmp").exists());
+
+        main(false, &archive, &new_layout()?)?;
+        let photo = "2024/2024-05-22_001751_Canon_40D";
+        assert!(root.join(format!("{photo}.jpg")).exists());
+        assert!(root.join(format!("{photo}.xmp")).exists());
+        let still = "2024/2024-07-15_143022_IMG_0001";
+        assert!(root.join(format!("{still}.jpg")).exists());
+        assert!(root.join(format!("{still}.xmp")).exists());
+        assert!(root.join(format!("{still}.mov.xmp")).exists());
+        assert!(!root.join("2024/05").exists());
+        assert!(!root.join("2024/07").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_relayout_resumes() -> anyhow::Result<()> {
+        let (_temp, archive) = synced(&["test/takeout_basic"])?;
+        let root = Path::new(&archive);
+        let archive_c = OsFileSystem::new(&archive);
+        let options = new_layout()?;
+        let plan = plan_relayout(&archive_c, &options)?;
+        // Interrupted after saving the plan and making its first move
+        std::fs::write(root.join(PLAN_PATH), serde_json::to_string(&plan)?)?;
+        let first = plan
+            .moves
+            .first()
+            .ok_or_else(|| anyhow!("nothing to move"))?;
+        let output: &dyn crate::fs::Output = &archive_c;
+        output.rename(false, &first.from, &first.to)?;
+
+        main(false, &archive, &options)?;
+        for m in &plan.moves {
+            assert!(root.join(&m.to).exists(), "{}", m.to);
+            assert!(!root.join(&m.from).exists(), "{}", m.from);
+        }
+        assert!(!root.join(PLAN_PATH).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_relayout_names_from_the_kept_copy() -> anyhow::Result<()> {
+        let (_temp, archive) = synced(&["test/takeout_basic"])?;
+        let root = Path::new(&archive);
+        let note = root.join("2024/05/22/0017-51000.md");
+        let md = read_to_string(&note)?;
+        assert!(md.contains("source-path: Google Photos/"));
+        // A merged copy whose path sorts before the kept one's
+        std::fs::write(
+            &note,
+            md.replace(
+                "original-paths:\n",
+                "original-paths:\n  - Archive/DSC_0001.jpg\n",
+            ),
+        )?;
+
+        main(false, &archive, &new_layout()?)?;
+        assert!(root.join("2024/2024-05-22_001751_Canon_40D.jpg").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_relayout_stops_when_the_plan_cant_be_saved() -> anyhow::Result<()> {
+        let (_temp, archive) = synced(&["test/takeout_basic"])?;
+        let root = Path::new(&archive);
+        let archive_c = OsFileSystem::new(&archive);
+        let plan = plan_relayout(&archive_c, &new_layout()?)?;
+        assert!(!plan.moves.is_empty());
+        // The plan is written beside its path first, where a directory is in the way
+        std::fs::create_dir(root.join(format!(".{PLAN_PATH}.partial")))?;
+
+        assert!(apply_plan(false, &archive_c, &plan).is_err());
+        for m in &plan.moves {
+            assert!(root.join(&m.from).exists(), "{}", m.from);
+        }
+        Ok(())
+    }
+}
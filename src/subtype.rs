@@ -0,0 +1,200 @@
+//! What sort of shot a media file is, beyond photo or video: screenshots and
+//! screen recordings that don't belong in a family timeline, and the special
+//! camera modes (panoramas, portraits, slow motion, time-lapse) worth finding.
+//!
+//! Nothing records this directly, so it's pieced together from what the phones
+//! leave behind: EXIF and XMP tags, Apple and Android QuickTime keys, and the
+//! names camera apps give their files. It's a best guess, and most files are
+//! none of these.
+
+use crate::exif_util::{TAG_GCAMERA_SPECIAL_TYPE_ID, TAG_GPANO_PROJECTION_TYPE};
+use crate::file_type::{AccurateFileType, MetadataType, metadata_type};
+use crate::media::MediaFileInfo;
+use nom_exif::ExifTag;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub(crate) enum MediaSubtype {
+    Screenshot,
+    ScreenRecording,
+    Panorama,
+    Portrait,
+    SloMo,
+    TimeLapse,
+}
+
+impl MediaSubtype {
+    /// The name recorded under `kind:` in frontmatter, and used as a folder name.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            MediaSubtype::Screenshot => "screenshot",
+            MediaSubtype::ScreenRecording => "screen-recording",
+            MediaSubtype::Panorama => "panorama",
+            MediaSubtype::Portrait => "portrait",
+            MediaSubtype::SloMo => "slo-mo",
+            MediaSubtype::TimeLapse => "time-lapse",
+        }
+    }
+}
+
+/// Photos at least this many times wider than tall are taken as panoramas.
+const PANORAMA_ASPECT_RATIO: f64 = 2.5;
+
+/// Best guess at the subtype of a media file, if it's one.
+pub(crate) fn media_subtype(media: &MediaFileInfo) -> Option<MediaSubtype> {
+    let is_video = matches!(
+        metadata_type(&media.accurate_file_type),
+        MetadataType::Track
+    );
+    let from_tags = if is_video {
+        video_subtype(media)
+    } else {
+        photo_subtype(media)
+    };
+    from_tags.or_else(|| {
+        media
+            .original_path
+            .iter()
+            .find_map(|p| subtype_from_name(p, is_video))
+    })
+}
+
+fn photo_subtype(media: &MediaFileInfo) -> Option<MediaSubtype> {
+    let exif = media.exif_info.as_ref()?;
+    let tag = |name: &str| exif.tags.get(name).map(|v| v.trim());
+    if tag(&ExifTag::UserComment.to_string()).is_some_and(|c| c.eq_ignore_ascii_case("screenshot"))
+    {
+        return Some(MediaSubtype::Screenshot);
+    }
+    // Screenshots are saved by the OS, not a camera, so name no camera. Edited
+    // and scanned images often don't either, so only PNGs are trusted on this.
+    let names_camera =
+        tag(&ExifTag::Make.to_string()).is_some() || tag(&ExifTag::Model.to_string()).is_some();
+    if media.accurate_file_type == AccurateFileType::Png
+        && !names_camera
+        && tag(&ExifTag::Software.to_string()).is_some()
+    {
+        return Some(MediaSubtype::Screenshot);
+    }
+    if tag(TAG_GPANO_PROJECTION_TYPE).is_some()
+        || tag("GPano:UsePanoramaViewer").is_some_and(|v| v.eq_ignore_ascii_case("true"))
+    {
+        return Some(MediaSubtype::Panorama);
+    }
+    if tag(TAG_GCAMERA_SPECIAL_TYPE_ID).is_some_and(|v| v.to_uppercase().contains("PORTRAIT")) {
+        return Some(MediaSubtype::Portrait);
+    }
+    let dimension = |t: ExifTag| tag(&t.to_string()).and_then(|v| v.parse::<f64>().ok());
+    if let (Some(width), Some(height)) = (
+        dimension(ExifTag::ExifImageWidth),
+        dimension(ExifTag::ExifImageHeight),
+    ) && height > 0.0
+        && width / height >= PANORAMA_ASPECT_RATIO
+    {
+        return Some(MediaSubtype::Panorama);
+    }
+    None
+}
+
+fn video_subtype(media: &MediaFileInfo) -> Option<MediaSubtype> {
+    let track = media.track_info.as_ref()?;
+    if track.full_frame_rate_playback_intent == Some(0) {
+        return Some(MediaSubtype::SloMo);
+    }
+    // Android only writes a capture rate when it differs from playback
+    match track.capture_fps {
+        Some(fps) if fps > 0.0 && fps < 24.0 => Some(MediaSubtype::TimeLapse),
+        Some(fps) if fps > 60.0 => Some(MediaSubtype::SloMo),
+        _ => None,
+    }
+}
+
+/// Subtype from the names phones and desktops give files, e.g.
+/// `Screenshot_20190830-123456.png`, `RPReplay_Final1567167296.MP4` or
+/// `PXL_20200101_101010000.PORTRAIT.jpg`.
+fn subtype_from_name(path: &str, is_video: bool) -> Option<MediaSubtype> {
+    let name = Path::new(path)
+        .file_name()?
+        .to_string_lossy()
+        .to_lowercase();
+    // `Screen Shot`, `screen_recording`, `Screen-Recording` are all written
+    let name = name.replace([' ', '_', '-'], "");
+    if is_video {
+        if name.starts_with("rpreplay")
+            || name.starts_with("screenrecord")
+            || name.starts_with("screen2")
+        {
+            return Some(MediaSubtype::ScreenRecording);
+        }
+        if name.contains("slomo") || name.contains("slowmotion") {
+            return Some(MediaSubtype::SloMo);
+        }
+        if name.contains("timelapse") || name.contains("hyperlapse") {
+            return Some(MediaSubtype::TimeLapse);
+        }
+    } else {
+        if name.starts_with("screenshot") {
+            return Some(MediaSubtype::Screenshot);
+        }
+        if name.starts_with("pano") || name.contains(".pano.") || name.contains("panorama") {
+            return Some(MediaSubtype::Panorama);
+        }
+        if name.contains("portrait") {
+            return Some(MediaSubtype::Portrait);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subtype_from_name() {
+        for (name, is_video, subtype) in [
+            (
+                "Screenshot_20190830-123456.png",
+                false,
+                MediaSubtype::Screenshot,
+            ),
+            (
+                "Screen Shot 2019-08-30 at 12.34.56.png",
+                false,
+                MediaSubtype::Screenshot,
+            ),
+            (
+                "RPReplay_Final1567167296.MP4",
+                true,
+                MediaSubtype::ScreenRecording,
+            ),
+            (
+                "Screen_Recording_20190830-123456.mp4",
+                true,
+                MediaSubtype::ScreenRecording,
+            ),
+            (
+                "screen-20190830-123456.mp4",
+                true,
+                MediaSubtype::ScreenRecording,
+            ),
+            ("PANO_20190830_123456.jpg", false, MediaSubtype::Panorama),
+            (
+                "PXL_20200101_101010000.PORTRAIT.jpg",
+                false,
+                MediaSubtype::Portrait,
+            ),
+            (
+                "00100sPORTRAIT_00100_BURST20200101.jpg",
+                false,
+                MediaSubtype::Portrait,
+            ),
+            ("VID_20190830_123456_SLOMO.mp4", true, MediaSubtype::SloMo),
+            ("VID_20190830_TIMELAPSE.mp4", true, MediaSubtype::TimeLapse),
+        ] {
+            assert_eq!(subtype_from_name(name, is_video), Some(subtype), "{name}");
+        }
+        assert_eq!(subtype_from_name("IMG_1234.jpg", false), None);
+        assert_eq!(subtype_from_name("Screenshot_1.mp4", true), None);
+    }
+}
@@ -75,6 +75,9 @@ pub(crate) struct PsSupplementalInfo {
     pub(crate) people: Vec<SupplementalInfoPerson>,
     pub(crate) photo_taken_time: Option<SupplementalInfoDateTime>,
     pub(crate) creation_time: Option<SupplementalInfoDateTime>,
+    /// Caption typed in Google Photos, empty when there's none.
+    #[serde(default)]
+    pub(crate) description: Option<String>,
 }
 
 fn parse_supplemental_info<R: Read>(json_reader: R) -> Option<PsSupplementalInfo> {
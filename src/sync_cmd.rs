@@ -1,27 +1,144 @@
 use crate::album::{Album, build_album_md, parse_album, split_album_notes};
-use crate::dedup::{DeDuplicationResult, Deduplicator};
+use crate::burst::{BurstInfo, burst_media_path, find_bursts};
+use crate::classify::generated_kind;
+use crate::db_cmd::HashInfo;
+use crate::dedup::{DeDuplicationResult, Deduplicator, KeepPolicy};
+use crate::embed::embedded_copy;
 use crate::file_type::QuickFileType;
-use crate::fs::{FileSystem, OsFileSystem, ZipFileSystem};
+use crate::fs::{FileSystem, OsFileSystem, Output, ZipFileSystem};
 use crate::inspect::inspect_media_files;
+use crate::layout::PathLayout;
 use crate::markdown::sync_markdown;
 use crate::media::{MediaFileDerivedInfo, MediaFileInfo, media_file_derived_from_media_info};
+use crate::phash::{find_similar, similar_groups, similar_report};
 use crate::progress::Progress;
-use crate::util::{ScanInfo, scan_fs};
+use crate::relate::find_relations;
+use crate::s3::S3Output;
+use crate::subtype::{MediaSubtype, media_subtype};
+use crate::thumbnail::sync_thumbnail;
+use crate::util::{ScanInfo, file_checksum, is_existing_file_same, scan_fs};
+use crate::xmp::sync_xmp;
 use anyhow::anyhow;
 use std::collections::HashMap;
-use std::io::Read;
+use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::path::Path;
 use std::sync::Arc;
-use tracing::{info, warn};
+use strum_macros::Display;
+use tracing::{debug, info, warn};
+
+/// What `sync` does with the animations, collages and movies Google Photos
+/// generated from other shots.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub(crate) enum GeneratedMedia {
+    /// Archive them like any other photo, tagged `generated:` in their notes
+    #[default]
+    Tag,
+    /// Leave them out of the archive
+    Skip,
+    /// Archive them tagged, but under `generated/` rather than the timeline
+    Separate,
+}
+
+/// How `sync` puts a photo or video from a directory into the archive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum, Display)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum Transfer {
+    /// Write a copy of its bytes
+    #[default]
+    Copy,
+    /// Link it in, sharing the file with the input (same file system only)
+    Hardlink,
+    /// Clone it, sharing blocks until either copy changes (Btrfs, XFS, APFS)
+    Reflink,
+    /// Move it out of the input into the archive
+    Move,
+}
+
+/// Where the report of photos that look alike is written, in the output directory.
+const SIMILAR_REPORT_PATH: &str = "similar.md";
+
+/// What `sync` writes, and where related files go.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SyncOptions {
+    pub(crate) skip_markdown: bool,
+    pub(crate) skip_media: bool,
+    pub(crate) skip_albums: bool,
+    /// Store edits as `<original>-edited.<ext>` rather than under their own date.
+    pub(crate) edits_next_to_original: bool,
+    /// Store burst frames other than the cover under `<day>/bursts/<id>/`.
+    pub(crate) bursts_in_subfolder: bool,
+    /// Also store the clip inside a Google Motion Photo as `<name>.mp4`.
+    pub(crate) extract_motion_photos: bool,
+    /// Also write an XMP sidecar, `<name>.xmp`, beside each media file.
+    pub(crate) write_xmp: bool,
+    /// Write the capture date and GPS position only the supplemental JSON has
+    /// into the archived copy.
+    pub(crate) embed_metadata: bool,
+    /// Also write a small JPEG preview of each photo under `.thumbnails/`.
+    pub(crate) thumbnails: bool,
+    pub(crate) generated: GeneratedMedia,
+    /// Kinds of media (screenshots, ...) left out of the archive.
+    pub(crate) skip_kinds: Vec<MediaSubtype>,
+    /// Kinds of media stored under `<kind>/` rather than the timeline.
+    pub(crate) separate_kinds: Vec<MediaSubtype>,
+    /// List photos that look alike under `similar:` in their notes.
+    pub(crate) link_similar: bool,
+    /// Which copy of a duplicate is kept.
+    pub(crate) keep: KeepPolicy,
+    /// Where media files are stored in the archive.
+    pub(crate) layout: PathLayout,
+    /// How media files get from a directory input into the archive.
+    pub(crate) transfer: Transfer,
+}
 
 pub(crate) fn main(
     dry_run: bool,
     input: &String,
     output_directory: &Option<String>,
-    skip_markdown: bool,
-    skip_media: bool,
-    skip_albums: bool,
+    options: &SyncOptions,
+) -> anyhow::Result<()> {
+    let mut output_container_o: Option<Box<dyn Output>> = None;
+    if let Some(output) = output_directory
+        && output.starts_with("s3://")
+    {
+        info!("Output bucket: {output}");
+        output_container_o = Some(Box::new(S3Output::from_url(output)?));
+    } else if let Some(output) = output_directory {
+        info!("Output directory: {output}");
+        let output_container = OsFileSystem::new(output);
+        if !output_container.root_exists() {
+            warn!("Output directory does not exist {output}");
+        }
+        output_container_o = Some(Box::new(output_container));
+    }
+    sync_into(dry_run, input, output_container_o.as_deref(), options)
+}
+
+/// Sync `input` into `output_container_o`, or only inspect it when there's no output.
+pub(crate) fn sync_into(
+    dry_run: bool,
+    input: &String,
+    output_container_o: Option<&dyn Output>,
+    options: &SyncOptions,
 ) -> anyhow::Result<()> {
+    let SyncOptions {
+        skip_markdown,
+        skip_media,
+        skip_albums,
+        edits_next_to_original,
+        bursts_in_subfolder: _,
+        extract_motion_photos,
+        write_xmp,
+        embed_metadata,
+        thumbnails,
+        generated,
+        skip_kinds,
+        separate_kinds: _,
+        link_similar,
+        keep,
+        layout,
+        transfer,
+    } = options.clone();
     let path = Path::new(input);
     if !path.exists() {
         return Err(anyhow!("Input path does not exist: {}", input));
@@ -31,22 +148,16 @@ pub(crate) fn main(
         Arc::new(OsFileSystem::new(input))
     } else {
         info!("Input zip: {input}");
+        if transfer != Transfer::Copy {
+            warn!("Files in a zip can only be copied, not transferred by {transfer}");
+        }
         Arc::new(ZipFileSystem::new(input)?)
     };
 
     let files = scan_fs(container.as_ref());
     info!("Found {} files in input", files.len());
 
-    let mut output_container_o: Option<OsFileSystem> = None;
-    if let Some(output) = output_directory {
-        info!("Output directory: {output}");
-        let output_container = OsFileSystem::new(output);
-        if !output_container.root_exists() {
-            warn!("Output directory does not exist {output}");
-        }
-        output_container_o = Some(output_container);
-    }
-    let mut deduper = Deduplicator::new();
+    let mut deduper = Deduplicator::with_policy(keep);
     let mut final_path_by_checksum = HashMap::<String, String>::new();
 
     // Albums are parsed up front so each photo's sidecar can record the albums it
@@ -80,42 +191,132 @@ pub(crate) fn main(
             warn!("{skipped} files could not be processed");
         }
         drop(prog);
+        // Then copies of one shot that sources exported with different bytes
+        deduper.merge_same_shots();
 
-        if let Some(ref mut output_container) = output_container_o {
-            let media_to_write = deduper.sorted_media();
-            info!("Outputting {} photo and video files", media_to_write.len());
-            let prog = Progress::new(media_to_write.len() as u64);
-            for media in media_to_write {
+        if let Some(output_container) = output_container_o {
+            let mut media_to_write = deduper.sorted_media();
+            if generated == GeneratedMedia::Skip {
+                media_to_write.retain(|m| generated_kind(&m.original_path).is_none());
+            }
+            media_to_write.retain(|m| !media_subtype(m).is_some_and(|k| skip_kinds.contains(&k)));
+            let relations = find_relations(&media_to_write);
+            let bursts = find_bursts(&media_to_write);
+            let similar = find_similar(&media_to_write);
+            // The file a media file is stored next to, and whether it takes that
+            // file's name outright (the clip of a Live Photo beside its still) or
+            // as `<name>-edited` (an edit beside its original).
+            let placed_after = |m: &MediaFileInfo| {
+                let long_checksum = &m.hash_info.long_checksum;
+                relations
+                    .leader_of(long_checksum)
+                    .map(|leader| (leader.clone(), true))
+                    .or_else(|| {
+                        relations
+                            .original_of(long_checksum)
+                            .filter(|_| edits_next_to_original)
+                            .map(|original| (original.clone(), false))
+                    })
+            };
+            // Those are written after the rest, once the other's final path is
+            // known. Edits go last as their original may itself be placed.
+            let (leaders, mut followers): (Vec<&MediaFileInfo>, Vec<&MediaFileInfo>) =
+                media_to_write
+                    .into_iter()
+                    .partition(|m| placed_after(m).is_none());
+            followers.sort_by_key(|m| !placed_after(m).is_some_and(|(_, shares_stem)| shares_stem));
+            info!(
+                "Outputting {} photo and video files",
+                leaders.len() + followers.len()
+            );
+            let prog = Progress::new((leaders.len() + followers.len()) as u64);
+            let mut written = Vec::new();
+            let mut motion_clips = HashMap::<String, String>::new();
+            for media in leaders.into_iter().chain(followers) {
                 prog.inc();
-                let derived = media_file_derived_from_media_info(media)?;
+                let long_checksum = &media.hash_info.long_checksum;
+                let mut derived = media_file_derived_from_media_info(media, &layout)?;
+                if let Some(path) = &derived.desired_media_path {
+                    derived.desired_media_path = Some(placed_media_path(
+                        path,
+                        options,
+                        generated_kind(&media.original_path).is_some(),
+                        media_subtype(media),
+                        bursts.get(long_checksum),
+                    ));
+                }
+                if let Some((leader, shares_stem)) = placed_after(media)
+                    && let Some(leader_path) = final_path_by_checksum.get(&leader)
+                {
+                    let stem = path_without_extension(leader_path);
+                    if shares_stem {
+                        derived.desired_media_path = Some(stem);
+                        derived.shares_stem = true;
+                    } else {
+                        derived.desired_media_path = Some(format!("{stem}-edited"));
+                    }
+                }
+                let embedded = if embed_metadata {
+                    embedded_copy(media, container.as_ref())
+                        .inspect_err(|e| {
+                            warn!(
+                                "Could not write recovered metadata into {:?}, archiving it as it is: {e}",
+                                media.original_file_this_run
+                            )
+                        })
+                        .unwrap_or_default()
+                } else {
+                    None
+                };
+                if let Some(bytes) = &embedded {
+                    derived.archived_checksum = Some(file_checksum(&mut Cursor::new(bytes))?);
+                }
                 let write_r = write_media(
                     media,
                     &derived,
+                    embedded,
                     dry_run,
+                    transfer,
                     container.as_ref(),
                     output_container,
                 );
                 match write_r {
                     Ok(final_path) => {
-                        let long_checksum = &media.hash_info.long_checksum;
-                        final_path_by_checksum.insert(long_checksum.clone(), final_path.clone());
-                        if !skip_markdown {
-                            let album_names =
-                                album_names_for(&album_names_by_path, &media.original_path);
-                            let sync_md_r = sync_markdown(
+                        if extract_motion_photos
+                            && let Some(offset) =
+                                media.exif_info.as_ref().and_then(|e| e.motion_photo_offset)
+                        {
+                            match extract_motion_clip(
+                                media,
+                                offset,
+                                &final_path,
+                                dry_run,
+                                container.as_ref(),
+                                output_container,
+                            ) {
+                                Ok(clip_path) => {
+                                    motion_clips.insert(long_checksum.clone(), clip_path);
+                                }
+                                Err(e) => warn!("Error extracting motion photo clip: {e}"),
+                            }
+                        }
+                        if thumbnails
+                            && let Err(e) = sync_thumbnail(
                                 dry_run,
                                 media,
                                 &derived,
-                                &album_names,
+                                &final_path,
+                                container.as_ref(),
                                 output_container,
-                            );
-                            if let Err(e) = sync_md_r {
-                                warn!(
-                                    "Error writing markdown file: {:?}, error: {}",
-                                    derived.desired_media_path, e
-                                );
-                            }
+                            )
+                        {
+                            warn!("Error writing thumbnail of {final_path:?}: {e}");
                         }
+                        final_path_by_checksum.insert(long_checksum.clone(), final_path.clone());
+                        // The sidecar sits beside the file actually written, which
+                        // has a checksum suffix when the desired name was taken.
+                        derived.desired_media_path = Some(path_without_extension(&final_path));
+                        written.push((media, derived));
                     }
                     Err(e) => {
                         warn!(
@@ -126,15 +327,93 @@ pub(crate) fn main(
                 }
             }
             drop(prog);
+
+            // Sidecars link to related files, so they are written once every
+            // media file's final path is known.
+            if !skip_markdown || write_xmp {
+                for (media, derived) in written {
+                    let album_names = album_names_for(&album_names_by_path, &media.original_path);
+                    let long_checksum = &media.hash_info.long_checksum;
+                    let mut links = relations.links(long_checksum, &final_path_by_checksum);
+                    if let Some(clip_path) = motion_clips.get(long_checksum) {
+                        links.insert("motion-video".to_string(), vec![clip_path.clone()]);
+                    }
+                    if link_similar {
+                        let mut similar_paths: Vec<String> = similar
+                            .get(long_checksum)
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|c| final_path_by_checksum.get(c).cloned())
+                            .collect();
+                        similar_paths.sort();
+                        if !similar_paths.is_empty() {
+                            links.insert("similar".to_string(), similar_paths);
+                        }
+                    }
+                    let burst = bursts.get(long_checksum);
+                    if !skip_markdown {
+                        let sync_md_r = sync_markdown(
+                            dry_run,
+                            media,
+                            &derived,
+                            &album_names,
+                            &links,
+                            burst,
+                            output_container,
+                        );
+                        if let Err(e) = sync_md_r {
+                            warn!(
+                                "Error writing markdown file: {:?}, error: {}",
+                                derived.desired_media_path, e
+                            );
+                        }
+                    }
+                    if write_xmp {
+                        let sync_xmp_r = sync_xmp(
+                            dry_run,
+                            media,
+                            &derived,
+                            &album_names,
+                            &links,
+                            burst,
+                            output_container,
+                        );
+                        if let Err(e) = sync_xmp_r {
+                            warn!(
+                                "Error writing XMP file: {:?}, error: {}",
+                                derived.desired_media_path, e
+                            );
+                        }
+                    }
+                }
+                // Only ever a report: which copy to keep is for a person to decide
+                let groups = similar_groups(&similar);
+                if let Some(md) = similar_report(&groups, &final_path_by_checksum) {
+                    info!("Listing photos that look alike in {SIMILAR_REPORT_PATH}");
+                    if let Err(e) = output_container.write_if_changed(
+                        dry_run,
+                        SIMILAR_REPORT_PATH,
+                        md.as_bytes(),
+                    ) {
+                        warn!("Error writing {SIMILAR_REPORT_PATH}: {e}");
+                    }
+                }
+            }
         }
     }
 
-    if !skip_albums && let Some(ref output_container) = output_container_o {
+    if !skip_albums && let Some(output_container) = output_container_o {
         info!("Outputting {} albums", albums.len());
         for album in &albums {
             let output_path = &album.desired_album_md_path;
             // Preserve any notes the user wrote below the marker before rebuilding.
-            let existing_notes = read_album_notes(output_container, output_path);
+            let existing_notes = match read_album_notes(output_container, output_path) {
+                Ok(notes) => notes,
+                Err(e) => {
+                    warn!("Skipping album whose notes could not be read: {output_path:?}: {e}");
+                    continue;
+                }
+            };
             let (md, resolved_count) = build_album_md(
                 album,
                 Some(deduper.by_checksum()),
@@ -149,7 +428,9 @@ pub(crate) fn main(
             // The photo list is regenerated every run. An unchanged album
             // yields identical content; only write when it actually differs
             // so a re-run leaves the file (and its mtime) untouched.
-            output_container.write_if_changed(dry_run, output_path, md.as_bytes());
+            if let Err(e) = output_container.write_if_changed(dry_run, output_path, md.as_bytes()) {
+                warn!("Error writing album: {output_path:?}, error: {e}");
+            }
         }
     }
 
@@ -219,27 +500,58 @@ fn album_names_for(
     names
 }
 
+/// Where a media file goes, given its path from the layout: under `generated/`
+/// or `<kind>/` when those are stored apart, and in its burst's subfolder.
+pub(crate) fn placed_media_path(
+    path: &str,
+    options: &SyncOptions,
+    generated: bool,
+    kind: Option<MediaSubtype>,
+    burst: Option<&BurstInfo>,
+) -> String {
+    let mut path = path.to_string();
+    if options.generated == GeneratedMedia::Separate && generated {
+        path = format!("generated/{path}");
+    } else if let Some(kind) = kind
+        && options.separate_kinds.contains(&kind)
+    {
+        path = format!("{}/{path}", kind.name());
+    }
+    if options.bursts_in_subfolder
+        && let Some(burst) = burst.filter(|b| !b.cover)
+    {
+        path = burst_media_path(&path, &burst.id);
+    }
+    path
+}
+
+/// A media output path without its extension, e.g. `2024/07/15/1430-22417.heic`
+/// -> `2024/07/15/1430-22417`.
+pub(crate) fn path_without_extension(path: &str) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, ext)) if !ext.contains('/') => stem.to_string(),
+        _ => path.to_string(),
+    }
+}
+
 /// Read the user-authored notes section from an existing album file, if any.
-fn read_album_notes(output_container: &OsFileSystem, path: &str) -> String {
-    if !output_container.exists(path) {
-        return String::new();
+fn read_album_notes(output_container: &dyn Output, path: &str) -> anyhow::Result<String> {
+    if !output_container.exists(path)? {
+        return Ok(String::new());
     }
-    let Ok(mut reader) = output_container.open(path) else {
-        return String::new();
-    };
     let mut bytes = Vec::new();
-    if reader.read_to_end(&mut bytes).is_err() {
-        return String::new();
-    }
-    split_album_notes(&String::from_utf8_lossy(&bytes))
+    output_container.open(path)?.read_to_end(&mut bytes)?;
+    Ok(split_album_notes(&String::from_utf8_lossy(&bytes)))
 }
 
 pub(crate) fn write_media(
     media_file: &MediaFileInfo,
     derived: &MediaFileDerivedInfo,
+    embedded: Option<Vec<u8>>,
     dry_run: bool,
+    transfer: Transfer,
     input_container: &dyn FileSystem,
-    output_container: &OsFileSystem,
+    output_container: &dyn Output,
 ) -> anyhow::Result<String> {
     let desired_output_path_with_ext =
         match Deduplicator::resolve_output_path(media_file, derived, output_container)? {
@@ -247,8 +559,62 @@ pub(crate) fn write_media(
             DeDuplicationResult::WritePath(path) => path,
         };
     info!("Output {:?}", desired_output_path_with_ext);
-    let reader = input_container.open(&media_file.original_file_this_run)?;
-    output_container.write(dry_run, &desired_output_path_with_ext.clone(), reader);
+    let source = input_container
+        .local_path(&media_file.original_file_this_run)
+        .filter(|_| transfer != Transfer::Copy);
+    // Linking and moving need the archive on the local disk too; elsewhere a
+    // move copies and then removes the input file. A copy with recovered
+    // metadata written in is always written out.
+    let transferred = match (&source, output_container.local()) {
+        (Some(source), Some(local)) if embedded.is_none() => {
+            let transfer_r = match transfer {
+                Transfer::Copy => Ok(true),
+                Transfer::Hardlink => local
+                    .hard_link(dry_run, source, &desired_output_path_with_ext)
+                    .map(|()| true),
+                Transfer::Reflink => local.reflink(dry_run, source, &desired_output_path_with_ext),
+                Transfer::Move => local
+                    .move_in(dry_run, source, &desired_output_path_with_ext)
+                    .map(|()| true),
+            };
+            transfer_r
+                .inspect_err(|e| warn!("Could not {transfer}, copying instead: {e}"))
+                .unwrap_or(false)
+        }
+        _ => false,
+    };
+    if !transferred {
+        match embedded {
+            Some(bytes) => output_container.write(
+                dry_run,
+                &desired_output_path_with_ext.clone(),
+                &mut Cursor::new(bytes),
+            )?,
+            None => {
+                let mut reader = input_container.open(&media_file.original_file_this_run)?;
+                output_container.write(
+                    dry_run,
+                    &desired_output_path_with_ext.clone(),
+                    &mut reader,
+                )?;
+            }
+        }
+        if transfer == Transfer::Move
+            && let Some(source) = &source
+        {
+            let archived_checksum = derived
+                .archived_checksum
+                .as_ref()
+                .unwrap_or(&media_file.hash_info.long_checksum);
+            remove_if_copied(
+                dry_run,
+                archived_checksum,
+                source,
+                &desired_output_path_with_ext,
+                output_container,
+            )?;
+        }
+    }
     output_container.set_modified(
         dry_run,
         &desired_output_path_with_ext.clone(),
@@ -257,9 +623,81 @@ pub(crate) fn write_media(
     Ok(desired_output_path_with_ext)
 }
 
+/// Finish a move that had to copy, by removing the input file once the copy
+/// is known to hold the same bytes.
+fn remove_if_copied(
+    dry_run: bool,
+    long_checksum: &str,
+    source: &Path,
+    path: &str,
+    output_container: &dyn Output,
+) -> anyhow::Result<()> {
+    if dry_run {
+        debug!("  Dry run: would remove {source:?} once copied");
+        return Ok(());
+    }
+    if is_existing_file_same(output_container, long_checksum, &path.to_string()) != Some(true) {
+        return Err(anyhow!(
+            "Copy of {source:?} at {path} doesn't match its checksum, so it wasn't removed"
+        ));
+    }
+    std::fs::remove_file(source)?;
+    debug!("  Removed {source:?}, copied to {path}");
+    Ok(())
+}
+
+/// Copy the MP4 a Motion Photo carries after its JPEG to `<name>.mp4` beside the
+/// archived still, which itself is stored untouched. Returns the clip's path.
+/// Named as any media file is, so a different file already at that name is kept
+/// and the clip given a checksum suffix.
+fn extract_motion_clip(
+    media_file: &MediaFileInfo,
+    offset: u64,
+    still_path: &str,
+    dry_run: bool,
+    input_container: &dyn FileSystem,
+    output_container: &dyn Output,
+) -> anyhow::Result<String> {
+    // Cut from the archived still, as `--transfer move` takes the input away
+    let mut reader = if output_container.exists(still_path)? {
+        output_container.open(still_path)?
+    } else {
+        input_container.open(&media_file.original_file_this_run)?
+    };
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut clip = Vec::new();
+    reader.read_to_end(&mut clip)?;
+    let checksum = file_checksum(&mut Cursor::new(&clip))?;
+    let clip_file = MediaFileInfo {
+        hash_info: HashInfo {
+            short_checksum: checksum.chars().take(7).collect(),
+            long_checksum: checksum,
+            payload_checksum: None,
+        },
+        ..media_file.clone()
+    };
+    let derived = MediaFileDerivedInfo {
+        desired_media_path: Some(path_without_extension(still_path)),
+        desired_media_extension: "mp4".to_string(),
+        shares_stem: true,
+        archived_checksum: None,
+    };
+    let clip_path = match Deduplicator::resolve_output_path(&clip_file, &derived, output_container)?
+    {
+        DeDuplicationResult::SkipWrite(path) => return Ok(path),
+        DeDuplicationResult::WritePath(path) => path,
+    };
+    info!("Output {clip_path:?}");
+    output_container.write(dry_run, &clip_path, &mut Cursor::new(clip))?;
+    output_container.set_modified(dry_run, &clip_path, &media_file.modified);
+    Ok(clip_path)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fs::MemoryOutput;
+    use crate::layout::{parse_dated_template, parse_undated_template};
     use crate::test_util::build_zip;
     use std::collections::BTreeMap;
     use std::fs;
@@ -271,14 +709,50 @@ mod tests {
     const TAKEOUT_BASIC: &str = "test/takeout_basic";
 
     fn run_sync(input: &str) -> anyhow::Result<(tempfile::TempDir, PathBuf)> {
+        run_sync_with(input, &SyncOptions::default())
+    }
+
+    fn run_sync_with(
+        input: &str,
+        options: &SyncOptions,
+    ) -> anyhow::Result<(tempfile::TempDir, PathBuf)> {
         crate::test_util::setup_log();
         let temp = tempfile::tempdir()?;
         let archive = temp.path().join("archive");
         let output = Some(archive.to_string_lossy().to_string());
-        main(false, &input.to_string(), &output, false, false, false)?;
+        main(false, &input.to_string(), &output, options)?;
         Ok((temp, archive))
     }
 
+    /// Copy the fixture directory `input` into a fresh temporary directory, for
+    /// tests that change their input.
+    fn copy_fixture(input: &str) -> anyhow::Result<(tempfile::TempDir, PathBuf)> {
+        let temp = tempfile::tempdir()?;
+        let root = Path::new(input);
+        for path in files_under(root)? {
+            let to = temp.path().join(path.strip_prefix(root)?);
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&path, &to)?;
+        }
+        let dir = temp.path().to_path_buf();
+        Ok((temp, dir))
+    }
+
+    /// Sync a copy of `TAKEOUT_BASIC` with `transfer`, returning the input and archive.
+    fn run_transfer(
+        transfer: Transfer,
+    ) -> anyhow::Result<(Vec<tempfile::TempDir>, PathBuf, PathBuf)> {
+        let (input_temp, input) = copy_fixture(TAKEOUT_BASIC)?;
+        let options = SyncOptions {
+            transfer,
+            ..Default::default()
+        };
+        let (archive_temp, archive) = run_sync_with(&input.to_string_lossy(), &options)?;
+        Ok((vec![input_temp, archive_temp], input, archive))
+    }
+
     fn output_tree(archive: &Path) -> anyhow::Result<BTreeMap<String, String>> {
         let mut tree = BTreeMap::new();
         for path in files_under(archive)? {
@@ -331,6 +805,243 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn sync_uses_path_templates() -> anyhow::Result<()> {
+        let options = SyncOptions {
+            layout: PathLayout {
+                dated: parse_dated_template(
+                    "{yyyy}/{yyyy}-{mm}/{yyyy}-{mm}-{dd}_{hh}{mi}{ss}_{stem}",
+                )?,
+                undated: parse_undated_template("undated/{kind}/{checksum}")?,
+            },
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with(TAKEOUT_BASIC, &options)?;
+        assert!(
+            archive
+                .join("2024/2024-05/2024-05-22_001751_Canon_40D.jpg")
+                .exists()
+        );
+        assert!(
+            archive
+                .join("2024/2024-05/2024-05-22_001751_Canon_40D.md")
+                .exists()
+        );
+        assert!(
+            archive
+                .join("2023/2023-11/2023-11-02_093000_Hello.mp4")
+                .exists()
+        );
+        let album = read_to_string(archive.join("albums/Holiday.md"))?;
+        assert!(album.contains("](../2024/2024-05/2024-05-22_001751_Canon_40D.jpg)"));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_transfers_by_copy_link_clone_or_move() -> anyhow::Result<()> {
+        let kept = "Google Photos/Holiday/Canon_40D.jpg";
+        let duplicate = "Google Photos/Photos from 2024/Canon_40D.jpg";
+        let photo = "2024/05/22/0017-51000.jpg";
+        let expected = fs::read(Path::new(TAKEOUT_BASIC).join(kept))?;
+
+        for transfer in [Transfer::Copy, Transfer::Hardlink, Transfer::Reflink] {
+            let (_temps, input, archive) = run_transfer(transfer)?;
+            assert_eq!(fs::read(archive.join(photo))?, expected, "{transfer}");
+            assert!(input.join(kept).exists(), "{transfer}");
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                let linked = fs::metadata(archive.join(photo))?.ino()
+                    == fs::metadata(input.join(kept))?.ino();
+                assert_eq!(linked, transfer == Transfer::Hardlink, "{transfer}");
+            }
+        }
+
+        // Only the kept copy leaves the input; the duplicate is left behind
+        let (_temps, input, archive) = run_transfer(Transfer::Move)?;
+        assert_eq!(fs::read(archive.join(photo))?, expected);
+        assert!(!input.join(kept).exists());
+        assert!(input.join(duplicate).exists());
+        assert!(
+            !input
+                .join("Google Photos/Photos from 2024/Hello.mp4")
+                .exists()
+        );
+        assert!(archive.join("2023/11/02/0930-00000.mp4").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn sync_writes_the_same_archive_to_any_output() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync(TAKEOUT_BASIC)?;
+        let output = MemoryOutput::default();
+        sync_into(
+            false,
+            &TAKEOUT_BASIC.to_string(),
+            Some(&output),
+            &SyncOptions::default(),
+        )?;
+        let mut in_memory = BTreeMap::new();
+        for path in output.paths() {
+            let bytes = output
+                .bytes(&path)
+                .ok_or_else(|| anyhow!("{path} vanished"))?;
+            in_memory.insert(path, format!("{:x}", md5::compute(bytes)));
+        }
+        assert_eq!(in_memory, output_tree(&archive)?);
+        let photo = "2024/05/22/0017-51000.jpg";
+        let on_disk = fs::metadata(archive.join(photo))?.modified()?;
+        let on_disk = on_disk.duration_since(std::time::UNIX_EPOCH)?.as_millis() as i64;
+        assert_eq!(output.modified(photo), Some(on_disk));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_writes_xmp_sidecars() -> anyhow::Result<()> {
+        let options = SyncOptions {
+            write_xmp: true,
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with(TAKEOUT_BASIC, &options)?;
+        let xmp = read_to_string(archive.join("2024/05/22/0017-51000.xmp"))?;
+        assert!(xmp.contains("<rdf:li>Albums|Holiday</rdf:li>"));
+        assert!(xmp.contains("exif:DateTimeOriginal=\"2024-05-22T"));
+        assert!(archive.join("2023/11/02/0930-00000.xmp").exists());
+
+        let before = mtimes_under(&archive)?;
+        let output = Some(archive.to_string_lossy().to_string());
+        main(false, &TAKEOUT_BASIC.to_string(), &output, &options)?;
+        assert_eq!(mtimes_under(&archive)?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_embeds_recovered_metadata_in_archived_copy() -> anyhow::Result<()> {
+        let input = "test/embed";
+        let source = fs::read("test/embed/Google Photos/Photos from 2024/IMG_0337.jpg")?;
+        let source_checksum = file_checksum(&mut Cursor::new(&source))?;
+        let (_temp, archive) = run_sync(input)?;
+        let photo = archive.join("2024/05/22/0017-51000.jpg");
+        assert_eq!(fs::read(&photo)?, source);
+
+        // A copy archived before is upgraded in place
+        let options = SyncOptions {
+            embed_metadata: true,
+            ..Default::default()
+        };
+        let output = Some(archive.to_string_lossy().to_string());
+        main(false, &input.to_string(), &output, &options)?;
+        let embedded = fs::read(&photo)?;
+        let exif = crate::exif_util::parse_exif_info(Cursor::new(&embedded))?
+            .ok_or_else(|| anyhow!("No EXIF in the archived copy"))?;
+        assert!(exif.tags.contains_key("DateTimeOriginal"));
+        assert!(exif.latitude.is_some_and(|l| l < -21.0));
+        let archived_checksum = file_checksum(&mut Cursor::new(&embedded))?;
+        let md = read_to_string(archive.join("2024/05/22/0017-51000.md"))?;
+        assert!(md.contains(&format!("checksum: {source_checksum}")));
+        assert!(md.contains(&format!("archived-checksum: {archived_checksum}")));
+        assert_eq!(files_under(&archive)?.len(), 2);
+
+        let before = mtimes_under(&archive)?;
+        main(false, &input.to_string(), &output, &options)?;
+        assert_eq!(mtimes_under(&archive)?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_embeds_without_writing_through_a_hard_link() -> anyhow::Result<()> {
+        let (_input_temp, input) = copy_fixture("test/embed")?;
+        let source = input.join("Google Photos/Photos from 2024/IMG_0337.jpg");
+        let original = fs::read(&source)?;
+        let input = input.to_string_lossy().to_string();
+        let options = SyncOptions {
+            transfer: Transfer::Hardlink,
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with(&input, &options)?;
+
+        let options = SyncOptions {
+            embed_metadata: true,
+            ..options
+        };
+        let output = Some(archive.to_string_lossy().to_string());
+        main(false, &input, &output, &options)?;
+        assert_ne!(
+            fs::read(archive.join("2024/05/22/0017-51000.jpg"))?,
+            original
+        );
+        assert_eq!(fs::read(&source)?, original);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_writes_thumbnails() -> anyhow::Result<()> {
+        let options = SyncOptions {
+            thumbnails: true,
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with(TAKEOUT_BASIC, &options)?;
+        let photo = fs::read(archive.join("2024/05/22/0017-51000.jpg"))?;
+        let thumbnail = fs::read(archive.join(".thumbnails/2024/05/22/0017-51000.jpg.jpg"))?;
+        // The EXIF thumbnail the camera wrote, after a comment with the photo's checksum
+        let comment_len = u16::from_be_bytes([thumbnail[4], thumbnail[5]]) as usize;
+        let comment = String::from_utf8_lossy(&thumbnail[6..4 + comment_len]);
+        let checksum = file_checksum(&mut Cursor::new(&photo))?;
+        assert_eq!(comment, format!("ptsync checksum {checksum}"));
+        let preview = &thumbnail[4 + comment_len..];
+        assert_eq!(preview.len() + 2, 1378);
+        assert!(photo.windows(preview.len()).any(|w| w == preview));
+        // Nothing for the video
+        assert_eq!(files_under(&archive.join(".thumbnails"))?.len(), 1);
+
+        let before = mtimes_under(&archive)?;
+        let output = Some(archive.to_string_lossy().to_string());
+        main(false, &TAKEOUT_BASIC.to_string(), &output, &options)?;
+        assert_eq!(mtimes_under(&archive)?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_records_xmp_rating_keywords_and_faces() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/xmp")?;
+        let md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        for expected in [
+            "rating: 4",
+            "title: Sunset over the bay",
+            "keywords:\n  - beach\n  - sunset",
+            "people:\n  - \"[[Alice]]\"\n  - \"[[Bob]]\"",
+        ] {
+            assert!(md.contains(expected), "{expected} missing from {md}");
+        }
+        // The sidecar isn't archived as media
+        assert!(!archive.join("2008/05/30/1556-01000.xmp").exists());
+        // XMP kept in a WebP's own chunk
+        let webp_md = read_to_string(archive.join("2024/08/01/1200-00000.md"))?;
+        assert!(webp_md.contains("title: Harbour"), "{webp_md}");
+        assert!(webp_md.contains("rating: 3"), "{webp_md}");
+        Ok(())
+    }
+
+    #[test]
+    fn sync_records_iptc_caption_keywords_creator_and_place() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/iptc")?;
+        let md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        for expected in [
+            "description: Lake at dusk",
+            "keywords:\n  - lake\n  - Zürich",
+            "creator:\n  - Jane Doe",
+            "location: Seefeld",
+            "city: Zürich",
+            "state: ZH",
+            "country: Switzerland",
+        ] {
+            assert!(md.contains(expected), "{expected} missing from {md}");
+        }
+        let scan = read_to_string(archive.join("1987/06/01/0900-00000.md"))?;
+        assert!(scan.contains("description: Grand-mère à Genève"), "{scan}");
+        Ok(())
+    }
+
     #[test]
     fn sync_deduplicates_identical_photo() -> anyhow::Result<()> {
         let (_temp, archive) = run_sync(TAKEOUT_BASIC)?;
@@ -369,7 +1080,7 @@ mod tests {
 
         // First run populates the archive: media files, markdown sidecars and
         // album files.
-        main(false, &input, &output, false, false, false)?;
+        main(false, &input, &output, &SyncOptions::default())?;
         let first = mtimes_under(&archive)?;
         assert!(
             first.contains_key("albums/Holiday.md")
@@ -381,7 +1092,7 @@ mod tests {
         // Re-running over identical input must be a no-op in writes: every
         // file keeps its modified time because nothing was rewritten - not even
         // the album and markdown files that are regenerated in memory each run.
-        main(false, &input, &output, false, false, false)?;
+        main(false, &input, &output, &SyncOptions::default())?;
         let second = mtimes_under(&archive)?;
         assert_eq!(
             first, second,
@@ -390,6 +1101,419 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn sync_stores_live_photo_clip_next_to_still() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/live_photo")?;
+        // The clip's own date differs from the still's, but it takes the still's name.
+        assert!(archive.join("2024/07/15/1430-22000.jpg").exists());
+        assert!(archive.join("2024/07/15/1430-22000.mov").exists());
+        assert!(!archive.join("2024/04").exists());
+
+        let still_md = read_to_string(archive.join("2024/07/15/1430-22000.md"))?;
+        assert!(still_md.contains("live-photo:\n  - \"[[2024/07/15/1430-22000.mov]]\""));
+        let clip_md = read_to_string(archive.join("2024/07/15/1430-22000.mov.md"))?;
+        assert!(clip_md.contains("live-photo:\n  - \"[[2024/07/15/1430-22000.jpg]]\""));
+        assert!(clip_md.contains("![](1430-22000.mov)"));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_stores_raw_next_to_developed_jpeg() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/raw")?;
+        // The RAW is dated from its own EXIF, but takes the JPEG's name.
+        assert!(archive.join("2008/05/30/1556-01000.jpg").exists());
+        assert!(archive.join("2008/05/30/1556-01000.dng").exists());
+        // A RAW shot without a JPEG is dated from its own EXIF.
+        assert!(archive.join("2019/03/02/1011-12000.orf").exists());
+
+        let jpg_md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        assert!(jpg_md.contains("raw:\n  - \"[[2008/05/30/1556-01000.dng]]\""));
+        let raw_md = read_to_string(archive.join("2008/05/30/1556-01000.dng.md"))?;
+        assert!(raw_md.contains("developed:\n  - \"[[2008/05/30/1556-01000.jpg]]\""));
+        // Its EXIF has no offset, so the wall clock time is kept without one
+        assert!(raw_md.contains("datetime: \"2024-07-15T14:30:25\""));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_archives_newer_image_formats() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/formats")?;
+        for path in [
+            "2024/08/01/1200-00000.webp",
+            "2024/08/02/1200-00000.jxl",
+            "2024/08/03/1200-00000.avif",
+            "1987/06/01/0900-00000.tif",
+        ] {
+            assert!(archive.join(path).exists(), "{path} missing");
+        }
+        // BMP carries no metadata, so is dated from the file itself.
+        let bmps: Vec<PathBuf> = files_under(&archive)?
+            .into_iter()
+            .filter(|p| p.extension().is_some_and(|e| e == "bmp"))
+            .collect();
+        assert_eq!(bmps.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_dates_legacy_and_matroska_video() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/video")?;
+        for path in [
+            "2005/12/24/1234-56000.avi",
+            "2009/08/15/1820-05000.mts",
+            "2021/05/01/0830-00000.mkv",
+            "2022/06/02/0945-10000.webm",
+        ] {
+            assert!(archive.join(path).exists(), "{path} missing");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sync_links_edits_to_originals() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/edits")?;
+        // The edit keeps its own date, so clashes with the original's name.
+        let original_md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        assert!(
+            original_md.contains("edited-version:\n  - \"[[2008/05/30/1556-01000-cfe0e9e.jpg]]\"")
+        );
+        let edit_md = read_to_string(archive.join("2008/05/30/1556-01000-cfe0e9e.md"))?;
+        assert!(edit_md.contains("original-version:\n  - \"[[2008/05/30/1556-01000.jpg]]\""));
+        // Google's `-edited` naming is recognised too.
+        let google_mds: Vec<String> = files_under(&archive.join("2024/08/01"))?
+            .iter()
+            .filter(|p| p.extension().is_some_and(|e| e == "md"))
+            .map(read_to_string)
+            .collect::<Result<_, _>>()?;
+        assert!(google_mds.iter().any(|md| md.contains("edited-version:")));
+        assert!(google_mds.iter().any(|md| md.contains("original-version:")));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_stores_edits_next_to_originals() -> anyhow::Result<()> {
+        let options = SyncOptions {
+            edits_next_to_original: true,
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with("test/edits", &options)?;
+        assert!(archive.join("2008/05/30/1556-01000.jpg").exists());
+        assert!(archive.join("2008/05/30/1556-01000-edited.jpg").exists());
+        assert!(archive.join("2024/08/01/1200-00000-edited.webp").exists());
+        let edit_md = read_to_string(archive.join("2008/05/30/1556-01000-edited.md"))?;
+        assert!(edit_md.contains("original-version:\n  - \"[[2008/05/30/1556-01000.jpg]]\""));
+        assert!(edit_md.contains("![](1556-01000-edited.jpg)"));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_groups_burst_frames() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/burst")?;
+        let notes = files_under(&archive.join("2008/05/30"))?;
+        let mut covers = 0;
+        for note in notes
+            .iter()
+            .filter(|p| p.extension().is_some_and(|e| e == "md"))
+        {
+            let md = read_to_string(note)?;
+            assert!(md.contains("burst: IMG_20190830_123456\n"), "{md}");
+            if md.contains("burst-cover: true") {
+                assert!(md.contains("IMG_20190830_123456_BURST001_COVER.jpg"));
+                covers += 1;
+            }
+        }
+        assert_eq!(covers, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_stores_burst_frames_in_subfolder() -> anyhow::Result<()> {
+        let options = SyncOptions {
+            bursts_in_subfolder: true,
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with("test/burst", &options)?;
+        let tree = output_tree(&archive)?;
+        let day: Vec<&String> = tree
+            .keys()
+            .filter(|k| k.starts_with("2008/05/30/") && k.ends_with(".jpg"))
+            .collect();
+        assert_eq!(day.len(), 3, "{tree:?}");
+        let in_subfolder = day
+            .iter()
+            .filter(|k| k.starts_with("2008/05/30/bursts/IMG_20190830_123456/"))
+            .count();
+        assert_eq!(in_subfolder, 2, "{tree:?}");
+        let cover_md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        assert!(cover_md.contains("burst-cover: true"));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_records_motion_photos() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/motion")?;
+        let md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        assert!(
+            md.contains("motion-photo: true\nmotion-photo-offset: 8320\n"),
+            "{md}"
+        );
+        assert!(!md.contains("motion-video:"));
+        assert!(!archive.join("2008/05/30/1556-01000.mp4").exists());
+        // The still is archived byte for byte, clip included
+        let still = fs::read(archive.join("2021/07/04/1020-30000.jpg"))?;
+        assert_eq!(
+            still,
+            fs::read("test/motion/PXL_20210704_102030000.MP.jpg")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sync_merges_same_shot_from_icloud_and_google() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/cross_source")?;
+        let tree = output_tree(&archive)?;
+        assert_eq!(
+            tree.keys().collect::<Vec<_>>(),
+            vec!["2008/05/30/1556-01000.jpg", "2008/05/30/1556-01000.md"]
+        );
+        // The iCloud copy is kept, as Google rewrote the other
+        assert_eq!(
+            fs::read(archive.join("2008/05/30/1556-01000.jpg"))?,
+            fs::read("test/cross_source/iCloud Photos/Photos/IMG_0009.JPG")?
+        );
+        let md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        let google_checksum = file_checksum(&mut fs::File::open(
+            "test/cross_source/Takeout/Google Photos/Photos from 2008/IMG_0009.JPG",
+        )?)?;
+        assert!(
+            md.contains(&format!("merged-checksums:\n  - {google_checksum}\n")),
+            "{md}"
+        );
+        // Only the bytes after the picture differ
+        let payload_checksum =
+            crate::payload::payload_checksum(&mut fs::File::open("test/Canon_40D.jpg")?)
+                .ok_or_else(|| anyhow!("no payload checksum"))?;
+        assert!(md.contains(&format!("payload-checksum: {payload_checksum}\n")));
+        assert!(md.contains("  - Takeout/Google Photos/Photos from 2008/IMG_0009.JPG\n"));
+        assert!(md.contains("  - iCloud Photos/Photos/IMG_0009.JPG\n"));
+        // Who Google recognised in it is kept too
+        assert!(md.contains("[[Tim Tam]]"));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_reports_similar_photos() -> anyhow::Result<()> {
+        // The same photo as a messaging app would pass it on: smaller and
+        // re-compressed, without its EXIF
+        let input = tempfile::tempdir()?;
+        fs::copy("test/Canon_40D.jpg", input.path().join("IMG_0001.jpg"))?;
+        image::open("test/Canon_40D.jpg")?
+            .thumbnail(60, 40)
+            .save(input.path().join("IMG-20190830-WA0001.jpg"))?;
+        let input = input.path().to_string_lossy().to_string();
+
+        let (_temp, archive) = run_sync(&input)?;
+        let report = read_to_string(archive.join(SIMILAR_REPORT_PATH))?;
+        let original = "2008/05/30/1556-01000.jpg";
+        let copy = output_tree(&archive)?
+            .into_keys()
+            .find(|p| p.ends_with(".jpg") && p != original)
+            .ok_or_else(|| anyhow!("copy not archived"))?;
+        assert!(
+            report.contains(&format!("- [[{original}]]\n- [[{copy}]]\n")),
+            "{report}"
+        );
+        let md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        assert!(!md.contains("similar:"));
+
+        let options = SyncOptions {
+            link_similar: true,
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with(&input, &options)?;
+        let md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        assert!(
+            md.contains(&format!("similar:\n  - \"[[{copy}]]\"")),
+            "{md}"
+        );
+        // Both are kept
+        assert!(archive.join(original).exists());
+        assert!(archive.join(&copy).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn sync_extracts_motion_photo_clips() -> anyhow::Result<()> {
+        let options = SyncOptions {
+            extract_motion_photos: true,
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with("test/motion", &options)?;
+        let hello = fs::read("test/Hello.mp4")?;
+        assert_eq!(fs::read(archive.join("2008/05/30/1556-01000.mp4"))?, hello);
+        assert_eq!(fs::read(archive.join("2021/07/04/1020-30000.mp4"))?, hello);
+        let md = read_to_string(archive.join("2021/07/04/1020-30000.md"))?;
+        assert!(
+            md.contains("motion-video:\n  - \"[[2021/07/04/1020-30000.mp4]]\""),
+            "{md}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sync_keeps_a_different_file_at_the_clip_name() -> anyhow::Result<()> {
+        crate::test_util::setup_log();
+        let temp = tempfile::tempdir()?;
+        let archive = temp.path().join("archive");
+        let taken = archive.join("2021/07/04/1020-30000.mp4");
+        fs::create_dir_all(archive.join("2021/07/04"))?;
+        fs::write(&taken, b"another video")?;
+        let options = SyncOptions {
+            extract_motion_photos: true,
+            ..Default::default()
+        };
+        let output = Some(archive.to_string_lossy().to_string());
+        main(false, &"test/motion".to_string(), &output, &options)?;
+
+        assert_eq!(fs::read(&taken)?, b"another video");
+        let hello = fs::read("test/Hello.mp4")?;
+        let short: String = file_checksum(&mut Cursor::new(&hello))?
+            .chars()
+            .take(7)
+            .collect();
+        let clip = format!("2021/07/04/1020-30000-{short}.mp4");
+        assert_eq!(fs::read(archive.join(&clip))?, hello);
+        let md = read_to_string(archive.join("2021/07/04/1020-30000.md"))?;
+        assert!(
+            md.contains(&format!("motion-video:\n  - \"[[{clip}]]\"")),
+            "{md}"
+        );
+
+        // Found again by its checksum on the next run
+        main(false, &"test/motion".to_string(), &output, &options)?;
+        assert_eq!(
+            output_tree(&archive)?
+                .keys()
+                .filter(|k| k.ends_with(".mp4"))
+                .count(),
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sync_tags_generated_media() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/generated")?;
+        let gif_md = read_to_string(archive.join("2019/08/30/1234-56000.md"))?;
+        assert!(gif_md.contains("generated: animation\n"), "{gif_md}");
+        assert!(gif_md.contains("generated-from:\n  - \"[[2008/05/30/1556-01000.jpg]]\""));
+        let source_md = read_to_string(archive.join("2008/05/30/1556-01000.md"))?;
+        assert!(!source_md.contains("generated:"));
+        let (_, used_in) = source_md
+            .split_once("used-in:\n")
+            .ok_or_else(|| anyhow!("no used-in links"))?;
+        assert!(used_in.starts_with("  - \"[[2"), "{source_md}");
+        assert!(used_in.contains("  - \"[[2019/08/30/1234-56000.gif]]\""));
+        Ok(())
+    }
+
+    #[test]
+    fn sync_skips_or_separates_generated_media() -> anyhow::Result<()> {
+        let options = SyncOptions {
+            generated: GeneratedMedia::Skip,
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with("test/generated", &options)?;
+        let media: Vec<String> = output_tree(&archive)?
+            .into_keys()
+            .filter(|k| !k.ends_with(".md"))
+            .collect();
+        assert_eq!(media, vec!["2008/05/30/1556-01000.jpg"]);
+
+        let options = SyncOptions {
+            generated: GeneratedMedia::Separate,
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with("test/generated", &options)?;
+        assert!(archive.join("2008/05/30/1556-01000.jpg").exists());
+        assert!(archive.join("generated/2008/05/30/1556-01000.jpg").exists());
+        assert!(archive.join("generated/2019/08/30/1234-56000.gif").exists());
+        let collage_md = read_to_string(archive.join("generated/2008/05/30/1556-01000.md"))?;
+        assert!(collage_md.contains("generated: collage\n"), "{collage_md}");
+        Ok(())
+    }
+
+    /// Source file name -> `kind:` from every sidecar under `archive`.
+    fn kinds_by_source(archive: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut kinds = BTreeMap::new();
+        for path in files_under(archive)? {
+            if path.extension().is_none_or(|e| e != "md") || path.ends_with(SIMILAR_REPORT_PATH) {
+                continue;
+            }
+            let md = read_to_string(&path)?;
+            let source = md
+                .split_once("original-paths:\n  - ")
+                .and_then(|(_, rest)| rest.lines().next())
+                .ok_or_else(|| anyhow!("no source in {md}"))?;
+            let kind = md
+                .lines()
+                .find_map(|l| l.strip_prefix("kind: "))
+                .unwrap_or_default();
+            kinds.insert(source.to_string(), kind.to_string());
+        }
+        Ok(kinds)
+    }
+
+    #[test]
+    fn sync_records_media_kinds() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/kinds")?;
+        let kinds = kinds_by_source(&archive)?;
+        let expected: BTreeMap<String, String> = [
+            ("IMG_0006.PNG", "screenshot"),
+            ("IMG_0007.jpg", "panorama"),
+            ("IMG_0008.MOV", "slo-mo"),
+            ("PXL_20200101_101010000.PORTRAIT.jpg", "portrait"),
+            ("RPReplay_Final1567167296.MP4", "screen-recording"),
+            ("Screenshot_20190830-123456.png", "screenshot"),
+            ("VID_20190830_123456.mp4", "time-lapse"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(kinds, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_skips_or_separates_media_kinds() -> anyhow::Result<()> {
+        let options = SyncOptions {
+            skip_kinds: vec![MediaSubtype::Screenshot, MediaSubtype::ScreenRecording],
+            separate_kinds: vec![MediaSubtype::Panorama],
+            ..Default::default()
+        };
+        let (_temp, archive) = run_sync_with("test/kinds", &options)?;
+        let kinds = kinds_by_source(&archive)?;
+        assert_eq!(
+            kinds.values().collect::<Vec<_>>(),
+            vec!["panorama", "slo-mo", "portrait", "time-lapse"]
+        );
+        assert!(archive.join("panorama/2008/05/30/1556-01000.jpg").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn sync_keeps_quicktime_family_extensions() -> anyhow::Result<()> {
+        let (_temp, archive) = run_sync("test/quicktime")?;
+        let mut extensions: Vec<String> = files_under(&archive)?
+            .iter()
+            .filter_map(|p| p.extension().map(|e| e.to_string_lossy().to_string()))
+            .filter(|e| e != "md")
+            .collect();
+        extensions.sort();
+        assert_eq!(extensions, vec!["3gp", "m4v", "mov"]);
+        Ok(())
+    }
+
     #[test]
     fn sync_zip_and_directory_produce_identical_output() -> anyhow::Result<()> {
         let (_dir_temp, dir_archive) = run_sync(TAKEOUT_BASIC)?;
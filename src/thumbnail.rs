@@ -0,0 +1,334 @@
+//! Small JPEG previews of archived photos under `.thumbnails/` (`sync --thumbnails`),
+//! for browsing the archive without opening every full size photo.
+//!
+//! A preview the file already carries is used when there is one: the EXIF
+//! thumbnail (`ThumbnailOffset` and `ThumbnailLength`, counted from the start of
+//! the TIFF block) of a JPEG, HEIC, AVIF or TIFF based RAW, or else the JPEG
+//! thumbnail item of a HEIC. Otherwise JPEG, PNG, GIF, WebP, BMP and TIFF photos
+//! are decoded and shrunk to fit in 256 pixels. There's no pure Rust decoder for
+//! the HEVC and AV1 coded previews most HEIC and AVIF files carry, so those
+//! without a JPEG preview get no thumbnail.
+//!
+//! Each thumbnail records the checksum of the file it shows in a JPEG comment,
+//! so a re-run leaves it alone unless the archived file changed.
+
+use crate::embed::{exif_block, heif_item};
+use crate::exif_util::PsExifInfo;
+use crate::file_type::AccurateFileType;
+use crate::fs::{FileSystem, Output};
+use crate::media::{MediaFileDerivedInfo, MediaFileInfo};
+use crate::phash::decodable_format;
+use anyhow::{Result, anyhow};
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use std::io::{Cursor, Read};
+use tracing::{debug, info};
+
+/// Directory of the output that thumbnails are written under, mirroring the archive.
+const THUMBNAIL_DIR: &str = ".thumbnails";
+
+/// Longest side of a thumbnail decoded from the photo itself.
+const THUMBNAIL_SIZE: u32 = 256;
+
+const JPEG_QUALITY: u8 = 80;
+
+/// Photos larger than this aren't decoded for a thumbnail, to bound memory use.
+const MAX_DECODED_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Start of the JPEG comment a thumbnail records the checksum of its photo in.
+const CHECKSUM_COMMENT: &str = "ptsync checksum ";
+
+/// Path of the thumbnail of the file archived at `media_path`. The photo's own
+/// extension is kept, so a RAW and the JPEG beside it each get their own.
+pub(crate) fn thumbnail_path(media_path: &str) -> String {
+    format!("{THUMBNAIL_DIR}/{media_path}.jpg")
+}
+
+/// Write the thumbnail of the photo archived at `media_path`, unless the one
+/// already there shows the same file. Returns its path, or `None` when no
+/// thumbnail can be made for this file.
+pub(crate) fn sync_thumbnail(
+    dry_run: bool,
+    media: &MediaFileInfo,
+    derived: &MediaFileDerivedInfo,
+    media_path: &str,
+    input_container: &dyn FileSystem,
+    output_container: &dyn Output,
+) -> Result<Option<String>> {
+    if !may_have_thumbnail(&media.accurate_file_type) {
+        return Ok(None);
+    }
+    let checksum = derived
+        .archived_checksum
+        .as_ref()
+        .unwrap_or(&media.hash_info.long_checksum);
+    let path = thumbnail_path(media_path);
+    if recorded_checksum(output_container, &path).as_ref() == Some(checksum) {
+        return Ok(Some(path));
+    }
+    // Made from the archived file, as `--transfer move` takes the input away
+    let mut reader = if output_container.exists(media_path)? {
+        output_container.open(media_path)?
+    } else {
+        input_container.open(&media.original_file_this_run)?
+    };
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let Some(jpeg) = thumbnail(&bytes, &media.accurate_file_type, media.exif_info.as_ref())? else {
+        debug!("No thumbnail for {media_path}");
+        return Ok(None);
+    };
+    info!("Output {path:?}");
+    let jpeg = with_comment(&jpeg, &format!("{CHECKSUM_COMMENT}{checksum}"))?;
+    output_container.write(dry_run, &path, &mut Cursor::new(jpeg))?;
+    output_container.set_modified(dry_run, &path, &media.modified);
+    Ok(Some(path))
+}
+
+fn may_have_thumbnail(file_type: &AccurateFileType) -> bool {
+    decodable_format(file_type).is_some() || has_tiff_exif(file_type) || is_heif(file_type)
+}
+
+/// RAW formats laid out as a TIFF, whose EXIF offsets count from the start of the file.
+fn has_tiff_exif(file_type: &AccurateFileType) -> bool {
+    matches!(
+        file_type,
+        AccurateFileType::Tiff
+            | AccurateFileType::Dng
+            | AccurateFileType::Cr2
+            | AccurateFileType::Nef
+            | AccurateFileType::Arw
+            | AccurateFileType::Orf
+    )
+}
+
+fn is_heif(file_type: &AccurateFileType) -> bool {
+    matches!(file_type, AccurateFileType::Heic | AccurateFileType::Avif)
+}
+
+/// A JPEG thumbnail of the photo in `bytes`, turned upright, or `None` when it
+/// has no preview and can't be decoded.
+fn thumbnail(
+    bytes: &[u8],
+    file_type: &AccurateFileType,
+    exif: Option<&PsExifInfo>,
+) -> Result<Option<Vec<u8>>> {
+    if let Some(preview) = embedded_preview(bytes, file_type, exif) {
+        let orientation = exif
+            .and_then(|e| e.tags.get("Orientation"))
+            .and_then(|o| o.parse::<u8>().ok())
+            .and_then(Orientation::from_exif)
+            .unwrap_or(Orientation::NoTransforms);
+        if orientation == Orientation::NoTransforms {
+            return Ok(Some(preview));
+        }
+        let mut image = image::load_from_memory_with_format(&preview, ImageFormat::Jpeg)?;
+        image.apply_orientation(orientation);
+        return encode(&image).map(Some);
+    }
+    let Some(format) = decodable_format(file_type) else {
+        return Ok(None);
+    };
+    if bytes.len() as u64 > MAX_DECODED_FILE_SIZE {
+        return Ok(None);
+    }
+    let mut decoder = ImageReader::with_format(Cursor::new(bytes), format).into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    if image.width() > THUMBNAIL_SIZE || image.height() > THUMBNAIL_SIZE {
+        image = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    }
+    encode(&image).map(Some)
+}
+
+/// The JPEG preview a photo carries: its EXIF thumbnail, or a HEIC's thumbnail item.
+fn embedded_preview(
+    bytes: &[u8],
+    file_type: &AccurateFileType,
+    exif: Option<&PsExifInfo>,
+) -> Option<Vec<u8>> {
+    let is_jpeg = |preview: &Vec<u8>| preview.starts_with(&[0xFF, 0xD8]);
+    let exif_thumbnail = || {
+        let tags = &exif?.tags;
+        let offset = tags.get("ThumbnailOffset")?.parse::<usize>().ok()?;
+        let length = tags.get("ThumbnailLength")?.parse::<usize>().ok()?;
+        let tiff = if has_tiff_exif(file_type) {
+            bytes.to_vec()
+        } else if matches!(file_type, AccurateFileType::Jpg) || is_heif(file_type) {
+            exif_block(bytes, file_type).ok()??
+        } else {
+            return None;
+        };
+        tiff.get(offset..offset.checked_add(length)?)
+            .map(|t| t.to_vec())
+    };
+    // Typically coded like the image itself, but some cameras write a JPEG
+    let heif_thumbnail = || {
+        is_heif(file_type)
+            .then(|| heif_item(bytes, b"jpeg").ok())
+            .flatten()
+    };
+    exif_thumbnail()
+        .filter(is_jpeg)
+        .or_else(|| heif_thumbnail().filter(is_jpeg))
+}
+
+fn encode(image: &DynamicImage) -> Result<Vec<u8>> {
+    let mut jpeg = Vec::new();
+    // JPEG has no alpha channel
+    image
+        .to_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY))?;
+    Ok(jpeg)
+}
+
+/// `jpeg` with a comment (`COM`) segment holding `comment` right after its SOI.
+fn with_comment(jpeg: &[u8], comment: &str) -> Result<Vec<u8>> {
+    let rest = jpeg
+        .strip_prefix(&[0xFF, 0xD8])
+        .ok_or_else(|| anyhow!("Thumbnail isn't a JPEG"))?;
+    let len = u16::try_from(2 + comment.len())?;
+    let mut out = Vec::with_capacity(jpeg.len() + 4 + comment.len());
+    out.extend_from_slice(&[0xFF, 0xD8, 0xFF, 0xFE]);
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(comment.as_bytes());
+    out.extend_from_slice(rest);
+    Ok(out)
+}
+
+/// Checksum of the photo the thumbnail at `path` was made from, if there's one there.
+fn recorded_checksum(output_container: &dyn Output, path: &str) -> Option<String> {
+    if !output_container.exists(path).ok()? {
+        return None;
+    }
+    let mut bytes = Vec::new();
+    output_container
+        .open(path)
+        .ok()?
+        .read_to_end(&mut bytes)
+        .ok()?;
+    // The comment is the first segment, as `with_comment` writes it
+    let (&[0xFF, 0xD8, 0xFF, 0xFE, hi, lo], rest) = bytes.split_first_chunk::<6>()? else {
+        return None;
+    };
+    let comment = rest.get(..(u16::from_be_bytes([hi, lo]) as usize).checked_sub(2)?)?;
+    std::str::from_utf8(comment)
+        .ok()?
+        .strip_prefix(CHECKSUM_COMMENT)
+        .map(|c| c.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif_util::parse_exif_info;
+    use crate::fs::MemoryOutput;
+    use std::fs::File;
+
+    fn decode(jpeg: &[u8]) -> anyhow::Result<DynamicImage> {
+        Ok(image::load_from_memory_with_format(
+            jpeg,
+            ImageFormat::Jpeg,
+        )?)
+    }
+
+    /// A HEIF file holding a single `jpeg` item.
+    fn heif_with_jpeg_item(jpeg: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let full_box = |kind: &[u8; 4], version: u8, content: &[u8]| {
+            let mut b = ((12 + content.len()) as u32).to_be_bytes().to_vec();
+            b.extend_from_slice(kind);
+            b.extend_from_slice(&[version, 0, 0, 0]);
+            b.extend_from_slice(content);
+            b
+        };
+        let infe = full_box(b"infe", 2, b"\0\x01\0\0jpeg\0");
+        let mut iinf_content = 1u16.to_be_bytes().to_vec();
+        iinf_content.extend_from_slice(&infe);
+        let iinf = full_box(b"iinf", 0, &iinf_content);
+        let iloc_len = 12 + 2 + 2 + 2 + 2 + 2 + 4 + 4;
+        let mut ftyp = 16u32.to_be_bytes().to_vec();
+        ftyp.extend_from_slice(b"ftypheic\0\0\0\0");
+        let meta_len = 12 + iinf.len() + iloc_len;
+        let jpeg_offset = u32::try_from(ftyp.len() + meta_len + 8)?;
+        // Offsets and lengths of 4 bytes, no base offset; one item of one extent
+        let mut iloc_content = vec![0x44, 0x00, 0, 1, 0, 1, 0, 0, 0, 1];
+        iloc_content.extend_from_slice(&jpeg_offset.to_be_bytes());
+        iloc_content.extend_from_slice(&u32::try_from(jpeg.len())?.to_be_bytes());
+        let iloc = full_box(b"iloc", 0, &iloc_content);
+        let mut meta_content = iinf;
+        meta_content.extend_from_slice(&iloc);
+        let mut heif = ftyp;
+        heif.extend_from_slice(&full_box(b"meta", 0, &meta_content));
+        heif.extend_from_slice(&u32::try_from(8 + jpeg.len())?.to_be_bytes());
+        heif.extend_from_slice(b"mdat");
+        heif.extend_from_slice(jpeg);
+        Ok(heif)
+    }
+
+    #[test]
+    fn test_uses_exif_thumbnail() -> anyhow::Result<()> {
+        let bytes = std::fs::read("test/Canon_40D.jpg")?;
+        let exif = parse_exif_info(File::open("test/Canon_40D.jpg")?)?;
+        let jpeg = thumbnail(&bytes, &AccurateFileType::Jpg, exif.as_ref())?
+            .ok_or_else(|| anyhow!("No thumbnail"))?;
+        // The camera's own, as it is
+        assert_eq!(jpeg.len(), 1378);
+        assert!(bytes.windows(jpeg.len()).any(|w| w == jpeg));
+        Ok(())
+    }
+
+    #[test]
+    fn test_uses_heic_thumbnail_item() -> anyhow::Result<()> {
+        let preview = std::fs::read("test/kinds/IMG_0007.jpg")?;
+        let heif = heif_with_jpeg_item(&preview)?;
+        assert_eq!(
+            thumbnail(&heif, &AccurateFileType::Heic, None)?,
+            Some(preview)
+        );
+        // An AVIF with no JPEG preview can't be decoded
+        let avif = std::fs::read("test/formats/photo.avif")?;
+        assert_eq!(thumbnail(&avif, &AccurateFileType::Avif, None)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decodes_photo_without_preview() -> anyhow::Result<()> {
+        let png = std::fs::read("test/kinds/IMG_0006.PNG")?;
+        let original = image::load_from_memory_with_format(&png, ImageFormat::Png)?;
+        let jpeg = thumbnail(&png, &AccurateFileType::Png, None)?
+            .ok_or_else(|| anyhow!("No thumbnail"))?;
+        let small = decode(&jpeg)?;
+        assert_eq!(
+            (small.width(), small.height()),
+            (original.width(), original.height())
+        );
+
+        // Shrunk to fit, keeping its shape
+        let mut large = Vec::new();
+        DynamicImage::new_rgb8(1024, 512)
+            .write_to(&mut Cursor::new(&mut large), ImageFormat::Png)?;
+        let jpeg = thumbnail(&large, &AccurateFileType::Png, None)?
+            .ok_or_else(|| anyhow!("No thumbnail"))?;
+        let small = decode(&jpeg)?;
+        assert_eq!((small.width(), small.height()), (256, 128));
+        Ok(())
+    }
+
+    #[test]
+    fn test_recorded_checksum_round_trip() -> anyhow::Result<()> {
+        let output = MemoryOutput::default();
+        let jpeg = std::fs::read("test/kinds/IMG_0007.jpg")?;
+        output.write(
+            false,
+            "a.jpg",
+            &mut Cursor::new(with_comment(&jpeg, &format!("{CHECKSUM_COMMENT}abc"))?),
+        )?;
+        assert_eq!(recorded_checksum(&output, "a.jpg"), Some("abc".to_string()));
+        output.write(false, "b.jpg", &mut Cursor::new(jpeg))?;
+        assert_eq!(recorded_checksum(&output, "b.jpg"), None);
+        assert_eq!(recorded_checksum(&output, "c.jpg"), None);
+        Ok(())
+    }
+}
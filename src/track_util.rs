@@ -1,7 +1,29 @@
+use crate::legacy_video::{
+    LegacyContainer, legacy_container, parse_avi_info, parse_transport_stream_info,
+};
+use crate::util::{PatchedReader, read_header};
 use nom_exif::{MediaKind, MediaParser, MediaSource, TrackInfo, TrackInfoTag};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io::{Read, Seek, SeekFrom};
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
+
+/// QuickTime key Apple writes into both halves of a Live Photo (the clip here,
+/// and the still's maker note) so the pair can be matched.
+pub(crate) const QT_KEY_CONTENT_IDENTIFIER: &str = "com.apple.quicktime.content.identifier";
+
+/// QuickTime key Apple writes into slow motion clips: `0` when the clip, though
+/// recorded at a high frame rate, is meant to play slowed down.
+pub(crate) const QT_KEY_FULL_FRAME_RATE_PLAYBACK_INTENT: &str =
+    "com.apple.quicktime.full-frame-rate-playback-intent";
+
+/// Key Android writes into slow motion and time-lapse clips: the frame rate
+/// the clip was captured at, rather than the one it plays at.
+pub(crate) const QT_KEY_ANDROID_CAPTURE_FPS: &str = "com.android.capture.fps";
+
+/// Largest `moov` atom read into memory when looking for QuickTime keys. Real
+/// files are a few hundred KB at most; anything bigger is treated as corrupt.
+const MAX_MOOV_SIZE: u64 = 32 * 1024 * 1024;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all(deserialize = "camelCase", serialize = "camelCase"))]
@@ -16,11 +38,27 @@ pub(crate) struct PsTrackInfo {
     pub software: Option<String>,
     pub author: Option<String>,
     pub gps_iso_6709: Option<String>,
+    /// Apple `ContentIdentifier`, shared with the still of a Live Photo
+    #[serde(default)]
+    pub content_identifier: Option<String>,
+    /// Apple full frame rate playback intent, `0` for a slow motion clip
+    #[serde(default)]
+    pub full_frame_rate_playback_intent: Option<u64>,
+    /// Android capture frame rate, only written when it differs from playback
+    #[serde(default)]
+    pub capture_fps: Option<f64>,
 }
 
 pub fn parse_track_info<R: Read + Seek>(mut reader: R) -> anyhow::Result<Option<PsTrackInfo>> {
-    reader.seek(SeekFrom::Start(0))?;
-    let ms_r = MediaSource::seekable(reader);
+    match legacy_container(&mut reader) {
+        Some(LegacyContainer::Avi) => return parse_avi_info(&mut reader).map(Some),
+        Some(LegacyContainer::TransportStream { packet_size }) => {
+            return parse_transport_stream_info(&mut reader, packet_size).map(Some);
+        }
+        None => {}
+    }
+    let qt_keys = parse_quicktime_keys(&mut reader);
+    let ms_r = MediaSource::seekable(m4v_as_mp4(reader)?);
     let Ok(ms) = ms_r else {
         warn!("Failed to read track media source");
         return Ok(None);
@@ -47,6 +85,13 @@ pub fn parse_track_info<R: Read + Seek>(mut reader: R) -> anyhow::Result<Option<
                 software: parse_to_o_s(&info.get(TrackInfoTag::Software)),
                 author: parse_to_o_s(&info.get(TrackInfoTag::Author)),
                 gps_iso_6709: parse_to_o_s(&info.get(TrackInfoTag::GpsIso6709)),
+                content_identifier: qt_keys.get(QT_KEY_CONTENT_IDENTIFIER).cloned(),
+                full_frame_rate_playback_intent: qt_keys
+                    .get(QT_KEY_FULL_FRAME_RATE_PLAYBACK_INTENT)
+                    .and_then(|v| v.parse().ok()),
+                capture_fps: qt_keys
+                    .get(QT_KEY_ANDROID_CAPTURE_FPS)
+                    .and_then(|v| v.parse().ok()),
             };
             info.iter()
                 // filter out known tags from above
@@ -72,6 +117,181 @@ pub fn parse_track_info<R: Read + Seek>(mut reader: R) -> anyhow::Result<Option<
     }
 }
 
+/// iTunes M4V is plain MP4 apart from its `M4V ` major brand, which nom-exif
+/// doesn't know, so present the brand as `mp42`.
+fn m4v_as_mp4<R: Read + Seek>(mut reader: R) -> anyhow::Result<PatchedReader<R>> {
+    match read_header::<R, 12>(&mut reader) {
+        Some(h) if &h[4..8] == b"ftyp" && &h[8..12] == b"M4V " => {
+            PatchedReader::new(reader, 8, b"mp42".to_vec())
+        }
+        _ => PatchedReader::unpatched(reader),
+    }
+}
+
+/// Apple QuickTime metadata from the `moov/meta` `keys` and `ilst` atoms, as
+/// key -> value (e.g. `com.apple.quicktime.content.identifier`). nom-exif only
+/// surfaces a fixed set of track tags, so these are read directly.
+///
+/// Only text and numeric values are kept. A file without the atoms, or one that
+/// can't be walked, yields an empty map.
+pub(crate) fn parse_quicktime_keys<R: Read + Seek>(mut reader: R) -> HashMap<String, String> {
+    match read_moov(&mut reader) {
+        Ok(Some(moov)) => quicktime_keys_from_moov(&moov),
+        Ok(None) => HashMap::new(),
+        Err(e) => {
+            debug!("Could not read QuickTime moov atom: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+/// Find the top level `moov` atom and return its body.
+fn read_moov<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    reader.seek(SeekFrom::Start(0))?;
+    loop {
+        let mut header = [0u8; 8];
+        if reader.read_exact(&mut header).is_err() {
+            return Ok(None);
+        }
+        let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let mut header_len = 8;
+        if size == 1 {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large)?;
+            size = u64::from_be_bytes(large);
+            header_len = 16;
+        }
+        if size == 0 {
+            // extends to the end of the file, nothing can follow it
+            if &header[4..8] != b"moov" {
+                return Ok(None);
+            }
+            let mut body = Vec::new();
+            reader.take(MAX_MOOV_SIZE).read_to_end(&mut body)?;
+            return Ok(Some(body));
+        }
+        if size < header_len {
+            return Err(anyhow::anyhow!("Invalid atom size {size}"));
+        }
+        let body_len = size - header_len;
+        if &header[4..8] == b"moov" {
+            if body_len > MAX_MOOV_SIZE {
+                return Err(anyhow::anyhow!("moov atom too large: {body_len}"));
+            }
+            let mut body = vec![0u8; body_len as usize];
+            reader.read_exact(&mut body)?;
+            return Ok(Some(body));
+        }
+        reader.seek(SeekFrom::Current(body_len as i64))?;
+    }
+}
+
+/// Split a buffer of ISO-BMFF atoms into `(type, body)` pairs, stopping at the
+/// first malformed atom.
+fn child_atoms(buf: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut atoms = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= buf.len() {
+        let size =
+            u32::from_be_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]) as usize;
+        let atom_type = [buf[pos + 4], buf[pos + 5], buf[pos + 6], buf[pos + 7]];
+        let end = if size == 0 { buf.len() } else { pos + size };
+        if (size != 0 && size < 8) || end > buf.len() {
+            break;
+        }
+        atoms.push((atom_type, &buf[pos + 8..end]));
+        pos = end;
+    }
+    atoms
+}
+
+fn quicktime_keys_from_moov(moov: &[u8]) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    for (atom_type, meta) in child_atoms(moov) {
+        if &atom_type != b"meta" {
+            continue;
+        }
+        // QuickTime `meta` has no version/flags, ISO `meta` has 4 bytes of them.
+        let meta = if meta.len() >= 8 && &meta[4..8] == b"hdlr" {
+            meta
+        } else {
+            meta.get(4..).unwrap_or_default()
+        };
+        let atoms = child_atoms(meta);
+        let mut key_names: Vec<String> = vec![];
+        for (t, body) in &atoms {
+            if t == b"keys" {
+                key_names = parse_keys_atom(body);
+            }
+        }
+        for (t, body) in &atoms {
+            if t != b"ilst" {
+                continue;
+            }
+            for (index, item) in child_atoms(body) {
+                // ilst items are named by their 1-based index into `keys`
+                let index = u32::from_be_bytes(index) as usize;
+                let Some(key) = index.checked_sub(1).and_then(|i| key_names.get(i)) else {
+                    continue;
+                };
+                if let Some(value) = parse_data_atom(item) {
+                    values.insert(key.clone(), value);
+                }
+            }
+        }
+    }
+    values
+}
+
+fn parse_keys_atom(body: &[u8]) -> Vec<String> {
+    let mut keys = Vec::new();
+    // version/flags (4) + entry count (4)
+    let mut pos = 8;
+    while pos + 8 <= body.len() {
+        let size =
+            u32::from_be_bytes([body[pos], body[pos + 1], body[pos + 2], body[pos + 3]]) as usize;
+        if size < 8 || pos + size > body.len() {
+            break;
+        }
+        keys.push(String::from_utf8_lossy(&body[pos + 8..pos + size]).to_string());
+        pos += size;
+    }
+    keys
+}
+
+/// Value of the `data` atom inside an `ilst` item, for the well-known UTF-8 and
+/// big-endian numeric types.
+fn parse_data_atom(item: &[u8]) -> Option<String> {
+    let (_, data) = child_atoms(item).into_iter().find(|(t, _)| t == b"data")?;
+    // type indicator (4) + locale (4)
+    let type_indicator = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?);
+    let value = data.get(8..)?;
+    match type_indicator {
+        1 => Some(
+            String::from_utf8_lossy(value)
+                .trim_end_matches('\0')
+                .to_string(),
+        ),
+        21 => Some(be_int(value, true)?.to_string()),
+        22 => Some(be_int(value, false)?.to_string()),
+        23 => Some(f32::from_be_bytes(value.try_into().ok()?).to_string()),
+        _ => None,
+    }
+}
+
+fn be_int(value: &[u8], signed: bool) -> Option<i64> {
+    Some(match (value.len(), signed) {
+        (1, true) => i8::from_be_bytes(value.try_into().ok()?) as i64,
+        (2, true) => i16::from_be_bytes(value.try_into().ok()?) as i64,
+        (4, true) => i32::from_be_bytes(value.try_into().ok()?) as i64,
+        (8, true) => i64::from_be_bytes(value.try_into().ok()?),
+        (1, false) => value[0] as i64,
+        (2, false) => u16::from_be_bytes(value.try_into().ok()?) as i64,
+        (4, false) => u32::from_be_bytes(value.try_into().ok()?) as i64,
+        _ => return None,
+    })
+}
+
 fn parse_to_o_u64(opt: &Option<&nom_exif::EntryValue>) -> Option<u64> {
     if let Some(v) = opt
         && let Ok(s) = v.to_string().parse::<u64>()
@@ -113,6 +333,60 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_parse_quicktime_content_identifier() -> anyhow::Result<()> {
+        use anyhow::anyhow;
+        crate::test_util::setup_log();
+        let c = OsFileSystem::new("test/live_photo");
+        let reader = c.open("IMG_0001.MOV")?;
+        let meta =
+            parse_track_info(reader)?.ok_or_else(|| anyhow!("Failed to parse track info"))?;
+        assert_eq!(
+            meta.content_identifier,
+            Some("6A1C8E1B-3F1A-4C3E-9E0B-1D2F3A4B5C6D".to_string())
+        );
+        assert_eq!(meta.duration_ms, Some(5000));
+
+        // A plain mp4 has no Apple keys at all.
+        let reader = c.open("../Hello.mp4")?;
+        assert!(parse_quicktime_keys(reader).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_track_quicktime_family() -> anyhow::Result<()> {
+        use anyhow::anyhow;
+        crate::test_util::setup_log();
+        let c = OsFileSystem::new("test/quicktime");
+        for name in ["Hello.mov", "Hello.m4v", "Hello.3gp"] {
+            let reader = c.open(name)?;
+            let meta = parse_track_info(reader)?
+                .ok_or_else(|| anyhow!("Failed to parse track info for {name}"))?;
+            assert_eq!(meta.width, Some(854), "{name}");
+            assert_eq!(meta.duration_ms, Some(5000), "{name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_track_legacy_and_matroska() -> anyhow::Result<()> {
+        use anyhow::anyhow;
+        crate::test_util::setup_log();
+        let c = OsFileSystem::new("test/video");
+        for (name, created, duration_ms) in [
+            ("clip.mkv", "2021-05-01T08:30:00+00:00", 4000),
+            ("screen.webm", "2022-06-02T09:45:10+00:00", 2500),
+            ("MVI_0001.AVI", "2005-12-24T12:34:56", 5004),
+            ("00001.MTS", "2009-08-15T18:20:05+09:00", 6000),
+        ] {
+            let meta = parse_track_info(c.open(name)?)?
+                .ok_or_else(|| anyhow!("Failed to parse track info for {name}"))?;
+            assert_eq!(meta.creation_time.as_deref(), Some(created), "{name}");
+            assert_eq!(meta.duration_ms, Some(duration_ms), "{name}");
+        }
+        Ok(())
+    }
+
     /// For research scal all MP4 files in input/ directory and look for unknown tags
     #[test]
     #[ignore]
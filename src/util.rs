@@ -1,15 +1,28 @@
 use crate::db_cmd::HashInfo;
 use crate::file_type::{QuickFileType, find_quick_file_type};
-use crate::fs::{FileSystem, OsFileSystem};
+use crate::fs::{FileSystem, Output};
+use crate::payload::payload_checksum;
 use anyhow::Result;
-use chrono::DateTime;
+use chrono::{DateTime, NaiveDateTime};
 use sha2::{Digest, Sha256};
 use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use tracing::{debug, warn};
 
-/// Similar to github generate a short and long hash from the bytes
+/// Similar to github generate a short and long hash from the bytes, plus a
+/// checksum of the image data alone for the types that have one
 pub(crate) fn checksum_bytes<R: Read + Seek>(reader: &mut R) -> Result<HashInfo> {
+    let long_checksum = file_checksum(reader)?;
+    Ok(HashInfo {
+        short_checksum: long_checksum.chars().take(7).collect(),
+        long_checksum,
+        payload_checksum: payload_checksum(reader),
+    })
+}
+
+/// Hex SHA-256 of all the bytes, the long checksum alone. For files already in
+/// the output, where only that is compared and a payload pass would read them twice.
+pub(crate) fn file_checksum<R: Read + Seek>(reader: &mut R) -> Result<String> {
     let mut hasher = Sha256::new();
     let mut buffer = [0; 64 * 1024]; // Read in 64KB chunks
     reader.seek(SeekFrom::Start(0))?;
@@ -20,13 +33,71 @@ pub(crate) fn checksum_bytes<R: Read + Seek>(reader: &mut R) -> Result<HashInfo>
         }
         hasher.update(&buffer[..count]);
     }
-    let digest = hasher.finalize();
-    let hex = hex::encode(digest);
-    let chars = hex.chars();
-    Ok(HashInfo {
-        short_checksum: chars.clone().take(7).collect(),
-        long_checksum: chars.take(64).collect(),
-    })
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Reader that shows `patch` in place of the bytes at `offset`, passing
+/// everything else through untouched.
+///
+/// Lets a parser accept a file whose only difference from a format it already
+/// understands is a magic number or brand (eg, an iTunes `M4V ` brand on an
+/// otherwise plain MP4). The underlying bytes, and so the checksum, never change.
+pub(crate) struct PatchedReader<R> {
+    inner: R,
+    pos: u64,
+    offset: u64,
+    patch: Vec<u8>,
+}
+
+impl<R: Read + Seek> PatchedReader<R> {
+    pub(crate) fn new(mut inner: R, offset: u64, patch: Vec<u8>) -> Result<Self> {
+        inner.seek(SeekFrom::Start(0))?;
+        Ok(PatchedReader {
+            inner,
+            pos: 0,
+            offset,
+            patch,
+        })
+    }
+
+    /// A reader that changes nothing.
+    pub(crate) fn unpatched(inner: R) -> Result<Self> {
+        Self::new(inner, 0, vec![])
+    }
+}
+
+impl<R: Read + Seek> Read for PatchedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = self.pos;
+        let count = self.inner.read(buf)?;
+        self.pos += count as u64;
+        for (i, b) in buf[..count].iter_mut().enumerate() {
+            let offset = start + i as u64;
+            if offset >= self.offset
+                && let Some(p) = self.patch.get((offset - self.offset) as usize)
+            {
+                *b = *p;
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl<R: Read + Seek> Seek for PatchedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// The first `N` bytes of a reader, or `None` if it is shorter. Leaves the
+/// reader at the start.
+pub(crate) fn read_header<R: Read + Seek, const N: usize>(reader: &mut R) -> Option<[u8; N]> {
+    let mut header = [0u8; N];
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    let r = reader.read_exact(&mut header);
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    r.ok().map(|_| header)
 }
 
 #[derive(Debug, Clone)]
@@ -73,20 +144,27 @@ pub(crate) fn scan_fs(fs: &dyn FileSystem) -> Vec<ScanInfo> {
 }
 
 pub(crate) fn is_existing_file_same(
-    fs: &OsFileSystem,
+    fs: &dyn Output,
     long_checksum: &str,
     output_path: &String,
 ) -> Option<bool> {
+    existing_file_checksum(fs, output_path).map(|c| c == long_checksum)
+}
+
+/// Checksum of the file already at `output_path`, if it can be read.
+pub(crate) fn existing_file_checksum(fs: &dyn Output, output_path: &String) -> Option<String> {
+    if let Some(existing_checksum) = fs.checksum(output_path) {
+        return Some(existing_checksum);
+    }
     let Ok(mut reader) = fs.open(output_path) else {
         debug!("Could not read file bytes for checksum: {output_path:?}");
         return None;
     };
-    let existing_file_hash_info_r = checksum_bytes(&mut reader);
-    let Ok(existing_file_hash_info) = existing_file_hash_info_r else {
+    let Ok(existing_checksum) = file_checksum(&mut reader) else {
         debug!("Could not read file for checksum: {output_path:?}");
         return None;
     };
-    Some(existing_file_hash_info.long_checksum.eq(long_checksum))
+    Some(existing_checksum)
 }
 
 pub(crate) fn dir_part(file_path_s: &String) -> String {
@@ -112,10 +190,26 @@ pub(crate) fn timestamp_to_rfc3339(ts: i64) -> Option<String> {
     DateTime::from_timestamp_millis(ts).map(|d| d.to_rfc3339())
 }
 
+/// A wall clock time recorded without a timezone, as most cameras record it,
+/// as ISO 8601 with no offset: no offset is made up for it.
+pub(crate) fn naive_to_iso_8601(dt: NaiveDateTime) -> String {
+    dt.format("%Y-%m-%dT%H:%M:%S%.f").to_string()
+}
+
+/// The wall clock time of a date taken, given as RFC 3339 or, when its offset
+/// isn't known, as ISO 8601 without one.
+pub(crate) fn parse_taken(dt: &str) -> Option<NaiveDateTime> {
+    DateTime::parse_from_rfc3339(dt)
+        .map(|d| d.naive_local())
+        .or_else(|_| NaiveDateTime::parse_from_str(dt, "%Y-%m-%dT%H:%M:%S%.f"))
+        .ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::fs::ZipFileSystem;
+    use crate::fs::{OsFileSystem, ZipFileSystem};
+    use chrono::NaiveDate;
 
     #[test]
     fn test_zip() -> anyhow::Result<()> {
@@ -137,16 +231,47 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_patched_reader() -> anyhow::Result<()> {
+        use std::io::Cursor;
+        let mut r = PatchedReader::new(Cursor::new(b"0123456789".to_vec()), 2, b"ab".to_vec())?;
+        let mut out = Vec::new();
+        r.read_to_end(&mut out)?;
+        assert_eq!(out, b"01ab456789");
+
+        // Patched bytes are also seen when reading from part way through.
+        r.seek(SeekFrom::Start(3))?;
+        let mut one = [0u8; 2];
+        r.read_exact(&mut one)?;
+        assert_eq!(&one, b"b4");
+        Ok(())
+    }
+
     #[test]
     fn test_files_checksum() -> anyhow::Result<()> {
         let c = OsFileSystem::new("test");
-        let mut b = c.open("Canon_40D.jpg")?;
+        let mut b = FileSystem::open(&c, "Canon_40D.jpg")?;
         let csm = checksum_bytes(&mut b)?;
         assert_eq!(csm.short_checksum, "6bfdabd".to_string());
         assert_eq!(
             csm.long_checksum,
             "6bfdabd4fc33d112283c147acccc574e770bbe6fbdbc3d4da968ba7b606ecc2f".to_string()
         );
+        assert!(csm.payload_checksum.is_some());
+        assert_eq!(file_checksum(&mut b)?, csm.long_checksum);
         Ok(())
     }
+
+    #[test]
+    fn test_parse_taken_keeps_the_wall_clock() {
+        let wall_clock =
+            NaiveDate::from_ymd_opt(2024, 7, 15).and_then(|d| d.and_hms_opt(14, 30, 22));
+        assert_eq!(parse_taken("2024-07-15T14:30:22+02:00"), wall_clock);
+        assert_eq!(parse_taken("2024-07-15T14:30:22"), wall_clock);
+        assert_eq!(
+            wall_clock.map(naive_to_iso_8601),
+            Some("2024-07-15T14:30:22".to_string())
+        );
+        assert_eq!(parse_taken("15/07/2024"), None);
+    }
 }
@@ -0,0 +1,317 @@
+//! XMP sidecars, so photo tools see what the notes record.
+//!
+//! With `sync --write-xmp` each media file gets a `<name>.xmp` next to it, named
+//! like its note, which Lightroom, darktable, digiKam and most photo managers
+//! read. It holds what the note's frontmatter does, in the properties those tools
+//! know:
+//!
+//! | Note | XMP |
+//! | --- | --- |
+//! | `datetime` | `exif:DateTimeOriginal`, `xmp:CreateDate`, `photoshop:DateCreated` |
+//! | `latitude`, `longitude` | `exif:GPSLatitude`, `exif:GPSLongitude` |
+//! | `people` | `Iptc4xmpExt:PersonInImage` |
+//! | `albums`, `kind`, `generated` | `dc:subject`, and `lr:hierarchicalSubject` under `Albums\|`, `Kind\|` and `Generated\|` |
+//! | `description`, `title` | `dc:description`, `dc:title` |
+//! | `rating` | `xmp:Rating` |
+//! | `keywords` | `dc:subject` |
+//! | `creator` | `dc:creator` |
+//! | `location`, `city`, `state`, `country` | `Iptc4xmpCore:Location`, `photoshop:City`, `photoshop:State`, `photoshop:Country` |
+//!
+//! A sidecar is rewritten on later runs when what it records changes, like a
+//! note. It's only ever replaced while it's still as ptsync wrote it: a tool
+//! that saves the sidecar names itself as its toolkit (`x:xmptk`), and from then
+//! on the sidecar is left to that tool.
+
+use crate::burst::BurstInfo;
+use crate::fs::Output;
+use crate::markdown::{PhotoSorterFrontMatter, mfm_from_media_file_info, sidecar_stem};
+use crate::media::{MediaFileDerivedInfo, MediaFileInfo};
+use crate::relate::MediaLinks;
+use anyhow::anyhow;
+use std::io::Read;
+use tracing::{debug, warn};
+
+/// Start of every sidecar ptsync writes, naming it as the toolkit that wrote it.
+const XMP_HEADER: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="ptsync">"#;
+
+pub(crate) fn sync_xmp(
+    dry_run: bool,
+    media_file: &MediaFileInfo,
+    derived: &MediaFileDerivedInfo,
+    album_names: &[String],
+    links: &MediaLinks,
+    burst: Option<&BurstInfo>,
+    output_c: &dyn Output,
+) -> anyhow::Result<()> {
+    let Some(desired_media_path) = &derived.desired_media_path else {
+        warn!(
+            "No desired media path for media file: {:?}",
+            media_file.original_path
+        );
+        return Ok(());
+    };
+    let output_path = format!("{}.xmp", sidecar_stem(derived, desired_media_path));
+    if output_c.exists(&output_path)? {
+        let mut existing = String::new();
+        output_c
+            .open(&output_path)?
+            .read_to_string(&mut existing)
+            .map_err(|e| anyhow!("Could not read existing XMP file at {output_path:?}: {e}"))?;
+        if !existing.starts_with(XMP_HEADER) {
+            debug!("Saved by another tool, leaving {output_path:?} as it is");
+            return Ok(());
+        }
+    }
+    let mfm = mfm_from_media_file_info(media_file, album_names, links, burst);
+    output_c.write_if_changed(
+        dry_run,
+        &output_path,
+        xmp_from_front_matter(&mfm).as_bytes(),
+    )?;
+    Ok(())
+}
+
+pub(crate) fn xmp_from_front_matter(fm: &PhotoSorterFrontMatter) -> String {
+    let mut attributes = Vec::new();
+    if let Some(dt) = &fm.datetime {
+        for property in [
+            "exif:DateTimeOriginal",
+            "xmp:CreateDate",
+            "photoshop:DateCreated",
+        ] {
+            attributes.push((property, dt.clone()));
+        }
+    }
+    if let Some(rating) = fm.rating {
+        attributes.push(("xmp:Rating", rating.to_string()));
+    }
+    if let (Some(lat), Some(long)) = (fm.latitude, fm.longitude) {
+        attributes.push(("exif:GPSLatitude", gps_coordinate(lat, 'N', 'S')));
+        attributes.push(("exif:GPSLongitude", gps_coordinate(long, 'E', 'W')));
+    }
+    for (property, place) in [
+        ("Iptc4xmpCore:Location", &fm.location),
+        ("photoshop:City", &fm.city),
+        ("photoshop:State", &fm.state),
+        ("photoshop:Country", &fm.country),
+    ] {
+        if let Some(place) = place {
+            attributes.push((property, place.clone()));
+        }
+    }
+
+    let albums: Vec<&str> = fm.albums.iter().map(|a| wikilink_target(a)).collect();
+    let tags: Vec<&str> = fm
+        .kind
+        .iter()
+        .chain(&fm.generated)
+        .map(String::as_str)
+        .collect();
+    // `|` separates the levels of a hierarchical keyword
+    let mut hierarchical: Vec<String> = albums
+        .iter()
+        .map(|a| format!("Albums|{}", a.replace('|', "/")))
+        .collect();
+    hierarchical.extend(fm.kind.iter().map(|k| format!("Kind|{k}")));
+    hierarchical.extend(fm.generated.iter().map(|g| format!("Generated|{g}")));
+    let subjects: Vec<String> = albums
+        .iter()
+        .chain(&tags)
+        .map(|s| s.to_string())
+        .chain(fm.keywords.iter().cloned())
+        .collect();
+    let people: Vec<String> = fm
+        .people
+        .iter()
+        .map(|p| wikilink_target(p).to_string())
+        .collect();
+
+    let mut xmp = String::new();
+    xmp.push_str(XMP_HEADER);
+    xmp.push_str("\n <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n");
+    xmp.push_str("  <rdf:Description rdf:about=\"\"\n");
+    for (prefix, uri) in [
+        ("xmp", "http://ns.adobe.com/xap/1.0/"),
+        ("exif", "http://ns.adobe.com/exif/1.0/"),
+        ("photoshop", "http://ns.adobe.com/photoshop/1.0/"),
+        ("dc", "http://purl.org/dc/elements/1.1/"),
+        ("lr", "http://ns.adobe.com/lightroom/1.0/"),
+        (
+            "Iptc4xmpCore",
+            "http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/",
+        ),
+        ("Iptc4xmpExt", "http://iptc.org/std/Iptc4xmpExt/2008-02-29/"),
+    ] {
+        xmp.push_str(&format!("    xmlns:{prefix}=\"{uri}\"\n"));
+    }
+    for (property, value) in &attributes {
+        xmp.push_str(&format!("    {property}=\"{}\"\n", escape(value)));
+    }
+    xmp.push_str("    xmp:CreatorTool=\"ptsync\">\n");
+    for (property, text) in [("dc:title", &fm.title), ("dc:description", &fm.description)] {
+        let Some(text) = text else {
+            continue;
+        };
+        xmp.push_str(&format!("   <{property}>\n    <rdf:Alt>\n"));
+        xmp.push_str(&format!(
+            "     <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n",
+            escape(text)
+        ));
+        xmp.push_str(&format!("    </rdf:Alt>\n   </{property}>\n"));
+    }
+    // Creators are in order of credit, the rest unordered
+    for (property, array, values) in [
+        ("dc:creator", "rdf:Seq", &fm.creator),
+        ("dc:subject", "rdf:Bag", &subjects),
+        ("lr:hierarchicalSubject", "rdf:Bag", &hierarchical),
+        ("Iptc4xmpExt:PersonInImage", "rdf:Bag", &people),
+    ] {
+        if values.is_empty() {
+            continue;
+        }
+        xmp.push_str(&format!("   <{property}>\n    <{array}>\n"));
+        for value in values {
+            xmp.push_str(&format!("     <rdf:li>{}</rdf:li>\n", escape(value)));
+        }
+        xmp.push_str(&format!("    </{array}>\n   </{property}>\n"));
+    }
+    xmp.push_str("  </rdf:Description>\n </rdf:RDF>\n</x:xmpmeta>\n");
+    xmp
+}
+
+/// The name a `[[wikilink]]` links to.
+fn wikilink_target(link: &str) -> &str {
+    link.strip_prefix("[[")
+        .and_then(|l| l.strip_suffix("]]"))
+        .unwrap_or(link)
+}
+
+/// A coordinate as XMP writes one: degrees, then decimal minutes and a direction.
+fn gps_coordinate(value: f64, positive: char, negative: char) -> String {
+    let direction = if value < 0.0 { negative } else { positive };
+    let value = value.abs();
+    let degrees = value.trunc();
+    format!("{},{:.6}{direction}", degrees, (value - degrees) * 60.0)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::MemoryOutput;
+    use std::io::Cursor;
+
+    fn front_matter() -> PhotoSorterFrontMatter {
+        PhotoSorterFrontMatter {
+            path_original: vec!["Google Photos/Trip/IMG_1.jpg".to_string()],
+            source_path: None,
+            checksum: "abcdefg".to_string(),
+            merged_checksums: vec![],
+            payload_checksum: None,
+            archived_checksum: None,
+            datetime: Some("2024-05-22T00:17:51+02:00".to_string()),
+            latitude: Some(51.5),
+            longitude: Some(-0.125),
+            people: vec!["[[Alice]]".to_string(), "[[Bob & Co]]".to_string()],
+            albums: vec!["[[Trip]]".to_string()],
+            links: MediaLinks::new(),
+            burst: None,
+            motion_photo_offset: None,
+            generated: None,
+            kind: Some("panorama".to_string()),
+            description: Some("Lunch <by the sea>".to_string()),
+            rating: Some(4),
+            title: Some("Lunch".to_string()),
+            keywords: vec!["food".to_string()],
+            creator: vec!["Jane Doe".to_string()],
+            location: Some("Seefeld".to_string()),
+            city: Some("Zürich".to_string()),
+            state: None,
+            country: Some("Switzerland".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_xmp_from_front_matter() {
+        let xmp = xmp_from_front_matter(&front_matter());
+        assert!(xmp.starts_with(XMP_HEADER));
+        for expected in [
+            r#"exif:DateTimeOriginal="2024-05-22T00:17:51+02:00""#,
+            r#"xmp:CreateDate="2024-05-22T00:17:51+02:00""#,
+            r#"photoshop:DateCreated="2024-05-22T00:17:51+02:00""#,
+            r#"exif:GPSLatitude="51,30.000000N""#,
+            r#"exif:GPSLongitude="0,7.500000W""#,
+            r#"<rdf:li xml:lang="x-default">Lunch &lt;by the sea&gt;</rdf:li>"#,
+            "<rdf:li>Trip</rdf:li>",
+            "<rdf:li>panorama</rdf:li>",
+            "<rdf:li>Albums|Trip</rdf:li>",
+            "<rdf:li>Kind|panorama</rdf:li>",
+            "<rdf:li>Alice</rdf:li>",
+            "<rdf:li>Bob &amp; Co</rdf:li>",
+            r#"xmp:Rating="4""#,
+            r#"<rdf:li xml:lang="x-default">Lunch</rdf:li>"#,
+            "<rdf:li>food</rdf:li>",
+            "<dc:creator>\n    <rdf:Seq>\n     <rdf:li>Jane Doe</rdf:li>",
+            r#"Iptc4xmpCore:Location="Seefeld""#,
+            r#"photoshop:City="Zürich""#,
+            r#"photoshop:Country="Switzerland""#,
+        ] {
+            assert!(xmp.contains(expected), "{expected} missing from {xmp}");
+        }
+    }
+
+    #[test]
+    fn test_xmp_leaves_out_what_is_unknown() {
+        let mut fm = front_matter();
+        fm.datetime = None;
+        fm.latitude = None;
+        fm.people = vec![];
+        fm.albums = vec![];
+        fm.kind = None;
+        fm.description = None;
+        fm.rating = None;
+        fm.title = None;
+        fm.keywords = vec![];
+        let xmp = xmp_from_front_matter(&fm);
+        for absent in [
+            "Date",
+            "GPS",
+            "dc:description",
+            "dc:title",
+            "Rating",
+            "dc:subject",
+            "PersonInImage",
+        ] {
+            assert!(!xmp.contains(absent), "{absent} in {xmp}");
+        }
+    }
+
+    #[test]
+    fn test_sync_xmp_leaves_sidecar_saved_by_another_tool() -> anyhow::Result<()> {
+        let output = MemoryOutput::default();
+        let media = MediaFileInfo::new_for_test();
+        let derived = MediaFileDerivedInfo::new_for_test(Some("2024/photo".to_string()), "jpg");
+        let links = MediaLinks::new();
+        sync_xmp(false, &media, &derived, &[], &links, None, &output)?;
+        let ours = output.bytes("2024/photo.xmp").unwrap_or_default();
+        assert!(ours.starts_with(XMP_HEADER.as_bytes()));
+
+        // Rewritten as ptsync's while it's still ptsync's
+        let albums = ["Trip".to_string()];
+        sync_xmp(false, &media, &derived, &albums, &links, None, &output)?;
+        let updated = output.bytes("2024/photo.xmp").unwrap_or_default();
+        assert!(String::from_utf8_lossy(&updated).contains("Albums|Trip"));
+
+        let theirs = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="XMP Core 6.0.0">"#;
+        output.write(false, "2024/photo.xmp", &mut Cursor::new(theirs))?;
+        sync_xmp(false, &media, &derived, &[], &links, None, &output)?;
+        assert_eq!(output.bytes("2024/photo.xmp"), Some(theirs.to_vec()));
+        Ok(())
+    }
+}
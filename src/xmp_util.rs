@@ -0,0 +1,365 @@
+//! XMP written into a photo, or beside it as a `.xmp` sidecar, by Lightroom,
+//! darktable, digiKam or the phone: its rating, title, keywords and the names
+//! of the faces tagged in it (MWG regions).
+//!
+//! A sidecar in the input, `IMG_1234.jpg.xmp` or `IMG_1234.xmp`, is where those
+//! tools keep edits they don't write into the file, so what it records takes
+//! the place of what's embedded.
+
+use crate::exif_util::embedded_xmp;
+use crate::fs::FileSystem;
+use roxmltree::{Document, Node};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Seek};
+use std::path::Path;
+use tracing::debug;
+
+const NS_RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const NS_XML: &str = "http://www.w3.org/XML/1998/namespace";
+const NS_XMP: &str = "http://ns.adobe.com/xap/1.0/";
+const NS_DC: &str = "http://purl.org/dc/elements/1.1/";
+const NS_MWG_REGIONS: &str = "http://www.metadataworkinggroup.com/schemas/regions/";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all(deserialize = "camelCase", serialize = "camelCase"))]
+pub(crate) struct PsXmpInfo {
+    /// `xmp:Rating`: 1 to 5 stars, 0 for unrated and -1 for rejected.
+    pub(crate) rating: Option<i64>,
+    /// `dc:title`
+    pub(crate) title: Option<String>,
+    /// `dc:subject`
+    #[serde(default)]
+    pub(crate) keywords: Vec<String>,
+    /// Names of the face regions.
+    #[serde(default)]
+    pub(crate) people: Vec<String>,
+}
+
+impl PsXmpInfo {
+    /// This, with each property `over` records in place of this one's.
+    fn overlaid_with(self, over: PsXmpInfo) -> PsXmpInfo {
+        let or_list = |over: Vec<String>, this: Vec<String>| {
+            if over.is_empty() { this } else { over }
+        };
+        PsXmpInfo {
+            rating: over.rating.or(self.rating),
+            title: over.title.or(self.title),
+            keywords: or_list(over.keywords, self.keywords),
+            people: or_list(over.people, self.people),
+        }
+    }
+}
+
+/// What the embedded XMP and the sidecar record together, the sidecar winning.
+pub(crate) fn combine_xmp(
+    embedded: Option<PsXmpInfo>,
+    sidecar: Option<PsXmpInfo>,
+) -> Option<PsXmpInfo> {
+    match (embedded, sidecar) {
+        (Some(embedded), Some(sidecar)) => Some(embedded.overlaid_with(sidecar)),
+        (embedded, sidecar) => sidecar.or(embedded),
+    }
+}
+
+/// XMP embedded in a photo, see [`embedded_xmp`].
+pub(crate) fn parse_xmp_info<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<PsXmpInfo>> {
+    Ok(embedded_xmp(reader)?
+        .as_deref()
+        .and_then(find_xmp_packet)
+        .and_then(parse_xmp))
+}
+
+/// Path of the sidecar of the media file at `path`, if it has one.
+pub(crate) fn detect_xmp_sidecar(path: &String, container: &dyn FileSystem) -> Option<String> {
+    let stem = Path::new(path)
+        .with_extension("")
+        .to_string_lossy()
+        .to_string();
+    [path, &stem]
+        .into_iter()
+        .flat_map(|base| [format!("{base}.xmp"), format!("{base}.XMP")])
+        .find(|candidate| container.exists(candidate))
+}
+
+pub(crate) fn load_xmp_sidecar(path: &String, container: &dyn FileSystem) -> Option<PsXmpInfo> {
+    let mut xmp = String::new();
+    let read_r = container.open(path).map(|mut r| r.read_to_string(&mut xmp));
+    if !matches!(read_r, Ok(Ok(_))) {
+        debug!("Could not read XMP sidecar: {path}");
+        return None;
+    }
+    debug!("  Loaded: {path}");
+    parse_xmp(&xmp)
+}
+
+/// The `x:xmpmeta` element of an XMP packet, without the `<?xpacket?>`
+/// wrapper and padding around it.
+fn find_xmp_packet(xmp: &str) -> Option<&str> {
+    [
+        ("<x:xmpmeta", "</x:xmpmeta>"),
+        ("<x:xapmeta", "</x:xapmeta>"),
+    ]
+    .into_iter()
+    .find_map(|(open, close)| {
+        let start = xmp.find(open)?;
+        let len = xmp[start..].find(close)? + close.len();
+        Some(&xmp[start..start + len])
+    })
+}
+
+/// What an XMP packet records, or `None` when it records nothing ptsync keeps.
+pub(crate) fn parse_xmp(xmp: &str) -> Option<PsXmpInfo> {
+    let doc = Document::parse(xmp)
+        .inspect_err(|e| debug!("Could not parse XMP: {e}"))
+        .ok()?;
+    let mut info = PsXmpInfo::default();
+    // Properties may be spread over several descriptions
+    for description in doc
+        .descendants()
+        .filter(|n| n.has_tag_name((NS_RDF, "Description")))
+        .filter(|n| n.parent().is_some_and(|p| p.has_tag_name((NS_RDF, "RDF"))))
+    {
+        info.rating = info.rating.or_else(|| {
+            // Some tools write a rating as a decimal
+            text_property(description, NS_XMP, "Rating")
+                .and_then(|r| r.parse::<f64>().ok())
+                .map(|r| r.round() as i64)
+        });
+        info.title = info.title.or_else(|| alt_text(description, NS_DC, "title"));
+        info.keywords
+            .extend(list_property(description, NS_DC, "subject"));
+        info.people.extend(face_names(description));
+    }
+    info.keywords.dedup();
+    info.people.dedup();
+    (info != PsXmpInfo::default()).then_some(info)
+}
+
+/// The simple properties `names`, each given by its namespace and name, in
+/// order, `None` for those the packet doesn't have.
+pub(crate) fn simple_properties(xmp: &str, names: &[(&str, &str)]) -> Vec<Option<String>> {
+    let Some(doc) = find_xmp_packet(xmp).and_then(|p| Document::parse(p).ok()) else {
+        return vec![None; names.len()];
+    };
+    let descriptions: Vec<Node> = doc
+        .descendants()
+        .filter(|n| n.has_tag_name((NS_RDF, "Description")))
+        .collect();
+    names
+        .iter()
+        .map(|(ns, name)| {
+            descriptions
+                .iter()
+                .find_map(|d| text_property(*d, ns, name))
+        })
+        .collect()
+}
+
+/// A simple property, written either as an attribute or as an element.
+fn text_property(node: Node, ns: &str, name: &str) -> Option<String> {
+    let value = match node.attribute((ns, name)) {
+        Some(value) => value.to_string(),
+        None => node
+            .children()
+            .find(|c| c.has_tag_name((ns, name)))?
+            .text()?
+            .to_string(),
+    };
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// The items of an array property (`rdf:Bag`, `rdf:Seq` or `rdf:Alt`).
+fn list_property(node: Node, ns: &str, name: &str) -> Vec<String> {
+    let Some(property) = node.children().find(|c| c.has_tag_name((ns, name))) else {
+        return text_property(node, ns, name).into_iter().collect();
+    };
+    property
+        .descendants()
+        .filter(|n| n.has_tag_name((NS_RDF, "li")))
+        .filter_map(|li| li.text())
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// A language alternative, in the default language if there is one.
+fn alt_text(node: Node, ns: &str, name: &str) -> Option<String> {
+    let property = node.children().find(|c| c.has_tag_name((ns, name)))?;
+    let items: Vec<Node> = property
+        .descendants()
+        .filter(|n| n.has_tag_name((NS_RDF, "li")))
+        .collect();
+    items
+        .iter()
+        .find(|li| li.attribute((NS_XML, "lang")) == Some("x-default"))
+        .or(items.first())
+        .and_then(|li| li.text())
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+/// Names of the face regions in `mwg-rs:Regions`. Each region's fields are
+/// attributes or elements of its `rdf:li`, or of an `rdf:Description` in it.
+fn face_names(node: Node) -> Vec<String> {
+    let Some(regions) = node
+        .children()
+        .find(|c| c.has_tag_name((NS_MWG_REGIONS, "Regions")))
+    else {
+        return vec![];
+    };
+    regions
+        .descendants()
+        .filter(|n| n.is_element())
+        .filter(|region| text_property(*region, NS_MWG_REGIONS, "Type").is_none_or(|t| t == "Face"))
+        .filter_map(|region| text_property(region, NS_MWG_REGIONS, "Name"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::OsFileSystem;
+    use anyhow::anyhow;
+    use std::fs::File;
+
+    #[test]
+    fn test_parse_lightroom_sidecar() -> anyhow::Result<()> {
+        let c = OsFileSystem::new("test/xmp");
+        let path = detect_xmp_sidecar(&"IMG_0010.jpg".to_string(), &c)
+            .ok_or_else(|| anyhow!("No sidecar found"))?;
+        assert_eq!(path, "IMG_0010.xmp");
+        let info = load_xmp_sidecar(&path, &c).ok_or_else(|| anyhow!("Nothing parsed"))?;
+        assert_eq!(
+            info,
+            PsXmpInfo {
+                rating: Some(4),
+                title: Some("Sunset over the bay".to_string()),
+                keywords: vec!["beach".to_string(), "sunset".to_string()],
+                people: vec!["Alice".to_string(), "Bob".to_string()],
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_embedded_xmp() -> anyhow::Result<()> {
+        let mut file = File::open("test/xmp/IMG_0010.jpg")?;
+        let info = parse_xmp_info(&mut file)?.ok_or_else(|| anyhow!("Nothing parsed"))?;
+        assert_eq!(info.rating, Some(2));
+        assert_eq!(info.title, Some("Bay".to_string()));
+        assert_eq!(info.people, vec!["Carol".to_string()]);
+
+        // HEIC keeps XMP in a `mime` item, WebP in an `XMP ` chunk
+        for (path, title, rating) in [
+            ("test/xmp/photo.heic", "Lighthouse", 5),
+            ("test/xmp/photo.webp", "Harbour", 3),
+        ] {
+            let info = parse_xmp_info(&mut File::open(path)?)?
+                .ok_or_else(|| anyhow!("Nothing parsed from {path}"))?;
+            assert_eq!(info.title.as_deref(), Some(title));
+            assert_eq!(info.rating, Some(rating));
+        }
+
+        // No XMP in the file, or none that records anything kept
+        assert_eq!(
+            parse_xmp_info(&mut File::open("test/Canon_40D.jpg")?)?,
+            None
+        );
+        let mut pano = File::open("test/kinds/IMG_0007.jpg")?;
+        assert_eq!(parse_xmp_info(&mut pano)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_simple_properties() {
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+            <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+            <rdf:Description
+                xmlns:GPano="http://ns.google.com/photos/1.0/panorama/"
+                xmlns:GCamera="http://ns.google.com/photos/1.0/camera/"
+                GPano:ProjectionType="equirectangular">
+            <GCamera:SpecialTypeID>com.google.SpecialType-PORTRAIT</GCamera:SpecialTypeID>
+            </rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        let names = [
+            (
+                "http://ns.google.com/photos/1.0/panorama/",
+                "ProjectionType",
+            ),
+            ("http://ns.google.com/photos/1.0/camera/", "SpecialTypeID"),
+            (
+                "http://ns.google.com/photos/1.0/panorama/",
+                "UsePanoramaViewer",
+            ),
+        ];
+        assert_eq!(
+            simple_properties(xmp, &names),
+            vec![
+                Some("equirectangular".to_string()),
+                Some("com.google.SpecialType-PORTRAIT".to_string()),
+                None,
+            ]
+        );
+        assert_eq!(simple_properties("not xmp", &names), vec![None; 3]);
+    }
+
+    #[test]
+    fn test_sidecar_takes_the_place_of_embedded() {
+        let embedded = PsXmpInfo {
+            rating: Some(2),
+            title: Some("Bay".to_string()),
+            keywords: vec!["sea".to_string()],
+            people: vec![],
+        };
+        let sidecar = PsXmpInfo {
+            rating: Some(5),
+            title: None,
+            keywords: vec![],
+            people: vec!["Alice".to_string()],
+        };
+        assert_eq!(
+            combine_xmp(Some(embedded.clone()), Some(sidecar)),
+            Some(PsXmpInfo {
+                rating: Some(5),
+                title: Some("Bay".to_string()),
+                keywords: vec!["sea".to_string()],
+                people: vec!["Alice".to_string()],
+            })
+        );
+        assert_eq!(combine_xmp(Some(embedded.clone()), None), Some(embedded));
+    }
+
+    #[test]
+    fn test_parse_element_and_attribute_forms() {
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/">
+   <xmp:Rating>3.0</xmp:Rating>
+  </rdf:Description>
+  <rdf:Description rdf:about=""
+    xmlns:mwg-rs="http://www.metadataworkinggroup.com/schemas/regions/">
+   <mwg-rs:Regions rdf:parseType="Resource">
+    <mwg-rs:RegionList>
+     <rdf:Bag>
+      <rdf:li rdf:parseType="Resource">
+       <mwg-rs:Name>Dave</mwg-rs:Name>
+       <mwg-rs:Type>Face</mwg-rs:Type>
+      </rdf:li>
+      <rdf:li rdf:parseType="Resource">
+       <mwg-rs:Name>Rex</mwg-rs:Name>
+       <mwg-rs:Type>Pet</mwg-rs:Type>
+      </rdf:li>
+     </rdf:Bag>
+    </mwg-rs:RegionList>
+   </mwg-rs:Regions>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+        let info = parse_xmp(xmp);
+        assert_eq!(info.as_ref().and_then(|i| i.rating), Some(3));
+        assert_eq!(
+            info.map(|i| i.people).unwrap_or_default(),
+            vec!["Dave".to_string()]
+        );
+    }
+}